@@ -0,0 +1,27 @@
+extern crate ingots;
+extern crate ingots_cgi;
+
+
+struct HelloWorld;
+
+impl ingots::Ingot for HelloWorld {
+    fn handle(&self, context: &mut dyn ingots::http::Context) {
+        let _ = context.response().set_header("Content-Type", "text/plain".into());
+
+        let path_info = context.request().path_info().into_owned();
+        let _ = writeln!(context.response(), "path info: {}", path_info);
+
+        let query = context.request().query_string().map(|s| s.into_owned());
+        let _ = writeln!(context.response(), "query: {:?}", query);
+
+        let headers = context.request().headers().clone();
+        let _ = writeln!(context.response(), "headers: {:?}", headers);
+
+        let remote_addr = context.remote_addr();
+        let _ = writeln!(context.response(), "remote addr: {:?}", remote_addr);
+    }
+}
+
+fn main() {
+    ingots_cgi::run(HelloWorld).unwrap();
+}
@@ -0,0 +1,702 @@
+use ingots;
+use ingots::http::{self, Buffering, HeaderMap, HeadersSentError, StatusCode};
+use ingots::http::params;
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+
+
+/// Request context for a request passed to the application as CGI meta-variables (RFC 3875).
+///
+/// This is shared by the gateway protocols derived from CGI, which all describe the request with the same variables.
+/// The request body is read from the stream, and the response is written back to it as a CGI response: a `Status`
+/// header and the other response headers, followed by the body.
+///
+/// If the `CONTENT_LENGTH` variable is set, no more than that many bytes of the body are read from the stream.
+/// Otherwise the body extends to the end of the stream.
+pub struct Context<S> {
+    stream: S,
+    /// Whether to send an HTTP status line instead of a `Status` header.
+    non_parsed_headers: bool,
+    /// Number of body bytes left to read, if the length is known.
+    remaining: Option<u64>,
+    remote_addr: SocketAddr,
+    server_addr: SocketAddr,
+    server_name: String,
+    method: String,
+    context_path: String,
+    path_info: String,
+    query_string: Option<String>,
+    secure: bool,
+    version: http::Version,
+    request_headers: HeaderMap,
+    status: StatusCode,
+    response_headers: HeaderMap,
+    headers_sent: bool,
+}
+
+impl<S: Read + Write> Context<S> {
+    /// Create a context from the meta-variables of a request, reading the body from and writing the response to the
+    /// given stream.
+    ///
+    /// Variables that CGI leaves optional fall back to a default when they are missing. If a required variable is
+    /// missing, or any variable is invalid, an error is returned with the stream, so that an error response can be sent
+    /// with `Error::respond`.
+    pub fn new<I: IntoIterator<Item = (String, String)>>(vars: I, stream: S) -> Result<Self, Error<S>> {
+        let vars: Vec<(String, String)> = vars.into_iter().collect();
+
+        match Vars(&vars).parse() {
+            Ok(request) => Ok(Context {
+                stream,
+                non_parsed_headers: false,
+                remaining: request.content_length,
+                remote_addr: request.remote_addr,
+                server_addr: request.server_addr,
+                server_name: request.server_name,
+                method: request.method,
+                context_path: request.context_path,
+                path_info: request.path_info,
+                query_string: request.query_string,
+                secure: request.secure,
+                version: request.version,
+                request_headers: request.headers,
+                status: 200,
+                response_headers: HeaderMap::new(),
+                headers_sent: false,
+            }),
+            Err((status, message)) => Err(Error {
+                status,
+                message,
+                stream,
+            }),
+        }
+    }
+
+    /// Set whether to send the response as a complete HTTP response, starting with a status line, instead of as CGI
+    /// response headers. This is what CGI calls a non-parsed header (NPH) response.
+    pub fn non_parsed_headers(mut self, enabled: bool) -> Self {
+        self.non_parsed_headers = enabled;
+        self
+    }
+
+    /// Complete the response, sending the headers if nothing has been written yet.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.send_headers()?;
+        self.stream.flush()
+    }
+
+    /// Get the stream the request is read from and the response written to.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Send the status and headers, if they have not been sent already.
+    fn send_headers(&mut self) -> io::Result<()> {
+        if self.headers_sent {
+            return Ok(());
+        }
+        self.headers_sent = true;
+
+        write_head(&mut self.stream, self.non_parsed_headers, self.status, &self.response_headers)
+    }
+
+    fn check_headers_sent(&self) -> Result<(), HeadersSentError> {
+        if self.headers_sent {
+            Err(HeadersSentError)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Write the head of a response: the status and headers, as CGI response headers or an HTTP status line and headers.
+pub(crate) fn write_head<W: Write>(stream: &mut W, non_parsed_headers: bool, status: StatusCode, headers: &HeaderMap)
+    -> io::Result<()>
+{
+    let reason = http::reason_phrase(status).unwrap_or("");
+    let mut head = if non_parsed_headers {
+        // The web server translates the response for the client, so the version is that of the gateway itself.
+        format!("HTTP/1.1 {} {}\r\n", status, reason)
+    } else {
+        format!("Status: {} {}\r\n", status, reason)
+    };
+
+    for (name, value) in headers.iter() {
+        // A line break would allow the value to inject additional headers.
+        if name.contains(['\r', '\n', ':']) || value.contains(['\r', '\n']) {
+            continue;
+        }
+
+        head.push_str(name);
+        head.push_str(": ");
+        head.push_str(value);
+        head.push_str("\r\n");
+    }
+
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes())
+}
+
+
+/// Request properties parsed from the meta-variables.
+struct ParsedRequest {
+    content_length: Option<u64>,
+    remote_addr: SocketAddr,
+    server_addr: SocketAddr,
+    server_name: String,
+    method: String,
+    context_path: String,
+    path_info: String,
+    query_string: Option<String>,
+    secure: bool,
+    version: http::Version,
+    headers: HeaderMap,
+}
+
+/// Meta-variables of a request, in the order they were received, so that request headers keep their order.
+struct Vars<'a>(&'a [(String, String)]);
+
+impl<'a> Vars<'a> {
+    /// Get a variable by name. If it was received more than once, the last value is used.
+    fn get(&self, name: &str) -> Option<&'a str> {
+        self.0.iter().rev().find(|var| var.0 == name).map(|var| var.1.as_str())
+    }
+
+    /// Get a variable that CGI requires the server to set. The server is at fault if it is missing.
+    fn require(&self, name: &str) -> Result<&'a str, (StatusCode, String)> {
+        self.get(name).ok_or_else(|| (500, format!("missing {} variable", name)))
+    }
+
+    /// Parse the request, returning the status to respond with and a message if the variables are invalid.
+    ///
+    /// Variables describing the request itself are checked as the client sent them, and answered with 400 if they are
+    /// invalid. The others are set by the server, which is misconfigured if they are invalid, so they are answered
+    /// with 500.
+    fn parse(&self) -> Result<ParsedRequest, (StatusCode, String)> {
+        let method = self.require("REQUEST_METHOD")?;
+        if !is_token(method) {
+            return Err((400, format!("invalid request method {:?}", method)));
+        }
+
+        let content_length = match self.get("CONTENT_LENGTH").map(str::trim) {
+            None | Some("") => None,
+            Some(length) => match length.parse() {
+                Ok(length) => Some(length),
+                Err(_) => return Err((400, format!("invalid content length {:?}", length))),
+            },
+        };
+
+        let remote_addr = SocketAddr::new(
+            self.ip_addr("REMOTE_ADDR", self.require("REMOTE_ADDR")?)?,
+            self.port("REMOTE_PORT")?.unwrap_or(0),
+        );
+        let server_addr = SocketAddr::new(
+            match self.get("SERVER_ADDR") {
+                Some(addr) => self.ip_addr("SERVER_ADDR", addr)?,
+                None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            },
+            self.port("SERVER_PORT")?.ok_or((500, String::from("missing SERVER_PORT variable")))?,
+        );
+
+        let (context_path, path_info) = self.paths();
+
+        // `QUERY_STRING` is required by CGI, but commonly left out when there is no query.
+        let query_string = self.get("QUERY_STRING").map(String::from).or_else(|| {
+            self.get("REQUEST_URI").and_then(|uri| uri.find('?').map(|index| uri[index+1..].to_string()))
+        });
+
+        let secure = self.get("REQUEST_SCHEME").map(|scheme| scheme.eq_ignore_ascii_case("https")).unwrap_or(false)
+            || self.get("HTTPS").map(|https| https.eq_ignore_ascii_case("on")).unwrap_or(false);
+
+        let version = self.get("SERVER_PROTOCOL")
+            .and_then(http::Version::parse)
+            .unwrap_or(http::Version::Http11);
+
+        let headers = self.0.iter()
+            .filter_map(|(name, value)| header_name(name).map(|name| (name, value.as_str())))
+            .collect();
+
+        Ok(ParsedRequest {
+            content_length,
+            remote_addr,
+            server_addr,
+            server_name: self.get("SERVER_NAME").unwrap_or_default().to_owned(),
+            method: method.to_owned(),
+            context_path,
+            path_info,
+            query_string,
+            secure,
+            version,
+            headers,
+        })
+    }
+
+    /// Get the context path and path info of the request.
+    ///
+    /// Servers that do not set `PATH_INFO`, such as nginx with its default parameters, set `SCRIPT_NAME` to the whole
+    /// path instead, so the path is then taken from `REQUEST_URI`, which is not defined by CGI but set by most servers.
+    /// Unlike `PATH_INFO`, it is sent as the client sent it, so it is percent-decoded.
+    fn paths(&self) -> (String, String) {
+        let (context_path, path_info) = match (self.get("PATH_INFO"), self.get("REQUEST_URI")) {
+            (Some(path_info), _) => (self.get("SCRIPT_NAME").unwrap_or_default(), Cow::Borrowed(path_info)),
+            (None, Some(uri)) => ("", params::decode_path(uri.split('?').next().unwrap_or_default())),
+            (None, None) => ("", Cow::Borrowed(self.get("SCRIPT_NAME").unwrap_or_default())),
+        };
+
+        let context_path = context_path.trim_end_matches('/');
+        if context_path.is_empty() && path_info.is_empty() {
+            return (String::new(), String::from("/"));
+        }
+
+        (context_path.to_owned(), path_info.into_owned())
+    }
+
+    /// Parse an IP address variable.
+    ///
+    /// Addresses are never resolved, since a host name would mean the server is misconfigured. Clients connected to
+    /// the server over a Unix socket, which nginx reports as `unix:`, have an unspecified address.
+    fn ip_addr(&self, name: &str, addr: &str) -> Result<IpAddr, (StatusCode, String)> {
+        if addr.starts_with("unix:") {
+            return Ok(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        }
+
+        IpAddr::from_str(addr).map_err(|_| (500, format!("invalid {} variable {:?}", name, addr)))
+    }
+
+    /// Parse an optional port number variable.
+    fn port(&self, name: &str) -> Result<Option<u16>, (StatusCode, String)> {
+        match self.get(name).map(str::trim) {
+            None | Some("") => Ok(None),
+            Some(port) => port.parse().map(Some).map_err(|_| (500, format!("invalid {} variable {:?}", name, port))),
+        }
+    }
+}
+
+/// Check if a string is a valid HTTP token, such as a method.
+fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Get the HTTP header name for a CGI meta-variable, if it represents a header.
+///
+/// Request headers are passed as `HTTP_*` variables, except for the two content headers which CGI defines separately.
+fn header_name(param: &str) -> Option<String> {
+    let name = match param {
+        "CONTENT_TYPE" | "CONTENT_LENGTH" => param,
+        _ if param.starts_with("HTTP_") => &param[5..],
+        _ => return None,
+    };
+
+    let words: Vec<String> = name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect(),
+                None => String::new(),
+            }
+        })
+        .collect();
+
+    Some(words.join("-"))
+}
+
+impl<S: Read + Write + Send> ingots::http::Context for Context<S> {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    fn request(&self) -> &dyn ingots::http::Request {
+        self
+    }
+
+    fn request_mut(&mut self) -> &mut dyn ingots::http::Request {
+        self
+    }
+
+    fn response(&mut self) -> &mut dyn ingots::http::Response {
+        self
+    }
+
+    /// A gateway has no way to hand the client connection over to the application; the web server in front owns it.
+    fn upgrade(&mut self) -> Result<Box<dyn ingots::http::Upgraded>, ingots::http::UpgradeError> {
+        Err(ingots::http::UpgradeError::Unsupported)
+    }
+}
+
+impl<S: Read + Write + Send> ingots::http::Request for Context<S> {
+    fn method(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.method)
+    }
+
+    fn context_path(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.context_path)
+    }
+
+    fn path_info(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.path_info)
+    }
+
+    fn query_string(&self) -> Option<Cow<'_, str>> {
+        self.query_string.as_ref().map(|s| Cow::Borrowed(s.as_str()))
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.request_headers
+    }
+
+    fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    fn version(&self) -> http::Version {
+        self.version
+    }
+}
+
+impl<S: Read + Write> io::Read for Context<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = match self.remaining {
+            Some(remaining) => remaining.min(buf.len() as u64) as usize,
+            None => buf.len(),
+        };
+
+        // An empty read can block on a socket until more data arrives, even though none of it would be returned.
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let read = self.stream.read(&mut buf[..len])?;
+        if let Some(ref mut remaining) = self.remaining {
+            *remaining -= read as u64;
+        }
+
+        Ok(read)
+    }
+}
+
+impl<S: Read + Write + Send> ingots::http::Response for Context<S> {
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    fn set_status(&mut self, status: StatusCode) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.status = status;
+        Ok(())
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.response_headers
+    }
+
+    fn set_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.response_headers.insert(name, value);
+        Ok(())
+    }
+
+    fn append_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.response_headers.append(name, value);
+        Ok(())
+    }
+
+    fn remove_header(&mut self, name: &str) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.response_headers.remove(name);
+        Ok(())
+    }
+
+    fn buffering(&self) -> Buffering {
+        Buffering::Off
+    }
+
+    fn headers_sent(&self) -> bool {
+        self.headers_sent
+    }
+}
+
+impl<S: Read + Write> io::Write for Context<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send_headers()?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_headers()?;
+        self.stream.flush()
+    }
+}
+
+
+/// Error returned when the meta-variables of a request are missing or invalid.
+///
+/// The error keeps the stream of the request, so that the gateway can still respond to it.
+pub struct Error<S> {
+    status: StatusCode,
+    message: String,
+    stream: S,
+}
+
+impl<S: Write> Error<S> {
+    /// Get the status to respond with: 400 if the request itself is invalid, or 500 if the server that sent the
+    /// variables is misconfigured.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Send an empty response with the error status, as CGI response headers or as a complete HTTP response.
+    pub fn respond(mut self, non_parsed_headers: bool) -> io::Result<()> {
+        write_head(&mut self.stream, non_parsed_headers, self.status, &HeaderMap::new())?;
+        self.stream.flush()
+    }
+}
+
+impl<S> fmt::Debug for Error<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Error")
+            .field("status", &self.status)
+            .field("message", &self.message)
+            .finish()
+    }
+}
+
+impl<S> fmt::Display for Error<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid request: {}", self.message)
+    }
+}
+
+impl<S> error::Error for Error<S> {}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    /// Variables for a minimal valid request.
+    const REQUIRED: &[(&str, &str)] = &[("REQUEST_METHOD", "GET"), ("REMOTE_ADDR", "127.0.0.1"), ("SERVER_PORT", "80")];
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect()
+    }
+
+    /// Parse the required variables, with the given variables added or replacing them.
+    fn parse(extra: &[(&str, &str)]) -> Result<ParsedRequest, (StatusCode, String)> {
+        let required = REQUIRED.iter().filter(|var| !extra.iter().any(|extra| extra.0 == var.0));
+        let vars = vars(&required.chain(extra).cloned().collect::<Vec<_>>());
+
+        Vars(&vars).parse()
+    }
+
+    /// Parse the required variables without the given one.
+    fn parse_without(name: &str) -> Result<ParsedRequest, (StatusCode, String)> {
+        let vars = vars(&REQUIRED.iter().filter(|var| var.0 != name).cloned().collect::<Vec<_>>());
+
+        Vars(&vars).parse()
+    }
+
+    fn status(result: Result<ParsedRequest, (StatusCode, String)>) -> StatusCode {
+        match result {
+            Ok(_) => panic!("expected the variables to be rejected"),
+            Err((status, _)) => status,
+        }
+    }
+
+    fn paths(extra: &[(&str, &str)]) -> (String, String) {
+        let vars = vars(extra);
+
+        Vars(&vars).paths()
+    }
+
+    #[test]
+    fn defaults() {
+        let request = parse(&[]).unwrap();
+
+        assert_eq!(request.content_length, None);
+        assert_eq!(request.remote_addr, SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0));
+        assert_eq!(request.server_addr, SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 80));
+        assert_eq!(request.server_name, "");
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.context_path, "");
+        assert_eq!(request.path_info, "/");
+        assert_eq!(request.query_string, None);
+        assert!(!request.secure);
+        assert_eq!(request.version, http::Version::Http11);
+        assert!(request.headers.is_empty());
+    }
+
+    #[test]
+    fn method() {
+        assert_eq!(parse(&[("REQUEST_METHOD", "PROPFIND")]).unwrap().method, "PROPFIND");
+        assert_eq!(status(parse_without("REQUEST_METHOD")), 500);
+        assert_eq!(status(parse(&[("REQUEST_METHOD", "")])), 400);
+        assert_eq!(status(parse(&[("REQUEST_METHOD", "GET /")])), 400);
+        assert_eq!(status(parse(&[("REQUEST_METHOD", "GET\r\n")])), 400);
+    }
+
+    #[test]
+    fn content_length() {
+        assert_eq!(parse(&[("CONTENT_LENGTH", "")]).unwrap().content_length, None);
+        assert_eq!(parse(&[("CONTENT_LENGTH", " 42 ")]).unwrap().content_length, Some(42));
+        assert_eq!(status(parse(&[("CONTENT_LENGTH", "-1")])), 400);
+        assert_eq!(status(parse(&[("CONTENT_LENGTH", "ten")])), 400);
+        assert_eq!(status(parse(&[("CONTENT_LENGTH", "18446744073709551616")])), 400);
+    }
+
+    #[test]
+    fn remote_addr() {
+        let request = parse(&[("REMOTE_ADDR", "::1"), ("REMOTE_PORT", "54321")]).unwrap();
+        assert_eq!(request.remote_addr, SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 54321));
+
+        assert_eq!(status(parse_without("REMOTE_ADDR")), 500);
+        assert_eq!(status(parse(&[("REMOTE_ADDR", "")])), 500);
+        assert_eq!(status(parse(&[("REMOTE_ADDR", "127.0.0")])), 500);
+        assert_eq!(status(parse(&[("REMOTE_PORT", "port")])), 500);
+    }
+
+    #[test]
+    fn remote_addr_host_name() {
+        // Resolving would succeed for `localhost`, so this shows that the name is not looked up.
+        assert_eq!(status(parse(&[("REMOTE_ADDR", "localhost")])), 500);
+        assert_eq!(status(parse(&[("REMOTE_ADDR", "example.com")])), 500);
+    }
+
+    #[test]
+    fn remote_addr_unix() {
+        let request = parse(&[("REMOTE_ADDR", "unix:")]).unwrap();
+        assert_eq!(request.remote_addr, SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+
+        let request = parse(&[("REMOTE_ADDR", "unix:/run/nginx.sock")]).unwrap();
+        assert_eq!(request.remote_addr.ip(), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    }
+
+    #[test]
+    fn server_addr() {
+        let request = parse(&[("SERVER_ADDR", "10.0.0.1"), ("SERVER_PORT", " 8080 "), ("SERVER_NAME", "example.com")])
+            .unwrap();
+        assert_eq!(request.server_addr, SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 8080));
+        assert_eq!(request.server_name, "example.com");
+
+        assert_eq!(status(parse(&[("SERVER_ADDR", "example.com")])), 500);
+    }
+
+    #[test]
+    fn server_port() {
+        assert_eq!(status(parse_without("SERVER_PORT")), 500);
+        assert_eq!(status(parse(&[("SERVER_PORT", "")])), 500);
+        assert_eq!(status(parse(&[("SERVER_PORT", "http")])), 500);
+        assert_eq!(status(parse(&[("SERVER_PORT", "65536")])), 500);
+        assert_eq!(status(parse(&[("SERVER_PORT", "-80")])), 500);
+    }
+
+    #[test]
+    fn paths_from_path_info() {
+        let expected = (String::from("/app"), String::from("/a b"));
+        assert_eq!(paths(&[("SCRIPT_NAME", "/app"), ("PATH_INFO", "/a b")]), expected);
+        assert_eq!(paths(&[("SCRIPT_NAME", "/app/"), ("PATH_INFO", "/a b")]), expected);
+
+        // `PATH_INFO` is already decoded by the server, and takes precedence over `REQUEST_URI`.
+        let vars = [("SCRIPT_NAME", "/app"), ("PATH_INFO", "/a%20b"), ("REQUEST_URI", "/app/a%2520b")];
+        assert_eq!(paths(&vars), (String::from("/app"), String::from("/a%20b")));
+
+        assert_eq!(paths(&[("PATH_INFO", "/a")]), (String::new(), String::from("/a")));
+        assert_eq!(paths(&[("SCRIPT_NAME", "/app"), ("PATH_INFO", "")]), (String::from("/app"), String::new()));
+        assert_eq!(paths(&[("SCRIPT_NAME", "/"), ("PATH_INFO", "")]), (String::new(), String::from("/")));
+    }
+
+    #[test]
+    fn paths_from_request_uri() {
+        // As sent by nginx, with `SCRIPT_NAME` set to the whole path.
+        let vars = [("SCRIPT_NAME", "/a b+c"), ("REQUEST_URI", "/a%20b+c?d=e%20f")];
+        assert_eq!(paths(&vars), (String::new(), String::from("/a b+c")));
+
+        assert_eq!(paths(&[("REQUEST_URI", "/caf%C3%A9")]), (String::new(), String::from("/café")));
+        assert_eq!(paths(&[("REQUEST_URI", "/100%")]), (String::new(), String::from("/100%")));
+        assert_eq!(paths(&[("REQUEST_URI", "?a=b")]), (String::new(), String::from("/")));
+    }
+
+    #[test]
+    fn paths_from_script_name() {
+        assert_eq!(paths(&[("SCRIPT_NAME", "/app/page")]), (String::new(), String::from("/app/page")));
+        assert_eq!(paths(&[]), (String::new(), String::from("/")));
+    }
+
+    #[test]
+    fn query_string() {
+        let vars = [("QUERY_STRING", "a=b"), ("REQUEST_URI", "/?c=d")];
+        assert_eq!(parse(&vars).unwrap().query_string.as_deref(), Some("a=b"));
+        assert_eq!(parse(&[("QUERY_STRING", "")]).unwrap().query_string.as_deref(), Some(""));
+        assert_eq!(parse(&[("REQUEST_URI", "/?c=d")]).unwrap().query_string.as_deref(), Some("c=d"));
+        assert_eq!(parse(&[("REQUEST_URI", "/")]).unwrap().query_string, None);
+    }
+
+    #[test]
+    fn secure() {
+        assert!(parse(&[("REQUEST_SCHEME", "HTTPS")]).unwrap().secure);
+        assert!(parse(&[("HTTPS", "on")]).unwrap().secure);
+        assert!(!parse(&[("REQUEST_SCHEME", "http"), ("HTTPS", "off")]).unwrap().secure);
+    }
+
+    #[test]
+    fn headers_in_order() {
+        let request = parse(&[
+            ("HTTP_X_SECOND", "2"),
+            ("CONTENT_TYPE", "text/plain"),
+            ("HTTP_ACCEPT_LANGUAGE", "en"),
+            ("HTTPS", "on"),
+        ]).unwrap();
+        let headers: Vec<_> = request.headers.iter().collect();
+
+        assert_eq!(headers, [("X-Second", "2"), ("Content-Type", "text/plain"), ("Accept-Language", "en")]);
+    }
+
+    #[test]
+    fn last_value_wins() {
+        assert_eq!(parse(&[("REQUEST_METHOD", "GET"), ("REQUEST_METHOD", "POST")]).unwrap().method, "POST");
+    }
+
+    #[test]
+    fn invalid_request_response() {
+        let mut stream = io::Cursor::new(Vec::new());
+        let error = match Context::new(vars(&[("REQUEST_METHOD", "GET")]), &mut stream) {
+            Ok(_) => panic!("expected the variables to be rejected"),
+            Err(error) => error,
+        };
+        assert_eq!(error.status(), 500);
+        assert_eq!(error.to_string(), "invalid request: missing REMOTE_ADDR variable");
+
+        error.respond(false).unwrap();
+        assert_eq!(stream.into_inner(), b"Status: 500 Internal Server Error\r\n\r\n");
+
+        let mut stream = io::Cursor::new(Vec::new());
+        let error = match Context::new(vars(&[("REQUEST_METHOD", "GET /")]), &mut stream) {
+            Ok(_) => panic!("expected the variables to be rejected"),
+            Err(error) => error,
+        };
+
+        error.respond(true).unwrap();
+        assert_eq!(stream.into_inner(), b"HTTP/1.1 400 Bad Request\r\n\r\n");
+    }
+}
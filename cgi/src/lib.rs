@@ -0,0 +1,85 @@
+//! Running an ingot as a CGI program (RFC 3875).
+//!
+//! A CGI program is started by the web server for every request. The request is described by meta-variables in the
+//! environment, with the body on standard input, and the response is written to standard output.
+//!
+//! The mapping from meta-variables to a request context is shared with the other gateway adapters through `Context`,
+//! and the servers for the protocols that keep running between requests share their plumbing through `server`.
+extern crate ingots;
+#[macro_use]
+extern crate log;
+extern crate signal_hook;
+
+pub mod context;
+#[cfg(unix)]
+pub mod server;
+
+pub use context::Context;
+
+use ingots::Ingot;
+use ingots::http::Response;
+use std::env;
+use std::io::{self, Read, Write};
+
+
+/// Handle the request given to the current process with an ingot.
+///
+/// The ingot is started before the request and stopped afterwards. If the request is invalid or the ingot fails to
+/// start, an error response is sent and the error is returned.
+pub fn run<I: Ingot>(mut ingot: I) -> io::Result<()> {
+    let vars = env::vars_os().map(|(name, value)| {
+        (name.to_string_lossy().into_owned(), value.to_string_lossy().into_owned())
+    });
+    let mut context = match Context::new(vars, Stdio::default()) {
+        Ok(context) => context,
+        Err(e) => {
+            let message = e.to_string();
+            e.respond(false)?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+    };
+
+    if let Err(e) = ingot.start() {
+        let _ = context.set_status(500);
+        context.finish()?;
+        return Err(io::Error::other(e));
+    }
+
+    ingot.handle(&mut context);
+    let result = context.finish();
+
+    ingot.stop();
+    result
+}
+
+
+/// Standard input and output of the process, as a single stream.
+struct Stdio {
+    stdin: io::Stdin,
+    stdout: io::Stdout,
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self {
+            stdin: io::stdin(),
+            stdout: io::stdout(),
+        }
+    }
+}
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdin.read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdout.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+}
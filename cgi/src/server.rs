@@ -0,0 +1,212 @@
+//! Running an ingot as a long-lived gateway server, for the protocols that keep a process running between requests.
+//!
+//! `start` puts an ingot into service and drains it on shutdown, for every such server. `Server` also accepts
+//! connections and handles them on a pool of threads, for protocols such as SCGI and uwsgi that open a connection for
+//! each request; those only differ in how the meta-variables are framed, which is up to their `Protocol`.
+use context::{write_head, Context};
+use ingots::Ingot;
+use ingots::blocking::BlockingPool;
+use ingots::http::{HeaderMap, StatusCode};
+use ingots::lifecycle::Lifecycle;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+
+/// Start an ingot and install the shutdown signal handlers.
+///
+/// On SIGTERM or SIGINT, the ingot stops accepting requests, requests in progress are given up to `drain_timeout` to
+/// complete, and the process exits once the ingot has been stopped.
+pub fn start<I: Ingot + 'static>(ingot: &Arc<Lifecycle<I>>, drain_timeout: Duration) -> io::Result<()> {
+    ingot.start().map_err(io::Error::other)?;
+
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    let ingot = ingot.clone();
+
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            if !ingot.shutdown(drain_timeout) {
+                warn!("{} requests still in progress after drain timeout", ingot.in_flight());
+            }
+            process::exit(0);
+        }
+    });
+
+    Ok(())
+}
+
+
+/// How a protocol with a connection per request frames the request at the start of the connection.
+pub trait Protocol: 'static {
+    /// Whether responses start with an HTTP status line instead of a `Status` header.
+    const NON_PARSED_HEADERS: bool;
+
+    /// Read the meta-variables of a request, leaving the body to be read from the stream afterwards.
+    ///
+    /// If the request cannot be handled, returns the status to answer it with instead.
+    fn read_request<R: Read>(stream: &mut R) -> Result<Vec<(String, String)>, StatusCode>;
+}
+
+/// Wraps a Rust ingot in a server for a protocol with a connection per request.
+///
+/// Connections are handled on a fixed number of threads. Connections accepted while every thread is busy wait in a
+/// queue of limited size, and are answered with `503 Service Unavailable` once it is full. The ingot is started when
+/// the server begins listening, and drained on shutdown as described for `start`.
+pub struct Server<P: Protocol, I: Ingot> {
+    ingot: Arc<Lifecycle<I>>,
+    threads: usize,
+    max_queued: usize,
+    timeout: Option<Duration>,
+    drain_timeout: Duration,
+    protocol: PhantomData<P>,
+}
+
+impl<P: Protocol, I: Ingot + 'static> Server<P, I> {
+    pub fn new(ingot: I) -> Self {
+        Server {
+            ingot: Arc::new(Lifecycle::new(ingot)),
+            threads: 8,
+            max_queued: 32,
+            timeout: Some(Duration::from_secs(30)),
+            drain_timeout: Duration::from_secs(30),
+            protocol: PhantomData,
+        }
+    }
+
+    /// Set the number of threads handling requests. Defaults to 8.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Set how many connections may wait for a thread when every thread is busy. Defaults to 32.
+    pub fn max_queued(mut self, max_queued: usize) -> Self {
+        self.max_queued = max_queued;
+        self
+    }
+
+    /// Set how long reading from or writing to a connection may block, or `None` to wait indefinitely. Defaults to 30
+    /// seconds.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set how long to wait for requests in progress to complete when shutting down.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Listen for requests over a UNIX socket created at the given path, which must not exist yet.
+    ///
+    /// Returns an error if the socket cannot be bound or the ingot fails to start.
+    pub fn listen_unix<A: AsRef<Path>>(&self, path: A) -> io::Result<()> {
+        let listener = UnixListener::bind(path)?;
+        start(&self.ingot, self.drain_timeout)?;
+        let pool = BlockingPool::new(self.threads, self.max_queued);
+
+        for stream in listener.incoming() {
+            self.spawn(&pool, stream);
+        }
+
+        Ok(())
+    }
+
+    /// Listen for requests over a TCP socket.
+    ///
+    /// Returns an error if the socket cannot be bound or the ingot fails to start.
+    pub fn listen_tcp<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        start(&self.ingot, self.drain_timeout)?;
+        let pool = BlockingPool::new(self.threads, self.max_queued);
+
+        for stream in listener.incoming() {
+            self.spawn(&pool, stream);
+        }
+
+        Ok(())
+    }
+
+    /// Handle an accepted connection on the pool, or turn it away if the pool's queue is full.
+    fn spawn<S: Connection>(&self, pool: &BlockingPool, stream: io::Result<S>) {
+        // Errors accepting a single connection, such as the client giving up, do not affect the others.
+        let mut stream = match stream.and_then(|stream| stream.set_timeout(self.timeout).map(|_| stream)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("failed to accept connection: {}", e);
+                return;
+            }
+        };
+
+        match pool.reserve() {
+            Some(reservation) => {
+                let ingot = self.ingot.clone();
+                reservation.spawn(move || Self::handle_connection(&ingot, stream));
+            }
+            None => {
+                let _ = write_head(&mut stream, P::NON_PARSED_HEADERS, 503, &HeaderMap::new());
+            }
+        }
+    }
+
+    fn handle_connection<S: Read + Write + Send>(ingot: &Lifecycle<I>, mut stream: S) {
+        let vars = match P::read_request(&mut stream) {
+            Ok(vars) => vars,
+            Err(status) => {
+                let _ = write_head(&mut stream, P::NON_PARSED_HEADERS, status, &HeaderMap::new());
+                return;
+            }
+        };
+
+        // The response ends when the connection is closed, once the context is dropped.
+        let mut context = match Context::new(vars, stream) {
+            Ok(context) => context.non_parsed_headers(P::NON_PARSED_HEADERS),
+            Err(e) => {
+                // A misconfigured web server fails every request, while an invalid request only concerns its client.
+                if e.status() >= 500 {
+                    warn!("{}", e);
+                } else {
+                    debug!("{}", e);
+                }
+                let _ = e.respond(P::NON_PARSED_HEADERS);
+                return;
+            }
+        };
+
+        ingot.handle(&mut context);
+        let _ = context.finish();
+
+        // Closing the connection with part of the body unread would reset it, and the response could be lost.
+        let _ = io::copy(&mut context, &mut io::sink());
+    }
+}
+
+
+/// A connection from the web server.
+trait Connection: Read + Write + Send + 'static {
+    /// Set the read and write timeouts of the connection.
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+}
@@ -5,28 +5,30 @@ extern crate ingots_fastcgi;
 struct HelloWorld;
 
 impl ingots::Ingot for HelloWorld {
-    fn handle(&self, context: &mut ingots::http::Context) {
-        context.response().write_header("Content-Type", "text/plain");
-        write!(context.response(), "\r\n");
+    fn handle(&self, context: &mut dyn ingots::http::Context) {
+        let _ = context.response().set_header("Content-Type", "text/plain".into());
 
-        let uri = context.request().uri();
-        writeln!(context.response(), "uri: {}", uri);
+        let path_info = context.request().path_info().into_owned();
+        let _ = writeln!(context.response(), "path info: {}", path_info);
 
-        let query = context.request().query_string();
-        writeln!(context.response(), "query: {:?}", query);
+        let query = context.request().query_string().map(|s| s.into_owned());
+        let _ = writeln!(context.response(), "query: {:?}", query);
 
-        let server_name = context.server_name();
-        writeln!(context.response(), "server name: {:?}", server_name);
+        let headers = context.request().headers().clone();
+        let _ = writeln!(context.response(), "headers: {:?}", headers);
+
+        let server_name = context.server_name().to_owned();
+        let _ = writeln!(context.response(), "server name: {:?}", server_name);
 
         let server_addr = context.server_addr();
-        writeln!(context.response(), "server addr: {:?}", server_addr);
+        let _ = writeln!(context.response(), "server addr: {:?}", server_addr);
 
         let remote_addr = context.remote_addr();
-        writeln!(context.response(), "remote addr: {:?}", remote_addr);
+        let _ = writeln!(context.response(), "remote addr: {:?}", remote_addr);
     }
 }
 
 fn main() {
     let server = ingots_fastcgi::Server::new(HelloWorld);
-    server.listen_tcp("localhost:9000");
+    server.listen_tcp("localhost:9000").unwrap();
 }
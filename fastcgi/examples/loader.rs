@@ -5,9 +5,9 @@ use ingots_loader::*;
 
 
 fn main() {
-    let ingot = DynamicIngot::open("target/debug/examples/libhello-2cb25cd11455e06f.dylib")
+    let ingot = DynamicIngot::open("target/debug/examples/libhello.so")
         .unwrap();
 
     let server = ingots_fastcgi::Server::new(ingot);
-    server.listen_tcp("localhost:9000");
+    server.listen_tcp("localhost:9000").unwrap();
 }
@@ -1,107 +1,39 @@
 use fastcgi;
-use ingots;
-use std::io;
-use std::net::{ToSocketAddrs, SocketAddr};
+use ingots_cgi;
+use std::io::{self, Read, Write};
 
 
-pub struct Context {
-    fastcgi_request: fastcgi::Request,
-    remote_addr: SocketAddr,
-    server_addr: SocketAddr,
-    server_name: String,
-    query_string: Option<String>,
-}
-
-impl From<fastcgi::Request> for Context {
-    fn from(request: fastcgi::Request) -> Context {
-        let remote_addr = request.param("REMOTE_ADDR").unwrap();
-        let remote_port: u16 = request.param("REMOTE_PORT").unwrap().parse().unwrap();
-        let remote_addr = (remote_addr.as_str(), remote_port).to_socket_addrs().unwrap().next().unwrap();
-
-        let server_addr = request.param("SERVER_ADDR").unwrap();
-        let server_port: u16 = request.param("SERVER_PORT").unwrap().parse().unwrap();
-        let server_addr = (server_addr.as_str(), server_port).to_socket_addrs().unwrap().next().unwrap();
-
-        let server_name = request.param("SERVER_NAME").unwrap_or(String::new());
+/// Request context for a FastCGI request, which carries the CGI meta-variables as its parameters.
+pub type Context = ingots_cgi::Context<RequestStream>;
 
-        let query_string = request.param("REQUEST_URI").and_then(|uri| {
-            if let Some(index) = uri.find('?') {
-                Some(uri[index+1..].to_string())
-            } else {
-                None
-            }
-        });
-
-        Context {
-            fastcgi_request: request,
-            remote_addr: remote_addr,
-            server_addr: server_addr,
-            server_name: server_name,
-            query_string: query_string,
-        }
-    }
+/// Create the context for a request received from the web server.
+///
+/// Returns an error if the parameters of the request are missing or invalid.
+pub fn from_request(request: fastcgi::Request) -> Result<Context, ingots_cgi::context::Error<RequestStream>> {
+    let params: Vec<_> = request.params().collect();
+    Context::new(params, RequestStream(Box::new(request)))
 }
 
-impl ingots::http::Context for Context {
-    fn remote_addr(&self) -> SocketAddr {
-        self.remote_addr.clone()
-    }
-
-    fn server_addr(&self) -> SocketAddr {
-        self.server_addr.clone()
-    }
+/// The input and output streams of a FastCGI request.
+pub struct RequestStream(Box<fastcgi::Request>);
 
-    fn server_name(&self) -> String {
-        self.server_name.clone()
-    }
+// The request shares its socket with the connection thread of the `fastcgi` crate through an `Rc`, so it must never be
+// dropped or cloned on another thread. The stream is private to this crate and dropped on the thread that created it;
+// ingots only borrow it through `&mut dyn http::Context`, and none of the methods reached that way touch the `Rc`.
+unsafe impl Send for RequestStream {}
 
-    fn request(&self) -> &ingots::http::Request {
-        self
-    }
-
-    fn response(&mut self) -> &mut ingots::http::Response {
-        self
-    }
-}
-
-impl ingots::http::Request for Context {
-    fn uri(&self) -> String {
-        self.fastcgi_request.param("REQUEST_URI").unwrap_or(String::from("/"))
-    }
-
-    fn method(&self) -> String {
-        self.fastcgi_request.param("REQUEST_METHOD").unwrap_or(String::from("GET"))
-    }
-
-    fn protocol(&self) -> String {
-        self.fastcgi_request.param("SERVER_PROTOCOL").unwrap()
-    }
-
-    fn query_string(&self) -> Option<String> {
-        self.query_string.clone()
-    }
-
-    fn is_secure(&self) -> bool {
-        self.fastcgi_request.param("REQUEST_SCHEME")
-            .map(|scheme| scheme.to_lowercase() == "https")
-            .unwrap_or(false)
-    }
-}
-
-impl io::Read for Context {
+impl Read for RequestStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.fastcgi_request.stdin().read(buf)
+        self.0.stdin().read(buf)
     }
 }
 
-impl ingots::http::Response for Context {}
-
-impl io::Write for Context {
+impl Write for RequestStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.fastcgi_request.stdout().write(buf)
+        self.0.stdout().write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.fastcgi_request.stdout().flush()
+        self.0.stdout().flush()
     }
 }
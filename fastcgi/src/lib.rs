@@ -1,43 +1,88 @@
 extern crate fastcgi;
 extern crate ingots;
+extern crate ingots_cgi;
+#[macro_use]
+extern crate log;
 
 mod context;
 
 use ingots::*;
+use ingots::lifecycle::Lifecycle;
+use ingots_cgi::server;
+use std::io;
 use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
 
 
 /// Wraps a Rust ingot in a FastCGI server.
+///
+/// The ingot is started when the server begins listening. On SIGTERM or SIGINT, the server stops accepting requests,
+/// waits up to the drain timeout for requests in progress to complete, stops the ingot and exits the process.
 pub struct Server<I: Ingot> {
-    ingot: I,
+    ingot: Arc<Lifecycle<I>>,
+    drain_timeout: Duration,
 }
 
-impl<I: Ingot> Server<I> {
+impl<I: Ingot + 'static> Server<I> {
     pub fn new(ingot: I) -> Server<I> {
         Server {
-            ingot: ingot,
+            ingot: Arc::new(Lifecycle::new(ingot)),
+            drain_timeout: Duration::from_secs(30),
         }
     }
 
+    /// Set how long to wait for requests in progress to complete when shutting down.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
     /// Listen for requests over a UNIX socket.
-    pub fn listen_unix(&self) {
-        fastcgi::run(|request| {
-            self.handle_request(request);
+    ///
+    /// Returns an error if the ingot fails to start.
+    pub fn listen_unix(&self) -> io::Result<()> {
+        server::start(&self.ingot, self.drain_timeout)?;
+        let ingot = self.ingot.clone();
+
+        fastcgi::run(move |request| {
+            Self::handle_request(&ingot, request);
         });
+
+        Ok(())
     }
 
     /// Listen for requests over a TCP socket.
-    pub fn listen_tcp<A: ToSocketAddrs>(&self, addr: A) {
-        let listener = TcpListener::bind(addr).unwrap();
+    ///
+    /// Returns an error if the socket cannot be bound or the ingot fails to start.
+    pub fn listen_tcp<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        server::start(&self.ingot, self.drain_timeout)?;
+        let ingot = self.ingot.clone();
 
-        fastcgi::run_tcp(|request| {
-            self.handle_request(request);
+        fastcgi::run_tcp(move |request| {
+            Self::handle_request(&ingot, request);
         }, &listener);
+
+        Ok(())
     }
 
-    fn handle_request(&self, request: fastcgi::Request) {
-        let mut context = context::Context::from(request);
+    fn handle_request(ingot: &Lifecycle<I>, request: fastcgi::Request) {
+        let mut context = match context::from_request(request) {
+            Ok(context) => context,
+            Err(e) => {
+                // A misconfigured web server fails every request, while an invalid request only concerns its client.
+                if e.status() >= 500 {
+                    warn!("{}", e);
+                } else {
+                    debug!("{}", e);
+                }
+                let _ = e.respond(false);
+                return;
+            }
+        };
 
-        self.ingot.handle(&mut context);
+        ingot.handle(&mut context);
+        let _ = context.finish();
     }
 }
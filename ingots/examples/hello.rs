@@ -1,18 +1,15 @@
 #[macro_use]
 extern crate ingots;
 
-
 struct HelloWorld;
 
 impl ingots::Ingot for HelloWorld {
-    fn handle(&self, context: &mut ingots::http::Context) {
-        context.response().set_header("Content-Type", "text/plain".into());
-        write!(context.response(), "\r\nHello");
+    fn handle(&self, context: &mut dyn ingots::http::Context) {
+        let _ = context.response().set_header("Content-Type", "text/plain".into());
+        let _ = write!(context.response(), "Hello");
     }
 }
 
 ingot_init! {
     HelloWorld
 }
-
-fn main() {}
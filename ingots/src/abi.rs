@@ -1,22 +1,1138 @@
+//! Stable C ABI for passing ingots across dynamic library boundaries.
+//!
+//! Rust trait objects do not have a stable layout, so an ingot compiled by one version of rustc cannot safely hand a
+//! `Box<Ingot>` to a server compiled by another. Instead, an ingot library exports a `#[repr(C)]` table of function
+//! pointers (`IngotVTable`), and the server passes each request to the ingot as a table of C-compatible callbacks into
+//! its own `http::Context` (`RawContext`).
+//!
+//! Any change to the layout of the types in this module must be accompanied by a bump of `INGOTS_VERSION`.
+use http;
+use std::borrow::Cow;
+use std::cmp;
+use std::ffi::c_void;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+use std::time::Duration;
+use super::{Ingot, INGOTS_VERSION};
+
+
+/// Status code returned across the ABI. Zero indicates success.
+pub type RawStatus = i32;
+
+/// The call completed successfully.
+pub const STATUS_OK: RawStatus = 0;
+
+/// The ingot, or the server in a callback, panicked while handling a call.
+pub const STATUS_PANIC: RawStatus = -1;
+
+/// The call failed, and a description of the error was passed to the error callback.
+pub const STATUS_ERROR: RawStatus = -2;
+
+/// The response status or headers could not be changed because the headers have already been sent.
+pub const STATUS_HEADERS_SENT: RawStatus = -3;
+
+/// The server does not support taking over the connection.
+pub const STATUS_UNSUPPORTED: RawStatus = -4;
+
+/// The server cannot take over any more connections for now.
+pub const STATUS_UNAVAILABLE: RawStatus = -5;
+
+/// Callback used to pass a borrowed string across the ABI. The string is only valid for the duration of the call.
+pub type StrCallback = extern "C" fn(user: *mut c_void, value: RawStr);
+
+/// Callback used to pass a borrowed header name and value across the ABI.
+pub type HeaderCallback = extern "C" fn(user: *mut c_void, name: RawStr, value: RawStr);
+
+
 /// Define the ingot entrypoint function.
+///
+/// The entrypoint returns the C ABI function table for the given ingot instance, which is what servers use to call into
+/// the ingot after loading the library.
 #[macro_export]
 macro_rules! ingot_init {
     ($init:expr) => {
-        use $crate::Ingot;
-
         #[no_mangle]
-        pub extern fn __ingot_init() -> *mut Ingot {
-            let mut instance = $init;
-            unsafe {
-                Box::into_raw(Box::new(instance))
+        pub extern "C" fn __ingot_init() -> $crate::abi::IngotVTable {
+            $crate::abi::IngotVTable::new($init)
+        }
+    }
+}
+
+
+/// Function table for an ingot instance exported by a shared library.
+#[repr(C)]
+pub struct IngotVTable {
+    /// Version of the ABI the ingot was compiled against.
+    pub abi_version: u16,
+
+    /// Opaque pointer to the ingot instance.
+    pub instance: *mut c_void,
+
+    /// Handle a single HTTP request.
+    pub handle: extern "C" fn(instance: *const c_void, context: *mut RawContext) -> RawStatus,
+
+    /// Put the ingot into service. If the ingot fails to start, the error message is passed to `error`.
+    pub start: extern "C" fn(instance: *mut c_void, user: *mut c_void, error: StrCallback) -> RawStatus,
+
+    /// Shut down the ingot.
+    pub stop: extern "C" fn(instance: *mut c_void) -> RawStatus,
+
+    /// Free the ingot instance. The table must not be used afterwards.
+    pub free: extern "C" fn(instance: *mut c_void),
+}
+
+impl IngotVTable {
+    /// Create a function table that takes ownership of the given ingot.
+    pub fn new<I: Ingot + 'static>(ingot: I) -> Self {
+        Self {
+            abi_version: INGOTS_VERSION,
+            instance: Box::into_raw(Box::new(ingot)) as *mut c_void,
+            handle: ingot_handle::<I>,
+            start: ingot_start::<I>,
+            stop: ingot_stop::<I>,
+            free: ingot_free::<I>,
+        }
+    }
+}
+
+extern "C" fn ingot_handle<I: Ingot>(instance: *const c_void, context: *mut RawContext) -> RawStatus {
+    let ingot = unsafe { &*(instance as *const I) };
+    let mut context = unsafe { ImportedContext::new(context) };
+
+    catch_panic(|| ingot.handle(&mut context))
+}
+
+extern "C" fn ingot_start<I: Ingot>(instance: *mut c_void, user: *mut c_void, error: StrCallback) -> RawStatus {
+    let ingot = unsafe { &mut *(instance as *mut I) };
+    let mut result = Ok(());
+
+    match catch_panic(|| result = ingot.start()) {
+        STATUS_OK => match result {
+            Ok(()) => STATUS_OK,
+            Err(e) => {
+                error(user, RawStr::from(e.message()));
+                STATUS_ERROR
             }
+        },
+        status => status,
+    }
+}
+
+extern "C" fn ingot_stop<I: Ingot>(instance: *mut c_void) -> RawStatus {
+    let ingot = unsafe { &mut *(instance as *mut I) };
+
+    catch_panic(|| ingot.stop())
+}
+
+extern "C" fn ingot_free<I: Ingot>(instance: *mut c_void) {
+    let _ = panic::catch_unwind(|| unsafe {
+        drop(Box::from_raw(instance as *mut I));
+    });
+}
+
+/// Unwinding across an `extern "C"` boundary aborts the process, so panics are converted into a status code instead.
+fn catch_panic<F: FnOnce()>(f: F) -> RawStatus {
+    guard(STATUS_PANIC, || {
+        f();
+        STATUS_OK
+    })
+}
+
+/// Call a function on one side of the boundary, returning the given value instead if it panics.
+///
+/// Callbacks that return a status report a panic as `STATUS_PANIC`. The others have no way to report it, and return a
+/// neutral value instead; the panic has already been printed by the panic hook.
+fn guard<T, F: FnOnce() -> T>(on_panic: T, f: F) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(on_panic)
+}
+
+
+/// A borrowed UTF-8 string.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RawStr {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl RawStr {
+    /// Copy the string into an owned `String`, replacing invalid UTF-8 sequences.
+    ///
+    /// Must only be called while the string is valid, such as within the callback it was passed to.
+    pub fn into_string(self) -> String {
+        if self.ptr.is_null() {
+            return String::new();
         }
 
-        #[no_mangle]
-        pub extern fn __ingot_free(ptr: *mut Ingot) {
-            unsafe {
-                Box::from_raw(ptr);
+        let bytes = unsafe { slice::from_raw_parts(self.ptr, self.len) };
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+impl<'a> From<&'a str> for RawStr {
+    fn from(value: &'a str) -> Self {
+        Self {
+            ptr: value.as_ptr(),
+            len: value.len(),
+        }
+    }
+}
+
+
+/// A C-compatible socket address.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RawSocketAddr {
+    /// Either 4 or 6, depending on the IP version.
+    pub family: u8,
+    /// IP address in network byte order. IPv4 addresses only use the first 4 bytes.
+    pub ip: [u8; 16],
+    pub port: u16,
+    pub flowinfo: u32,
+    pub scope_id: u32,
+}
+
+impl From<SocketAddr> for RawSocketAddr {
+    fn from(addr: SocketAddr) -> Self {
+        let mut raw = RawSocketAddr {
+            family: 4,
+            ip: [0; 16],
+            port: addr.port(),
+            flowinfo: 0,
+            scope_id: 0,
+        };
+
+        match addr {
+            SocketAddr::V4(addr) => {
+                raw.ip[..4].copy_from_slice(&addr.ip().octets());
+            }
+            SocketAddr::V6(addr) => {
+                raw.family = 6;
+                raw.ip.copy_from_slice(&addr.ip().octets());
+                raw.flowinfo = addr.flowinfo();
+                raw.scope_id = addr.scope_id();
             }
         }
+
+        raw
+    }
+}
+
+impl From<RawSocketAddr> for SocketAddr {
+    fn from(raw: RawSocketAddr) -> Self {
+        if raw.family == 6 {
+            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(raw.ip), raw.port, raw.flowinfo, raw.scope_id))
+        } else {
+            let ip = Ipv4Addr::new(raw.ip[0], raw.ip[1], raw.ip[2], raw.ip[3]);
+            SocketAddr::V4(SocketAddrV4::new(ip, raw.port))
+        }
+    }
+}
+
+
+/// A C-compatible response buffering policy.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RawBuffering {
+    pub enabled: bool,
+    pub size: u32,
+}
+
+impl From<http::Buffering> for RawBuffering {
+    fn from(buffering: http::Buffering) -> Self {
+        match buffering {
+            http::Buffering::On(size) => RawBuffering { enabled: true, size },
+            http::Buffering::Off => RawBuffering { enabled: false, size: 0 },
+        }
+    }
+}
+
+impl From<RawBuffering> for http::Buffering {
+    fn from(raw: RawBuffering) -> Self {
+        if raw.enabled {
+            http::Buffering::On(raw.size)
+        } else {
+            http::Buffering::Off
+        }
+    }
+}
+
+
+/// Table of callbacks into a server's `http::Context`, passed to an ingot for a single request.
+///
+/// All callbacks must be invoked with `data` as the first argument, and only for the duration of the `handle` call that
+/// the context was passed to.
+#[repr(C)]
+pub struct RawContext {
+    pub data: *mut c_void,
+
+    pub remote_addr: extern "C" fn(data: *mut c_void) -> RawSocketAddr,
+    pub server_addr: extern "C" fn(data: *mut c_void) -> RawSocketAddr,
+    pub server_name: extern "C" fn(data: *mut c_void, user: *mut c_void, callback: StrCallback),
+
+    pub method: extern "C" fn(data: *mut c_void, user: *mut c_void, callback: StrCallback),
+    pub context_path: extern "C" fn(data: *mut c_void, user: *mut c_void, callback: StrCallback),
+    pub path_info: extern "C" fn(data: *mut c_void, user: *mut c_void, callback: StrCallback),
+    /// Invokes the callback only if a query string is present.
+    pub query_string: extern "C" fn(data: *mut c_void, user: *mut c_void, callback: StrCallback),
+    /// Invokes the callback once for each request header.
+    pub headers: extern "C" fn(data: *mut c_void, user: *mut c_void, callback: HeaderCallback),
+    pub is_secure: extern "C" fn(data: *mut c_void) -> bool,
+    /// Returns the HTTP version as the major version times ten plus the minor version, such as 11 for HTTP/1.1.
+    pub version: extern "C" fn(data: *mut c_void) -> u16,
+    pub read: extern "C" fn(data: *mut c_void, buf: *mut u8, len: usize, read: *mut usize) -> RawStatus,
+
+    pub status: extern "C" fn(data: *mut c_void) -> http::StatusCode,
+    pub set_status: extern "C" fn(data: *mut c_void, status: http::StatusCode) -> RawStatus,
+    /// Invokes the callback once for each response header that has been set so far.
+    pub response_headers: extern "C" fn(data: *mut c_void, user: *mut c_void, callback: HeaderCallback),
+    pub set_header: extern "C" fn(data: *mut c_void, name: RawStr, value: RawStr) -> RawStatus,
+    pub append_header: extern "C" fn(data: *mut c_void, name: RawStr, value: RawStr) -> RawStatus,
+    pub remove_header: extern "C" fn(data: *mut c_void, name: RawStr) -> RawStatus,
+    pub buffering: extern "C" fn(data: *mut c_void) -> RawBuffering,
+    pub set_buffering: extern "C" fn(data: *mut c_void, buffering: bool) -> bool,
+    pub headers_sent: extern "C" fn(data: *mut c_void) -> bool,
+    pub write: extern "C" fn(data: *mut c_void, buf: *const u8, len: usize, written: *mut usize) -> RawStatus,
+    pub flush: extern "C" fn(data: *mut c_void) -> RawStatus,
+    /// Sends the response headers and takes over the connection, initializing `stream` on success.
+    pub upgrade: extern "C" fn(data: *mut c_void, stream: *mut RawStream) -> RawStatus,
+}
+
+impl RawContext {
+    /// Expose a server context through the C ABI for the duration of the given closure.
+    pub fn with<F, R>(context: &mut dyn http::Context, f: F) -> R
+        where F: FnOnce(&mut RawContext) -> R
+    {
+        let mut context = context;
+
+        let mut raw = RawContext {
+            data: &mut context as *mut &mut dyn http::Context as *mut c_void,
+            remote_addr: host_remote_addr,
+            server_addr: host_server_addr,
+            server_name: host_server_name,
+            method: host_method,
+            context_path: host_context_path,
+            path_info: host_path_info,
+            query_string: host_query_string,
+            headers: host_headers,
+            is_secure: host_is_secure,
+            version: host_version,
+            read: host_read,
+            status: host_status,
+            set_status: host_set_status,
+            response_headers: host_response_headers,
+            set_header: host_set_header,
+            append_header: host_append_header,
+            remove_header: host_remove_header,
+            buffering: host_buffering,
+            set_buffering: host_set_buffering,
+            headers_sent: host_headers_sent,
+            write: host_write,
+            flush: host_flush,
+            upgrade: host_upgrade,
+        };
+
+        f(&mut raw)
+    }
+}
+
+/// Recover the server context from the `data` pointer of a `RawContext`.
+unsafe fn host_context<'a>(data: *mut c_void) -> &'a mut dyn http::Context {
+    &mut **(data as *mut &mut dyn http::Context)
+}
+
+/// The address reported if the server panics while getting an address.
+const UNSPECIFIED_ADDR: RawSocketAddr = RawSocketAddr {
+    family: 4,
+    ip: [0; 16],
+    port: 0,
+    flowinfo: 0,
+    scope_id: 0,
+};
+
+extern "C" fn host_remote_addr(data: *mut c_void) -> RawSocketAddr {
+    guard(UNSPECIFIED_ADDR, || unsafe { host_context(data) }.remote_addr().into())
+}
+
+extern "C" fn host_server_addr(data: *mut c_void) -> RawSocketAddr {
+    guard(UNSPECIFIED_ADDR, || unsafe { host_context(data) }.server_addr().into())
+}
+
+extern "C" fn host_server_name(data: *mut c_void, user: *mut c_void, callback: StrCallback) {
+    guard((), || callback(user, unsafe { host_context(data) }.server_name().into()));
+}
+
+extern "C" fn host_method(data: *mut c_void, user: *mut c_void, callback: StrCallback) {
+    guard((), || callback(user, (&*unsafe { host_context(data) }.request().method()).into()));
+}
+
+extern "C" fn host_context_path(data: *mut c_void, user: *mut c_void, callback: StrCallback) {
+    guard((), || callback(user, (&*unsafe { host_context(data) }.request().context_path()).into()));
+}
+
+extern "C" fn host_path_info(data: *mut c_void, user: *mut c_void, callback: StrCallback) {
+    guard((), || callback(user, (&*unsafe { host_context(data) }.request().path_info()).into()));
+}
+
+extern "C" fn host_query_string(data: *mut c_void, user: *mut c_void, callback: StrCallback) {
+    guard((), || {
+        if let Some(query_string) = unsafe { host_context(data) }.request().query_string() {
+            callback(user, (&*query_string).into());
+        }
+    });
+}
+
+extern "C" fn host_headers(data: *mut c_void, user: *mut c_void, callback: HeaderCallback) {
+    guard((), || {
+        for (name, value) in unsafe { host_context(data) }.request().headers() {
+            callback(user, name.into(), value.into());
+        }
+    });
+}
+
+extern "C" fn host_is_secure(data: *mut c_void) -> bool {
+    guard(false, || unsafe { host_context(data) }.request().is_secure())
+}
+
+extern "C" fn host_version(data: *mut c_void) -> u16 {
+    guard(11, || match unsafe { host_context(data) }.request().version() {
+        http::Version::Http09 => 9,
+        http::Version::Http10 => 10,
+        http::Version::Http11 => 11,
+        http::Version::Http2 => 20,
+        http::Version::Http3 => 30,
+    })
+}
+
+extern "C" fn host_read(data: *mut c_void, buf: *mut u8, len: usize, read: *mut usize) -> RawStatus {
+    let buf = unsafe { slice::from_raw_parts_mut(buf, len) };
+
+    guard(STATUS_PANIC, || match unsafe { host_context(data) }.request_mut().read(buf) {
+        Ok(count) => {
+            unsafe { *read = count; }
+            STATUS_OK
+        }
+        Err(e) => error_to_status(&e),
+    })
+}
+
+extern "C" fn host_status(data: *mut c_void) -> http::StatusCode {
+    guard(500, || unsafe { host_context(data) }.response().status())
+}
+
+extern "C" fn host_set_status(data: *mut c_void, status: http::StatusCode) -> RawStatus {
+    guard(STATUS_PANIC, || headers_sent_to_status(unsafe { host_context(data) }.response().set_status(status)))
+}
+
+extern "C" fn host_response_headers(data: *mut c_void, user: *mut c_void, callback: HeaderCallback) {
+    guard((), || {
+        for (name, value) in unsafe { host_context(data) }.response().headers() {
+            callback(user, name.into(), value.into());
+        }
+    });
+}
+
+extern "C" fn host_set_header(data: *mut c_void, name: RawStr, value: RawStr) -> RawStatus {
+    guard(STATUS_PANIC, || {
+        let result = unsafe { host_context(data) }.response().set_header(&name.into_string(), value.into_string());
+        headers_sent_to_status(result)
+    })
+}
+
+extern "C" fn host_append_header(data: *mut c_void, name: RawStr, value: RawStr) -> RawStatus {
+    guard(STATUS_PANIC, || {
+        let result = unsafe { host_context(data) }.response().append_header(&name.into_string(), value.into_string());
+        headers_sent_to_status(result)
+    })
+}
+
+extern "C" fn host_remove_header(data: *mut c_void, name: RawStr) -> RawStatus {
+    guard(STATUS_PANIC, || {
+        headers_sent_to_status(unsafe { host_context(data) }.response().remove_header(&name.into_string()))
+    })
+}
+
+fn headers_sent_to_status(result: Result<(), http::HeadersSentError>) -> RawStatus {
+    match result {
+        Ok(()) => STATUS_OK,
+        Err(http::HeadersSentError) => STATUS_HEADERS_SENT,
+    }
+}
+
+/// A panic in the server is resumed in the ingot, as it would be if the ingot were called directly.
+fn status_to_headers_sent(status: RawStatus) -> Result<(), http::HeadersSentError> {
+    match status {
+        STATUS_HEADERS_SENT => Err(http::HeadersSentError),
+        STATUS_PANIC => panic!("the server panicked while changing the response"),
+        _ => Ok(()),
+    }
+}
+
+extern "C" fn host_buffering(data: *mut c_void) -> RawBuffering {
+    guard(http::Buffering::Off.into(), || unsafe { host_context(data) }.response().buffering().into())
+}
+
+extern "C" fn host_set_buffering(data: *mut c_void, buffering: bool) -> bool {
+    guard(false, || unsafe { host_context(data) }.response().set_buffering(buffering))
+}
+
+extern "C" fn host_headers_sent(data: *mut c_void) -> bool {
+    // Reporting the headers as sent keeps the ingot from relying on changing them.
+    guard(true, || unsafe { host_context(data) }.response().headers_sent())
+}
+
+extern "C" fn host_write(data: *mut c_void, buf: *const u8, len: usize, written: *mut usize) -> RawStatus {
+    let buf = unsafe { slice::from_raw_parts(buf, len) };
+
+    guard(STATUS_PANIC, || match unsafe { host_context(data) }.response().write(buf) {
+        Ok(count) => {
+            unsafe { *written = count; }
+            STATUS_OK
+        }
+        Err(e) => error_to_status(&e),
+    })
+}
+
+extern "C" fn host_flush(data: *mut c_void) -> RawStatus {
+    guard(STATUS_PANIC, || match unsafe { host_context(data) }.response().flush() {
+        Ok(()) => STATUS_OK,
+        Err(e) => error_to_status(&e),
+    })
+}
+
+extern "C" fn host_upgrade(data: *mut c_void, stream: *mut RawStream) -> RawStatus {
+    guard(STATUS_PANIC, || match unsafe { host_context(data) }.upgrade() {
+        Ok(upgraded) => {
+            unsafe { stream.write(RawStream::new(upgraded)); }
+            STATUS_OK
+        }
+        Err(http::UpgradeError::Unsupported) => STATUS_UNSUPPORTED,
+        Err(http::UpgradeError::Unavailable) => STATUS_UNAVAILABLE,
+        Err(http::UpgradeError::HeadersSent) => STATUS_HEADERS_SENT,
+        Err(http::UpgradeError::Handshake(_)) => STATUS_ERROR,
+        Err(http::UpgradeError::Io(e)) => error_to_status(&e),
+    })
+}
+
+
+/// Table of callbacks into a connection taken over from the server, owned by the ingot.
+///
+/// Unlike a `RawContext`, the stream may be used from any thread and after the request has been handled, until it is
+/// freed. The connection is closed when the stream is freed.
+#[repr(C)]
+pub struct RawStream {
+    pub data: *mut c_void,
+
+    pub read: extern "C" fn(data: *mut c_void, buf: *mut u8, len: usize, read: *mut usize) -> RawStatus,
+    pub write: extern "C" fn(data: *mut c_void, buf: *const u8, len: usize, written: *mut usize) -> RawStatus,
+    pub flush: extern "C" fn(data: *mut c_void) -> RawStatus,
+    /// Sets the read timeout in milliseconds, or lets reads wait indefinitely if zero.
+    pub set_read_timeout: extern "C" fn(data: *mut c_void, millis: u64) -> RawStatus,
+    /// Close the connection and free the stream. The stream must not be used afterwards.
+    pub free: extern "C" fn(data: *mut c_void),
+}
+
+impl RawStream {
+    /// Expose a server connection through the C ABI, passing ownership to the receiver.
+    pub fn new(stream: Box<dyn http::Upgraded>) -> Self {
+        Self {
+            data: Box::into_raw(Box::new(stream)) as *mut c_void,
+            read: stream_read,
+            write: stream_write,
+            flush: stream_flush,
+            set_read_timeout: stream_set_read_timeout,
+            free: stream_free,
+        }
+    }
+}
+
+unsafe fn host_stream<'a>(data: *mut c_void) -> &'a mut Box<dyn http::Upgraded> {
+    &mut *(data as *mut Box<dyn http::Upgraded>)
+}
+
+extern "C" fn stream_read(data: *mut c_void, buf: *mut u8, len: usize, read: *mut usize) -> RawStatus {
+    let buf = unsafe { slice::from_raw_parts_mut(buf, len) };
+
+    guard(STATUS_PANIC, || match unsafe { host_stream(data) }.read(buf) {
+        Ok(count) => {
+            unsafe { *read = count; }
+            STATUS_OK
+        }
+        Err(e) => error_to_status(&e),
+    })
+}
+
+extern "C" fn stream_write(data: *mut c_void, buf: *const u8, len: usize, written: *mut usize) -> RawStatus {
+    let buf = unsafe { slice::from_raw_parts(buf, len) };
+
+    guard(STATUS_PANIC, || match unsafe { host_stream(data) }.write(buf) {
+        Ok(count) => {
+            unsafe { *written = count; }
+            STATUS_OK
+        }
+        Err(e) => error_to_status(&e),
+    })
+}
+
+extern "C" fn stream_flush(data: *mut c_void) -> RawStatus {
+    guard(STATUS_PANIC, || match unsafe { host_stream(data) }.flush() {
+        Ok(()) => STATUS_OK,
+        Err(e) => error_to_status(&e),
+    })
+}
+
+extern "C" fn stream_set_read_timeout(data: *mut c_void, millis: u64) -> RawStatus {
+    let timeout = match millis {
+        0 => None,
+        millis => Some(Duration::from_millis(millis)),
+    };
+
+    guard(STATUS_PANIC, || match unsafe { host_stream(data) }.set_read_timeout(timeout) {
+        Ok(()) => STATUS_OK,
+        Err(e) => error_to_status(&e),
+    })
+}
+
+extern "C" fn stream_free(data: *mut c_void) {
+    let _ = panic::catch_unwind(|| unsafe {
+        drop(Box::from_raw(data as *mut Box<dyn http::Upgraded>));
+    });
+}
+
+
+/// I/O error kinds that can be passed across the ABI. Any other kind is reported as `Other`.
+const ERROR_KINDS: &[io::ErrorKind] = &[
+    io::ErrorKind::Other,
+    io::ErrorKind::NotFound,
+    io::ErrorKind::PermissionDenied,
+    io::ErrorKind::ConnectionRefused,
+    io::ErrorKind::ConnectionReset,
+    io::ErrorKind::ConnectionAborted,
+    io::ErrorKind::NotConnected,
+    io::ErrorKind::AddrInUse,
+    io::ErrorKind::AddrNotAvailable,
+    io::ErrorKind::BrokenPipe,
+    io::ErrorKind::AlreadyExists,
+    io::ErrorKind::WouldBlock,
+    io::ErrorKind::InvalidInput,
+    io::ErrorKind::InvalidData,
+    io::ErrorKind::TimedOut,
+    io::ErrorKind::WriteZero,
+    io::ErrorKind::Interrupted,
+    io::ErrorKind::UnexpectedEof,
+    io::ErrorKind::Unsupported,
+];
+
+/// I/O errors are passed as a positive status code identifying the error kind.
+fn error_to_status(error: &io::Error) -> RawStatus {
+    ERROR_KINDS.iter()
+        .position(|&kind| kind == error.kind())
+        .unwrap_or(0) as RawStatus + 1
+}
+
+fn status_to_error(status: RawStatus) -> io::Error {
+    if status == STATUS_PANIC {
+        return io::Error::other("the ingot server panicked");
+    }
+
+    let kind = (status as usize).checked_sub(1)
+        .and_then(|index| ERROR_KINDS.get(index))
+        .cloned()
+        .unwrap_or(io::ErrorKind::Other);
+
+    io::Error::new(kind, "I/O error reported by the ingot server")
+}
+
+
+/// An `http::Context` implemented on top of a `RawContext` passed in from the server.
+///
+/// Request metadata is copied out of the server when the context is created, so that it can be borrowed from the
+/// context as the `http` traits require. Response headers are mirrored locally for the same reason, and every change is
+/// forwarded to the server.
+struct ImportedContext {
+    server_name: String,
+    remote_addr: SocketAddr,
+    server_addr: SocketAddr,
+    request: ImportedRequest,
+    response: ImportedResponse,
+}
+
+struct ImportedRequest {
+    raw: *mut RawContext,
+    method: String,
+    context_path: String,
+    path_info: String,
+    query_string: Option<String>,
+    headers: http::HeaderMap,
+    is_secure: bool,
+    version: http::Version,
+}
+
+struct ImportedResponse {
+    raw: *mut RawContext,
+    headers: http::HeaderMap,
+}
+
+// The raw context wraps the server's `http::Context`, which is `Send`, and is only used through `&mut self`.
+unsafe impl Send for ImportedRequest {}
+unsafe impl Send for ImportedResponse {}
+
+extern "C" fn put_string(user: *mut c_void, value: RawStr) {
+    let target = unsafe { &mut *(user as *mut Option<String>) };
+    guard((), || *target = Some(value.into_string()));
+}
+
+extern "C" fn put_header(user: *mut c_void, name: RawStr, value: RawStr) {
+    let target = unsafe { &mut *(user as *mut http::HeaderMap) };
+    guard((), || target.append(&name.into_string(), value.into_string()));
+}
+
+impl ImportedContext {
+    unsafe fn new(raw: *mut RawContext) -> Self {
+        let context = &*raw;
+        let get_string = |f: extern "C" fn(*mut c_void, *mut c_void, StrCallback)| {
+            let mut value: Option<String> = None;
+            f(context.data, &mut value as *mut Option<String> as *mut c_void, put_string);
+            value
+        };
+
+        let get_headers = |f: extern "C" fn(*mut c_void, *mut c_void, HeaderCallback)| {
+            let mut headers = http::HeaderMap::new();
+            f(context.data, &mut headers as *mut http::HeaderMap as *mut c_void, put_header);
+            headers
+        };
+
+        Self {
+            server_name: get_string(context.server_name).unwrap_or_default(),
+            remote_addr: (context.remote_addr)(context.data).into(),
+            server_addr: (context.server_addr)(context.data).into(),
+            request: ImportedRequest {
+                raw,
+                method: get_string(context.method).unwrap_or_default(),
+                context_path: get_string(context.context_path).unwrap_or_default(),
+                path_info: get_string(context.path_info).unwrap_or_default(),
+                query_string: get_string(context.query_string),
+                headers: get_headers(context.headers),
+                is_secure: (context.is_secure)(context.data),
+                version: match (context.version)(context.data) {
+                    9 => http::Version::Http09,
+                    10 => http::Version::Http10,
+                    20 => http::Version::Http2,
+                    30 => http::Version::Http3,
+                    _ => http::Version::Http11,
+                },
+            },
+            response: ImportedResponse {
+                raw,
+                headers: get_headers(context.response_headers),
+            },
+        }
+    }
+}
+
+impl http::Context for ImportedContext {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    fn request(&self) -> &dyn http::Request {
+        &self.request
+    }
+
+    fn request_mut(&mut self) -> &mut dyn http::Request {
+        &mut self.request
+    }
+
+    fn response(&mut self) -> &mut dyn http::Response {
+        &mut self.response
+    }
+
+    fn upgrade(&mut self) -> Result<Box<dyn http::Upgraded>, http::UpgradeError> {
+        let raw = unsafe { &*self.request.raw };
+        let mut stream = MaybeUninit::uninit();
+
+        match (raw.upgrade)(raw.data, stream.as_mut_ptr()) {
+            STATUS_OK => Ok(Box::new(ImportedStream(unsafe { stream.assume_init() }))),
+            STATUS_UNSUPPORTED => Err(http::UpgradeError::Unsupported),
+            STATUS_UNAVAILABLE => Err(http::UpgradeError::Unavailable),
+            STATUS_HEADERS_SENT => Err(http::UpgradeError::HeadersSent),
+            status => Err(http::UpgradeError::Io(status_to_error(status))),
+        }
+    }
+}
+
+impl http::Request for ImportedRequest {
+    fn method(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.method)
+    }
+
+    fn context_path(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.context_path)
+    }
+
+    fn path_info(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.path_info)
+    }
+
+    fn query_string(&self) -> Option<Cow<'_, str>> {
+        self.query_string.as_ref().map(|s| Cow::Borrowed(s.as_str()))
+    }
+
+    fn headers(&self) -> &http::HeaderMap {
+        &self.headers
+    }
+
+    fn is_secure(&self) -> bool {
+        self.is_secure
+    }
+
+    fn version(&self) -> http::Version {
+        self.version
+    }
+}
+
+impl io::Read for ImportedRequest {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let raw = unsafe { &*self.raw };
+        let mut count = 0;
+
+        match (raw.read)(raw.data, buf.as_mut_ptr(), buf.len(), &mut count) {
+            STATUS_OK => Ok(count),
+            status => Err(status_to_error(status)),
+        }
+    }
+}
+
+impl ImportedResponse {
+    fn raw(&self) -> &RawContext {
+        unsafe { &*self.raw }
+    }
+}
+
+impl http::Response for ImportedResponse {
+    fn status(&self) -> http::StatusCode {
+        (self.raw().status)(self.raw().data)
+    }
+
+    fn set_status(&mut self, status: http::StatusCode) -> Result<(), http::HeadersSentError> {
+        status_to_headers_sent((self.raw().set_status)(self.raw().data, status))
+    }
+
+    fn headers(&self) -> &http::HeaderMap {
+        &self.headers
+    }
+
+    fn set_header(&mut self, name: &str, value: String) -> Result<(), http::HeadersSentError> {
+        status_to_headers_sent((self.raw().set_header)(self.raw().data, name.into(), value.as_str().into()))?;
+        self.headers.insert(name, value);
+        Ok(())
+    }
+
+    fn append_header(&mut self, name: &str, value: String) -> Result<(), http::HeadersSentError> {
+        status_to_headers_sent((self.raw().append_header)(self.raw().data, name.into(), value.as_str().into()))?;
+        self.headers.append(name, value);
+        Ok(())
+    }
+
+    fn remove_header(&mut self, name: &str) -> Result<(), http::HeadersSentError> {
+        status_to_headers_sent((self.raw().remove_header)(self.raw().data, name.into()))?;
+        self.headers.remove(name);
+        Ok(())
+    }
+
+    fn buffering(&self) -> http::Buffering {
+        (self.raw().buffering)(self.raw().data).into()
+    }
+
+    fn set_buffering(&mut self, buffering: bool) -> bool {
+        (self.raw().set_buffering)(self.raw().data, buffering)
+    }
+
+    fn headers_sent(&self) -> bool {
+        (self.raw().headers_sent)(self.raw().data)
+    }
+}
+
+impl io::Write for ImportedResponse {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut count = 0;
+
+        match (self.raw().write)(self.raw().data, buf.as_ptr(), buf.len(), &mut count) {
+            STATUS_OK => Ok(count),
+            status => Err(status_to_error(status)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match (self.raw().flush)(self.raw().data) {
+            STATUS_OK => Ok(()),
+            status => Err(status_to_error(status)),
+        }
+    }
+}
+
+
+/// A connection taken over from the server through a `RawStream`.
+struct ImportedStream(RawStream);
+
+// The server's stream is `Send`, and the table is only used through `&mut self`.
+unsafe impl Send for ImportedStream {}
+
+impl io::Read for ImportedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut count = 0;
+
+        match (self.0.read)(self.0.data, buf.as_mut_ptr(), buf.len(), &mut count) {
+            STATUS_OK => Ok(count),
+            status => Err(status_to_error(status)),
+        }
+    }
+}
+
+impl io::Write for ImportedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut count = 0;
+
+        match (self.0.write)(self.0.data, buf.as_ptr(), buf.len(), &mut count) {
+            STATUS_OK => Ok(count),
+            status => Err(status_to_error(status)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match (self.0.flush)(self.0.data) {
+            STATUS_OK => Ok(()),
+            status => Err(status_to_error(status)),
+        }
+    }
+}
+
+impl http::Upgraded for ImportedStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        // A timeout shorter than a millisecond is sent as one millisecond, rather than as no timeout at all.
+        let millis = timeout.map_or(0, |timeout| cmp::max(timeout.as_millis(), 1).min(u64::MAX as u128) as u64);
+
+        match (self.0.set_read_timeout)(self.0.data, millis) {
+            STATUS_OK => Ok(()),
+            status => Err(status_to_error(status)),
+        }
+    }
+}
+
+impl Drop for ImportedStream {
+    fn drop(&mut self) {
+        (self.0.free)(self.0.data);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+    use testing::{MockContext, TestRequest, Violation};
+
+    /// Call an ingot through its function table, as a server does after loading it.
+    fn call<I: Ingot + 'static>(ingot: I, context: &mut dyn http::Context) -> RawStatus {
+        let vtable = IngotVTable::new(ingot);
+        let status = RawContext::with(context, |raw| (vtable.handle)(vtable.instance, raw));
+        (vtable.free)(vtable.instance);
+        status
+    }
+
+    /// Describes the request in the response, and checks what the imported response reports back.
+    struct Inspect;
+
+    impl Ingot for Inspect {
+        fn handle(&self, context: &mut dyn http::Context) {
+            let mut body = String::new();
+            context.request_mut().read_to_string(&mut body).unwrap();
+
+            let description = format!(
+                "{} {} {} {}{} {:?} {:?} {} {:?} {}",
+                context.remote_addr(),
+                context.server_addr(),
+                context.server_name(),
+                context.request().context_path(),
+                context.request().path_info(),
+                context.request().query_string(),
+                context.request().headers().get("X-Test"),
+                context.request().method(),
+                context.request().version(),
+                context.request().is_secure(),
+            );
+
+            let response = context.response();
+            response.set_status(201).unwrap();
+            response.set_header("X-Single", "a".into()).unwrap();
+            response.append_header("X-Multi", "b".into()).unwrap();
+            response.append_header("X-Multi", "c".into()).unwrap();
+            response.set_header("X-Removed", "d".into()).unwrap();
+            response.remove_header("X-Removed").unwrap();
+
+            assert_eq!(response.status(), 201);
+            assert_eq!(response.headers().get_all("X-Multi").collect::<Vec<_>>(), ["b", "c"]);
+            assert!(!response.headers_sent());
+            assert!(matches!(response.buffering(), http::Buffering::On(_)));
+
+            write!(response, "{} {}", description, body).unwrap();
+            response.flush().unwrap();
+
+            assert!(response.headers_sent());
+            assert!(response.set_status(500).is_err());
+            assert!(response.set_header("X-Late", "e".into()).is_err());
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut context = TestRequest::post("/items?page=2")
+            .context_path("/app")
+            .header("X-Test", "value")
+            .body("payload")
+            .remote_addr("[::1]:4000".parse().unwrap())
+            .server_name("example.com")
+            .secure(true)
+            .version(http::Version::Http2)
+            .into_context();
+
+        assert_eq!(call(Inspect, &mut context), STATUS_OK);
+
+        let response = context.finish();
+        let expected = concat!(
+            "[::1]:4000 127.0.0.1:80 example.com /app/items ",
+            "Some(\"page=2\") Some(\"value\") POST Http2 true payload",
+        );
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.header("X-Single"), Some("a"));
+        assert_eq!(response.headers().get_all("X-Multi").collect::<Vec<_>>(), ["b", "c"]);
+        assert_eq!(response.header("X-Removed"), None);
+        assert_eq!(response.text(), expected);
+
+        // The changes attempted after the flush reached the server, which refused them.
+        let violations = [Violation::HeadersAlreadySent("set_status"), Violation::HeadersAlreadySent("set_header")];
+        assert_eq!(response.violations(), violations);
+    }
+
+    struct Echo;
+
+    impl Ingot for Echo {
+        fn handle(&self, context: &mut dyn http::Context) {
+            context.response().set_status(101).unwrap();
+            let mut stream = match context.upgrade() {
+                Ok(stream) => stream,
+                Err(http::UpgradeError::Unsupported) => return context.response().set_status(501).unwrap(),
+                Err(e) => panic!("{}", e),
+            };
+
+            let mut input = [0; 5];
+            stream.read_exact(&mut input).unwrap();
+            stream.write_all(&input).unwrap();
+            stream.flush().unwrap();
+
+            // The client sends nothing more, so with a timeout the next read fails instead of waiting.
+            stream.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+            assert_eq!(stream.read(&mut input).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        }
+    }
+
+    #[test]
+    fn upgrade() {
+        let mut context = TestRequest::get("/").upgradable("hello").into_context();
+
+        assert_eq!(call(Echo, &mut context), STATUS_OK);
+
+        let response = context.finish();
+        assert_eq!(response.status(), 101);
+        assert_eq!(response.upgraded(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn upgrade_unsupported() {
+        let mut context = TestRequest::get("/").into_context();
+
+        assert_eq!(call(Echo, &mut context), STATUS_OK);
+        assert_eq!(context.finish().status(), 501);
+    }
+
+    #[test]
+    fn ingot_panic() {
+        struct Panic;
+
+        impl Ingot for Panic {
+            fn handle(&self, _: &mut dyn http::Context) {
+                panic!("ingot failed");
+            }
+        }
+
+        let mut context = TestRequest::get("/").into_context();
+
+        assert_eq!(call(Panic, &mut context), STATUS_PANIC);
+    }
+
+    /// A server context whose response panics when used.
+    struct PanickingContext(MockContext);
+
+    impl http::Context for PanickingContext {
+        fn remote_addr(&self) -> SocketAddr {
+            self.0.remote_addr()
+        }
+
+        fn server_addr(&self) -> SocketAddr {
+            panic!("server failed");
+        }
+
+        fn server_name(&self) -> &str {
+            self.0.server_name()
+        }
+
+        fn request(&self) -> &dyn http::Request {
+            self.0.request()
+        }
+
+        fn request_mut(&mut self) -> &mut dyn http::Request {
+            self.0.request_mut()
+        }
+
+        fn response(&mut self) -> &mut dyn http::Response {
+            panic!("server failed");
+        }
+    }
+
+    /// Records how each call into the panicking server failed.
+    struct Observe(Arc<Mutex<Vec<String>>>);
+
+    impl Ingot for Observe {
+        fn handle(&self, context: &mut dyn http::Context) {
+            let mut observed = self.0.lock().unwrap();
+            observed.push(context.server_addr().to_string());
+            observed.push(context.response().headers_sent().to_string());
+            observed.push(context.response().write(b"body").unwrap_err().to_string());
+            drop(observed);
+
+            let _ = context.response().set_status(404);
+        }
+    }
+
+    #[test]
+    fn server_panic() {
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        let mut context = PanickingContext(TestRequest::get("/").into_context());
+
+        // The panic in the server is resumed in the ingot, which is reported back to the server as a panic.
+        assert_eq!(call(Observe(observed.clone()), &mut context), STATUS_PANIC);
+        assert_eq!(*observed.lock().unwrap(), ["0.0.0.0:0", "true", "the ingot server panicked"]);
     }
 }
@@ -0,0 +1,185 @@
+//! Non-blocking counterparts of `std::io::Read` and `std::io::Write`.
+//!
+//! These traits follow the same polling model as `std::future::Future`: when no data can be transferred immediately,
+//! an implementation returns `Poll::Pending` and arranges for the task's waker to be notified once progress is
+//! possible. The futures in this module provide `async`-friendly wrappers around the polling methods.
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+
+/// Read bytes from a source asynchronously.
+pub trait AsyncRead {
+    /// Attempt to read data into the given buffer, returning the number of bytes read.
+    ///
+    /// A return value of `Ok(0)` indicates the end of the stream. If no data is available yet, `Poll::Pending` is
+    /// returned and the current task is woken once more data can be read.
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>>;
+}
+
+/// Write bytes to a sink asynchronously.
+pub trait AsyncWrite {
+    /// Attempt to write data from the given buffer, returning the number of bytes written.
+    ///
+    /// If the sink cannot accept more data yet, `Poll::Pending` is returned and the current task is woken once it can.
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>>;
+
+    /// Attempt to flush any buffered data to its destination.
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>>;
+}
+
+impl<R: AsyncRead + ?Sized> AsyncRead for &mut R {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        (**self).poll_read(cx, buf)
+    }
+}
+
+impl<W: AsyncWrite + ?Sized> AsyncWrite for &mut W {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        (**self).poll_write(cx, buf)
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        (**self).poll_flush(cx)
+    }
+}
+
+/// Future-returning helper methods for `AsyncRead`, available on every reader including trait objects.
+pub trait AsyncReadExt: AsyncRead {
+    /// Read some bytes into the given buffer.
+    fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> Read<'a, Self> {
+        Read {
+            reader: self,
+            buf,
+        }
+    }
+}
+
+impl<R: AsyncRead + ?Sized> AsyncReadExt for R {}
+
+/// Future-returning helper methods for `AsyncWrite`, available on every writer including trait objects.
+pub trait AsyncWriteExt: AsyncWrite {
+    /// Write some bytes from the given buffer.
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> Write<'a, Self> {
+        Write {
+            writer: self,
+            buf,
+        }
+    }
+
+    /// Write an entire buffer.
+    fn write_all<'a>(&'a mut self, buf: &'a [u8]) -> WriteAll<'a, Self> {
+        WriteAll {
+            writer: self,
+            buf,
+        }
+    }
+
+    /// Flush any buffered data.
+    fn flush(&mut self) -> Flush<'_, Self> {
+        Flush {
+            writer: self,
+        }
+    }
+}
+
+impl<W: AsyncWrite + ?Sized> AsyncWriteExt for W {}
+
+
+/// Future returned by `AsyncReadExt::read`.
+pub struct Read<'a, R: 'a + ?Sized> {
+    reader: &'a mut R,
+    buf: &'a mut [u8],
+}
+
+impl<'a, R: AsyncRead + ?Sized> Future for Read<'a, R> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.reader.poll_read(cx, this.buf)
+    }
+}
+
+/// Future returned by `AsyncWriteExt::write`.
+pub struct Write<'a, W: 'a + ?Sized> {
+    writer: &'a mut W,
+    buf: &'a [u8],
+}
+
+impl<'a, W: AsyncWrite + ?Sized> Future for Write<'a, W> {
+    type Output = io::Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.writer.poll_write(cx, this.buf)
+    }
+}
+
+/// Future returned by `AsyncWriteExt::write_all`.
+pub struct WriteAll<'a, W: 'a + ?Sized> {
+    writer: &'a mut W,
+    buf: &'a [u8],
+}
+
+impl<'a, W: AsyncWrite + ?Sized> Future for WriteAll<'a, W> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while !this.buf.is_empty() {
+            match this.writer.poll_write(cx, this.buf) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(count)) => this.buf = &this.buf[count..],
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Future returned by `AsyncWriteExt::flush`.
+pub struct Flush<'a, W: 'a + ?Sized> {
+    writer: &'a mut W,
+}
+
+impl<'a, W: AsyncWrite + ?Sized> Future for Flush<'a, W> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().writer.poll_flush(cx)
+    }
+}
+
+
+/// Run a future to completion on the current thread, parking the thread while the future is pending.
+///
+/// This is only meant for bridging blocking code to asynchronous streams that are driven by another thread, such as
+/// the event loop of an async server.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+
+        thread::park();
+    }
+}
@@ -0,0 +1,692 @@
+//! Running blocking ingots inside asynchronous servers.
+//!
+//! A blocking `Ingot` ties up its thread for the entire duration of a request, so it cannot be called from an async
+//! server's event loop. `Blocking` wraps such an ingot as an `AsyncIngot` that runs each request on a `BlockingPool`, a
+//! fixed set of worker threads that bounds how many blocking requests can be in progress at once. Requests that arrive
+//! while the pool's queue is full are answered with `503 Service Unavailable`.
+use async_io::{self, AsyncReadExt, AsyncWriteExt};
+use http;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use super::{AsyncIngot, HandleFuture, Ingot, StartError};
+
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed-size pool of threads for running blocking work.
+///
+/// Jobs submitted while every thread is busy are queued and run in submission order. The queue holds a limited number
+/// of jobs, so that a burst of requests cannot pile up without bound behind slow ones.
+pub struct BlockingPool {
+    shared: Arc<PoolShared>,
+    threads: usize,
+}
+
+struct PoolShared {
+    queue: Mutex<PoolQueue>,
+    condvar: Condvar,
+}
+
+struct PoolQueue {
+    jobs: VecDeque<Job>,
+    /// Number of jobs that are queued, running, or reserved but not yet submitted.
+    pending: usize,
+    /// Maximum value of `pending`.
+    capacity: usize,
+    shutdown: bool,
+}
+
+impl BlockingPool {
+    /// Create a new pool with the given number of worker threads, which queues at most `max_queued` jobs while every
+    /// thread is busy.
+    pub fn new(threads: usize, max_queued: usize) -> Self {
+        let threads = threads.max(1);
+        let shared = Arc::new(PoolShared {
+            queue: Mutex::new(PoolQueue {
+                jobs: VecDeque::new(),
+                pending: 0,
+                capacity: threads + max_queued,
+                shutdown: false,
+            }),
+            condvar: Condvar::new(),
+        });
+
+        for i in 0..threads {
+            let shared = shared.clone();
+
+            thread::Builder::new()
+                .name(format!("ingots-blocking-{}", i))
+                .spawn(move || shared.run())
+                .expect("failed to spawn blocking pool thread");
+        }
+
+        Self {
+            shared,
+            threads,
+        }
+    }
+
+    /// Get the number of worker threads in the pool.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Reserve a place in the queue for a job, or get `None` if the queue is full.
+    ///
+    /// This allows a caller to find out whether a job will be accepted before giving up ownership of what it needs.
+    pub fn reserve(&self) -> Option<Reservation<'_>> {
+        let mut queue = self.shared.queue.lock().unwrap();
+
+        if queue.pending >= queue.capacity {
+            return None;
+        }
+
+        queue.pending += 1;
+
+        Some(Reservation {
+            pool: self,
+        })
+    }
+
+    /// Run a blocking function on the pool, returning a future that resolves to its result.
+    ///
+    /// If the queue is full, the function is handed back. If the function panics, the future resolves to an error.
+    pub fn spawn<F, T>(&self, f: F) -> Result<Task<T>, F>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        match self.reserve() {
+            Some(reservation) => Ok(reservation.spawn(f)),
+            None => Err(f),
+        }
+    }
+}
+
+impl Drop for BlockingPool {
+    fn drop(&mut self) {
+        // Let the worker threads finish any queued jobs, then exit.
+        self.shared.queue.lock().unwrap().shutdown = true;
+        self.shared.condvar.notify_all();
+    }
+}
+
+impl PoolShared {
+    fn run(&self) {
+        loop {
+            let job = {
+                let mut queue = self.queue.lock().unwrap();
+
+                loop {
+                    if let Some(job) = queue.jobs.pop_front() {
+                        break job;
+                    }
+
+                    if queue.shutdown {
+                        return;
+                    }
+
+                    queue = self.condvar.wait(queue).unwrap();
+                }
+            };
+
+            // Jobs catch panics from the function they run, so this always returns.
+            job();
+            self.queue.lock().unwrap().pending -= 1;
+        }
+    }
+}
+
+
+/// A place in the queue of a `BlockingPool`, which is released if dropped without being used.
+pub struct Reservation<'a> {
+    pool: &'a BlockingPool,
+}
+
+impl<'a> Reservation<'a> {
+    /// Run a blocking function on the pool, returning a future that resolves to its result.
+    ///
+    /// If the function panics, the future resolves to an error.
+    pub fn spawn<F, T>(self, f: F) -> Task<T>
+        where F: FnOnce() -> T + Send + 'static,
+              T: Send + 'static
+    {
+        let state = Arc::new(Mutex::new(TaskState {
+            result: None,
+            waker: None,
+        }));
+        let task_state = state.clone();
+
+        let job: Job = Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(|_| ());
+            let mut state = task_state.lock().unwrap();
+
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+
+        let pool = self.pool;
+
+        // The job takes over the place counted by the reservation.
+        mem::forget(self);
+        pool.shared.queue.lock().unwrap().jobs.push_back(job);
+        pool.shared.condvar.notify_one();
+
+        Task {
+            state,
+        }
+    }
+}
+
+impl<'a> Drop for Reservation<'a> {
+    fn drop(&mut self) {
+        self.pool.shared.queue.lock().unwrap().pending -= 1;
+    }
+}
+
+
+/// Future for the result of a function run on a `BlockingPool`.
+pub struct Task<T> {
+    state: Arc<Mutex<TaskState<T>>>,
+}
+
+struct TaskState<T> {
+    result: Option<Result<T, ()>>,
+    waker: Option<Waker>,
+}
+
+impl<T> Future for Task<T> {
+    /// The result of the function, or `Err` if it panicked.
+    type Output = Result<T, ()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Resolves once a request handled on the pool is complete, whether or not the ingot panicked.
+struct Completion(Task<()>);
+
+impl Future for Completion {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        Pin::new(&mut self.0).poll(cx).map(|_| ())
+    }
+}
+
+/// Answers a request that could not be queued with `503 Service Unavailable`.
+struct Unavailable(Box<dyn http::AsyncContext>);
+
+impl Future for Unavailable {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let response = self.0.response_mut();
+
+        if !response.headers_sent() {
+            let _ = response.set_status(503);
+        }
+
+        response.poll_flush(cx).map(|_| ())
+    }
+}
+
+
+/// Adapter that serves a blocking `Ingot` as an `AsyncIngot`.
+///
+/// Each request is handled on a thread from a `BlockingPool`. Reads and writes made by the ingot block the pool thread
+/// until the server's event loop makes progress on the underlying asynchronous streams.
+pub struct Blocking<I> {
+    ingot: Arc<I>,
+    pool: Arc<BlockingPool>,
+    in_flight: Arc<InFlight>,
+}
+
+/// Count of requests still running on the pool, each of which holds a reference to the ingot.
+struct InFlight {
+    count: Mutex<usize>,
+    idle: Condvar,
+}
+
+/// Marks a request as in flight until dropped.
+struct InFlightGuard(Arc<InFlight>);
+
+impl InFlightGuard {
+    fn new(in_flight: &Arc<InFlight>) -> Self {
+        *in_flight.count.lock().unwrap() += 1;
+        InFlightGuard(in_flight.clone())
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut count = self.0.count.lock().unwrap();
+
+        *count -= 1;
+        if *count == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+impl<I: Ingot + 'static> Blocking<I> {
+    /// Wrap an ingot, running its requests on the given pool.
+    ///
+    /// A pool may be shared between multiple ingots to bound the total number of blocking threads.
+    pub fn new(ingot: I, pool: Arc<BlockingPool>) -> Self {
+        Self {
+            ingot: Arc::new(ingot),
+            pool,
+            in_flight: Arc::new(InFlight {
+                count: Mutex::new(0),
+                idle: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Get the wrapped ingot.
+    ///
+    /// This allows a server to also call the ingot from its own threads, or to manage its lifecycle itself.
+    pub fn get_ref(&self) -> &I {
+        &self.ingot
+    }
+
+    /// Get the wrapped ingot, waiting for any requests still running on the pool to complete.
+    fn ingot_mut(&mut self) -> &mut I {
+        let count = self.in_flight.count.lock().unwrap();
+        drop(self.in_flight.idle.wait_while(count, |count| *count > 0).unwrap());
+
+        Arc::get_mut(&mut self.ingot).expect("ingot referenced by a completed request")
+    }
+}
+
+impl<I: Ingot + 'static> AsyncIngot for Blocking<I> {
+    fn handle(&self, context: Box<dyn http::AsyncContext>) -> HandleFuture {
+        let reservation = match self.pool.reserve() {
+            Some(reservation) => reservation,
+            None => return Box::pin(Unavailable(context)),
+        };
+
+        let guard = InFlightGuard::new(&self.in_flight);
+        let ingot = self.ingot.clone();
+        let task = reservation.spawn(move || {
+            // Locals are dropped in reverse order, so the request only stops counting as in flight once its reference
+            // to the ingot is gone, even if the ingot panics.
+            let _guard = guard;
+            let ingot = ingot;
+            let mut context = BlockingContext(context);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| ingot.handle(&mut context)));
+
+            if let Err(payload) = result {
+                // Report the failure to the client if the ingot panicked before starting its response.
+                let response = context.0.response_mut();
+                if !response.headers_sent() {
+                    let _ = response.set_status(500);
+                }
+                panic::resume_unwind(payload);
+            }
+        });
+
+        Box::pin(Completion(task))
+    }
+
+    /// Start the wrapped ingot.
+    ///
+    /// If requests are still running on the pool, this blocks until they complete. Servers should wait for the futures
+    /// of any requests they have started before calling it.
+    fn start(&mut self) -> Result<(), StartError> {
+        self.ingot_mut().start()
+    }
+
+    /// Stop the wrapped ingot.
+    ///
+    /// If requests are still running on the pool, this blocks until they complete. Servers should wait for the futures
+    /// of any requests they have started before calling it.
+    fn stop(&mut self) {
+        self.ingot_mut().stop()
+    }
+}
+
+
+/// Presents an asynchronous context to a blocking ingot.
+struct BlockingContext(Box<dyn http::AsyncContext>);
+
+impl http::Context for BlockingContext {
+    fn remote_addr(&self) -> SocketAddr {
+        self.0.remote_addr()
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.0.server_addr()
+    }
+
+    fn server_name(&self) -> &str {
+        self.0.server_name()
+    }
+
+    fn request(&self) -> &dyn http::Request {
+        self
+    }
+
+    fn request_mut(&mut self) -> &mut dyn http::Request {
+        self
+    }
+
+    fn response(&mut self) -> &mut dyn http::Response {
+        self
+    }
+
+    fn upgrade(&mut self) -> Result<Box<dyn http::Upgraded>, http::UpgradeError> {
+        let mut stream = self.0.upgrade()?;
+
+        // A blocking ingot expects the upgrade response to have been sent once this returns.
+        async_io::block_on(stream.flush())?;
+        Ok(Box::new(BlockingStream(stream)))
+    }
+}
+
+impl http::Request for BlockingContext {
+    fn method(&self) -> Cow<'_, str> {
+        self.0.request().method()
+    }
+
+    fn context_path(&self) -> Cow<'_, str> {
+        self.0.request().context_path()
+    }
+
+    fn path_info(&self) -> Cow<'_, str> {
+        self.0.request().path_info()
+    }
+
+    fn query_string(&self) -> Option<Cow<'_, str>> {
+        self.0.request().query_string()
+    }
+
+    fn headers(&self) -> &http::HeaderMap {
+        self.0.request().headers()
+    }
+
+    fn is_secure(&self) -> bool {
+        self.0.request().is_secure()
+    }
+
+    fn version(&self) -> http::Version {
+        self.0.request().version()
+    }
+}
+
+impl io::Read for BlockingContext {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        async_io::block_on(self.0.request_mut().read(buf))
+    }
+}
+
+impl http::Response for BlockingContext {
+    fn status(&self) -> http::StatusCode {
+        self.0.response().status()
+    }
+
+    fn set_status(&mut self, status: http::StatusCode) -> Result<(), http::HeadersSentError> {
+        self.0.response_mut().set_status(status)
+    }
+
+    fn headers(&self) -> &http::HeaderMap {
+        self.0.response().headers()
+    }
+
+    fn set_header(&mut self, name: &str, value: String) -> Result<(), http::HeadersSentError> {
+        self.0.response_mut().set_header(name, value)
+    }
+
+    fn append_header(&mut self, name: &str, value: String) -> Result<(), http::HeadersSentError> {
+        self.0.response_mut().append_header(name, value)
+    }
+
+    fn remove_header(&mut self, name: &str) -> Result<(), http::HeadersSentError> {
+        self.0.response_mut().remove_header(name)
+    }
+
+    fn buffering(&self) -> http::Buffering {
+        self.0.response().buffering()
+    }
+
+    fn set_buffering(&mut self, buffering: bool) -> bool {
+        self.0.response_mut().set_buffering(buffering)
+    }
+
+    fn headers_sent(&self) -> bool {
+        self.0.response().headers_sent()
+    }
+}
+
+impl io::Write for BlockingContext {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        async_io::block_on(self.0.response_mut().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        async_io::block_on(self.0.response_mut().flush())
+    }
+}
+
+/// Presents a connection upgraded by an asynchronous server to a blocking ingot.
+///
+/// Read timeouts are not supported, since the pool thread waits on the server's event loop rather than a socket.
+struct BlockingStream(Box<dyn http::AsyncUpgraded>);
+
+impl http::Upgraded for BlockingStream {}
+
+impl io::Read for BlockingStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        async_io::block_on(self.0.read(buf))
+    }
+}
+
+impl io::Write for BlockingStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        async_io::block_on(self.0.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        async_io::block_on(self.0.flush())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use async_io;
+    use http;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::thread;
+    use std::time::Duration;
+    use testing::TestRequest;
+    use websocket::CLOSE_NORMAL;
+    use super::*;
+
+    type Events = Arc<Mutex<Vec<&'static str>>>;
+
+    /// Records what happens to it, holds requests to `/wait` until released, and echoes one WebSocket message on
+    /// `/echo`.
+    struct Recorder {
+        events: Events,
+        started: Mutex<Sender<()>>,
+        release: Mutex<Receiver<()>>,
+    }
+
+    impl Ingot for Recorder {
+        fn handle(&self, context: &mut dyn http::Context) {
+            if context.request().path_info() == "/wait" {
+                self.started.lock().unwrap().send(()).unwrap();
+                self.release.lock().unwrap().recv().unwrap();
+            }
+
+            if context.request().path_info() == "/panic" {
+                panic!("handler panicked");
+            }
+
+            if context.request().path_info() == "/echo" {
+                let mut socket = context.upgrade_websocket(None).unwrap();
+                let message = socket.receive().unwrap();
+                socket.send(message).unwrap();
+                socket.close(CLOSE_NORMAL, "").unwrap();
+                return;
+            }
+
+            self.events.lock().unwrap().push("handled");
+            context.response().set_header("Content-Type", String::from("text/plain")).unwrap();
+            context.response().write_all(b"Hello").unwrap();
+        }
+
+        fn start(&mut self) -> Result<(), StartError> {
+            self.events.lock().unwrap().push("started");
+            Ok(())
+        }
+
+        fn stop(&mut self) {
+            self.events.lock().unwrap().push("stopped");
+        }
+    }
+
+    fn recorder(pool: BlockingPool) -> (Blocking<Recorder>, Events, Receiver<()>, Sender<()>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (started, started_receiver) = mpsc::channel();
+        let (release_sender, release) = mpsc::channel();
+        let ingot = Recorder {
+            events: events.clone(),
+            started: Mutex::new(started),
+            release: Mutex::new(release),
+        };
+
+        (Blocking::new(ingot, Arc::new(pool)), events, started_receiver, release_sender)
+    }
+
+    #[test]
+    fn handle() {
+        let (blocking, events, _, _) = recorder(BlockingPool::new(1, 0));
+        let response = TestRequest::get("/").run_async(&blocking);
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.header("Content-Type"), Some("text/plain"));
+        assert_eq!(response.text(), "Hello");
+        assert!(response.violations().is_empty(), "{:?}", response.violations());
+        assert_eq!(*events.lock().unwrap(), ["handled"]);
+    }
+
+    #[test]
+    fn panic_completes_request() {
+        let (mut blocking, events, _, _) = recorder(BlockingPool::new(1, 0));
+        let response = TestRequest::get("/panic").run_async(&blocking);
+
+        assert_eq!(response.status(), 500);
+        assert_eq!(response.body(), b"");
+
+        // The panicked request no longer counts as in flight.
+        blocking.stop();
+        assert_eq!(*events.lock().unwrap(), ["stopped"]);
+    }
+
+    #[test]
+    fn upgrade() {
+        let (blocking, _, _, _) = recorder(BlockingPool::new(1, 0));
+        let response = TestRequest::get("/echo")
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("Sec-WebSocket-Version", "13")
+            .upgradable(vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58])
+            .run_async(&blocking);
+
+        assert_eq!(response.status(), 101);
+        assert_eq!(response.upgraded(), Some(&b"\x81\x05Hello\x88\x02\x03\xe8"[..]));
+    }
+
+    #[test]
+    fn spawn_rejects_when_queue_full() {
+        let pool = BlockingPool::new(1, 1);
+        let (release, receiver) = mpsc::channel::<()>();
+
+        let running = pool.spawn(move || receiver.recv().unwrap()).ok().unwrap();
+        let queued = pool.spawn(|| 2).ok().unwrap();
+        assert!(pool.spawn(|| 3).is_err());
+        assert!(pool.reserve().is_none());
+
+        release.send(()).unwrap();
+        assert_eq!(async_io::block_on(running), Ok(()));
+        assert_eq!(async_io::block_on(queued), Ok(2));
+
+        // A job is only removed from the count once it has finished, so wait for the worker to catch up.
+        let task = loop {
+            match pool.spawn(|| 4) {
+                Ok(task) => break task,
+                Err(_) => thread::yield_now(),
+            }
+        };
+        assert_eq!(async_io::block_on(task), Ok(4));
+    }
+
+    #[test]
+    fn dropped_reservation_is_released() {
+        let pool = BlockingPool::new(1, 0);
+
+        drop(pool.reserve().unwrap());
+        assert_eq!(async_io::block_on(pool.spawn(|| 1).ok().unwrap()), Ok(1));
+    }
+
+    #[test]
+    fn full_queue_responds_unavailable() {
+        let (blocking, events, started, release) = recorder(BlockingPool::new(1, 0));
+        let (context, pending) = TestRequest::get("/wait").into_async_context();
+        let future = blocking.handle(context);
+        started.recv().unwrap();
+
+        let response = TestRequest::get("/").run_async(&blocking);
+        assert_eq!(response.status(), 503);
+        assert_eq!(*events.lock().unwrap(), Vec::<&str>::new());
+
+        release.send(()).unwrap();
+        async_io::block_on(future);
+        assert_eq!(pending.wait().text(), "Hello");
+    }
+
+    #[test]
+    fn stop_waits_for_requests_in_flight() {
+        let (mut blocking, events, started, release) = recorder(BlockingPool::new(1, 0));
+        blocking.start().unwrap();
+
+        let (context, pending) = TestRequest::get("/wait").into_async_context();
+        let future = blocking.handle(context);
+        started.recv().unwrap();
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            release.send(()).unwrap();
+        });
+
+        blocking.stop();
+        assert_eq!(*events.lock().unwrap(), ["started", "handled", "stopped"]);
+
+        async_io::block_on(future);
+        assert_eq!(pending.wait().status(), 200);
+    }
+}
@@ -0,0 +1,688 @@
+//! Compression of response bodies, negotiated from the request's `Accept-Encoding` header.
+//!
+//! The `Compression` middleware compresses response bodies with brotli, gzip or deflate, whichever the client prefers.
+//! How the body is compressed depends on the buffering policy of the server:
+//!
+//! - With `Buffering::On(n)`, a body of up to `n` bytes is held back until the ingot returns, then compressed as a whole
+//!   and sent with a `Content-Length`. Bodies that turn out to be smaller than the minimum size are sent as they are.
+//! - With `Buffering::Off`, the body is compressed as it is written, and the compressor is flushed after every write so
+//!   that streaming responses still reach the client promptly.
+//!
+//! Responses are sent uncompressed if their content type is already compressed, if they already have a
+//! `Content-Encoding` or `Content-Range`, or if they carry `Cache-Control: no-transform`. Any response that could have
+//! been compressed gets `Vary: Accept-Encoding`.
+use brotli::CompressorWriter;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use http::{self, Buffering, HeaderMap, HeadersSentError, StatusCode};
+use middleware::{Middleware, Next};
+use std::io::{self, Write};
+use std::mem;
+use std::net::SocketAddr;
+
+
+/// Default minimum size of a body for it to be compressed.
+pub const DEFAULT_MIN_SIZE: usize = 1024;
+
+/// Content types that are not worth compressing, because they are compressed already.
+const COMPRESSED_TYPES: &[&str] = &[
+    "application/gzip",
+    "application/pdf",
+    "application/vnd.rar",
+    "application/wasm",
+    "application/x-7z-compressed",
+    "application/x-bzip2",
+    "application/x-gzip",
+    "application/x-rar-compressed",
+    "application/x-xz",
+    "application/zip",
+    "application/zstd",
+    "font/woff",
+    "font/woff2",
+];
+
+/// Quality used for brotli, trading some compression for speed since bodies are compressed on every request.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+
+/// Middleware that compresses response bodies.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    min_size: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            min_size: DEFAULT_MIN_SIZE,
+        }
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the minimum size of a body for it to be compressed.
+    ///
+    /// The size of a streamed body is only known if the ingot sets `Content-Length`; otherwise it is always compressed.
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, context: &mut dyn http::Context, next: Next<'_>) {
+        let request = context.request();
+        let encoding = if request.method().eq_ignore_ascii_case("HEAD") {
+            None
+        } else {
+            negotiate(request.headers().get_all("Accept-Encoding"))
+        };
+
+        let response = context.response();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let buffering = response.buffering();
+
+        let mut compressor = Compressor {
+            context,
+            encoding,
+            min_size: self.min_size,
+            state: State::Pending(Vec::new()),
+            status,
+            headers,
+            buffering,
+        };
+
+        next.run(&mut compressor);
+
+        // There is no one to report a failure to at this point; the connection is most likely gone.
+        let _ = compressor.finish();
+    }
+}
+
+
+/// A content coding supported by the middleware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Get the name of the coding, as used in `Accept-Encoding` and `Content-Encoding`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encoder(&self) -> Encoder {
+        match *self {
+            Encoding::Brotli => Encoder::Brotli(Box::new(
+                CompressorWriter::new(Vec::new(), BROTLI_BUFFER_SIZE, BROTLI_QUALITY, BROTLI_WINDOW))),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default())),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(Vec::new(), flate2::Compression::default())),
+        }
+    }
+}
+
+/// Choose the encoding preferred by the client from the values of its `Accept-Encoding` headers.
+///
+/// Among codings with the same quality value, brotli is preferred over gzip, and gzip over deflate. Returns `None` if
+/// the client accepts none of them.
+pub fn negotiate<'a, I: IntoIterator<Item = &'a str>>(headers: I) -> Option<Encoding> {
+    let mut qualities: [Option<f32>; 3] = [None; 3];
+    let mut wildcard = None;
+
+    for item in headers.into_iter().flat_map(|header| header.split(',')) {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim();
+        let quality = params
+            .filter_map(|param| {
+                let param = param.trim();
+                if param.len() > 2 && param[..2].eq_ignore_ascii_case("q=") {
+                    param[2..].trim().parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+
+        let index = match coding.to_ascii_lowercase().as_str() {
+            "br" => 0,
+            "gzip" | "x-gzip" => 1,
+            "deflate" => 2,
+            "*" => {
+                wildcard = Some(quality);
+                continue;
+            }
+            _ => continue,
+        };
+        qualities[index] = Some(quality);
+    }
+
+    let encodings = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+    let mut best: Option<(Encoding, f32)> = None;
+
+    for (&encoding, quality) in encodings.iter().zip(qualities.iter()) {
+        match quality.or(wildcard) {
+            Some(quality) if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) => {
+                best = Some((encoding, quality));
+            }
+            _ => {}
+        }
+    }
+
+    best.map(|(encoding, _)| encoding)
+}
+
+
+/// A compressor writing into a buffer that is drained into the response after every operation.
+enum Encoder {
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn writer(&mut self) -> &mut dyn Write {
+        match *self {
+            Encoder::Brotli(ref mut encoder) => &mut **encoder,
+            Encoder::Gzip(ref mut encoder) => encoder,
+            Encoder::Deflate(ref mut encoder) => encoder,
+        }
+    }
+
+    /// Take the compressed output produced so far.
+    fn take_output(&mut self) -> Vec<u8> {
+        mem::take(match *self {
+            Encoder::Brotli(ref mut encoder) => encoder.get_mut(),
+            Encoder::Gzip(ref mut encoder) => encoder.get_mut(),
+            Encoder::Deflate(ref mut encoder) => encoder.get_mut(),
+        })
+    }
+
+    /// End the compressed stream, returning the remaining output.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+enum State {
+    /// The response has not been started, and the body written so far is held back.
+    Pending(Vec<u8>),
+    /// The body is sent as it is.
+    Identity,
+    /// The body is compressed as it is written.
+    Compressing(Encoder),
+}
+
+
+/// Context wrapper that compresses the response written to the wrapped context.
+///
+/// Once the response has been started, the headers are reported as sent, since the decision whether to compress the
+/// body cannot be revisited.
+///
+/// The status, headers and buffering policy of the wrapped response are mirrored, since they can only be read from the
+/// wrapped context through a mutable borrow.
+struct Compressor<'a> {
+    context: &'a mut dyn http::Context,
+    encoding: Option<Encoding>,
+    min_size: usize,
+    state: State,
+    status: StatusCode,
+    headers: HeaderMap,
+    buffering: Buffering,
+}
+
+impl<'a> Compressor<'a> {
+    /// Check whether the response can be compressed at all, regardless of what the client accepts.
+    fn is_compressible(&self) -> bool {
+        let headers = &self.headers;
+
+        match self.status {
+            100..=199 | 204 | 206 | 304 => return false,
+            _ => {}
+        }
+
+        if headers.get("Content-Encoding").is_some() || headers.get("Content-Range").is_some() {
+            return false;
+        }
+
+        if headers.get_all("Cache-Control").any(|value| {
+            value.split(',').any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+        }) {
+            return false;
+        }
+
+        match headers.get("Content-Type") {
+            Some(content_type) => {
+                let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+                let compressed = match mime.split('/').next() {
+                    Some("image") => mime != "image/svg+xml" && mime != "image/x-icon",
+                    Some("audio") | Some("video") => true,
+                    _ => COMPRESSED_TYPES.contains(&mime.as_str()),
+                };
+                !compressed
+            }
+            None => true,
+        }
+    }
+
+    /// Decide how to send the body, given the length of the whole body if it is known.
+    ///
+    /// Returns the encoding to compress the body with, after adding the headers for it.
+    fn start(&mut self, length: Option<usize>) -> Option<Encoding> {
+        if !self.is_compressible() {
+            return None;
+        }
+
+        self.add_vary();
+
+        let length = length.or_else(|| self.headers.get("Content-Length").and_then(|value| value.trim().parse().ok()));
+        if length.is_some_and(|length| length < self.min_size) {
+            return None;
+        }
+
+        let encoding = self.encoding?;
+        let _ = http::Response::set_header(self, "Content-Encoding", encoding.name().into());
+        let _ = http::Response::remove_header(self, "Content-Length");
+
+        // The compressed body is a different representation, so it cannot share a strong validator with the original.
+        if let Some(etag) = self.headers.get("ETag").map(String::from) {
+            if etag.starts_with('"') {
+                let _ = http::Response::set_header(self, "ETag", format!("W/{}", etag));
+            }
+        }
+
+        Some(encoding)
+    }
+
+    /// Start streaming the response, sending the body held back so far.
+    fn start_streaming(&mut self) -> io::Result<()> {
+        let buffer = match self.state {
+            State::Pending(ref mut buffer) => mem::take(buffer),
+            _ => return Ok(()),
+        };
+
+        self.state = match self.start(None) {
+            Some(encoding) => State::Compressing(encoding.encoder()),
+            None => State::Identity,
+        };
+
+        self.write_body(&buffer)
+    }
+
+    fn write_body(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self.state {
+            State::Pending(ref mut buffer) => buffer.extend_from_slice(buf),
+            State::Identity => self.context.response().write_all(buf)?,
+            State::Compressing(ref mut encoder) => {
+                encoder.writer().write_all(buf)?;
+
+                // Without buffering the client expects to see output as it is written.
+                if let Buffering::Off = self.buffering {
+                    encoder.writer().flush()?;
+                }
+
+                let output = encoder.take_output();
+                self.context.response().write_all(&output)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send the rest of the response once the ingot is done with it.
+    fn finish(mut self) -> io::Result<()> {
+        match mem::replace(&mut self.state, State::Pending(Vec::new())) {
+            State::Pending(buffer) => {
+                if buffer.is_empty() && self.headers.contains("Content-Length") {
+                    // Most likely a HEAD request; leave the response alone.
+                    if self.is_compressible() {
+                        self.add_vary();
+                    }
+                    return Ok(());
+                }
+
+                let body = match self.start(Some(buffer.len())) {
+                    Some(encoding) => {
+                        let mut encoder = encoding.encoder();
+                        encoder.writer().write_all(&buffer)?;
+                        let body = encoder.finish()?;
+                        let _ = self.context.response().set_header("Content-Length", body.len().to_string());
+                        body
+                    }
+                    None => buffer,
+                };
+
+                self.state = State::Identity;
+                self.context.response().write_all(&body)
+            }
+            State::Identity => Ok(()),
+            State::Compressing(encoder) => {
+                let output = encoder.finish()?;
+                self.context.response().write_all(&output)
+            }
+        }
+    }
+
+    /// Add `Accept-Encoding` to the `Vary` header, unless it is listed already.
+    fn add_vary(&mut self) {
+        let listed = self.headers.get_all("Vary").any(|value| {
+            value.split(',').any(|name| {
+                let name = name.trim();
+                name == "*" || name.eq_ignore_ascii_case("Accept-Encoding")
+            })
+        });
+
+        if !listed {
+            let _ = http::Response::append_header(self, "Vary", "Accept-Encoding".into());
+        }
+    }
+
+    fn check_headers_sent(&self) -> Result<(), HeadersSentError> {
+        match self.state {
+            State::Pending(_) => Ok(()),
+            _ => Err(HeadersSentError),
+        }
+    }
+}
+
+impl<'a> http::Context for Compressor<'a> {
+    fn remote_addr(&self) -> SocketAddr {
+        self.context.remote_addr()
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.context.server_addr()
+    }
+
+    fn server_name(&self) -> &str {
+        self.context.server_name()
+    }
+
+    fn request(&self) -> &dyn http::Request {
+        self.context.request()
+    }
+
+    fn request_mut(&mut self) -> &mut dyn http::Request {
+        self.context.request_mut()
+    }
+
+    fn response(&mut self) -> &mut dyn http::Response {
+        self
+    }
+
+    fn upgrade(&mut self) -> Result<Box<dyn http::Upgraded>, http::UpgradeError> {
+        self.check_headers_sent()?;
+        let stream = self.context.upgrade()?;
+
+        // The response is complete with the headers, so there is nothing left to compress.
+        self.state = State::Identity;
+        Ok(stream)
+    }
+}
+
+impl<'a> http::Response for Compressor<'a> {
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    fn set_status(&mut self, status: StatusCode) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.context.response().set_status(status)?;
+        self.status = status;
+        Ok(())
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn set_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.context.response().set_header(name, value.clone())?;
+        self.headers.insert(name, value);
+        Ok(())
+    }
+
+    fn append_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.context.response().append_header(name, value.clone())?;
+        self.headers.append(name, value);
+        Ok(())
+    }
+
+    fn remove_header(&mut self, name: &str) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.context.response().remove_header(name)?;
+        self.headers.remove(name);
+        Ok(())
+    }
+
+    fn buffering(&self) -> Buffering {
+        self.buffering
+    }
+
+    fn set_buffering(&mut self, buffering: bool) -> bool {
+        let response = self.context.response();
+        let result = response.set_buffering(buffering);
+        self.buffering = response.buffering();
+        result
+    }
+
+    fn headers_sent(&self) -> bool {
+        self.check_headers_sent().is_err()
+    }
+}
+
+impl<'a> Write for Compressor<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let State::Pending(ref buffer) = self.state {
+            let limit = match self.buffering {
+                Buffering::On(size) => size as usize,
+                Buffering::Off => 0,
+            };
+
+            if buffer.len() + buf.len() > limit {
+                self.start_streaming()?;
+            }
+        }
+
+        self.write_body(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.start_streaming()?;
+
+        if let State::Compressing(ref mut encoder) = self.state {
+            encoder.writer().flush()?;
+            let output = encoder.take_output();
+            self.context.response().write_all(&output)?;
+        }
+
+        self.context.response().flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use brotli::Decompressor;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use http::Context;
+    use middleware::Builder;
+    use std::io::Read;
+    use testing::{TestRequest, TestResponse};
+    use Ingot;
+    use super::*;
+
+    /// Responds with the given headers and body, written in chunks.
+    struct Respond {
+        headers: Vec<(&'static str, &'static str)>,
+        chunks: Vec<Vec<u8>>,
+    }
+
+    impl Ingot for Respond {
+        fn handle(&self, context: &mut dyn Context) {
+            for &(name, value) in self.headers.iter() {
+                context.response().set_header(name, value.into()).unwrap();
+            }
+
+            for chunk in self.chunks.iter() {
+                context.response().write_all(chunk).unwrap();
+            }
+        }
+    }
+
+    fn text() -> Vec<u8> {
+        "All work and no play makes Jack a dull boy. ".repeat(50).into_bytes()
+    }
+
+    fn run(request: TestRequest, headers: Vec<(&'static str, &'static str)>, chunks: Vec<Vec<u8>>) -> TestResponse {
+        let ingot = Builder::new().with(Compression::new()).build(Respond {
+            headers,
+            chunks,
+        });
+        let response = request.run(&ingot);
+
+        assert!(response.violations().is_empty(), "{:?}", response.violations());
+        response
+    }
+
+    fn decode(response: &TestResponse) -> Vec<u8> {
+        let body = response.body();
+        let mut decoded = Vec::new();
+
+        match response.header("Content-Encoding") {
+            Some("br") => Decompressor::new(body, 4096).read_to_end(&mut decoded),
+            Some("gzip") => GzDecoder::new(body).read_to_end(&mut decoded),
+            Some("deflate") => ZlibDecoder::new(body).read_to_end(&mut decoded),
+            encoding => panic!("unexpected encoding {:?}", encoding),
+        }.unwrap();
+
+        decoded
+    }
+
+    #[test]
+    fn negotiate_quality() {
+        assert_eq!(negotiate(vec!["gzip;q=0.5, br;q=0.8, deflate"]), Some(Encoding::Deflate));
+        assert_eq!(negotiate(vec!["gzip;q=0.5", "br;q=0.4"]), Some(Encoding::Gzip));
+        assert_eq!(negotiate(vec!["deflate, gzip, br"]), Some(Encoding::Brotli));
+        assert_eq!(negotiate(vec!["GZIP; Q=0.9, x-gzip;q=0.1"]), Some(Encoding::Gzip));
+        assert_eq!(negotiate(vec!["br;q=0, gzip;q=0"]), None);
+        assert_eq!(negotiate(vec!["br;q=nonsense"]), Some(Encoding::Brotli));
+        assert_eq!(negotiate(vec!["identity", "compress"]), None);
+        assert_eq!(negotiate(Vec::new()), None);
+    }
+
+    #[test]
+    fn negotiate_wildcard() {
+        assert_eq!(negotiate(vec!["*"]), Some(Encoding::Brotli));
+        assert_eq!(negotiate(vec!["*;q=0.5, gzip;q=0.9"]), Some(Encoding::Gzip));
+        assert_eq!(negotiate(vec!["br;q=0, *"]), Some(Encoding::Gzip));
+        assert_eq!(negotiate(vec!["*;q=0, deflate"]), Some(Encoding::Deflate));
+        assert_eq!(negotiate(vec!["*;q=0"]), None);
+    }
+
+    #[test]
+    fn buffered() {
+        for &(accept, encoding) in &[("br", "br"), ("gzip", "gzip"), ("deflate", "deflate")] {
+            let request = TestRequest::get("/").header("Accept-Encoding", accept);
+            let headers = vec![("Content-Type", "text/plain"), ("ETag", "\"v1\"")];
+            let response = run(request, headers, vec![text()]);
+
+            assert_eq!(response.header("Content-Encoding"), Some(encoding));
+            assert_eq!(response.header("Content-Length"), Some(response.body().len().to_string().as_str()));
+            assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+            assert_eq!(response.header("ETag"), Some("W/\"v1\""));
+            assert!(response.body().len() < text().len());
+            assert_eq!(decode(&response), text());
+        }
+    }
+
+    #[test]
+    fn streaming() {
+        let request = TestRequest::get("/").header("Accept-Encoding", "gzip").buffering(false);
+        let chunks = vec![text(), text(), b"end".to_vec()];
+        let response = run(request, vec![("Content-Type", "text/html")], chunks);
+
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("Content-Length"), None);
+        assert_eq!(decode(&response), [text(), text(), b"end".to_vec()].concat());
+    }
+
+    #[test]
+    fn not_accepted() {
+        let response = run(TestRequest::get("/"), vec![("Content-Type", "text/plain")], vec![text()]);
+
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body(), &text()[..]);
+    }
+
+    #[test]
+    fn small_body() {
+        let request = TestRequest::get("/").header("Accept-Encoding", "gzip");
+        let response = run(request, vec![("Content-Type", "text/plain")], vec![b"tiny".to_vec()]);
+
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.text(), "tiny");
+    }
+
+    #[test]
+    fn incompressible() {
+        let cases = vec![
+            vec![("Content-Type", "image/png")],
+            vec![("Content-Type", "application/zip")],
+            vec![("Content-Type", "video/mp4")],
+            vec![("Content-Encoding", "gzip")],
+            vec![("Cache-Control", "public, no-transform")],
+        ];
+
+        for headers in cases {
+            let request = TestRequest::get("/").header("Accept-Encoding", "gzip");
+            let response = run(request, headers.clone(), vec![text()]);
+
+            assert_eq!(response.body(), &text()[..], "{:?}", headers);
+            assert_eq!(response.header("Vary"), None, "{:?}", headers);
+        }
+    }
+
+    #[test]
+    fn octet_stream_is_compressed() {
+        let request = TestRequest::get("/").header("Accept-Encoding", "gzip");
+        let response = run(request, vec![("Content-Type", "application/octet-stream")], vec![text()]);
+
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(decode(&response), text());
+    }
+
+    #[test]
+    fn head() {
+        assert_eq!(text().len(), 2200);
+
+        let request = TestRequest::head("/").header("Accept-Encoding", "gzip");
+        let response = run(request, vec![("Content-Type", "text/plain"), ("Content-Length", "2200")], Vec::new());
+
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Content-Length"), Some("2200"));
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+    }
+}
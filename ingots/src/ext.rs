@@ -1,20 +1,73 @@
-pub trait RequestExt {}
+//! Convenience methods for requests and responses, built on top of the core HTTP interface.
+//!
+//! The traits are implemented for every `Request` and `Response`, including trait objects, so importing them is enough
+//! to use the methods.
+use http::{HeadersSentError, Params, Request, Response};
+use http::cookie::{self, Cookie};
+use http::sse::{self, EventStream};
 
-impl Request {
+
+pub trait RequestExt: Request {
+    /// Get the absolute URL of the root of the application, built from the `Host` header and the context path.
     fn get_app_root(&self) -> String {
-        format!("{}://{}/{}", self.is_secure() ? "https" : "http", self.hostname().unwrap_or("localhost"), self.context_path())
+        format!("{}://{}{}/",
+            if self.is_secure() { "https" } else { "http" },
+            self.get_header("Host").unwrap_or("localhost"),
+            self.context_path())
+    }
+
+    /// Get the cookies sent by the client.
+    fn cookies(&self) -> Params {
+        cookie::parse_cookies(self.headers().get_all("Cookie"))
+    }
+
+    /// Get the value of the cookie with the given name, if the client sent it.
+    fn get_cookie(&self, name: &str) -> Option<String> {
+        self.cookies().get(name).map(String::from)
+    }
+
+    /// Get the ID of the last Server-Sent Event received by a client that is reconnecting to an event stream.
+    fn last_event_id(&self) -> Option<&str> {
+        self.get_header("Last-Event-ID")
     }
 }
 
-impl Response {
-    fn redirect(&mut self, url: &str) {
-        self.set_status(301);
-        self.set_header("Location", url);
+impl<R: Request + ?Sized> RequestExt for R {}
+
+
+pub trait ResponseExt: Response {
+    /// Redirect the client permanently to another URL.
+    fn redirect(&mut self, url: &str) -> Result<(), HeadersSentError> {
+        self.set_status(301)?;
+        self.set_header("Location", url.into())
     }
 
-    fn download(&mut self, filename: &str) {
-        self.set_header("Content-Type", "application/octet-stream");
-        self.set_header("Content-Transfer-Encoding", "binary");
-        self.set_header("Content-Disposition", &format!("attachment; filename=\"{}\"", filename));
+    /// Ask the client to save the response body as a file with the given name.
+    fn download(&mut self, filename: &str) -> Result<(), HeadersSentError> {
+        let filename = filename.replace('\\', "\\\\").replace('"', "\\\"").replace(['\r', '\n'], "");
+
+        self.set_header("Content-Type", "application/octet-stream".into())?;
+        self.set_header("Content-Transfer-Encoding", "binary".into())?;
+        self.set_header("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+    }
+
+    /// Send a cookie to the client, in addition to any other cookies already set.
+    fn set_cookie(&mut self, cookie: &Cookie) -> Result<(), HeadersSentError> {
+        self.append_header("Set-Cookie", cookie.to_string())
+    }
+
+    /// Tell the client to delete a cookie that was set with the default path and domain.
+    ///
+    /// For cookies set with a path or domain, send `Cookie::removal` with the same attributes instead. Returns an error
+    /// if the name is not a valid cookie name.
+    fn remove_cookie(&mut self, name: &str) -> Result<(), cookie::Error> {
+        Ok(self.set_cookie(&Cookie::removal(name)?)?)
+    }
+
+    /// Start streaming Server-Sent Events as the response body. See `EventStream::new`.
+    fn event_stream(&mut self) -> Result<EventStream<'_, Self>, sse::Error> {
+        EventStream::new(self)
     }
 }
+
+impl<R: Response + ?Sized> ResponseExt for R {}
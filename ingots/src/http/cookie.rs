@@ -0,0 +1,355 @@
+//! Parsing of request cookies and building of `Set-Cookie` response headers.
+//!
+//! See `ext::RequestExt::cookies` and `ext::ResponseExt::set_cookie` for the usual way to use cookies from an ingot.
+use super::date::format_http_date;
+use super::{params, HeadersSentError, Params};
+use std::error;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+
+/// Parse the cookies sent by the client in one or more `Cookie` header values.
+///
+/// Pairs without a `=` are ignored, and values surrounded by double quotes are unquoted. Values are percent-decoded, so
+/// that a value sent with `Cookie` reads back the same; a `+` is not taken for a space.
+pub fn parse_cookies<'a, I: IntoIterator<Item = &'a str>>(headers: I) -> Params {
+    headers.into_iter()
+        .flat_map(|header| header.split(';'))
+        .filter_map(|pair| {
+            let index = pair.find('=')?;
+            let name = pair[..index].trim();
+            let value = pair[index + 1..].trim();
+            let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+                &value[1..value.len() - 1]
+            } else {
+                value
+            };
+
+            if name.is_empty() {
+                None
+            } else {
+                Some((name, params::decode_path(value)))
+            }
+        })
+        .collect()
+}
+
+
+/// Value of the `SameSite` cookie attribute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Send the cookie with cross-site requests. Browsers require such cookies to also be `Secure`.
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        })
+    }
+}
+
+/// Builder for a cookie sent to the client in a `Set-Cookie` header.
+///
+/// The `Display` implementation produces the header value. Characters that are not allowed in a cookie value, such as
+/// spaces, commas and semicolons, are percent-encoded, and characters that are not allowed in an attribute are left
+/// out, so that a cookie built from untrusted input cannot inject attributes or headers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<SystemTime>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// Create a session cookie with the given name and value and no attributes.
+    ///
+    /// Returns an error if the name is not a valid cookie name.
+    pub fn new<N: Into<String>, V: Into<String>>(name: N, value: V) -> Result<Self, Error> {
+        let name = name.into();
+
+        if !Self::is_valid_name(&name) {
+            return Err(Error::InvalidName(name));
+        }
+
+        Ok(Self {
+            name,
+            value: value.into(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        })
+    }
+
+    /// Create a cookie that tells the client to delete the cookie with the given name.
+    ///
+    /// The path and domain must match the ones the cookie was set with.
+    pub fn removal<N: Into<String>>(name: N) -> Result<Self, Error> {
+        Ok(Self::new(name, "")?.max_age(Duration::from_secs(0)).expires(SystemTime::UNIX_EPOCH))
+    }
+
+    /// Check if a string can be used as a cookie name: it must be a non-empty token (RFC 6265).
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty() && name.chars().all(is_token)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Limit the cookie to URL paths under the given path.
+    pub fn path<S: Into<String>>(mut self, path: S) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Send the cookie to the given domain and its subdomains.
+    pub fn domain<S: Into<String>>(mut self, domain: S) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// Expire the cookie after the given duration, with one second precision.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Expire the cookie at the given time. Clients that support `Max-Age` ignore this if it is also set.
+    pub fn expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Only send the cookie over secure connections.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Hide the cookie from scripts running in the browser.
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Control whether the cookie is sent with cross-site requests.
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}=", self.name)?;
+
+        for c in self.value.chars() {
+            if is_cookie_octet(c) {
+                write!(f, "{}", c)?;
+            } else {
+                for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                    write!(f, "%{:02X}", byte)?;
+                }
+            }
+        }
+
+        if let Some(ref path) = self.path {
+            write!(f, "; Path={}", sanitize_attribute(path))?;
+        }
+        if let Some(ref domain) = self.domain {
+            write!(f, "; Domain={}", sanitize_attribute(domain))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", format_http_date(expires))?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site)?;
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Error returned when a cookie cannot be created or sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The name is not a valid cookie name.
+    InvalidName(String),
+    /// The response has already been started, so the cookie can no longer be sent.
+    HeadersSent,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidName(ref name) => write!(f, "invalid cookie name {:?}", name),
+            Error::HeadersSent => HeadersSentError.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<HeadersSentError> for Error {
+    fn from(_: HeadersSentError) -> Error {
+        Error::HeadersSent
+    }
+}
+
+
+/// Check if a character is allowed in a token, such as a cookie name (RFC 7230).
+pub(crate) fn is_token(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// Check if a character is allowed in a cookie value (RFC 6265).
+fn is_cookie_octet(c: char) -> bool {
+    c.is_ascii_graphic() && c != '"' && c != ',' && c != ';' && c != '\\'
+}
+
+fn sanitize_attribute(value: &str) -> String {
+    value.chars().filter(|&c| (c.is_ascii_graphic() || c == ' ') && c != ';').collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ext::{RequestExt, ResponseExt};
+    use http::Context;
+    use std::time::{Duration, SystemTime};
+    use testing::TestRequest;
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let cookies = parse_cookies(vec!["a=1; b=\"quoted value\"; flag; =empty", " c = 3 ;a=4"]);
+
+        assert_eq!(cookies.iter().collect::<Vec<_>>(), [("a", "1"), ("b", "quoted value"), ("c", "3"), ("a", "4")]);
+        assert_eq!(parse_cookies(vec!["x=%20\"+%zz"]).get("x"), Some(" \"+%zz"));
+        assert!(parse_cookies(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn request_and_response() {
+        let mut context = TestRequest::get("/")
+            .header("Cookie", "theme=dark; id=7")
+            .header("Cookie", "lang=en")
+            .into_context();
+
+        assert_eq!(context.request().get_cookie("id"), Some(String::from("7")));
+        assert_eq!(context.request().get_cookie("lang"), Some(String::from("en")));
+
+        context.response().set_cookie(&Cookie::new("id", "8").unwrap().http_only(true)).unwrap();
+        context.response().remove_cookie("theme").unwrap();
+
+        let response = context.finish();
+        assert_eq!(
+            response.headers().get_all("Set-Cookie").collect::<Vec<_>>(),
+            ["id=8; HttpOnly", "theme=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"]
+        );
+    }
+
+    #[test]
+    fn serialize() {
+        let cookie = Cookie::new("session", "abc123").unwrap()
+            .path("/app")
+            .domain("example.com")
+            .max_age(Duration::from_secs(3600))
+            .expires(SystemTime::UNIX_EPOCH + Duration::from_secs(784111777))
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax);
+
+        assert_eq!(
+            cookie.to_string(),
+            "session=abc123; Path=/app; Domain=example.com; Max-Age=3600; Expires=Sun, 06 Nov 1994 08:49:37 GMT; \
+             Secure; HttpOnly; SameSite=Lax"
+        );
+        assert_eq!(Cookie::new("a", "").unwrap().to_string(), "a=");
+    }
+
+    #[test]
+    fn removal() {
+        assert_eq!(Cookie::removal("id").unwrap().to_string(), "id=; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    fn value_is_percent_encoded() {
+        let encode = |value: &str| Cookie::new("a", value).unwrap().to_string();
+
+        assert_eq!(encode("two words, then; more"), "a=two%20words%2C%20then%3B%20more");
+        assert_eq!(encode("\"q\"\\caf\u{e9}"), "a=%22q%22%5Ccaf%C3%A9");
+        assert_eq!(encode("x\r\nSet-Cookie: b=1"), "a=x%0D%0ASet-Cookie:%20b=1");
+    }
+
+    #[test]
+    fn attributes_cannot_inject() {
+        let cookie = Cookie::new("a", "1").unwrap().path("/; Secure\r\n").domain("example.com;x");
+
+        assert_eq!(cookie.to_string(), "a=1; Path=/ Secure; Domain=example.comx");
+    }
+
+    #[test]
+    fn valid_names() {
+        assert!(Cookie::is_valid_name("__Host-id"));
+        assert!(!Cookie::is_valid_name(""));
+        assert!(!Cookie::is_valid_name("a b"));
+        assert!(!Cookie::is_valid_name("a=b"));
+        assert!(!Cookie::is_valid_name("caf\u{e9}"));
+    }
+
+    #[test]
+    fn invalid_name() {
+        assert_eq!(Cookie::new("; =", "value"), Err(Error::InvalidName(String::from("; ="))));
+        assert_eq!(Cookie::removal(""), Err(Error::InvalidName(String::new())));
+        assert_eq!(Cookie::new("a b", "").unwrap_err().to_string(), "invalid cookie name \"a b\"");
+
+        let mut context = TestRequest::get("/").into_context();
+        assert_eq!(context.response().remove_cookie("a b"), Err(Error::InvalidName(String::from("a b"))));
+        assert!(context.finish().header("Set-Cookie").is_none());
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = ["plain", "", "two words, then; more", "\"quoted\"", "back\\slash", "caf\u{e9}", "100%", "a+b"];
+
+        for &value in values.iter() {
+            let header = Cookie::new("v", value).unwrap().http_only(true).to_string();
+            let pair = header.split("; ").next().unwrap();
+            let context = TestRequest::get("/").header("Cookie", &format!("other=1; {}", pair)).into_context();
+
+            assert_eq!(context.request().get_cookie("v").as_deref(), Some(value), "{}", header);
+            assert_eq!(context.request().cookies().len(), 2);
+        }
+    }
+}
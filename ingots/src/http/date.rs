@@ -0,0 +1,147 @@
+//! Formatting and parsing of HTTP dates, as used in `Expires`, `Date` and `Last-Modified` headers.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Format a time in the IMF-fixdate format, such as `Sun, 06 Nov 1994 08:49:37 GMT`.
+///
+/// Times before the Unix epoch are formatted as the epoch.
+pub fn format_http_date(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    let days = seconds / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize], day, MONTHS[month as usize - 1], year,
+        seconds % 86400 / 3600, seconds % 3600 / 60, seconds % 60)
+}
+
+/// Parse an HTTP date in any of the formats allowed by RFC 7231: IMF-fixdate, RFC 850 and asctime.
+///
+/// Returns `None` if the date is invalid or before the Unix epoch.
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let mut tokens = value.split(|c: char| c.is_whitespace() || c == ',' || c == '-' || c == ':')
+        .filter(|token| !token.is_empty());
+
+    let _weekday = tokens.next()?;
+    let first = tokens.next()?;
+    let (day, month, year, time) = match month_number(first) {
+        // asctime: `Sun Nov  6 08:49:37 1994`
+        Some(month) => {
+            let day = tokens.next()?;
+            let time = [tokens.next()?, tokens.next()?, tokens.next()?];
+            (day, month, tokens.next()?, time)
+        }
+        // IMF-fixdate: `Sun, 06 Nov 1994 08:49:37 GMT`, or RFC 850: `Sunday, 06-Nov-94 08:49:37 GMT`
+        None => {
+            let month = month_number(tokens.next()?)?;
+            let year = tokens.next()?;
+            (first, month, year, [tokens.next()?, tokens.next()?, tokens.next()?])
+        }
+    };
+
+    let day: u32 = day.parse().ok()?;
+    let mut year: i64 = year.parse().ok()?;
+    // RFC 850 uses two-digit years; interpret them as the closest matching year.
+    if year < 100 {
+        year += if year < 70 { 2000 } else { 1900 };
+    }
+    let hour: u64 = time[0].parse().ok()?;
+    let minute: u64 = time[1].parse().ok()?;
+    let second: u64 = time[2].parse().ok()?;
+
+    if day == 0 || day > 31 || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+
+    Some(UNIX_EPOCH + Duration::from_secs(days as u64 * 86400 + hour * 3600 + minute * 60 + second))
+}
+
+fn month_number(name: &str) -> Option<u32> {
+    MONTHS.iter().position(|month| month.eq_ignore_ascii_case(name)).map(|index| index as u32 + 1)
+}
+
+/// Convert a year, month and day in the proleptic Gregorian calendar to a number of days since the Unix epoch.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * shifted_month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// Convert a number of days since the Unix epoch to a year, month and day in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Algorithm from Howard Hinnant's "chrono-Compatible Low-Level Date Algorithms".
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use super::*;
+
+    #[test]
+    fn format() {
+        assert_eq!(format_http_date(UNIX_EPOCH + Duration::from_secs(784111777)), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format_http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format_http_date(UNIX_EPOCH - Duration::from_secs(1)), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format_http_date(UNIX_EPOCH + Duration::from_secs(951782400)), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn parse_all_formats() {
+        let expected = Some(UNIX_EPOCH + Duration::from_secs(784111777));
+
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), expected);
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        for &seconds in &[0, 68169600, 951782400, 1709164800, 4102444799] {
+            let time = UNIX_EPOCH + Duration::from_secs(seconds);
+            assert_eq!(parse_http_date(&format_http_date(time)), Some(time));
+        }
+    }
+
+    #[test]
+    fn parse_two_digit_years() {
+        assert_eq!(parse_http_date("Thursday, 01-Jan-70 00:00:00 GMT"), Some(UNIX_EPOCH));
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(parse_http_date("Tuesday, 29-Feb-00 00:00:00 GMT"), Some(leap_day));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(parse_http_date(""), None);
+        assert_eq!(parse_http_date("yesterday"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 32 Nov 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+        assert_eq!(parse_http_date("Wed, 31 Dec 1969 23:59:59 GMT"), None);
+    }
+}
@@ -0,0 +1,378 @@
+//! Storage for HTTP header fields.
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::slice;
+
+
+/// An ordered, case-insensitive multimap of HTTP header fields.
+///
+/// Header names are compared case-insensitively, but the casing used when a field is first inserted is preserved.
+/// Fields may have multiple values, which are kept in the order they were added. Lookups by name do not allocate.
+#[derive(Clone, Default)]
+pub struct HeaderMap {
+    entries: Vec<Entry>,
+    index: HashMap<Key, usize>,
+}
+
+#[derive(Clone)]
+struct Entry {
+    name: String,
+    values: Vec<String>,
+}
+
+impl HeaderMap {
+    /// Create a new, empty header map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the number of header values in the map, counting each value of a multi-valued field separately.
+    pub fn len(&self) -> usize {
+        self.entries.iter().map(|entry| entry.values.len()).sum()
+    }
+
+    /// Check if the map contains no headers.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Check if a header with the given name is present.
+    pub fn contains(&self, name: &str) -> bool {
+        self.index.contains_key(Uncased::new(name))
+    }
+
+    /// Get the first value of the header with the given name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entry(name)
+            .and_then(|entry| entry.values.first())
+            .map(String::as_str)
+    }
+
+    /// Get all values of the header with the given name, in the order they were added.
+    pub fn get_all(&self, name: &str) -> Values<'_> {
+        Values {
+            inner: self.entry(name)
+                .map(|entry| entry.values.iter())
+                .unwrap_or_else(|| [].iter()),
+        }
+    }
+
+    /// Set the value of a header, replacing any existing values.
+    pub fn insert<V: Into<String>>(&mut self, name: &str, value: V) {
+        let value = value.into();
+
+        match self.index.get(Uncased::new(name)) {
+            Some(&i) => {
+                let values = &mut self.entries[i].values;
+                values.clear();
+                values.push(value);
+            }
+            None => self.push_entry(name, value),
+        }
+    }
+
+    /// Add a value to a header, keeping any existing values.
+    pub fn append<V: Into<String>>(&mut self, name: &str, value: V) {
+        let value = value.into();
+
+        match self.index.get(Uncased::new(name)) {
+            Some(&i) => self.entries[i].values.push(value),
+            None => self.push_entry(name, value),
+        }
+    }
+
+    /// Remove all values of a header, returning them if the header was present.
+    pub fn remove(&mut self, name: &str) -> Option<Vec<String>> {
+        let i = self.index.remove(Uncased::new(name))?;
+        let entry = self.entries.remove(i);
+
+        for index in self.index.values_mut() {
+            if *index > i {
+                *index -= 1;
+            }
+        }
+
+        Some(entry.values)
+    }
+
+    /// Remove all headers.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.index.clear();
+    }
+
+    /// Iterate over the distinct header names, in the order they were first added.
+    pub fn names(&self) -> Names<'_> {
+        Names {
+            inner: self.entries.iter(),
+        }
+    }
+
+    /// Iterate over every header name and value pair.
+    ///
+    /// A multi-valued header produces one pair per value.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            entries: self.entries.iter(),
+            current: None,
+        }
+    }
+
+    fn entry(&self, name: &str) -> Option<&Entry> {
+        self.index.get(Uncased::new(name)).map(|&i| &self.entries[i])
+    }
+
+    fn push_entry(&mut self, name: &str, value: String) {
+        self.index.insert(Key(name.into()), self.entries.len());
+        self.entries.push(Entry {
+            name: name.into(),
+            values: vec![value],
+        });
+    }
+}
+
+impl fmt::Debug for HeaderMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl<N: AsRef<str>, V: Into<String>> Extend<(N, V)> for HeaderMap {
+    fn extend<T: IntoIterator<Item = (N, V)>>(&mut self, iter: T) {
+        for (name, value) in iter {
+            self.append(name.as_ref(), value);
+        }
+    }
+}
+
+impl<N: AsRef<str>, V: Into<String>> FromIterator<(N, V)> for HeaderMap {
+    fn from_iter<T: IntoIterator<Item = (N, V)>>(iter: T) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.extend(iter);
+        headers
+    }
+}
+
+
+/// Iterator over the values of a single header.
+pub struct Values<'a> {
+    inner: slice::Iter<'a, String>,
+}
+
+impl<'a> Iterator for Values<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        self.inner.next().map(String::as_str)
+    }
+}
+
+/// Iterator over the distinct names in a header map.
+pub struct Names<'a> {
+    inner: slice::Iter<'a, Entry>,
+}
+
+impl<'a> Iterator for Names<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        self.inner.next().map(|entry| entry.name.as_str())
+    }
+}
+
+/// Iterator over all name and value pairs in a header map.
+pub struct Iter<'a> {
+    entries: slice::Iter<'a, Entry>,
+    current: Option<(&'a str, slice::Iter<'a, String>)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        loop {
+            if let Some((name, ref mut values)) = self.current {
+                if let Some(value) = values.next() {
+                    return Some((name, value));
+                }
+            }
+
+            let entry = self.entries.next()?;
+            self.current = Some((&entry.name, entry.values.iter()));
+        }
+    }
+}
+
+
+/// A header name that hashes and compares without regard to ASCII case.
+#[derive(Clone)]
+struct Key(Box<str>);
+
+/// Borrowed form of `Key`, so that the index can be queried with a plain `&str`.
+#[repr(transparent)]
+struct Uncased(str);
+
+impl Uncased {
+    fn new(name: &str) -> &Uncased {
+        // Safe because `Uncased` is a transparent wrapper around `str`.
+        unsafe { &*(name as *const str as *const Uncased) }
+    }
+}
+
+impl PartialEq for Uncased {
+    fn eq(&self, other: &Uncased) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl Eq for Uncased {}
+
+impl Hash for Uncased {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for byte in self.0.bytes() {
+            state.write_u8(byte.to_ascii_lowercase());
+        }
+        state.write_u8(0xff);
+    }
+}
+
+impl Borrow<Uncased> for Key {
+    fn borrow(&self) -> &Uncased {
+        Uncased::new(&self.0)
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        Uncased::new(&self.0) == Uncased::new(&other.0)
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Uncased::new(&self.0).hash(state)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(headers: &HeaderMap) -> Vec<(&str, &str)> {
+        headers.iter().collect()
+    }
+
+    #[test]
+    fn get_ignores_case() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", "text/plain");
+
+        assert_eq!(headers.get("content-type"), Some("text/plain"));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("text/plain"));
+        assert!(headers.contains("cOnTeNt-TyPe"));
+        assert_eq!(headers.get("Content-Length"), None);
+        assert_eq!(headers.get_all("Content-Length").count(), 0);
+    }
+
+    #[test]
+    fn insert_replaces() {
+        let mut headers = HeaderMap::new();
+        headers.append("Vary", "Accept");
+        headers.append("vary", "Accept-Encoding");
+        headers.insert("VARY", "Origin");
+
+        // The field keeps the casing it was first added with.
+        assert_eq!(pairs(&headers), [("Vary", "Origin")]);
+        assert_eq!(headers.len(), 1);
+    }
+
+    #[test]
+    fn append_adds() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Set-Cookie", "a=1");
+        headers.append("set-cookie", "b=2");
+        headers.append("Set-Cookie", "c=3");
+
+        assert_eq!(headers.get("Set-Cookie"), Some("a=1"));
+        assert_eq!(headers.get_all("SET-COOKIE").collect::<Vec<_>>(), ["a=1", "b=2", "c=3"]);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers.names().collect::<Vec<_>>(), ["Set-Cookie"]);
+    }
+
+    #[test]
+    fn remove_updates_index() {
+        let mut headers: HeaderMap = vec![("A", "1"), ("B", "2"), ("C", "3"), ("B", "4"), ("D", "5")]
+            .into_iter()
+            .collect();
+
+        assert_eq!(headers.remove("b"), Some(vec![String::from("2"), String::from("4")]));
+        assert_eq!(headers.remove("B"), None);
+
+        // The fields after the removed one have moved, and must still be found.
+        assert_eq!(headers.get("A"), Some("1"));
+        assert_eq!(headers.get("C"), Some("3"));
+        assert_eq!(headers.get("D"), Some("5"));
+
+        headers.insert("c", "6");
+        headers.append("D", "7");
+        headers.append("B", "8");
+        assert_eq!(pairs(&headers), [("A", "1"), ("C", "6"), ("D", "5"), ("D", "7"), ("B", "8")]);
+
+        assert_eq!(headers.remove("A"), Some(vec![String::from("1")]));
+        assert_eq!(headers.remove("B"), Some(vec![String::from("8")]));
+        assert_eq!(pairs(&headers), [("C", "6"), ("D", "5"), ("D", "7")]);
+    }
+
+    #[test]
+    fn iteration_order() {
+        let mut headers = HeaderMap::new();
+        headers.append("Host", "example.com");
+        headers.append("Accept", "text/html");
+        headers.append("Cookie", "a=1");
+        headers.append("accept", "*/*");
+
+        // Values are grouped under the field, which stays where it was first added.
+        let expected = [("Host", "example.com"), ("Accept", "text/html"), ("Accept", "*/*"), ("Cookie", "a=1")];
+        assert_eq!(pairs(&headers), expected);
+        assert_eq!(headers.names().collect::<Vec<_>>(), ["Host", "Accept", "Cookie"]);
+        assert_eq!((&headers).into_iter().count(), 4);
+    }
+
+    #[test]
+    fn clear() {
+        let mut headers = HeaderMap::new();
+        headers.insert("A", "1");
+        headers.clear();
+
+        assert!(headers.is_empty());
+        assert!(!headers.contains("A"));
+
+        headers.insert("B", "2");
+        assert_eq!(pairs(&headers), [("B", "2")]);
+    }
+
+    #[test]
+    fn non_ascii_names() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Ä", "1");
+
+        // Only ASCII letters are folded.
+        assert_eq!(headers.get("x-Ä"), Some("1"));
+        assert_eq!(headers.get("x-ä"), None);
+    }
+}
@@ -0,0 +1,474 @@
+//! Ingot's HTTP interface.
+//!
+//! This HTTP module is not meant to be full-featured. The API was designed to have as little surface area as possible
+//! while still being idiomatic and easy to use. This helps reduce the amount of work required for both servers and
+//! handler frameworks to implement and use the interface.
+use async_io::{AsyncRead, AsyncWrite};
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use websocket::{self, WebSocket};
+
+pub mod cookie;
+pub mod date;
+pub mod headers;
+pub mod multipart;
+pub mod params;
+pub mod sse;
+pub mod status;
+
+pub use self::cookie::{Cookie, SameSite};
+pub use self::headers::HeaderMap;
+pub use self::multipart::{Multipart, MultipartError};
+pub use self::params::{FormError, Params};
+pub use self::status::reason_phrase;
+
+pub type StatusCode = u16;
+
+/// Version of the HTTP protocol that a request was received with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Version {
+    Http09,
+    Http10,
+    Http11,
+    Http2,
+    Http3,
+}
+
+impl Version {
+    /// Parse a protocol name as it appears in a request line or in the CGI `SERVER_PROTOCOL` variable.
+    pub fn parse(name: &str) -> Option<Version> {
+        match name.trim().to_ascii_uppercase().as_str() {
+            "HTTP/0.9" => Some(Version::Http09),
+            "HTTP/1.0" => Some(Version::Http10),
+            "HTTP/1.1" => Some(Version::Http11),
+            "HTTP/2" | "HTTP/2.0" => Some(Version::Http2),
+            "HTTP/3" | "HTTP/3.0" => Some(Version::Http3),
+            _ => None,
+        }
+    }
+
+    /// Get the protocol name, such as `HTTP/1.1`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Version::Http09 => "HTTP/0.9",
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+            Version::Http2 => "HTTP/2",
+            Version::Http3 => "HTTP/3",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Encapsulates the state of an individual HTTP request from the web server.
+pub trait Context: Send {
+    /// Get the address of the remote client.
+    fn remote_addr(&self) -> SocketAddr;
+
+    /// Get the address of the server.
+    fn server_addr(&self) -> SocketAddr;
+
+    /// Get the name of the server.
+    fn server_name(&self) -> &str;
+
+    /// Get the HTTP request for the current request.
+    fn request(&self) -> &dyn Request;
+
+    /// Get the HTTP request for the current request, for reading the request body.
+    fn request_mut(&mut self) -> &mut dyn Request;
+
+    /// Get the HTTP response for the current request.
+    fn response(&mut self) -> &mut dyn Response;
+
+    /// Send the response status and headers, and take over the connection to switch to another protocol.
+    ///
+    /// The status, normally 101, and the `Upgrade` and `Connection` headers must be set beforehand. The returned stream
+    /// reads and writes the raw connection, and the server closes the connection once it is dropped. No response body
+    /// can be written after an upgrade.
+    ///
+    /// Servers that cannot hand over the connection, such as gateways to another web server, return
+    /// `UpgradeError::Unsupported`, and servers that have already handed over as many connections as they allow return
+    /// `UpgradeError::Unavailable`. Either way the response is left untouched.
+    fn upgrade(&mut self) -> Result<Box<dyn Upgraded>, UpgradeError> {
+        Err(UpgradeError::Unsupported)
+    }
+
+    /// Accept a WebSocket handshake and switch the connection to the WebSocket protocol.
+    ///
+    /// If given, `protocol` is sent as the subprotocol chosen from those offered by the client. If the request is not a
+    /// valid handshake, the response status is set to 400 and `UpgradeError::Handshake` is returned; if the server
+    /// does not support upgrades, the status is set to 501, or to 503 if it cannot take over any more connections.
+    /// Either way the ingot can then finish the response as usual.
+    fn upgrade_websocket(&mut self, protocol: Option<&str>) -> Result<WebSocket, UpgradeError> {
+        let accept = match websocket::check_handshake(self.request()) {
+            Ok(accept) => accept,
+            Err(e) => {
+                let response = self.response();
+                response.set_status(400)?;
+                if let UpgradeError::Handshake(websocket::UNSUPPORTED_VERSION) = e {
+                    response.set_header("Sec-WebSocket-Version", websocket::VERSION.into())?;
+                }
+                return Err(e);
+            }
+        };
+
+        {
+            let response = self.response();
+            response.set_status(101)?;
+            response.set_header("Upgrade", "websocket".into())?;
+            response.set_header("Connection", "Upgrade".into())?;
+            response.set_header("Sec-WebSocket-Accept", accept)?;
+            if let Some(protocol) = protocol {
+                response.set_header("Sec-WebSocket-Protocol", protocol.into())?;
+            }
+        }
+
+        let error = match self.upgrade() {
+            Ok(stream) => return Ok(WebSocket::new(stream)),
+            Err(e) => e,
+        };
+        let status = match error {
+            UpgradeError::Unsupported => 501,
+            UpgradeError::Unavailable => 503,
+            _ => return Err(error),
+        };
+
+        let response = self.response();
+        response.set_status(status)?;
+        for name in &["Upgrade", "Connection", "Sec-WebSocket-Accept", "Sec-WebSocket-Protocol"] {
+            response.remove_header(name)?;
+        }
+        Err(error)
+    }
+}
+
+/// A connection taken over from the server with `Context::upgrade`.
+pub trait Upgraded: io::Read + io::Write + Send {
+    /// Set how long a read may wait for data from the client before failing with a `WouldBlock` or `TimedOut` error,
+    /// or `None` to wait indefinitely, which is the default.
+    ///
+    /// Servers that cannot time out reads on the connection return an `Unsupported` error.
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "the server does not support read timeouts"))
+    }
+}
+
+/// A connection taken over from the server with `AsyncContext::upgrade`.
+pub trait AsyncUpgraded: AsyncRead + AsyncWrite + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Send + ?Sized> AsyncUpgraded for T {}
+
+/// An incoming HTTP request.
+///
+/// Provides information about a client request, including parameters, attributes, and a request body stream.
+pub trait Request: io::Read + Send {
+    /// Get the HTTP request method.
+    ///
+    /// The string returned is not required to follow strict casing. You should normalize the returned string before
+    /// checking for specific HTTP methods.
+    fn method(&self) -> Cow<'_, str>;
+
+    /// Get the portion of the URI path that corresponds to this application object.
+    fn context_path(&self) -> Cow<'_, str>;
+
+    fn path_info(&self) -> Cow<'_, str>;
+
+    /// Get the query string contained in the request URI, if present.
+    fn query_string(&self) -> Option<Cow<'_, str>> {
+        None
+    }
+
+    /// Get the decoded parameters of the query string.
+    fn query(&self) -> Params {
+        self.query_string().map(|query| Params::parse(&query)).unwrap_or_default()
+    }
+
+    /// Read the request body as an `application/x-www-form-urlencoded` form, up to `limit` bytes.
+    ///
+    /// This consumes the request body. See `params::read_form` for details.
+    fn form(&mut self, limit: usize) -> Result<Params, FormError> {
+        params::read_form(self, limit)
+    }
+
+    /// Get the request headers.
+    ///
+    /// Header names are not required to have the same letter casing as sent by the client.
+    fn headers(&self) -> &HeaderMap;
+
+    /// Get the first value of a request header.
+    ///
+    /// The header name is case-insensitive, and may not match the exact casing as sent by the client.
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers().get(name)
+    }
+
+    /// Check if this is a secure HTTPS connection.
+    fn is_secure(&self) -> bool {
+        false
+    }
+
+    /// Get the version of the HTTP protocol negotiated for the request.
+    fn version(&self) -> Version {
+        Version::Http11
+    }
+}
+
+/// An outgoing HTTP response.
+///
+/// Response objects are not constructed by the application; they are constructed by the web server that is proxying the
+/// response. The response is a "write-oriented" API, where content is written to the response sequentially.
+///
+/// The status and headers are held back until the body is first sent to the client, which happens on the first write
+/// when buffering is disabled, or when the buffer is first flushed when buffering is enabled. Once the headers have been
+/// sent they can no longer be changed, and methods that would modify them return `HeadersSentError`.
+pub trait Response: io::Write + Send {
+    /// Get the response status code.
+    fn status(&self) -> StatusCode;
+
+    /// Set the response status code.
+    fn set_status(&mut self, status: StatusCode) -> Result<(), HeadersSentError>;
+
+    /// Get the response headers that have been set so far.
+    fn headers(&self) -> &HeaderMap;
+
+    /// Set the value of the specified header.
+    ///
+    /// This method will replace any existing headers for the specified field.
+    fn set_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError>;
+
+    /// Add a value for the specified header, keeping any existing values.
+    ///
+    /// Use this for fields that may be repeated, such as `Set-Cookie`.
+    fn append_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError>;
+
+    /// Remove all values of the specified header.
+    fn remove_header(&mut self, name: &str) -> Result<(), HeadersSentError>;
+
+    /// Check if buffering is currently enabled for the response body.
+    ///
+    /// When output buffering is enabled, data written to the response body is collected in a buffer before being sent
+    /// to the client. When disabled, the body is streamed to the client as it written.
+    ///
+    /// Note that this only indicates if the response is buffered between the web server and the client. Even if
+    /// buffering is disabled, there may be additional layers between the server and the user that may perform response
+    /// buffering, such as proxies.
+    ///
+    /// Servers are not required to implement all modes of response buffering, but they *should* return an accurate
+    /// description of how the server will handle the response body.
+    fn buffering(&self) -> Buffering;
+
+    /// Request for output buffering to be enabled or disabled, returning whether the request is honored.
+    ///
+    /// This method does not explicitly control buffering; it is up to the web server whether responses are buffered or
+    /// not. A return value of `true` means that the rest of the body will be buffered, or not, as requested; `false`
+    /// means that the server did not make the change, and `buffering` still describes how the body is handled. Servers
+    /// are not required to support changing the buffering at all.
+    ///
+    /// The buffering policy API does not offer as much control to the application as more explicit APIs do, but some
+    /// servers may not support all of the ways of handling a response body.
+    ///
+    /// This method *may* have no effect if the response body has been written partially or completely, or if headers
+    /// have already been written.
+    fn set_buffering(&mut self, buffering: bool) -> bool {
+        false
+    }
+
+    /// Check if the response headers have already been sent.
+    fn headers_sent(&self) -> bool;
+}
+
+/// Encapsulates the state of an individual HTTP request handled by an `AsyncIngot`.
+///
+/// This is the non-blocking counterpart of `Context`. The request and response provide the same information as their
+/// blocking versions, but bodies are streamed through `AsyncRead` and `AsyncWrite`.
+pub trait AsyncContext: Send {
+    /// Get the address of the remote client.
+    fn remote_addr(&self) -> SocketAddr;
+
+    /// Get the address of the server.
+    fn server_addr(&self) -> SocketAddr;
+
+    /// Get the name of the server.
+    fn server_name(&self) -> &str;
+
+    /// Get the HTTP request for the current request.
+    fn request(&self) -> &dyn AsyncRequest;
+
+    /// Get the HTTP request for the current request, for reading the request body.
+    fn request_mut(&mut self) -> &mut dyn AsyncRequest;
+
+    /// Get the HTTP response for the current request.
+    fn response(&self) -> &dyn AsyncResponse;
+
+    /// Get the HTTP response for the current request, for modifying the response and writing the response body.
+    fn response_mut(&mut self) -> &mut dyn AsyncResponse;
+
+    /// Take over the connection to switch to another protocol.
+    ///
+    /// See `Context::upgrade`. The status and headers are sent ahead of anything written to the returned stream, so
+    /// flush the stream to send them before waiting for the client to speak the new protocol.
+    fn upgrade(&mut self) -> Result<Box<dyn AsyncUpgraded>, UpgradeError> {
+        Err(UpgradeError::Unsupported)
+    }
+}
+
+/// An incoming HTTP request with a non-blocking body stream.
+///
+/// See `Request` for a description of each method.
+pub trait AsyncRequest: AsyncRead + Send {
+    /// Get the HTTP request method.
+    fn method(&self) -> Cow<'_, str>;
+
+    /// Get the portion of the URI path that corresponds to this application object.
+    fn context_path(&self) -> Cow<'_, str>;
+
+    fn path_info(&self) -> Cow<'_, str>;
+
+    /// Get the query string contained in the request URI, if present.
+    fn query_string(&self) -> Option<Cow<'_, str>> {
+        None
+    }
+
+    /// Get the decoded parameters of the query string.
+    fn query(&self) -> Params {
+        self.query_string().map(|query| Params::parse(&query)).unwrap_or_default()
+    }
+
+    /// Get the request headers.
+    fn headers(&self) -> &HeaderMap;
+
+    /// Get the first value of a request header.
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers().get(name)
+    }
+
+    /// Check if this is a secure HTTPS connection.
+    fn is_secure(&self) -> bool {
+        false
+    }
+
+    /// Get the version of the HTTP protocol negotiated for the request.
+    fn version(&self) -> Version {
+        Version::Http11
+    }
+}
+
+/// An outgoing HTTP response with a non-blocking body stream.
+///
+/// See `Response` for a description of each method.
+pub trait AsyncResponse: AsyncWrite + Send {
+    /// Get the response status code.
+    fn status(&self) -> StatusCode;
+
+    /// Set the response status code.
+    fn set_status(&mut self, status: StatusCode) -> Result<(), HeadersSentError>;
+
+    /// Get the response headers that have been set so far.
+    fn headers(&self) -> &HeaderMap;
+
+    /// Set the value of the specified header, replacing any existing values.
+    fn set_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError>;
+
+    /// Add a value for the specified header, keeping any existing values.
+    fn append_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError>;
+
+    /// Remove all values of the specified header.
+    fn remove_header(&mut self, name: &str) -> Result<(), HeadersSentError>;
+
+    /// Check if buffering is currently enabled for the response body.
+    fn buffering(&self) -> Buffering;
+
+    /// Request for output buffering to be enabled or disabled, returning whether the request is honored.
+    ///
+    /// See `Response::set_buffering`.
+    fn set_buffering(&mut self, buffering: bool) -> bool {
+        false
+    }
+
+    /// Check if the response headers have already been sent.
+    fn headers_sent(&self) -> bool;
+}
+
+/// Defines a policy for buffering the content of a response.
+#[derive(Clone, Copy, Debug)]
+pub enum Buffering {
+    /// Content buffering is enabled with a maximum buffer size (in bytes).
+    On(u32),
+
+    /// Content buffering is disabled.
+    ///
+    /// Unless the `Content-Length` header is explicitly set, the web server *should* stream content written to the
+    /// response to the browser. For HTTP/1.1, the web server may use chunked transfer encoding; in HTTP/2, content
+    /// might be written to the output stream directly.
+    Off,
+}
+
+
+/// Error returned when the status or headers of a response are modified after the headers have been sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeadersSentError;
+
+impl fmt::Display for HeadersSentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("response headers have already been sent")
+    }
+}
+
+impl error::Error for HeadersSentError {}
+
+impl From<HeadersSentError> for io::Error {
+    fn from(error: HeadersSentError) -> io::Error {
+        io::Error::other(error)
+    }
+}
+
+
+/// Error returned when a connection cannot be upgraded to another protocol.
+#[derive(Debug)]
+pub enum UpgradeError {
+    /// The server cannot hand over the connection.
+    Unsupported,
+    /// The server is already handling as many upgraded connections as it allows.
+    Unavailable,
+    /// The response has already been started, so the upgrade response can no longer be sent.
+    HeadersSent,
+    /// The request is not a valid handshake for the protocol.
+    Handshake(&'static str),
+    /// Sending the upgrade response failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for UpgradeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UpgradeError::Unsupported => f.write_str("the server does not support protocol upgrades"),
+            UpgradeError::Unavailable => f.write_str("the server cannot take over any more connections"),
+            UpgradeError::HeadersSent => HeadersSentError.fmt(f),
+            UpgradeError::Handshake(message) => write!(f, "invalid handshake: {}", message),
+            UpgradeError::Io(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for UpgradeError {}
+
+impl From<HeadersSentError> for UpgradeError {
+    fn from(_: HeadersSentError) -> UpgradeError {
+        UpgradeError::HeadersSent
+    }
+}
+
+impl From<io::Error> for UpgradeError {
+    fn from(error: io::Error) -> UpgradeError {
+        UpgradeError::Io(error)
+    }
+}
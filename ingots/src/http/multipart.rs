@@ -0,0 +1,927 @@
+//! Streaming parser for `multipart/form-data` request bodies, as used for file uploads.
+//!
+//! The body is read in small chunks and each part is streamed to the application through `io::Read`, so memory use is
+//! bounded no matter how large the uploaded files are. Parts that need to be kept around can be spooled to memory or, if
+//! they are large, to a temporary file.
+//!
+//! ```ignore
+//! let mut multipart = Multipart::from_request(context.request_mut(), Limits::default())?;
+//!
+//! while let Some(part) = multipart.next_part()? {
+//!     if part.filename().is_some() {
+//!         let upload = part.spool(64 * 1024)?;
+//!     }
+//! }
+//! ```
+use super::{params, HeaderMap, Request, StatusCode};
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// Number of bytes read from the request body at a time.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Limits on the size of a multipart body, to protect the server from resource exhaustion.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    max_parts: usize,
+    max_size: u64,
+    max_header_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_parts: 128,
+            max_size: 64 * 1024 * 1024,
+            max_header_size: 8 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// Set the maximum number of parts in the body.
+    pub fn max_parts(mut self, max_parts: usize) -> Self {
+        self.max_parts = max_parts;
+        self
+    }
+
+    /// Set the maximum total size of the body in bytes, including headers and boundaries.
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Set the maximum size in bytes of the header block of a single part.
+    pub fn max_header_size(mut self, max_header_size: usize) -> Self {
+        self.max_header_size = max_header_size;
+        self
+    }
+}
+
+
+/// Error returned when a multipart body cannot be parsed.
+#[derive(Debug)]
+pub enum MultipartError {
+    /// The request body is not `multipart/form-data`.
+    UnsupportedMediaType,
+    /// The `Content-Type` of the request does not specify a valid boundary.
+    MissingBoundary,
+    /// The body does not follow the multipart format.
+    Malformed(&'static str),
+    /// The body has more parts than the limit.
+    TooManyParts(usize),
+    /// The body is larger than the limit, in bytes.
+    TooLarge(u64),
+    /// The headers of a part are larger than the limit, in bytes.
+    HeadersTooLarge(usize),
+    /// The request body could not be read.
+    Io(io::Error),
+}
+
+impl MultipartError {
+    /// Get the response status code that best describes the error.
+    pub fn status(&self) -> StatusCode {
+        match *self {
+            MultipartError::UnsupportedMediaType => 415,
+            MultipartError::TooManyParts(_) | MultipartError::TooLarge(_) | MultipartError::HeadersTooLarge(_) => 413,
+            MultipartError::MissingBoundary | MultipartError::Malformed(_) | MultipartError::Io(_) => 400,
+        }
+    }
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MultipartError::UnsupportedMediaType => f.write_str("request body is not multipart/form-data"),
+            MultipartError::MissingBoundary => f.write_str("multipart request does not specify a boundary"),
+            MultipartError::Malformed(reason) => write!(f, "malformed multipart body: {}", reason),
+            MultipartError::TooManyParts(limit) => write!(f, "multipart body has more than {} parts", limit),
+            MultipartError::TooLarge(limit) => write!(f, "request body is larger than {} bytes", limit),
+            MultipartError::HeadersTooLarge(limit) => write!(f, "multipart headers are larger than {} bytes", limit),
+            MultipartError::Io(ref e) => write!(f, "failed to read request body: {}", e),
+        }
+    }
+}
+
+impl error::Error for MultipartError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            MultipartError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(error: io::Error) -> Self {
+        // Parse errors are passed through `io::Read` as the inner error of an `io::Error`.
+        if error.get_ref().is_some_and(|inner| inner.is::<MultipartError>()) {
+            return *error.into_inner().unwrap().downcast::<MultipartError>().unwrap();
+        }
+
+        MultipartError::Io(error)
+    }
+}
+
+impl From<MultipartError> for io::Error {
+    fn from(error: MultipartError) -> Self {
+        match error {
+            MultipartError::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// Reading the body of a part, or the preamble before the first part.
+    Body,
+    /// A delimiter has been consumed, and the next part or the end of the body follows.
+    Boundary,
+    /// The final delimiter has been read.
+    End,
+}
+
+/// Streaming reader for a `multipart/form-data` body.
+pub struct Multipart<R> {
+    reader: R,
+    /// The delimiter that precedes every part, including the line break before it.
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    /// Start of the unconsumed data in the buffer.
+    position: usize,
+    state: State,
+    limits: Limits,
+    /// Number of bytes read from the reader so far.
+    size: u64,
+    parts: usize,
+}
+
+impl<'a, R: Request + ?Sized> Multipart<&'a mut R> {
+    /// Create a parser for the body of a request, taking the boundary from its `Content-Type` header.
+    pub fn from_request(request: &'a mut R, limits: Limits) -> Result<Self, MultipartError> {
+        let boundary = {
+            let content_type = request.get_header("Content-Type").ok_or(MultipartError::UnsupportedMediaType)?;
+            let media_type = content_type.split(';').next().unwrap_or("").trim();
+
+            if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+                return Err(MultipartError::UnsupportedMediaType);
+            }
+
+            header_params(content_type)
+                .into_iter()
+                .find(|param| param.0.eq_ignore_ascii_case("boundary"))
+                .map(|param| param.1)
+                .ok_or(MultipartError::MissingBoundary)?
+        };
+
+        // Refuse early if the client announced a body that is too large.
+        let length = request.get_header("Content-Length").and_then(|value| value.trim().parse::<u64>().ok());
+        if length.is_some_and(|length| length > limits.max_size) {
+            return Err(MultipartError::TooLarge(limits.max_size));
+        }
+
+        Self::new(request, &boundary, limits)
+    }
+}
+
+impl<R: Read> Multipart<R> {
+    /// Create a parser for a multipart body read from `reader`, using the given boundary.
+    pub fn new(reader: R, boundary: &str, limits: Limits) -> Result<Self, MultipartError> {
+        // RFC 2046 limits boundaries to 70 characters, which also keeps the search window small.
+        if boundary.is_empty() || boundary.len() > 70 {
+            return Err(MultipartError::MissingBoundary);
+        }
+
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+
+        Ok(Self {
+            reader,
+            delimiter,
+            // The first delimiter may not be preceded by a line break, so pretend there is one. The preamble is then
+            // skipped like the body of a part.
+            buffer: b"\r\n".to_vec(),
+            position: 0,
+            state: State::Body,
+            limits,
+            size: 0,
+            parts: 0,
+        })
+    }
+
+    /// Advance to the next part, skipping whatever remains of the current one.
+    ///
+    /// Returns `None` once the final boundary has been read.
+    pub fn next_part(&mut self) -> Result<Option<Part<'_, R>>, MultipartError> {
+        let mut scratch = [0; 1024];
+        while self.state == State::Body {
+            self.read_body(&mut scratch)?;
+        }
+
+        if self.state == State::End {
+            return Ok(None);
+        }
+
+        self.ensure(2)?;
+        if self.data().starts_with(b"--") {
+            self.state = State::End;
+            return Ok(None);
+        }
+
+        // The delimiter may be followed by transport padding before the line break.
+        loop {
+            self.ensure(1)?;
+            match self.data()[0] {
+                b' ' | b'\t' => self.position += 1,
+                _ => break,
+            }
+        }
+        self.ensure(2)?;
+        if !self.data().starts_with(b"\r\n") {
+            return Err(MultipartError::Malformed("expected a line break after the boundary"));
+        }
+        self.position += 2;
+
+        self.parts += 1;
+        if self.parts > self.limits.max_parts {
+            return Err(MultipartError::TooManyParts(self.limits.max_parts));
+        }
+
+        let headers = self.read_headers()?;
+        self.state = State::Body;
+
+        let (name, filename) = match headers.get("Content-Disposition") {
+            Some(value) => parse_disposition(value),
+            None => (None, None),
+        };
+
+        Ok(Some(Part {
+            multipart: self,
+            headers,
+            name,
+            filename,
+        }))
+    }
+
+    /// Get the unconsumed data in the buffer.
+    fn data(&self) -> &[u8] {
+        &self.buffer[self.position..]
+    }
+
+    /// Read the next chunk of the body into the buffer, returning the number of bytes read.
+    fn fill(&mut self) -> io::Result<usize> {
+        if self.position > 0 {
+            self.buffer.drain(..self.position);
+            self.position = 0;
+        }
+
+        let start = self.buffer.len();
+        self.buffer.resize(start + CHUNK_SIZE, 0);
+        let result = self.reader.read(&mut self.buffer[start..]);
+        self.buffer.truncate(start + *result.as_ref().unwrap_or(&0));
+        let read = result?;
+
+        self.size += read as u64;
+        if self.size > self.limits.max_size {
+            return Err(MultipartError::TooLarge(self.limits.max_size).into());
+        }
+
+        Ok(read)
+    }
+
+    /// Make sure at least `len` bytes of unconsumed data are in the buffer.
+    fn ensure(&mut self, len: usize) -> Result<(), MultipartError> {
+        while self.data().len() < len {
+            if self.fill()? == 0 {
+                return Err(MultipartError::Malformed("unexpected end of body"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_headers(&mut self) -> Result<HeaderMap, MultipartError> {
+        let mut headers = HeaderMap::new();
+
+        // A part without headers starts with an empty line.
+        self.ensure(2)?;
+        if self.data().starts_with(b"\r\n") {
+            self.position += 2;
+            return Ok(headers);
+        }
+
+        let mut searched = 0;
+        let end = loop {
+            if let Some(index) = find(&self.data()[searched..], b"\r\n\r\n") {
+                break searched + index;
+            }
+            searched = self.data().len().saturating_sub(3);
+
+            if self.data().len() > self.limits.max_header_size {
+                return Err(MultipartError::HeadersTooLarge(self.limits.max_header_size));
+            }
+            if self.fill()? == 0 {
+                return Err(MultipartError::Malformed("unexpected end of body"));
+            }
+        };
+
+        if end > self.limits.max_header_size {
+            return Err(MultipartError::HeadersTooLarge(self.limits.max_header_size));
+        }
+
+        for line in String::from_utf8_lossy(&self.data()[..end]).split("\r\n") {
+            match line.find(':') {
+                Some(index) => headers.append(line[..index].trim(), line[index + 1..].trim()),
+                None => return Err(MultipartError::Malformed("invalid part header")),
+            }
+        }
+        self.position += end + 4;
+
+        Ok(headers)
+    }
+
+    /// Read from the body of the current part, returning zero once the next delimiter is reached.
+    fn read_body(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.state != State::Body || buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            let available = match find(self.data(), &self.delimiter) {
+                Some(0) => {
+                    self.position += self.delimiter.len();
+                    self.state = State::Boundary;
+                    return Ok(0);
+                }
+                Some(index) => index,
+                // The end of the buffer may hold the start of a delimiter, so keep it until more data is read.
+                None => self.data().len().saturating_sub(self.delimiter.len() - 1),
+            };
+
+            if available > 0 {
+                let len = available.min(buf.len());
+                buf[..len].copy_from_slice(&self.data()[..len]);
+                self.position += len;
+                return Ok(len);
+            }
+
+            if self.fill()? == 0 {
+                return Err(MultipartError::Malformed("unexpected end of body").into());
+            }
+        }
+    }
+}
+
+
+/// A single part of a multipart body.
+///
+/// The body of the part is read through `io::Read`. Any of it left unread is skipped when the next part is requested.
+pub struct Part<'a, R: 'a> {
+    multipart: &'a mut Multipart<R>,
+    headers: HeaderMap,
+    name: Option<String>,
+    filename: Option<String>,
+}
+
+impl<'a, R: Read> Part<'a, R> {
+    /// Get the headers of the part.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Get the name of the form field, from the `Content-Disposition` header.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Get the file name sent by the client for a file upload, from the `Content-Disposition` header.
+    ///
+    /// The file name is untrusted input, and may contain path separators.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// Get the media type of the part, from the `Content-Type` header.
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type")
+    }
+
+    /// Read the rest of the body of the part, keeping it in memory if it is at most `memory_limit` bytes long, or in a
+    /// temporary file otherwise.
+    pub fn spool(mut self, memory_limit: usize) -> io::Result<Spooled> {
+        let mut data = Vec::new();
+        (&mut self).take(memory_limit as u64 + 1).read_to_end(&mut data)?;
+
+        if data.len() <= memory_limit {
+            return Ok(Spooled::Memory(data));
+        }
+
+        let mut file = TempFile::new()?;
+        file.file.write_all(&data)?;
+        io::copy(&mut self, &mut file.file)?;
+        file.file.seek(SeekFrom::Start(0))?;
+
+        Ok(Spooled::File(file))
+    }
+}
+
+impl<'a, R: Read> Read for Part<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.multipart.read_body(buf)
+    }
+}
+
+
+/// The body of a part that has been read in full.
+#[derive(Debug)]
+pub enum Spooled {
+    /// The body was small enough to be kept in memory.
+    Memory(Vec<u8>),
+    /// The body was written to a temporary file, which is positioned at the start.
+    File(TempFile),
+}
+
+impl Spooled {
+    /// Get the length of the body in bytes.
+    pub fn len(&self) -> io::Result<u64> {
+        match *self {
+            Spooled::Memory(ref data) => Ok(data.len() as u64),
+            Spooled::File(ref file) => file.file.metadata().map(|metadata| metadata.len()),
+        }
+    }
+
+    /// Check if the body is empty.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        self.len().map(|len| len == 0)
+    }
+}
+
+/// A temporary file that is removed when dropped, unless it is persisted.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl TempFile {
+    fn new() -> io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        loop {
+            let name = format!("ingots-upload-{}-{}", process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+            let path = env::temp_dir().join(name);
+
+            match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok(Self { path, file }),
+                // Left behind by an earlier process with the same ID.
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Get the path of the temporary file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the open file.
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Move the file to a permanent location, so that it is not removed.
+    ///
+    /// The destination must be on the same file system as the temporary directory.
+    pub fn persist<P: AsRef<Path>>(mut self, path: P) -> io::Result<File> {
+        fs::rename(&self.path, path)?;
+        self.path = PathBuf::new();
+
+        // Swap in a handle to the same file, since `Drop` prevents moving out of `self`.
+        let file = self.file.try_clone()?;
+        Ok(mem::replace(&mut self.file, file))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+
+/// Get the name and file name parameters of a `Content-Disposition` header.
+fn parse_disposition(value: &str) -> (Option<String>, Option<String>) {
+    let mut name = None;
+    let mut filename = None;
+    let mut extended_filename = None;
+
+    for (key, value) in header_params(value) {
+        if key.eq_ignore_ascii_case("name") {
+            name = Some(value);
+        } else if key.eq_ignore_ascii_case("filename") {
+            filename = Some(value);
+        } else if key.eq_ignore_ascii_case("filename*") {
+            extended_filename = decode_extended(&value);
+        }
+    }
+
+    // The extended form supports any character set, so it takes precedence.
+    (name, extended_filename.or(filename))
+}
+
+/// Decode an RFC 5987 extended parameter value in UTF-8, such as `UTF-8''na%C3%AFve.txt`.
+fn decode_extended(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let encoded = parts.next()?;
+
+    if !charset.eq_ignore_ascii_case("UTF-8") {
+        return None;
+    }
+
+    let decoded = params::percent_decode_bytes(encoded.as_bytes(), false);
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Parse the `; key=value` parameters that follow the first item of a header value. Values may be quoted strings.
+fn header_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().skip_while(|&c| c != ';').peekable();
+
+    while chars.next().is_some() {
+        let mut key = String::new();
+        let mut value = String::new();
+
+        while let Some(&c) = chars.peek() {
+            if c == ';' {
+                break;
+            }
+            chars.next();
+            if c == '=' {
+                break;
+            }
+            key.push(c);
+        }
+
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+            // Skip anything between the closing quote and the next parameter.
+            while chars.peek().is_some_and(|&c| c != ';') {
+                chars.next();
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ';' {
+                    break;
+                }
+                value.push(c);
+                chars.next();
+            }
+            value = value.trim().to_owned();
+        }
+
+        let key = key.trim();
+        if !key.is_empty() {
+            params.push((key.to_owned(), value));
+        }
+    }
+
+    params
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use http::Context;
+    use std::fs;
+    use testing::TestRequest;
+    use super::*;
+
+    /// Reader that returns at most a few bytes at a time, so that delimiters are split across reads.
+    struct Trickle<'a>(&'a [u8], usize);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.0.len().min(buf.len()).min(self.1);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    type Parts = Vec<(Option<String>, Vec<u8>)>;
+
+    /// Read every part of a body, returning the name and contents of each.
+    fn parts<R: Read>(mut multipart: Multipart<R>) -> Result<Parts, MultipartError> {
+        let mut parts = Vec::new();
+
+        while let Some(mut part) = multipart.next_part()? {
+            let mut data = Vec::new();
+            part.read_to_end(&mut data)?;
+            parts.push((part.name().map(String::from), data));
+        }
+
+        Ok(parts)
+    }
+
+    fn body(boundary: &str, parts: &[&[u8]]) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        for (index, data) in parts.iter().enumerate() {
+            let headers = format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n", boundary, index);
+            body.extend_from_slice(headers.as_bytes());
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+        body
+    }
+
+    #[test]
+    fn multiple_parts() {
+        let body = "This preamble is ignored.\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\
+            \r\n\
+            Hello\r\n\
+            --XyZ  \r\n\
+            Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            line one\r\nline two, then --XyZ without a line break before it\r\n\
+            --XyZ\r\n\
+            \r\n\
+            no headers\r\n\
+            --XyZ--\r\n\
+            This epilogue is ignored, even if it contains\r\n--XyZ\r\n";
+        let mut multipart = Multipart::new(body.as_bytes(), "XyZ", Limits::default()).unwrap();
+
+        let mut part = multipart.next_part().unwrap().unwrap();
+        let mut data = String::new();
+        part.read_to_string(&mut data).unwrap();
+        assert_eq!(part.name(), Some("title"));
+        assert_eq!(part.filename(), None);
+        assert_eq!(data, "Hello");
+
+        let mut part = multipart.next_part().unwrap().unwrap();
+        let mut data = String::new();
+        part.read_to_string(&mut data).unwrap();
+        assert_eq!(part.name(), Some("upload"));
+        assert_eq!(part.filename(), Some("a.txt"));
+        assert_eq!(part.content_type(), Some("text/plain"));
+        assert_eq!(data, "line one\r\nline two, then --XyZ without a line break before it");
+
+        let mut part = multipart.next_part().unwrap().unwrap();
+        let mut data = String::new();
+        part.read_to_string(&mut data).unwrap();
+        assert!(part.headers().is_empty());
+        assert_eq!(data, "no headers");
+
+        assert!(multipart.next_part().unwrap().is_none());
+        assert!(multipart.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn unread_parts_are_skipped() {
+        let body = body("b", &[&[b'x'; 3 * CHUNK_SIZE], b"second"]);
+        let mut multipart = Multipart::new(&body[..], "b", Limits::default()).unwrap();
+
+        assert_eq!(multipart.next_part().unwrap().unwrap().name(), Some("0"));
+
+        let mut part = multipart.next_part().unwrap().unwrap();
+        let mut data = Vec::new();
+        part.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"second");
+        assert!(multipart.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn delimiter_split_across_reads() {
+        let boundary = "----boundary";
+
+        // Place the delimiter at every offset around the end of the first chunk.
+        for len in CHUNK_SIZE - 120..CHUNK_SIZE + 20 {
+            let data = vec![b'a'; len];
+            let body = body(boundary, &[&data, b"b"]);
+            let parts = parts(Multipart::new(&body[..], boundary, Limits::default()).unwrap()).unwrap();
+
+            assert_eq!(parts.len(), 2, "part length {}", len);
+            assert_eq!(parts[0].1.len(), len, "part length {}", len);
+            assert_eq!(parts[1].1, b"b", "part length {}", len);
+        }
+
+        // Data that looks like the start of a delimiter is kept, however the reads are split.
+        let data = b"\r\n--\r\n------bound\r\n----boundar";
+        let body = body(boundary, &[data, b""]);
+        for size in 1..8 {
+            let parts = parts(Multipart::new(Trickle(&body, size), boundary, Limits::default()).unwrap()).unwrap();
+
+            assert_eq!(parts, [(Some(String::from("0")), data.to_vec()), (Some(String::from("1")), Vec::new())]);
+        }
+    }
+
+    #[test]
+    fn malformed_body() {
+        let multipart = Multipart::new(&b"--b\r\n\r\ndata"[..], "b", Limits::default()).unwrap();
+
+        match parts(multipart) {
+            Err(MultipartError::Malformed(reason)) => assert_eq!(reason, "unexpected end of body"),
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let body = b"--b\r\nContent-Disposition form-data\r\n\r\n\r\n--b--";
+        let result = parts(Multipart::new(&body[..], "b", Limits::default()).unwrap());
+        assert!(matches!(result, Err(MultipartError::Malformed(reason)) if reason == "invalid part header"));
+    }
+
+    #[test]
+    fn max_parts() {
+        let body = body("b", &[b"1", b"2", b"3"]);
+
+        assert_eq!(parts(Multipart::new(&body[..], "b", Limits::default().max_parts(3)).unwrap()).unwrap().len(), 3);
+
+        let error = parts(Multipart::new(&body[..], "b", Limits::default().max_parts(2)).unwrap()).unwrap_err();
+        assert!(matches!(error, MultipartError::TooManyParts(2)));
+        assert_eq!(error.status(), 413);
+    }
+
+    #[test]
+    fn max_size() {
+        let body = body("b", &[&[b'x'; 2 * CHUNK_SIZE]]);
+        let limit = body.len() as u64;
+
+        assert_eq!(parts(Multipart::new(&body[..], "b", Limits::default().max_size(limit)).unwrap()).unwrap().len(), 1);
+
+        let error = parts(Multipart::new(&body[..], "b", Limits::default().max_size(limit - 1)).unwrap()).unwrap_err();
+        assert!(matches!(error, MultipartError::TooLarge(size) if size == limit - 1));
+        assert_eq!(error.status(), 413);
+    }
+
+    #[test]
+    fn max_size_from_content_length() {
+        let body = body("b", &[b"data"]);
+        let mut context = TestRequest::post("/")
+            .header("Content-Type", "multipart/form-data; boundary=b")
+            .header("Content-Length", &body.len().to_string())
+            .body(body.clone())
+            .into_context();
+        let limits = Limits::default().max_size(body.len() as u64 - 1);
+
+        // Refused before any of the body is read.
+        assert!(matches!(Multipart::from_request(context.request_mut(), limits), Err(MultipartError::TooLarge(_))));
+
+        let multipart = Multipart::from_request(context.request_mut(), Limits::default()).unwrap();
+        assert_eq!(parts(multipart).unwrap(), [(Some(String::from("0")), b"data".to_vec())]);
+    }
+
+    #[test]
+    fn from_request_checks_content_type() {
+        let result = |content_type: &str| {
+            let mut context = TestRequest::post("/").header("Content-Type", content_type).into_context();
+            Multipart::from_request(context.request_mut(), Limits::default()).map(|_| ())
+        };
+
+        assert!(result("multipart/form-data; charset=utf-8; boundary=\"a b\"").is_ok());
+        assert_eq!(result("application/x-www-form-urlencoded").unwrap_err().status(), 415);
+        assert_eq!(result("multipart/form-data").unwrap_err().status(), 400);
+    }
+
+    #[test]
+    fn max_header_size() {
+        let body = b"--b\r\nContent-Disposition: form-data; name=\"field\"\r\n\r\ndata\r\n--b--\r\n";
+        let header_size = "Content-Disposition: form-data; name=\"field\"".len();
+
+        let limits = Limits::default().max_header_size(header_size);
+        assert_eq!(parts(Multipart::new(&body[..], "b", limits).unwrap()).unwrap().len(), 1);
+
+        let limits = Limits::default().max_header_size(header_size - 1);
+        let error = parts(Multipart::new(&body[..], "b", limits).unwrap()).unwrap_err();
+        assert!(matches!(error, MultipartError::HeadersTooLarge(size) if size == header_size - 1));
+
+        // Headers that never end are refused once they exceed the limit, without reading the rest of the body.
+        let mut body = b"--b\r\nX-Long: ".to_vec();
+        body.extend_from_slice(&[b'x'; 4 * CHUNK_SIZE]);
+        let limits = Limits::default().max_header_size(CHUNK_SIZE);
+        let result = parts(Multipart::new(&body[..], "b", limits).unwrap());
+        assert!(matches!(result, Err(MultipartError::HeadersTooLarge(_))));
+    }
+
+    #[test]
+    fn spool_in_memory() {
+        let body = body("b", &[b"small"]);
+        let mut multipart = Multipart::new(&body[..], "b", Limits::default()).unwrap();
+
+        match multipart.next_part().unwrap().unwrap().spool(5).unwrap() {
+            Spooled::Memory(data) => assert_eq!(data, b"small"),
+            spooled => panic!("unexpected {:?}", spooled),
+        }
+        assert!(multipart.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn spool_to_file() {
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| i as u8).collect();
+        let body = body("b", &[&data, b"next"]);
+        let mut multipart = Multipart::new(&body[..], "b", Limits::default()).unwrap();
+
+        let mut file = match multipart.next_part().unwrap().unwrap().spool(CHUNK_SIZE).unwrap() {
+            Spooled::File(file) => file,
+            spooled => panic!("unexpected {:?}", spooled),
+        };
+        let mut contents = Vec::new();
+        file.file().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+        assert_eq!(Spooled::File(file).len().unwrap(), data.len() as u64);
+
+        // The file is removed when dropped.
+        let file = match multipart.next_part().unwrap().unwrap().spool(0).unwrap() {
+            Spooled::File(file) => file,
+            spooled => panic!("unexpected {:?}", spooled),
+        };
+        let path = file.path().to_owned();
+        assert!(path.exists());
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn persist() {
+        let body = body("b", &[b"keep me"]);
+        let mut multipart = Multipart::new(&body[..], "b", Limits::default()).unwrap();
+        let file = match multipart.next_part().unwrap().unwrap().spool(0).unwrap() {
+            Spooled::File(file) => file,
+            spooled => panic!("unexpected {:?}", spooled),
+        };
+
+        let temp_path = file.path().to_owned();
+        let path = env::temp_dir().join(format!("ingots-persist-test-{}", process::id()));
+        let mut persisted = file.persist(&path).unwrap();
+
+        // Persisting does not move the file position, and the file outlives the temporary file.
+        let mut contents = String::new();
+        persisted.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "keep me");
+        assert!(!temp_path.exists());
+        assert_eq!(fs::read(&path).unwrap(), b"keep me");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn filename(disposition: &str) -> Option<String> {
+        let body = format!("--b\r\nContent-Disposition: {}\r\n\r\ndata\r\n--b--\r\n", disposition);
+        let mut multipart = Multipart::new(body.as_bytes(), "b", Limits::default()).unwrap();
+        let part = multipart.next_part().unwrap().unwrap();
+
+        part.filename().map(String::from)
+    }
+
+    #[test]
+    fn extended_filename() {
+        assert_eq!(filename("form-data; name=\"f\"; filename*=UTF-8''na%C3%AFve.txt").as_deref(), Some("naïve.txt"));
+        assert_eq!(filename("form-data; filename=\"a.txt\"; filename*=utf-8'en'b%20c").as_deref(), Some("b c"));
+    }
+
+    #[test]
+    fn extended_filename_with_malformed_escape() {
+        // The escape is cut short by a multi-byte character, which must not be split.
+        assert_eq!(decode_extended("UTF-8''%a\u{e9}.txt").as_deref(), Some("%a\u{e9}.txt"));
+        assert_eq!(decode_extended("UTF-8''\u{e9}%\u{e9}").as_deref(), Some("\u{e9}%\u{e9}"));
+        assert_eq!(filename("form-data; filename*=UTF-8''%a\u{e9}").as_deref(), Some("%a\u{e9}"));
+    }
+
+    #[test]
+    fn extended_filename_with_invalid_utf8_falls_back() {
+        assert_eq!(filename("form-data; filename=\"plain.txt\"; filename*=UTF-8''%FF").as_deref(), Some("plain.txt"));
+    }
+}
@@ -0,0 +1,367 @@
+//! Parsing of URL-encoded parameters, as found in query strings and `application/x-www-form-urlencoded` bodies.
+use super::{Request, StatusCode};
+use std::borrow::Cow;
+use std::error;
+use std::fmt;
+use std::io::{self, Read};
+use std::iter::FromIterator;
+use std::slice;
+
+
+/// Default maximum size of a URL-encoded form body, in bytes.
+pub const DEFAULT_FORM_LIMIT: usize = 1024 * 1024;
+
+/// An ordered multimap of decoded request parameters.
+///
+/// Unlike header names, parameter names are case-sensitive. Parameters are kept in the order they appeared in the
+/// input, and a name that appears more than once has multiple values.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Params {
+    pairs: Vec<(String, String)>,
+}
+
+impl Params {
+    /// Create a new, empty parameter map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse URL-encoded parameters, such as a query string.
+    ///
+    /// Pairs are separated by `&`, and names are separated from values by the first `=`. A pair without an `=` has an
+    /// empty value, and empty pairs are skipped. `+` is decoded as a space, and invalid percent escapes are kept as-is.
+    pub fn parse(input: &str) -> Self {
+        Self::parse_bytes(input.as_bytes())
+    }
+
+    /// Parse URL-encoded parameters from raw bytes, replacing any invalid UTF-8 after decoding.
+    pub fn parse_bytes(input: &[u8]) -> Self {
+        input.split(|&byte| byte == b'&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.iter().position(|&byte| byte == b'=') {
+                Some(index) => (decode_bytes(&pair[..index]), decode_bytes(&pair[index + 1..])),
+                None => (decode_bytes(pair), String::new()),
+            })
+            .collect()
+    }
+
+    /// Get the number of parameters, counting each value of a repeated name separately.
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    /// Check if there are no parameters.
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// Check if a parameter with the given name is present.
+    pub fn contains(&self, name: &str) -> bool {
+        self.pairs.iter().any(|pair| pair.0 == name)
+    }
+
+    /// Get the first value of the parameter with the given name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.pairs.iter().find(|pair| pair.0 == name).map(|pair| pair.1.as_str())
+    }
+
+    /// Get all values of the parameter with the given name, in the order they appeared.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.pairs.iter()
+            .filter(move |pair| pair.0 == name)
+            .map(|pair| pair.1.as_str())
+    }
+
+    /// Add a parameter value, keeping any existing values.
+    pub fn append<N: Into<String>, V: Into<String>>(&mut self, name: N, value: V) {
+        self.pairs.push((name.into(), value.into()));
+    }
+
+    /// Iterate over every parameter name and value pair, in the order they appeared.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            inner: self.pairs.iter(),
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Params {
+    type Item = (&'a str, &'a str);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl<N: Into<String>, V: Into<String>> Extend<(N, V)> for Params {
+    fn extend<T: IntoIterator<Item = (N, V)>>(&mut self, iter: T) {
+        for (name, value) in iter {
+            self.append(name, value);
+        }
+    }
+}
+
+impl<N: Into<String>, V: Into<String>> FromIterator<(N, V)> for Params {
+    fn from_iter<T: IntoIterator<Item = (N, V)>>(iter: T) -> Self {
+        let mut params = Params::new();
+        params.extend(iter);
+        params
+    }
+}
+
+/// Iterator over all name and value pairs in a parameter map.
+pub struct Iter<'a> {
+    inner: slice::Iter<'a, (String, String)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        self.inner.next().map(|pair| (pair.0.as_str(), pair.1.as_str()))
+    }
+}
+
+
+/// Decode a URL-encoded string, treating `+` as a space.
+///
+/// Invalid percent escapes are kept as-is, and invalid UTF-8 in the decoded bytes is replaced.
+pub fn decode(input: &str) -> Cow<'_, str> {
+    if input.contains(['%', '+']) {
+        Cow::Owned(decode_bytes(input.as_bytes()))
+    } else {
+        Cow::Borrowed(input)
+    }
+}
+
+/// Decode the percent escapes in a URL path, where `+` stands for itself rather than a space.
+///
+/// Invalid percent escapes are kept as-is, and invalid UTF-8 in the decoded bytes is replaced.
+pub fn decode_path(input: &str) -> Cow<'_, str> {
+    if input.contains('%') {
+        Cow::Owned(into_string(percent_decode_bytes(input.as_bytes(), false)))
+    } else {
+        Cow::Borrowed(input)
+    }
+}
+
+fn decode_bytes(input: &[u8]) -> String {
+    into_string(percent_decode_bytes(input, true))
+}
+
+fn into_string(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+/// Decode percent escapes, and optionally `+` as a space. Invalid escapes are kept as-is.
+pub(crate) fn percent_decode_bytes(input: &[u8], plus_as_space: bool) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+
+    while i < input.len() {
+        match input[i] {
+            b'+' if plus_as_space => output.push(b' '),
+            b'%' if i + 2 < input.len() => {
+                match (hex_value(input[i + 1]), hex_value(input[i + 2])) {
+                    (Some(high), Some(low)) => {
+                        output.push(high << 4 | low);
+                        i += 3;
+                        continue;
+                    }
+                    _ => output.push(b'%'),
+                }
+            }
+            byte => output.push(byte),
+        }
+        i += 1;
+    }
+
+    output
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+
+/// Error returned when a URL-encoded form body cannot be read.
+#[derive(Debug)]
+pub enum FormError {
+    /// The request body is not `application/x-www-form-urlencoded`.
+    UnsupportedMediaType,
+    /// The request body is larger than the limit, in bytes.
+    TooLarge(usize),
+    /// The request body could not be read.
+    Io(io::Error),
+}
+
+impl FormError {
+    /// Get the response status code that best describes the error.
+    pub fn status(&self) -> StatusCode {
+        match *self {
+            FormError::UnsupportedMediaType => 415,
+            FormError::TooLarge(_) => 413,
+            FormError::Io(_) => 400,
+        }
+    }
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FormError::UnsupportedMediaType => f.write_str("request body is not application/x-www-form-urlencoded"),
+            FormError::TooLarge(limit) => write!(f, "request body is larger than {} bytes", limit),
+            FormError::Io(ref e) => write!(f, "failed to read request body: {}", e),
+        }
+    }
+}
+
+impl error::Error for FormError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            FormError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FormError {
+    fn from(error: io::Error) -> Self {
+        FormError::Io(error)
+    }
+}
+
+/// Read and parse a URL-encoded form from the body of a request.
+///
+/// The `Content-Type` of the request must be `application/x-www-form-urlencoded`. At most `limit` bytes of the body are
+/// read; a larger body is rejected without being parsed.
+pub fn read_form<R: Request + ?Sized>(request: &mut R, limit: usize) -> Result<Params, FormError> {
+    let is_form = request.get_header("Content-Type")
+        .map(|value| value.split(';').next().unwrap_or("").trim())
+        .is_some_and(|media_type| media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded"));
+
+    if !is_form {
+        return Err(FormError::UnsupportedMediaType);
+    }
+
+    // Refuse early if the client announced a body that is too large.
+    let length = request.get_header("Content-Length").and_then(|value| value.trim().parse::<u64>().ok());
+    if length.is_some_and(|length| length > limit as u64) {
+        return Err(FormError::TooLarge(limit));
+    }
+
+    let mut body = Vec::new();
+    Read::take(&mut *request, limit as u64 + 1).read_to_end(&mut body)?;
+
+    if body.len() > limit {
+        return Err(FormError::TooLarge(limit));
+    }
+
+    Ok(Params::parse_bytes(&body))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use http::Context;
+    use testing::TestRequest;
+    use super::*;
+
+    fn pairs(params: &Params) -> Vec<(&str, &str)> {
+        params.iter().collect()
+    }
+
+    #[test]
+    fn parse() {
+        let params = Params::parse("a=1&b=two+words&a=3&&flag&=empty&c=x=y");
+
+        let expected = [("a", "1"), ("b", "two words"), ("a", "3"), ("flag", ""), ("", "empty"), ("c", "x=y")];
+
+        assert_eq!(pairs(&params), expected);
+        assert_eq!(params.get("a"), Some("1"));
+        assert_eq!(params.get_all("a").collect::<Vec<_>>(), ["1", "3"]);
+        assert_eq!(params.get("A"), None);
+        assert!(params.contains("flag"));
+        assert!(Params::parse("").is_empty());
+    }
+
+    #[test]
+    fn decode_escapes() {
+        assert_eq!(decode("caf%C3%A9%20au+lait"), "café au lait");
+        assert_eq!(decode("%2B%3d%26"), "+=&");
+        assert_eq!(decode("plain"), "plain");
+        assert!(matches!(decode("plain"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn decode_invalid_escapes() {
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%4"), "%4");
+        assert_eq!(decode("%zz%41"), "%zzA");
+        assert_eq!(decode("%FF"), "\u{fffd}");
+        assert_eq!(percent_decode_bytes(b"a+%FF", false), b"a+\xff");
+    }
+
+    #[test]
+    fn decode_path_escapes() {
+        assert_eq!(decode_path("/caf%C3%A9/a+b%20c"), "/café/a+b c");
+        assert_eq!(decode_path("/100%/%zz"), "/100%/%zz");
+        assert!(matches!(decode_path("/plain+path"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn query() {
+        let context = TestRequest::get("/search?q=rust+web&page=2").into_context();
+
+        assert_eq!(pairs(&context.request().query()), [("q", "rust web"), ("page", "2")]);
+    }
+
+    #[test]
+    fn form() {
+        let mut context = TestRequest::post("/")
+            .header("Content-Type", "application/x-www-form-urlencoded; charset=UTF-8")
+            .body("name=J%C3%BCrgen&tags=a&tags=b")
+            .into_context();
+
+        let form = context.request_mut().form(DEFAULT_FORM_LIMIT).unwrap();
+        assert_eq!(pairs(&form), [("name", "Jürgen"), ("tags", "a"), ("tags", "b")]);
+    }
+
+    #[test]
+    fn form_wrong_content_type() {
+        let mut context = TestRequest::post("/").header("Content-Type", "text/plain").body("a=1").into_context();
+
+        match context.request_mut().form(DEFAULT_FORM_LIMIT) {
+            Err(FormError::UnsupportedMediaType) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn form_too_large() {
+        let request = TestRequest::post("/")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("a=12345");
+
+        // Announced by Content-Length.
+        let mut context = request.clone().into_context();
+        let error = context.request_mut().form(6).unwrap_err();
+        assert_eq!(error.status(), 413);
+
+        // Found while reading a body without a length.
+        let mut context = request.header("Transfer-Encoding", "chunked").into_context();
+        assert!(matches!(context.request_mut().form(6), Err(FormError::TooLarge(6))));
+
+        let mut context = TestRequest::post("/")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("a=12345")
+            .into_context();
+        assert_eq!(pairs(&context.request_mut().form(7).unwrap()), [("a", "12345")]);
+    }
+}
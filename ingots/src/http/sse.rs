@@ -0,0 +1,360 @@
+//! Streaming responses in the Server-Sent Events format (`text/event-stream`).
+//!
+//! An `EventStream` sets up the response headers, turns response buffering off so that every event reaches the client
+//! as soon as it is sent, and formats events. Since the ingot owns the request thread for as long as the stream is
+//! open, heartbeats are sent from the ingot's own loop: `heartbeat` sends a comment if one is due and returns how long
+//! the ingot may wait for its next event before calling it again.
+//!
+//! ```ignore
+//! let last_id = context.request().last_event_id().map(String::from);
+//! let mut events = context.response().event_stream()?;
+//!
+//! loop {
+//!     let timeout = events.heartbeat()?;
+//!     match updates.recv_timeout(timeout) {
+//!         Ok(update) => events.send(&Event::new().id(update.id).data(update.json))?,
+//!         Err(RecvTimeoutError::Timeout) => {}
+//!         Err(RecvTimeoutError::Disconnected) => break,
+//!     }
+//! }
+//! ```
+use super::{Buffering, HeadersSentError, Response};
+use std::error;
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant};
+
+
+/// Default time without any output after which a heartbeat comment is sent.
+///
+/// Proxies and load balancers commonly drop connections that are idle for 30 to 60 seconds.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+
+/// A single event to send to the client.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the event type, which selects the listener called by the client. Defaults to `message`.
+    pub fn event<S: Into<String>>(mut self, event: S) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// Set the event ID, which the client sends back in the `Last-Event-ID` header when it reconnects.
+    pub fn id<S: Into<String>>(mut self, id: S) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Set the event data. Line breaks are allowed, and are sent as multiple `data` fields.
+    ///
+    /// The client only dispatches events that have data; an event without it can still set the ID or retry time.
+    pub fn data<S: Into<String>>(mut self, data: S) -> Self {
+        self.data = Some(data.into());
+        self
+    }
+
+    /// Set how long the client should wait before reconnecting if the connection is lost.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Format the event as it is sent on the stream, including the blank line that ends it.
+    fn encode(&self) -> io::Result<String> {
+        let mut output = String::new();
+
+        if let Some(ref event) = self.event {
+            check_field("event type", event, &['\r', '\n'])?;
+            output.push_str(&format!("event: {}\n", event));
+        }
+
+        if let Some(ref id) = self.id {
+            // Clients ignore IDs containing a null character.
+            check_field("event ID", id, &['\r', '\n', '\0'])?;
+            output.push_str(&format!("id: {}\n", id));
+        }
+
+        if let Some(retry) = self.retry {
+            output.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+
+        if let Some(ref data) = self.data {
+            push_lines(&mut output, "data: ", data);
+        }
+
+        output.push('\n');
+        Ok(output)
+    }
+}
+
+/// Check that a single-line field does not contain any of the given characters, which cannot be escaped.
+fn check_field(name: &str, value: &str, forbidden: &[char]) -> io::Result<()> {
+    if value.contains(forbidden) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} contains a line break or null", name)));
+    }
+
+    Ok(())
+}
+
+/// Add each line of a value to the output with the given prefix, accepting any of the line breaks the format allows.
+fn push_lines(output: &mut String, prefix: &str, value: &str) {
+    for line in value.replace("\r\n", "\n").split(['\r', '\n']) {
+        output.push_str(prefix);
+        output.push_str(line);
+        output.push('\n');
+    }
+}
+
+
+/// A response streaming Server-Sent Events to the client.
+///
+/// Every event is flushed as soon as it is sent. The response is complete once the stream is dropped and the ingot
+/// returns.
+pub struct EventStream<'a, R: Response + ?Sized + 'a> {
+    response: &'a mut R,
+    heartbeat_interval: Option<Duration>,
+    last_write: Instant,
+}
+
+impl<'a, R: Response + ?Sized + 'a> EventStream<'a, R> {
+    /// Start an event stream on a response, sending the headers to the client right away.
+    ///
+    /// Fails if the headers have already been sent, or if the server cannot turn buffering off for the response, in
+    /// which case the events would be held back instead of reaching the client as they are sent.
+    pub fn new(response: &'a mut R) -> Result<Self, Error> {
+        if response.headers_sent() {
+            return Err(Error::HeadersSent);
+        }
+
+        // Servers that never buffer may not support changing the buffering, and refuse the request.
+        let honored = response.set_buffering(false);
+        if !honored && !matches!(response.buffering(), Buffering::Off) {
+            return Err(Error::Buffering);
+        }
+
+        response.set_header("Content-Type", "text/event-stream".into())?;
+        response.set_header("Cache-Control", "no-cache".into())?;
+        // Ask proxies such as nginx not to buffer the stream either.
+        response.set_header("X-Accel-Buffering", "no".into())?;
+        response.remove_header("Content-Length")?;
+        response.flush()?;
+
+        Ok(Self {
+            response,
+            heartbeat_interval: Some(DEFAULT_HEARTBEAT_INTERVAL),
+            last_write: Instant::now(),
+        })
+    }
+
+    /// Set the time without any output after which `heartbeat` sends a comment, or `None` to never send one.
+    pub fn heartbeat_interval(mut self, interval: Option<Duration>) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Send an event to the client.
+    pub fn send(&mut self, event: &Event) -> io::Result<()> {
+        let output = event.encode()?;
+        self.write(&output)
+    }
+
+    /// Send a comment, which the client ignores.
+    pub fn comment(&mut self, text: &str) -> io::Result<()> {
+        let mut output = String::new();
+        push_lines(&mut output, ": ", text);
+        output.push('\n');
+
+        self.write(&output)
+    }
+
+    /// Send a heartbeat comment if nothing has been sent for the heartbeat interval, and get the time until the next
+    /// one is due.
+    ///
+    /// Call this whenever the ingot is about to wait for its next event, and wait for no longer than the returned time.
+    /// Sending a heartbeat also detects clients that have gone away, since writing to the connection fails. If
+    /// heartbeats are disabled, `Duration::MAX` is returned.
+    pub fn heartbeat(&mut self) -> io::Result<Duration> {
+        let interval = match self.heartbeat_interval {
+            Some(interval) => interval,
+            None => return Ok(Duration::MAX),
+        };
+
+        let elapsed = self.last_write.elapsed();
+        if elapsed < interval {
+            return Ok(interval - elapsed);
+        }
+
+        self.write(":\n\n")?;
+        Ok(interval)
+    }
+
+    fn write(&mut self, output: &str) -> io::Result<()> {
+        self.response.write_all(output.as_bytes())?;
+        self.response.flush()?;
+        self.last_write = Instant::now();
+
+        Ok(())
+    }
+}
+
+
+/// Error returned when an event stream cannot be started.
+#[derive(Debug)]
+pub enum Error {
+    /// The response has already been started, so the event stream headers can no longer be sent.
+    HeadersSent,
+    /// The server cannot turn buffering off for the response.
+    Buffering,
+    /// Sending the headers failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::HeadersSent => HeadersSentError.fmt(f),
+            Error::Buffering => f.write_str("the server cannot turn off buffering for an event stream"),
+            Error::Io(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<HeadersSentError> for Error {
+    fn from(_: HeadersSentError) -> Error {
+        Error::HeadersSent
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error::Io(error)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ext::ResponseExt;
+    use http::Context;
+    use testing::TestRequest;
+    use super::*;
+
+    fn encode(event: Event) -> String {
+        event.encode().unwrap()
+    }
+
+    #[test]
+    fn fields() {
+        let event = Event::new().event("update").id("7").retry(Duration::from_millis(2500)).data("{}");
+
+        assert_eq!(encode(event), "event: update\nid: 7\nretry: 2500\ndata: {}\n\n");
+        assert_eq!(encode(Event::new()), "\n");
+    }
+
+    #[test]
+    fn data_lines() {
+        let expected = "data: one\ndata: two\ndata: three\ndata: four\n\n";
+        assert_eq!(encode(Event::new().data("one\r\ntwo\rthree\nfour")), expected);
+        assert_eq!(encode(Event::new().data("")), "data: \n\n");
+        assert_eq!(encode(Event::new().data("end\n")), "data: end\ndata: \n\n");
+        assert_eq!(encode(Event::new().data("\n\n")), "data: \ndata: \ndata: \n\n");
+    }
+
+    #[test]
+    fn single_line_fields() {
+        let invalid = [Event::new().event("a\nb"), Event::new().event("a\r"), Event::new().id("1\n"), Event::new().id("1\0")];
+        for event in invalid {
+            assert_eq!(event.encode().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+
+        // Only an ID with a null character is ignored by clients.
+        assert_eq!(encode(Event::new().event("a\0b")), "event: a\0b\n\n");
+    }
+
+    #[test]
+    fn stream() {
+        let mut context = TestRequest::get("/events").into_context();
+        {
+            let mut events = context.response().event_stream().unwrap();
+            events.send(&Event::new().id("1").data("first\nsecond")).unwrap();
+            events.comment("note\nmore").unwrap();
+        }
+
+        let response = context.finish();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.header("Content-Type"), Some("text/event-stream"));
+        assert_eq!(response.header("Cache-Control"), Some("no-cache"));
+        assert_eq!(response.header("X-Accel-Buffering"), Some("no"));
+        assert_eq!(response.text(), "id: 1\ndata: first\ndata: second\n\n: note\n: more\n\n");
+        assert!(response.violations().is_empty(), "{:?}", response.violations());
+    }
+
+    #[test]
+    fn stream_invalid_event() {
+        let mut context = TestRequest::get("/events").into_context();
+        {
+            let mut events = context.response().event_stream().unwrap();
+            assert!(events.send(&Event::new().id("1\n2").data("x")).is_err());
+        }
+
+        assert_eq!(context.finish().text(), "");
+    }
+
+    #[test]
+    fn buffering_cannot_be_disabled() {
+        let mut context = TestRequest::get("/events").fixed_buffering(true).into_context();
+        assert!(matches!(context.response().event_stream(), Err(Error::Buffering)));
+        assert!(!context.response().headers_sent());
+
+        let response = context.finish();
+        assert_eq!(response.header("Content-Type"), None);
+        assert!(response.violations().is_empty(), "{:?}", response.violations());
+    }
+
+    #[test]
+    fn buffering_already_off() {
+        let mut context = TestRequest::get("/events").buffering(false).fixed_buffering(true).into_context();
+        assert!(context.response().event_stream().is_ok());
+        assert_eq!(context.finish().header("Content-Type"), Some("text/event-stream"));
+    }
+
+    #[test]
+    fn headers_already_sent() {
+        let mut context = TestRequest::get("/events").into_context();
+        context.response().write_all(b"started").unwrap();
+        context.response().flush().unwrap();
+
+        assert!(matches!(context.response().event_stream(), Err(Error::HeadersSent)));
+    }
+
+    #[test]
+    fn heartbeat() {
+        let mut context = TestRequest::get("/events").into_context();
+        {
+            let mut events = context.response().event_stream().unwrap().heartbeat_interval(Some(Duration::ZERO));
+            assert_eq!(events.heartbeat().unwrap(), Duration::ZERO);
+
+            let mut events = events.heartbeat_interval(Some(Duration::from_secs(60)));
+            assert!(events.heartbeat().unwrap() <= Duration::from_secs(60));
+
+            let mut events = events.heartbeat_interval(None);
+            assert_eq!(events.heartbeat().unwrap(), Duration::MAX);
+        }
+
+        assert_eq!(context.finish().text(), ":\n\n");
+    }
+}
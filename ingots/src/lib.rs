@@ -1,13 +1,33 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
+#[cfg(feature = "compression")]
+extern crate brotli;
+#[cfg(feature = "compression")]
+extern crate flate2;
+
 #[macro_use]
-mod abi;
+pub mod abi;
+pub mod async_io;
+pub mod blocking;
+#[cfg(feature = "compression")]
+pub mod compress;
+pub mod ext;
 pub mod http;
+pub mod lifecycle;
+pub mod middleware;
+pub mod static_files;
+pub mod testing;
+pub mod websocket;
+
+pub use lifecycle::StartError;
+
+use std::future::Future;
+use std::pin::Pin;
 
 
 /// Get the version of the ingots specification this library conforms to.
 #[no_mangle]
-pub static INGOTS_VERSION: u16 = 1;
+pub static INGOTS_VERSION: u16 = 8;
 
 
 /// Primary trait for a Rust ingot. An ingot acts as an entry point for a web application, and provides methods for
@@ -17,10 +37,54 @@ pub static INGOTS_VERSION: u16 = 1;
 /// and must handle synchronization internally.
 pub trait Ingot: Send + Sync {
     /// Handle a single HTTP request.
-    fn handle(&self, context: &mut http::Context);
+    fn handle(&self, context: &mut dyn http::Context);
+
+    /// Called by the ingot server when the ingot is put into service, before it handles any requests.
+    ///
+    /// If an error is returned, the server will not route any requests to the ingot.
+    fn start(&mut self) -> Result<(), StartError> {
+        Ok(())
+    }
+
+    /// Called by the ingot server when the ingot is shut down.
+    fn stop(&mut self) {}
+}
+
+impl<I: Ingot + ?Sized> Ingot for Box<I> {
+    fn handle(&self, context: &mut dyn http::Context) {
+        (**self).handle(context)
+    }
+
+    fn start(&mut self) -> Result<(), StartError> {
+        (**self).start()
+    }
+
+    fn stop(&mut self) {
+        (**self).stop()
+    }
+}
+
+
+/// Future returned by an asynchronous ingot for a single request.
+pub type HandleFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Asynchronous counterpart of `Ingot`, for servers that handle many concurrent connections without dedicating a thread
+/// to each request.
+///
+/// Existing blocking ingots can be served by an asynchronous server by wrapping them in `blocking::Blocking`.
+pub trait AsyncIngot: Send + Sync {
+    /// Handle a single HTTP request.
+    ///
+    /// The ingot takes ownership of the request context. The request is complete once the returned future resolves and
+    /// the context has been dropped.
+    fn handle(&self, context: Box<dyn http::AsyncContext>) -> HandleFuture;
 
-    /// Called by the ingot server when the ingot is put into service.
-    fn start(&mut self) {}
+    /// Called by the ingot server when the ingot is put into service, before it handles any requests.
+    ///
+    /// If an error is returned, the server will not route any requests to the ingot.
+    fn start(&mut self) -> Result<(), StartError> {
+        Ok(())
+    }
 
     /// Called by the ingot server when the ingot is shut down.
     fn stop(&mut self) {}
@@ -0,0 +1,355 @@
+//! Managing when an ingot is put into service and taken out of it.
+//!
+//! Servers wrap each ingot in a `Lifecycle`, which calls `Ingot::start` before the first request is handled and
+//! `Ingot::stop` once the ingot has been drained of requests during shutdown. Requests that arrive while the ingot is
+//! not in service are answered with `503 Service Unavailable` instead of being passed to the ingot.
+use http;
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::{Condvar, Mutex, RwLock};
+use std::time::Duration;
+use super::Ingot;
+
+
+/// Error returned by an ingot that could not be put into service.
+#[derive(Clone, Debug)]
+pub struct StartError {
+    message: String,
+}
+
+impl StartError {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+
+    /// Get a description of why the ingot failed to start.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl error::Error for StartError {}
+
+impl From<String> for StartError {
+    fn from(message: String) -> Self {
+        Self::new(message)
+    }
+}
+
+impl<'a> From<&'a str> for StartError {
+    fn from(message: &'a str) -> Self {
+        Self::new(message)
+    }
+}
+
+impl From<io::Error> for StartError {
+    fn from(error: io::Error) -> Self {
+        Self::new(error.to_string())
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    /// The ingot has not been started yet.
+    New,
+    /// The ingot is serving requests.
+    Running,
+    /// The ingot failed to start, and will never serve requests.
+    Failed,
+    /// The ingot is finishing the requests in progress before it is stopped.
+    Draining,
+    /// The ingot has been stopped.
+    Stopped,
+}
+
+struct State {
+    phase: Phase,
+    in_flight: usize,
+}
+
+/// Wrapper that tracks whether an ingot is in service and how many requests it is handling.
+pub struct Lifecycle<I> {
+    ingot: RwLock<I>,
+    state: Mutex<State>,
+    idle: Condvar,
+}
+
+impl<I: Ingot> Lifecycle<I> {
+    pub fn new(ingot: I) -> Self {
+        Self {
+            ingot: RwLock::new(ingot),
+            state: Mutex::new(State {
+                phase: Phase::New,
+                in_flight: 0,
+            }),
+            idle: Condvar::new(),
+        }
+    }
+
+    /// Start the ingot, allowing it to handle requests.
+    ///
+    /// If the ingot fails to start, it is never started again and all requests are refused.
+    pub fn start(&self) -> Result<(), StartError> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.phase != Phase::New {
+            return Ok(());
+        }
+
+        match self.ingot.write().unwrap().start() {
+            Ok(()) => {
+                state.phase = Phase::Running;
+                Ok(())
+            }
+            Err(e) => {
+                state.phase = Phase::Failed;
+                Err(e)
+            }
+        }
+    }
+
+    /// Check if the ingot is currently accepting requests.
+    pub fn is_running(&self) -> bool {
+        self.state.lock().unwrap().phase == Phase::Running
+    }
+
+    /// Handle a request, or refuse it if the ingot is not in service.
+    pub fn handle(&self, context: &mut dyn http::Context) {
+        {
+            let mut state = self.state.lock().unwrap();
+
+            if state.phase != Phase::Running {
+                drop(state);
+                let _ = context.response().set_status(503);
+                return;
+            }
+
+            state.in_flight += 1;
+        }
+
+        // Decrement the count even if the ingot panics.
+        let _guard = InFlight(self);
+
+        self.ingot.read().unwrap().handle(context);
+    }
+
+    /// Stop accepting new requests, wait for the requests in progress to complete, and stop the ingot.
+    ///
+    /// Returns `false` if requests were still in progress after the timeout elapsed. The ingot is not stopped in that
+    /// case, since it is still in use; calling this method again will retry.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        match state.phase {
+            Phase::Running => state.phase = Phase::Draining,
+            Phase::Draining => {}
+            // Never started, so there is nothing to stop.
+            Phase::New | Phase::Failed => {
+                state.phase = Phase::Stopped;
+                return true;
+            }
+            Phase::Stopped => return true,
+        }
+
+        let (mut state, _) = self.idle.wait_timeout_while(state, timeout, |state| state.in_flight > 0).unwrap();
+
+        if state.in_flight > 0 {
+            return false;
+        }
+
+        self.ingot.write().unwrap().stop();
+        state.phase = Phase::Stopped;
+
+        true
+    }
+
+    /// Get the number of requests currently being handled.
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+}
+
+struct InFlight<'a, I: 'a>(&'a Lifecycle<I>);
+
+impl<'a, I> Drop for InFlight<'a, I> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        state.in_flight -= 1;
+        if state.in_flight == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use http;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::thread;
+    use std::time::Duration;
+    use testing::TestRequest;
+    use super::*;
+
+    type Events = Arc<Mutex<Vec<&'static str>>>;
+
+    /// Records what happens to it, holds requests to `/wait` until released, and panics on `/panic`.
+    struct Gate {
+        events: Events,
+        broken: bool,
+        started: Mutex<Sender<()>>,
+        release: Mutex<Receiver<()>>,
+    }
+
+    impl Ingot for Gate {
+        fn handle(&self, context: &mut dyn http::Context) {
+            match &*context.request().path_info() {
+                "/wait" => {
+                    self.started.lock().unwrap().send(()).unwrap();
+                    self.release.lock().unwrap().recv().unwrap();
+                }
+                "/panic" => panic!("handler panicked"),
+                _ => {}
+            }
+
+            self.events.lock().unwrap().push("handled");
+        }
+
+        fn start(&mut self) -> Result<(), StartError> {
+            if self.broken {
+                return Err(StartError::new("broken"));
+            }
+
+            self.events.lock().unwrap().push("started");
+            Ok(())
+        }
+
+        fn stop(&mut self) {
+            self.events.lock().unwrap().push("stopped");
+        }
+    }
+
+    fn gate(broken: bool) -> (Arc<Lifecycle<Gate>>, Events, Receiver<()>, Sender<()>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (started, started_receiver) = mpsc::channel();
+        let (release_sender, release) = mpsc::channel();
+        let ingot = Gate {
+            events: events.clone(),
+            broken,
+            started: Mutex::new(started),
+            release: Mutex::new(release),
+        };
+
+        (Arc::new(Lifecycle::new(ingot)), events, started_receiver, release_sender)
+    }
+
+    fn status(lifecycle: &Lifecycle<Gate>, path: &str) -> http::StatusCode {
+        let mut context = TestRequest::get(path).into_context();
+        lifecycle.handle(&mut context);
+        context.finish().status()
+    }
+
+    /// Handle a request to `/wait` on another thread, returning once the ingot has received it.
+    fn hold(lifecycle: &Arc<Lifecycle<Gate>>, started: &Receiver<()>) -> thread::JoinHandle<http::StatusCode> {
+        let lifecycle = lifecycle.clone();
+        let request = thread::spawn(move || status(&lifecycle, "/wait"));
+
+        started.recv().unwrap();
+        request
+    }
+
+    #[test]
+    fn refuses_requests_before_start() {
+        let (lifecycle, events, _, _) = gate(false);
+
+        assert!(!lifecycle.is_running());
+        assert_eq!(status(&lifecycle, "/"), 503);
+
+        lifecycle.start().unwrap();
+        assert!(lifecycle.is_running());
+        assert_eq!(status(&lifecycle, "/"), 200);
+        assert_eq!(*events.lock().unwrap(), ["started", "handled"]);
+    }
+
+    #[test]
+    fn refuses_requests_after_failed_start() {
+        let (lifecycle, events, _, _) = gate(true);
+
+        assert_eq!(lifecycle.start().unwrap_err().message(), "broken");
+        assert!(!lifecycle.is_running());
+        assert_eq!(status(&lifecycle, "/"), 503);
+
+        // An ingot that never started is not stopped.
+        assert!(lifecycle.shutdown(Duration::from_secs(0)));
+        assert!(events.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn shutdown_drains_requests_in_flight() {
+        let (lifecycle, events, started, release) = gate(false);
+        lifecycle.start().unwrap();
+
+        let request = hold(&lifecycle, &started);
+        assert_eq!(lifecycle.in_flight(), 1);
+
+        let shutdown = {
+            let lifecycle = lifecycle.clone();
+            thread::spawn(move || lifecycle.shutdown(Duration::from_secs(10)))
+        };
+        while lifecycle.is_running() {
+            thread::yield_now();
+        }
+
+        // New requests are refused while draining, and do not count as in flight.
+        assert_eq!(status(&lifecycle, "/"), 503);
+        assert_eq!(lifecycle.in_flight(), 1);
+
+        release.send(()).unwrap();
+        assert_eq!(request.join().unwrap(), 200);
+        assert!(shutdown.join().unwrap());
+        assert_eq!(lifecycle.in_flight(), 0);
+        assert_eq!(*events.lock().unwrap(), ["started", "handled", "stopped"]);
+    }
+
+    #[test]
+    fn shutdown_times_out() {
+        let (lifecycle, events, started, release) = gate(false);
+        lifecycle.start().unwrap();
+
+        let request = hold(&lifecycle, &started);
+        assert!(!lifecycle.shutdown(Duration::from_millis(10)));
+        assert_eq!(*events.lock().unwrap(), ["started"]);
+
+        // Calling it again retries once the request has completed.
+        release.send(()).unwrap();
+        assert_eq!(request.join().unwrap(), 200);
+        assert!(lifecycle.shutdown(Duration::from_millis(10)));
+        assert_eq!(*events.lock().unwrap(), ["started", "handled", "stopped"]);
+    }
+
+    #[test]
+    fn panic_is_no_longer_in_flight() {
+        let (lifecycle, events, _, _) = gate(false);
+        lifecycle.start().unwrap();
+
+        let mut context = TestRequest::get("/panic").into_context();
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| lifecycle.handle(&mut context))).is_err());
+        assert_eq!(lifecycle.in_flight(), 0);
+
+        assert!(lifecycle.shutdown(Duration::from_secs(0)));
+        assert_eq!(*events.lock().unwrap(), ["started", "stopped"]);
+    }
+}
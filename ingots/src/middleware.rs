@@ -0,0 +1,325 @@
+//! Composing ingots from reusable layers of request handling.
+//!
+//! A middleware wraps the handling of a request by an ingot. It can inspect or modify the request context before
+//! passing it on, modify the response after the inner handler has run, replace the context with a wrapper, or answer
+//! the request itself without calling the inner handler at all.
+//!
+//! Middlewares are stacked around an ingot with a `Builder`. The resulting `Stack` is itself an `Ingot`, so it can be
+//! exported with `ingot_init!` or served directly by any server:
+//!
+//! ```
+//! # use ingots::Ingot;
+//! # use ingots::http;
+//! # use ingots::middleware::{Builder, Middleware, Next};
+//! # use ingots::testing::TestRequest;
+//! # struct MyIngot;
+//! # impl Ingot for MyIngot {
+//! #     fn handle(&self, context: &mut dyn http::Context) {}
+//! # }
+//! /// Logs the status of every response.
+//! struct RequestLogger;
+//!
+//! impl Middleware for RequestLogger {
+//!     fn handle(&self, context: &mut dyn http::Context, next: Next) {
+//!         let path = context.request().path_info().into_owned();
+//!         next.run(context);
+//!         println!("{} {}", context.response().status(), path);
+//!     }
+//! }
+//!
+//! let ingot = Builder::new()
+//!     .with(RequestLogger)
+//!     .with(|context: &mut dyn http::Context, next: Next| {
+//!         let _ = context.response().set_header("X-Frame-Options", "DENY".into());
+//!         next.run(context);
+//!     })
+//!     .build(MyIngot);
+//!
+//! let response = TestRequest::get("/").run(&ingot);
+//! assert_eq!(response.header("X-Frame-Options"), Some("DENY"));
+//! ```
+use http;
+use super::{Ingot, StartError};
+
+
+/// A layer of request handling that wraps an ingot.
+pub trait Middleware: Send + Sync {
+    /// Handle a single HTTP request.
+    ///
+    /// Call `next.run` to pass the request on to the rest of the stack. Not calling it short-circuits the request, in
+    /// which case the middleware is responsible for the response. Changes made to the response after `next.run`
+    /// returns only take effect if the headers have not been sent yet; check `Response::headers_sent`.
+    fn handle(&self, context: &mut dyn http::Context, next: Next<'_>);
+
+    /// Called when the stack is put into service, before the ingot is started.
+    fn start(&mut self) -> Result<(), StartError> {
+        Ok(())
+    }
+
+    /// Called when the stack is shut down, after the ingot has been stopped.
+    fn stop(&mut self) {}
+}
+
+impl<F> Middleware for F where F: Fn(&mut dyn http::Context, Next<'_>) + Send + Sync {
+    fn handle(&self, context: &mut dyn http::Context, next: Next<'_>) {
+        self(context, next)
+    }
+}
+
+
+/// The remainder of a middleware stack, below the middleware currently handling the request.
+pub struct Next<'a> {
+    middlewares: &'a [Box<dyn Middleware>],
+    ingot: &'a dyn Ingot,
+}
+
+impl<'a> Next<'a> {
+    /// Pass the request on to the next middleware, or to the ingot if this is the last one.
+    pub fn run(self, context: &mut dyn http::Context) {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => middleware.handle(context, Next {
+                middlewares: rest,
+                ingot: self.ingot,
+            }),
+            None => self.ingot.handle(context),
+        }
+    }
+}
+
+
+/// Builder for a stack of middlewares around an ingot.
+#[derive(Default)]
+pub struct Builder {
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a middleware to the stack.
+    ///
+    /// Middlewares see the request in the order they were added, so the first one added is the outermost layer.
+    pub fn with<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// Wrap an ingot in the middlewares added so far.
+    pub fn build<I: Ingot>(self, ingot: I) -> Stack<I> {
+        Stack {
+            middlewares: self.middlewares,
+            ingot,
+        }
+    }
+}
+
+
+/// An ingot wrapped in a stack of middlewares.
+pub struct Stack<I> {
+    middlewares: Vec<Box<dyn Middleware>>,
+    ingot: I,
+}
+
+impl<I> Stack<I> {
+    /// Get the wrapped ingot.
+    pub fn ingot(&self) -> &I {
+        &self.ingot
+    }
+}
+
+impl<I: Ingot> Ingot for Stack<I> {
+    fn handle(&self, context: &mut dyn http::Context) {
+        Next {
+            middlewares: &self.middlewares,
+            ingot: &self.ingot,
+        }.run(context)
+    }
+
+    /// Start the middlewares from the outside in, then the ingot.
+    ///
+    /// If any of them fails to start, the ones that were already started are stopped again.
+    fn start(&mut self) -> Result<(), StartError> {
+        for i in 0..self.middlewares.len() {
+            if let Err(e) = self.middlewares[i].start() {
+                self.middlewares[..i].iter_mut().rev().for_each(|middleware| middleware.stop());
+                return Err(e);
+            }
+        }
+
+        if let Err(e) = self.ingot.start() {
+            self.middlewares.iter_mut().rev().for_each(|middleware| middleware.stop());
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Stop the ingot, then the middlewares from the inside out.
+    fn stop(&mut self) {
+        self.ingot.stop();
+        self.middlewares.iter_mut().rev().for_each(|middleware| middleware.stop());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use testing::TestRequest;
+    use super::*;
+
+    type Events = Arc<Mutex<Vec<String>>>;
+
+    /// Records what happens to a middleware or an ingot, and fails to start if asked to.
+    struct Recorder {
+        name: &'static str,
+        events: Events,
+        broken: bool,
+    }
+
+    impl Recorder {
+        fn new(name: &'static str, events: &Events) -> Self {
+            Self {
+                name,
+                events: events.clone(),
+                broken: false,
+            }
+        }
+
+        fn broken(mut self) -> Self {
+            self.broken = true;
+            self
+        }
+
+        fn record(&self, event: &str) {
+            self.events.lock().unwrap().push(format!("{} {}", self.name, event));
+        }
+
+        fn start(&mut self) -> Result<(), StartError> {
+            if self.broken {
+                self.record("failed");
+                return Err(StartError::new(self.name));
+            }
+
+            self.record("started");
+            Ok(())
+        }
+    }
+
+    impl Middleware for Recorder {
+        fn handle(&self, context: &mut dyn http::Context, next: Next<'_>) {
+            self.record("before");
+            next.run(context);
+            self.record("after");
+        }
+
+        fn start(&mut self) -> Result<(), StartError> {
+            Recorder::start(self)
+        }
+
+        fn stop(&mut self) {
+            self.record("stopped");
+        }
+    }
+
+    impl Ingot for Recorder {
+        fn handle(&self, context: &mut dyn http::Context) {
+            self.record("handled");
+            context.response().write_all(b"Hello").unwrap();
+        }
+
+        fn start(&mut self) -> Result<(), StartError> {
+            Recorder::start(self)
+        }
+
+        fn stop(&mut self) {
+            self.record("stopped");
+        }
+    }
+
+    fn take(events: &Events) -> Vec<String> {
+        events.lock().unwrap().drain(..).collect()
+    }
+
+    #[test]
+    fn run_order() {
+        let events = Events::default();
+        let stack = Builder::new()
+            .with(Recorder::new("outer", &events))
+            .with(Recorder::new("inner", &events))
+            .build(Recorder::new("ingot", &events));
+
+        assert_eq!(TestRequest::get("/").run(&stack).text(), "Hello");
+        assert_eq!(take(&events), ["outer before", "inner before", "ingot handled", "inner after", "outer after"]);
+
+        // Without middlewares, requests go straight to the ingot.
+        assert_eq!(TestRequest::get("/").run(&Builder::new().build(Recorder::new("ingot", &events))).text(), "Hello");
+        assert_eq!(take(&events), ["ingot handled"]);
+    }
+
+    #[test]
+    fn short_circuit() {
+        let events = Events::default();
+        let stack = Builder::new()
+            .with(Recorder::new("outer", &events))
+            .with(|context: &mut dyn http::Context, next: Next| {
+                if context.request().path_info() == "/forbidden" {
+                    context.response().set_status(403).unwrap();
+                } else {
+                    next.run(context);
+                }
+            })
+            .with(Recorder::new("inner", &events))
+            .build(Recorder::new("ingot", &events));
+
+        let response = TestRequest::get("/forbidden").run(&stack);
+        assert_eq!(response.status(), 403);
+        assert_eq!(response.body(), b"");
+        assert_eq!(take(&events), ["outer before", "outer after"]);
+
+        assert_eq!(TestRequest::get("/").run(&stack).status(), 200);
+        assert_eq!(take(&events), ["outer before", "inner before", "ingot handled", "inner after", "outer after"]);
+    }
+
+    #[test]
+    fn start_and_stop_order() {
+        let events = Events::default();
+        let mut stack = Builder::new()
+            .with(Recorder::new("outer", &events))
+            .with(Recorder::new("inner", &events))
+            .build(Recorder::new("ingot", &events));
+
+        stack.start().unwrap();
+        assert_eq!(take(&events), ["outer started", "inner started", "ingot started"]);
+
+        stack.stop();
+        assert_eq!(take(&events), ["ingot stopped", "inner stopped", "outer stopped"]);
+    }
+
+    #[test]
+    fn middleware_start_failure_rolls_back() {
+        let events = Events::default();
+        let mut stack = Builder::new()
+            .with(Recorder::new("outer", &events))
+            .with(Recorder::new("middle", &events).broken())
+            .with(Recorder::new("inner", &events))
+            .build(Recorder::new("ingot", &events));
+
+        assert_eq!(stack.start().unwrap_err().message(), "middle");
+        assert_eq!(take(&events), ["outer started", "middle failed", "outer stopped"]);
+    }
+
+    #[test]
+    fn ingot_start_failure_rolls_back() {
+        let events = Events::default();
+        let mut stack = Builder::new()
+            .with(Recorder::new("outer", &events))
+            .with(Recorder::new("inner", &events))
+            .build(Recorder::new("ingot", &events).broken());
+
+        assert_eq!(stack.start().unwrap_err().message(), "ingot");
+        assert_eq!(take(&events), ["outer started", "inner started", "ingot failed", "inner stopped", "outer stopped"]);
+    }
+}
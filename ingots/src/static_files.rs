@@ -0,0 +1,595 @@
+//! An ingot that serves files from a directory.
+use http::{self, Context, StatusCode};
+use http::date::{format_http_date, parse_http_date};
+use http::params;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::Ingot;
+
+
+/// Maximum number of ranges accepted in a single `Range` header. Requests with more ranges get the whole file.
+const MAX_RANGES: usize = 32;
+
+/// Ingot that serves the files in a directory.
+///
+/// The request's path info is mapped to a file under the root directory. Paths containing `..` segments or characters
+/// that could escape the root are refused with `400 Bad Request`; symbolic links inside the root are followed.
+/// Directories are served through their index file, if any, and are never listed.
+///
+/// Responses carry `ETag` and `Last-Modified` validators, conditional requests are answered with
+/// `304 Not Modified`, and `Range` requests are answered with a single range or a `multipart/byteranges` body.
+pub struct StaticFiles {
+    root: PathBuf,
+    index_file: Option<String>,
+}
+
+impl StaticFiles {
+    /// Create an ingot serving the files under the given directory.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            index_file: Some(String::from("index.html")),
+        }
+    }
+
+    /// Set the file served for requests to a directory, or `None` to not serve directories at all.
+    pub fn index_file<S: Into<String>>(mut self, index_file: Option<S>) -> Self {
+        self.index_file = index_file.map(Into::into);
+        self
+    }
+
+    /// Get the directory files are served from.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn serve(&self, context: &mut dyn Context) -> Result<(), StatusCode> {
+        let method = context.request().method().to_ascii_uppercase();
+        let head = match method.as_str() {
+            "GET" => false,
+            "HEAD" => true,
+            _ => {
+                let _ = context.response().set_header("Allow", "GET, HEAD".into());
+                return Err(405);
+            }
+        };
+
+        let path_info = context.request().path_info().into_owned();
+        let mut path = self.resolve(&path_info).ok_or(400u16)?;
+        let mut metadata = fs::metadata(&path).map_err(|e| io_status(&e))?;
+
+        if metadata.is_dir() {
+            // Relative links in the index file only work if the directory URL ends with a slash.
+            if !path_info.ends_with('/') {
+                let request = context.request();
+                let mut location = format!("{}{}/", request.context_path(), path_info);
+                if let Some(query) = request.query_string() {
+                    location.push('?');
+                    location.push_str(&query);
+                }
+
+                let _ = context.response().set_header("Location", location);
+                return Err(301);
+            }
+
+            path.push(self.index_file.as_ref().ok_or(404u16)?);
+            metadata = fs::metadata(&path).map_err(|e| io_status(&e))?;
+
+            if !metadata.is_file() {
+                return Err(404);
+            }
+        }
+
+        let mut file = File::open(&path).map_err(|e| io_status(&e))?;
+        let validators = Validators::new(&metadata);
+        let content_type = mime_type(&path);
+
+        {
+            let response = context.response();
+            let _ = response.set_header("ETag", validators.etag.clone());
+            if let Some(modified) = validators.modified {
+                let _ = response.set_header("Last-Modified", format_http_date(modified));
+            }
+            let _ = response.set_header("Accept-Ranges", "bytes".into());
+        }
+
+        if validators.not_modified(context.request()) {
+            return Err(304);
+        }
+
+        let len = metadata.len();
+        let ranges = match context.request().get_header("Range") {
+            Some(range) if !head && validators.matches_if_range(context.request()) => parse_range(range, len),
+            _ => None,
+        };
+
+        let response = context.response();
+
+        match ranges {
+            None => {
+                let _ = response.set_header("Content-Type", content_type.into());
+                let _ = response.set_header("Content-Length", len.to_string());
+
+                if !head {
+                    copy_range(&mut file, response, 0, len).map_err(|_| 500u16)?;
+                }
+            }
+            Some(Err(())) => {
+                let _ = response.set_header("Content-Range", format!("bytes */{}", len));
+                return Err(416);
+            }
+            Some(Ok(ref ranges)) if ranges.len() == 1 => {
+                let (start, end) = ranges[0];
+                let _ = response.set_status(206);
+                let _ = response.set_header("Content-Type", content_type.into());
+                let _ = response.set_header("Content-Range", format!("bytes {}-{}/{}", start, end, len));
+                let _ = response.set_header("Content-Length", (end - start + 1).to_string());
+
+                copy_range(&mut file, response, start, end - start + 1).map_err(|_| 500u16)?;
+            }
+            Some(Ok(ranges)) => {
+                let boundary = boundary();
+                let part_header = |&(start, end): &(u64, u64)| {
+                    format!("\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                        boundary, content_type, start, end, len)
+                };
+                let trailer = format!("\r\n--{}--\r\n", boundary);
+                let body_len = ranges.iter()
+                    .map(|range| part_header(range).len() as u64 + range.1 - range.0 + 1)
+                    .sum::<u64>() + trailer.len() as u64;
+
+                let _ = response.set_status(206);
+                let _ = response.set_header("Content-Type", format!("multipart/byteranges; boundary={}", boundary));
+                let _ = response.set_header("Content-Length", body_len.to_string());
+
+                for range in ranges.iter() {
+                    response.write_all(part_header(range).as_bytes()).map_err(|_| 500u16)?;
+                    copy_range(&mut file, response, range.0, range.1 - range.0 + 1).map_err(|_| 500u16)?;
+                }
+                response.write_all(trailer.as_bytes()).map_err(|_| 500u16)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Map the path info of a request to a file path under the root, or `None` if it is not a safe path.
+    fn resolve(&self, path_info: &str) -> Option<PathBuf> {
+        let decoded = percent_decode(path_info)?;
+        let mut path = self.root.clone();
+
+        for segment in decoded.split('/') {
+            if segment.is_empty() || segment == "." {
+                continue;
+            }
+
+            // Refuse anything that the file system could interpret as more than a single file name, such as `..`,
+            // drive prefixes or backslash separators on Windows.
+            if segment.contains(['\0', '\\']) {
+                return None;
+            }
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(name)), None) if name == segment => path.push(segment),
+                _ => return None,
+            }
+        }
+
+        Some(path)
+    }
+}
+
+impl Ingot for StaticFiles {
+    fn handle(&self, context: &mut dyn Context) {
+        if let Err(status) = self.serve(context) {
+            let response = context.response();
+
+            if !response.headers_sent() {
+                let _ = response.set_status(status);
+
+                // Validators and range information are kept on the responses where they are meaningful.
+                if status != 304 {
+                    let _ = response.remove_header("ETag");
+                    let _ = response.remove_header("Last-Modified");
+                }
+                if status >= 400 {
+                    let _ = response.remove_header("Accept-Ranges");
+                }
+            }
+        }
+    }
+}
+
+
+/// Cache validators of a file.
+struct Validators {
+    etag: String,
+    modified: Option<SystemTime>,
+}
+
+impl Validators {
+    fn new(metadata: &Metadata) -> Self {
+        let modified = metadata.modified().ok();
+        let since_epoch = modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+
+        Self {
+            etag: format!("\"{:x}-{:x}-{:x}\"", metadata.len(), since_epoch.as_secs(), since_epoch.subsec_nanos()),
+            modified,
+        }
+    }
+
+    /// Check if a conditional request can be answered with `304 Not Modified`.
+    fn not_modified(&self, request: &dyn http::Request) -> bool {
+        // If-None-Match takes precedence over If-Modified-Since (RFC 7232, section 6).
+        if let Some(value) = request.get_header("If-None-Match") {
+            return value.split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || weak_tag(tag) == weak_tag(&self.etag));
+        }
+
+        match (request.get_header("If-Modified-Since").and_then(parse_http_date), self.modified) {
+            (Some(since), Some(modified)) => seconds(modified) <= seconds(since),
+            _ => false,
+        }
+    }
+
+    /// Check if a `Range` header should be honored given the request's `If-Range` header.
+    fn matches_if_range(&self, request: &dyn http::Request) -> bool {
+        match request.get_header("If-Range").map(str::trim) {
+            None => true,
+            // Ranges are only combined with a strong match, and weak tags never match strongly.
+            Some(tag) if tag.starts_with('"') || tag.starts_with("W/") => tag == self.etag,
+            Some(date) => match (parse_http_date(date), self.modified) {
+                (Some(date), Some(modified)) => seconds(date) == seconds(modified),
+                _ => false,
+            },
+        }
+    }
+}
+
+fn weak_tag(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+
+/// Parse a `Range` header into a sorted list of inclusive byte ranges, merging any that overlap.
+///
+/// Returns `None` if the header should be ignored, or `Err` if none of the ranges can be satisfied.
+fn parse_range(value: &str, len: u64) -> Option<Result<Vec<(u64, u64)>, ()>> {
+    let specs = value.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+
+    for (i, spec) in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()).enumerate() {
+        if i == MAX_RANGES {
+            return None;
+        }
+
+        let index = spec.find('-')?;
+        let (first, last) = (spec[..index].trim(), spec[index + 1..].trim());
+
+        let range = if first.is_empty() {
+            // A suffix range selects the last bytes of the file.
+            let suffix: u64 = last.parse().ok()?;
+            match suffix {
+                0 => None,
+                _ if len == 0 => None,
+                _ => Some((len.saturating_sub(suffix), len - 1)),
+            }
+        } else {
+            let first: u64 = first.parse().ok()?;
+            let last: u64 = match last {
+                "" => u64::MAX,
+                last => last.parse().ok()?,
+            };
+
+            if last < first {
+                return None;
+            }
+
+            if first < len {
+                Some((first, last.min(len - 1)))
+            } else {
+                None
+            }
+        };
+
+        ranges.extend(range);
+    }
+
+    if ranges.is_empty() {
+        return Some(Err(()));
+    }
+
+    ranges.sort();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    Some(Ok(merged))
+}
+
+fn copy_range(file: &mut File, output: &mut dyn Write, start: u64, len: u64) -> io::Result<()> {
+    file.seek(SeekFrom::Start(start))?;
+    let copied = io::copy(&mut file.take(len), output)?;
+
+    if copied < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file was truncated while being served"));
+    }
+
+    Ok(())
+}
+
+/// Generate a boundary for a `multipart/byteranges` body.
+fn boundary() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.subsec_nanos()).unwrap_or(0);
+    format!("ingots-byteranges-{:08x}{:04x}", nanos, COUNTER.fetch_add(1, Ordering::Relaxed) & 0xffff)
+}
+
+/// Decode percent escapes in a URL path. Unlike in query strings, `+` is not a space.
+///
+/// Returns `None` if an escape decodes to invalid UTF-8.
+fn percent_decode(path: &str) -> Option<String> {
+    String::from_utf8(params::percent_decode_bytes(path.as_bytes(), false)).ok()
+}
+
+fn io_status(error: &io::Error) -> StatusCode {
+    match error.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => 404,
+        io::ErrorKind::PermissionDenied => 403,
+        _ => 500,
+    }
+}
+
+
+/// Guess the media type of a file from its extension.
+///
+/// Text types are assumed to be encoded in UTF-8. Unknown extensions are served as `application/octet-stream`.
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => extension.to_ascii_lowercase(),
+        None => return "application/octet-stream",
+    };
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "xml" => "application/xml",
+        "txt" | "text" | "log" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "md" | "markdown" => "text/markdown; charset=utf-8",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/vnd.microsoft.icon",
+        "bmp" => "image/bmp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" => "audio/ogg",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "ogv" => "video/ogg",
+        _ => "application/octet-stream",
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use testing::{TestRequest, TestResponse};
+    use super::*;
+
+    /// Create a directory of files to serve, unique to the test.
+    fn root(test: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("ingots-static-{}-{}", process::id(), test));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("digits.txt"), "0123456789").unwrap();
+        fs::write(root.join("docs/index.html"), "<p>Docs</p>").unwrap();
+        root
+    }
+
+    fn get(root: &Path, request: TestRequest) -> TestResponse {
+        let response = request.run(&StaticFiles::new(root));
+        assert!(response.violations().is_empty(), "{:?}", response.violations());
+        response
+    }
+
+    #[test]
+    fn serve_file() {
+        let root = root("serve_file");
+        let response = get(&root, TestRequest::get("/digits.txt"));
+
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text(), "0123456789");
+        assert_eq!(response.header("Content-Type"), Some("text/plain; charset=utf-8"));
+        assert_eq!(response.header("Content-Length"), Some("10"));
+        assert_eq!(response.header("Accept-Ranges"), Some("bytes"));
+        assert!(response.header("ETag").is_some());
+        assert!(response.header("Last-Modified").and_then(parse_http_date).is_some());
+
+        let head = get(&root, TestRequest::head("/digits.txt"));
+        assert_eq!(head.status(), 200);
+        assert_eq!(head.header("Content-Length"), Some("10"));
+        assert_eq!(head.body(), b"");
+    }
+
+    #[test]
+    fn directories() {
+        let root = root("directories");
+
+        let response = get(&root, TestRequest::get("/docs?page=2").context_path("/static"));
+        assert_eq!(response.status(), 301);
+        assert_eq!(response.header("Location"), Some("/static/docs/?page=2"));
+
+        assert_eq!(get(&root, TestRequest::get("/docs/")).text(), "<p>Docs</p>");
+        assert_eq!(get(&root, TestRequest::get("/docs%2Findex.html")).text(), "<p>Docs</p>");
+        assert_eq!(get(&root, TestRequest::get("/")).status(), 404);
+    }
+
+    #[test]
+    fn refused_paths() {
+        let root = root("refused_paths");
+
+        assert_eq!(get(&root, TestRequest::get("/../digits.txt")).status(), 400);
+        assert_eq!(get(&root, TestRequest::get("/docs/%2e%2e/%2e%2e/etc/passwd")).status(), 400);
+        assert_eq!(get(&root, TestRequest::get("/docs\\index.html")).status(), 400);
+        assert_eq!(get(&root, TestRequest::get("/%FF")).status(), 400);
+        assert_eq!(get(&root, TestRequest::get("/missing.txt")).status(), 404);
+
+        let response = get(&root, TestRequest::post("/digits.txt"));
+        assert_eq!(response.status(), 405);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD"));
+        assert_eq!(response.header("ETag"), None);
+    }
+
+    #[test]
+    fn if_none_match() {
+        let root = root("if_none_match");
+        let etag = get(&root, TestRequest::get("/digits.txt")).header("ETag").unwrap().to_owned();
+
+        let response = get(&root, TestRequest::get("/digits.txt").header("If-None-Match", &etag));
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.header("ETag"), Some(etag.as_str()));
+        assert_eq!(response.body(), b"");
+
+        let weak = format!("\"other\", W/{}", etag);
+        assert_eq!(get(&root, TestRequest::get("/digits.txt").header("If-None-Match", &weak)).status(), 304);
+        assert_eq!(get(&root, TestRequest::get("/digits.txt").header("If-None-Match", "*")).status(), 304);
+
+        // If-None-Match takes precedence over If-Modified-Since.
+        let request = TestRequest::get("/digits.txt")
+            .header("If-None-Match", "\"other\"")
+            .header("If-Modified-Since", "Fri, 31 Dec 9999 23:59:59 GMT");
+        assert_eq!(get(&root, request).status(), 200);
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let root = root("if_modified_since");
+        let modified = get(&root, TestRequest::get("/digits.txt")).header("Last-Modified").unwrap().to_owned();
+
+        let request = TestRequest::get("/digits.txt").header("If-Modified-Since", &modified);
+        assert_eq!(get(&root, request).status(), 304);
+
+        let request = TestRequest::get("/digits.txt").header("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(get(&root, request).status(), 200);
+
+        let request = TestRequest::get("/digits.txt").header("If-Modified-Since", "not a date");
+        assert_eq!(get(&root, request).status(), 200);
+    }
+
+    #[test]
+    fn single_range() {
+        let root = root("single_range");
+
+        for &(range, content_range, body) in &[
+            ("bytes=2-4", "bytes 2-4/10", "234"),
+            ("bytes=7-", "bytes 7-9/10", "789"),
+            ("bytes=-3", "bytes 7-9/10", "789"),
+            ("bytes=8-100", "bytes 8-9/10", "89"),
+            ("bytes=0-1,2-3", "bytes 0-3/10", "0123"),
+        ] {
+            let response = get(&root, TestRequest::get("/digits.txt").header("Range", range));
+
+            assert_eq!(response.status(), 206, "{}", range);
+            assert_eq!(response.header("Content-Range"), Some(content_range), "{}", range);
+            assert_eq!(response.text(), body, "{}", range);
+        }
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        let root = root("multiple_ranges");
+        let response = get(&root, TestRequest::get("/digits.txt").header("Range", "bytes=6-7, 0-1"));
+
+        assert_eq!(response.status(), 206);
+        let content_type = response.header("Content-Type").unwrap();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+
+        let expected = format!(
+            "\r\n--{0}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{0}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 6-7/10\r\n\r\n67\
+             \r\n--{0}--\r\n",
+            boundary
+        );
+        assert_eq!(response.text(), expected);
+    }
+
+    #[test]
+    fn unsatisfiable_range() {
+        let root = root("unsatisfiable_range");
+        let response = get(&root, TestRequest::get("/digits.txt").header("Range", "bytes=10-20"));
+
+        assert_eq!(response.status(), 416);
+        assert_eq!(response.header("Content-Range"), Some("bytes */10"));
+        assert_eq!(response.header("Accept-Ranges"), None);
+
+        // Headers that cannot be parsed are ignored.
+        for &range in &["bytes=5-2", "items=0-1", "bytes=a-b"] {
+            let response = get(&root, TestRequest::get("/digits.txt").header("Range", range));
+            assert_eq!(response.status(), 200, "{}", range);
+        }
+    }
+
+    #[test]
+    fn if_range() {
+        let root = root("if_range");
+        let full = get(&root, TestRequest::get("/digits.txt"));
+        let etag = full.header("ETag").unwrap();
+        let modified = full.header("Last-Modified").unwrap();
+
+        let cases = [(etag, 206), (modified, 206), ("\"other\"", 200), ("Thu, 01 Jan 1970 00:00:00 GMT", 200)];
+
+        for &(if_range, status) in &cases {
+            let request = TestRequest::get("/digits.txt").header("Range", "bytes=0-0").header("If-Range", if_range);
+            assert_eq!(get(&root, request).status(), status, "{}", if_range);
+        }
+
+        let weak = format!("W/{}", etag);
+        let request = TestRequest::get("/digits.txt").header("Range", "bytes=0-0").header("If-Range", &weak);
+        assert_eq!(get(&root, request).status(), 200);
+    }
+
+    #[test]
+    fn range_limit() {
+        let specs: Vec<String> = (0..MAX_RANGES as u64 + 1).map(|i| format!("{}-{}", i * 2, i * 2)).collect();
+
+        let ranges = parse_range(&format!("bytes={}", specs[..MAX_RANGES].join(",")), 100);
+
+        assert_eq!(ranges.unwrap().unwrap().len(), MAX_RANGES);
+        assert_eq!(parse_range(&format!("bytes={}", specs.join(",")), 100), None);
+        assert_eq!(parse_range("bytes=-5", 0), Some(Err(())));
+    }
+}
@@ -0,0 +1,1008 @@
+//! Running ingots against in-memory requests, for unit tests.
+//!
+//! A `TestRequest` describes a request as a client would send it. Running it through an ingot produces a
+//! `TestResponse` with everything the ingot sent, without building a library or starting a server:
+//!
+//! ```ignore
+//! let response = TestRequest::get("/hello")
+//!     .header("Accept", "text/plain")
+//!     .run(&MyIngot);
+//!
+//! assert_eq!(response.status(), 200);
+//! assert_eq!(response.text(), "Hello");
+//! assert!(response.violations().is_empty(), "{:?}", response.violations());
+//! ```
+//!
+//! The mock server behaves like the real ones where ingots can tell the difference: the response is buffered up to
+//! `BUFFER_SIZE` bytes unless buffering is turned off, the headers are sent on the first flush, and changing them
+//! afterwards fails with `HeadersSentError`. Mistakes that a real server would silently ignore or mangle, such as
+//! ignoring that error, are recorded as `Violation`s on the response.
+//!
+//! Asynchronous ingots are tested the same way with `TestRequest::run_async`, against a mock server whose streams are
+//! always ready.
+//!
+//! The mock server only lets an ingot take over the connection if the request is built with `TestRequest::upgradable`,
+//! which also gives what the client sends afterwards.
+use async_io::{self, AsyncRead, AsyncWrite};
+use http::{self, Buffering, HeaderMap, HeadersSentError, StatusCode, Version};
+use http::cookie::is_token;
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use {AsyncIngot, Ingot};
+
+
+/// Size of the response buffer, unless the ingot turns buffering off.
+pub const BUFFER_SIZE: usize = 64 * 1024;
+
+
+/// Builder for a request to run through an ingot.
+#[derive(Clone, Debug)]
+pub struct TestRequest {
+    method: String,
+    context_path: String,
+    path: String,
+    query: Option<String>,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    remote_addr: SocketAddr,
+    server_addr: SocketAddr,
+    server_name: String,
+    secure: bool,
+    version: Version,
+    buffering: bool,
+    fixed_buffering: bool,
+    upgrade: Option<Vec<u8>>,
+}
+
+impl TestRequest {
+    /// Create a request with the given method and path, which may include a query string.
+    ///
+    /// The path is the part of the URL handled by the ingot, below the context path. The request comes from a client
+    /// on the loopback interface, and is sent over plain HTTP/1.1 to a server named `localhost` on port 80.
+    pub fn new(method: &str, path: &str) -> Self {
+        let (path, query) = match path.find('?') {
+            Some(index) => (&path[..index], Some(path[index + 1..].to_owned())),
+            None => (path, None),
+        };
+
+        Self {
+            method: method.to_owned(),
+            context_path: String::new(),
+            path: path.to_owned(),
+            query,
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+            remote_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 50000),
+            server_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 80),
+            server_name: String::from("localhost"),
+            secure: false,
+            version: Version::Http11,
+            buffering: true,
+            fixed_buffering: false,
+            upgrade: None,
+        }
+    }
+
+    pub fn get(path: &str) -> Self {
+        Self::new("GET", path)
+    }
+
+    /// Create a HEAD request. Any body the ingot writes is discarded, as a server would.
+    pub fn head(path: &str) -> Self {
+        Self::new("HEAD", path)
+    }
+
+    pub fn post(path: &str) -> Self {
+        Self::new("POST", path)
+    }
+
+    pub fn put(path: &str) -> Self {
+        Self::new("PUT", path)
+    }
+
+    pub fn delete(path: &str) -> Self {
+        Self::new("DELETE", path)
+    }
+
+    /// Set the path the ingot is mounted at. Defaults to the root.
+    pub fn context_path(mut self, context_path: &str) -> Self {
+        self.context_path = context_path.to_owned();
+        self
+    }
+
+    /// Set the query string, replacing any given with the path.
+    pub fn query(mut self, query: &str) -> Self {
+        self.query = Some(query.to_owned());
+        self
+    }
+
+    /// Add a request header, keeping any existing values.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Set the request body. `Content-Length` is sent along with it, unless a header says otherwise.
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Set the address of the client.
+    pub fn remote_addr(mut self, addr: SocketAddr) -> Self {
+        self.remote_addr = addr;
+        self
+    }
+
+    /// Set the address of the server. The server name is left alone.
+    pub fn server_addr(mut self, addr: SocketAddr) -> Self {
+        self.server_addr = addr;
+        self
+    }
+
+    pub fn server_name(mut self, name: &str) -> Self {
+        self.server_name = name.to_owned();
+        self
+    }
+
+    /// Set whether the request is made over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn version(mut self, version: Version) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Set whether the response is buffered to begin with. The ingot can still change it, unless `fixed_buffering` is
+    /// set.
+    pub fn buffering(mut self, buffering: bool) -> Self {
+        self.buffering = buffering;
+        self
+    }
+
+    /// Refuse requests from the ingot to change the buffering, as servers that do not support it do.
+    pub fn fixed_buffering(mut self, fixed: bool) -> Self {
+        self.fixed_buffering = fixed;
+        self
+    }
+
+    /// Let the ingot take over the connection, after which the client sends the given bytes and closes it.
+    ///
+    /// If the ingot sets a read timeout on the connection, the client keeps it open instead, and reads past the given
+    /// bytes time out with a `WouldBlock` error.
+    ///
+    /// What the ingot writes to the connection can be read from `TestResponse::upgraded`.
+    pub fn upgradable<B: Into<Vec<u8>>>(mut self, input: B) -> Self {
+        self.upgrade = Some(input.into());
+        self
+    }
+
+    /// Create a context for the request, for calling an ingot or middleware directly.
+    pub fn into_context(mut self) -> MockContext {
+        let has_length = self.headers.contains("Content-Length") || self.headers.contains("Transfer-Encoding");
+        if !self.body.is_empty() && !has_length {
+            self.headers.insert("Content-Length", self.body.len().to_string());
+        }
+
+        let head = self.method.eq_ignore_ascii_case("HEAD");
+
+        MockContext {
+            remote_addr: self.remote_addr,
+            server_addr: self.server_addr,
+            server_name: self.server_name,
+            request: MockRequest {
+                method: self.method,
+                context_path: self.context_path,
+                path: self.path,
+                query: self.query,
+                headers: self.headers,
+                body: io::Cursor::new(self.body),
+                secure: self.secure,
+                version: self.version,
+            },
+            response: MockResponse {
+                status: 200,
+                headers: HeaderMap::new(),
+                buffer_size: if self.buffering { BUFFER_SIZE } else { 0 },
+                fixed_buffering: self.fixed_buffering,
+                body: Vec::new(),
+                sent: 0,
+                headers_sent: false,
+                head,
+                violations: Vec::new(),
+            },
+            upgrade: self.upgrade,
+            upgraded: None,
+        }
+    }
+
+    /// Run the request through an ingot, and get the response it sent.
+    ///
+    /// The ingot is not started first; call `Ingot::start` beforehand if it needs it.
+    pub fn run<I: Ingot + ?Sized>(self, ingot: &I) -> TestResponse {
+        let mut context = self.into_context();
+        ingot.handle(&mut context);
+        context.finish()
+    }
+
+    /// Create an asynchronous context for the request, for calling an `AsyncIngot` directly.
+    ///
+    /// The response can be collected from the `PendingResponse` once the ingot has dropped the context.
+    pub fn into_async_context(self) -> (Box<dyn http::AsyncContext>, PendingResponse) {
+        let (sender, receiver) = mpsc::channel();
+        let context = MockAsyncContext {
+            context: Some(self.into_context()),
+            done: sender,
+        };
+
+        (Box::new(context), PendingResponse(receiver))
+    }
+
+    /// Run the request through an asynchronous ingot, blocking until the request is complete, and get the response it
+    /// sent.
+    pub fn run_async<I: AsyncIngot + ?Sized>(self, ingot: &I) -> TestResponse {
+        let (context, response) = self.into_async_context();
+        async_io::block_on(ingot.handle(context));
+        response.wait()
+    }
+}
+
+
+/// An in-memory request context, created with `TestRequest::into_context`.
+pub struct MockContext {
+    remote_addr: SocketAddr,
+    server_addr: SocketAddr,
+    server_name: String,
+    request: MockRequest,
+    response: MockResponse,
+    /// What the client sends after an upgrade, if the connection can be upgraded.
+    upgrade: Option<Vec<u8>>,
+    /// What the ingot has written since upgrading the connection.
+    upgraded: Option<Arc<Mutex<Vec<u8>>>>,
+}
+
+impl MockContext {
+    /// Complete the response as a server would once the ingot returns, and get what was sent.
+    pub fn finish(self) -> TestResponse {
+        let mut response = self.response;
+        response.flush_buffer();
+
+        if !response.body_allowed() && !response.body.is_empty() {
+            response.violations.push(Violation::BodyNotAllowed(response.status));
+        }
+
+        if let Some(declared) = response.headers.get("Content-Length") {
+            let actual = response.body.len() as u64;
+
+            match declared.trim().parse() {
+                // A HEAD response carries the length of the body that would have been sent.
+                Ok(declared) if declared != actual && !response.head && response.body_allowed() => {
+                    response.violations.push(Violation::ContentLengthMismatch {
+                        declared,
+                        actual,
+                    });
+                }
+                Ok(_) => {}
+                Err(_) => response.violations.push(Violation::InvalidHeaderValue(String::from("Content-Length"))),
+            }
+        }
+
+        // Servers discard the body of a response to a HEAD request.
+        if response.head {
+            response.body.clear();
+        }
+
+        TestResponse {
+            status: response.status,
+            headers: response.headers,
+            body: response.body,
+            upgraded: self.upgraded.map(|output| output.lock().unwrap().clone()),
+            violations: response.violations,
+        }
+    }
+
+    /// Send the headers and hand the connection over to the ingot.
+    fn take_over(&mut self) -> Result<MockStream, http::UpgradeError> {
+        if self.response.headers_sent {
+            return Err(http::UpgradeError::HeadersSent);
+        }
+        let input = self.upgrade.take().ok_or(http::UpgradeError::Unsupported)?;
+
+        self.response.flush_buffer();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        self.upgraded = Some(output.clone());
+
+        Ok(MockStream {
+            input: io::Cursor::new(input),
+            output,
+            timeout: None,
+        })
+    }
+}
+
+impl http::Context for MockContext {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    fn request(&self) -> &dyn http::Request {
+        &self.request
+    }
+
+    fn request_mut(&mut self) -> &mut dyn http::Request {
+        &mut self.request
+    }
+
+    fn response(&mut self) -> &mut dyn http::Response {
+        &mut self.response
+    }
+
+    fn upgrade(&mut self) -> Result<Box<dyn http::Upgraded>, http::UpgradeError> {
+        Ok(Box::new(self.take_over()?))
+    }
+}
+
+
+/// An in-memory context for an asynchronous request, which hands the response over to the `PendingResponse` when it is
+/// dropped.
+struct MockAsyncContext {
+    context: Option<MockContext>,
+    done: Sender<MockContext>,
+}
+
+impl MockAsyncContext {
+    fn inner(&self) -> &MockContext {
+        self.context.as_ref().unwrap()
+    }
+
+    fn inner_mut(&mut self) -> &mut MockContext {
+        self.context.as_mut().unwrap()
+    }
+}
+
+impl http::AsyncContext for MockAsyncContext {
+    fn remote_addr(&self) -> SocketAddr {
+        self.inner().remote_addr
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.inner().server_addr
+    }
+
+    fn server_name(&self) -> &str {
+        &self.inner().server_name
+    }
+
+    fn request(&self) -> &dyn http::AsyncRequest {
+        &self.inner().request
+    }
+
+    fn request_mut(&mut self) -> &mut dyn http::AsyncRequest {
+        &mut self.inner_mut().request
+    }
+
+    fn response(&self) -> &dyn http::AsyncResponse {
+        &self.inner().response
+    }
+
+    fn response_mut(&mut self) -> &mut dyn http::AsyncResponse {
+        &mut self.inner_mut().response
+    }
+
+    fn upgrade(&mut self) -> Result<Box<dyn http::AsyncUpgraded>, http::UpgradeError> {
+        Ok(Box::new(self.inner_mut().take_over()?))
+    }
+}
+
+impl Drop for MockAsyncContext {
+    fn drop(&mut self) {
+        if let Some(context) = self.context.take() {
+            let _ = self.done.send(context);
+        }
+    }
+}
+
+/// The response to a request whose context has been given to an asynchronous ingot.
+pub struct PendingResponse(Receiver<MockContext>);
+
+impl PendingResponse {
+    /// Wait for the ingot to drop the context, then complete the response as a server would and get what was sent.
+    pub fn wait(self) -> TestResponse {
+        self.0.recv().expect("context was leaked").finish()
+    }
+}
+
+
+struct MockRequest {
+    method: String,
+    context_path: String,
+    path: String,
+    query: Option<String>,
+    headers: HeaderMap,
+    body: io::Cursor<Vec<u8>>,
+    secure: bool,
+    version: Version,
+}
+
+impl http::Request for MockRequest {
+    fn method(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.method)
+    }
+
+    fn context_path(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.context_path)
+    }
+
+    fn path_info(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.path)
+    }
+
+    fn query_string(&self) -> Option<Cow<'_, str>> {
+        self.query.as_ref().map(|query| Cow::Borrowed(query.as_str()))
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    fn version(&self) -> Version {
+        self.version
+    }
+}
+
+impl io::Read for MockRequest {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+impl http::AsyncRequest for MockRequest {
+    fn method(&self) -> Cow<'_, str> {
+        http::Request::method(self)
+    }
+
+    fn context_path(&self) -> Cow<'_, str> {
+        http::Request::context_path(self)
+    }
+
+    fn path_info(&self) -> Cow<'_, str> {
+        http::Request::path_info(self)
+    }
+
+    fn query_string(&self) -> Option<Cow<'_, str>> {
+        http::Request::query_string(self)
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    fn version(&self) -> Version {
+        self.version
+    }
+}
+
+impl AsyncRead for MockRequest {
+    fn poll_read(&mut self, _: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.read(buf))
+    }
+}
+
+
+struct MockResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    /// Maximum size of the buffer, or zero if buffering is off.
+    buffer_size: usize,
+    /// Whether requests to change the buffering are refused.
+    fixed_buffering: bool,
+    /// The body written so far, of which the first `sent` bytes have been sent.
+    body: Vec<u8>,
+    sent: usize,
+    headers_sent: bool,
+    /// Whether this is the response to a HEAD request.
+    head: bool,
+    violations: Vec<Violation>,
+}
+
+impl MockResponse {
+    fn flush_buffer(&mut self) {
+        self.headers_sent = true;
+        self.sent = self.body.len();
+    }
+
+    fn body_allowed(&self) -> bool {
+        !matches!(self.status, 100..=199 | 204 | 304)
+    }
+
+    /// Check that the headers can still be changed, recording a violation if not.
+    fn check_headers_sent(&mut self, method: &'static str) -> Result<(), HeadersSentError> {
+        if self.headers_sent {
+            self.violations.push(Violation::HeadersAlreadySent(method));
+            return Err(HeadersSentError);
+        }
+
+        Ok(())
+    }
+
+    fn check_header(&mut self, name: &str, value: &str) {
+        if name.is_empty() || !name.chars().all(is_token) {
+            self.violations.push(Violation::InvalidHeaderName(name.to_owned()));
+        }
+
+        // A line break would allow the value to inject additional headers.
+        if value.contains(['\r', '\n', '\0']) {
+            self.violations.push(Violation::InvalidHeaderValue(name.to_owned()));
+        }
+    }
+}
+
+impl http::Response for MockResponse {
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    fn set_status(&mut self, status: StatusCode) -> Result<(), HeadersSentError> {
+        self.check_headers_sent("set_status")?;
+
+        if !(100..=599).contains(&status) {
+            self.violations.push(Violation::InvalidStatus(status));
+        }
+
+        self.status = status;
+        Ok(())
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn set_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError> {
+        self.check_headers_sent("set_header")?;
+        self.check_header(name, &value);
+        self.headers.insert(name, value);
+        Ok(())
+    }
+
+    fn append_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError> {
+        self.check_headers_sent("append_header")?;
+        self.check_header(name, &value);
+        self.headers.append(name, value);
+        Ok(())
+    }
+
+    fn remove_header(&mut self, name: &str) -> Result<(), HeadersSentError> {
+        self.check_headers_sent("remove_header")?;
+        self.headers.remove(name);
+        Ok(())
+    }
+
+    fn buffering(&self) -> Buffering {
+        match self.buffer_size {
+            0 => Buffering::Off,
+            size => Buffering::On(size as u32),
+        }
+    }
+
+    fn set_buffering(&mut self, buffering: bool) -> bool {
+        if self.fixed_buffering {
+            return false;
+        }
+
+        self.buffer_size = if buffering { BUFFER_SIZE } else { 0 };
+        true
+    }
+
+    fn headers_sent(&self) -> bool {
+        self.headers_sent
+    }
+}
+
+impl io::Write for MockResponse {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.body.extend_from_slice(buf);
+
+        if self.body.len() - self.sent > self.buffer_size {
+            self.flush_buffer();
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buffer();
+        Ok(())
+    }
+}
+
+impl http::AsyncResponse for MockResponse {
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    fn set_status(&mut self, status: StatusCode) -> Result<(), HeadersSentError> {
+        http::Response::set_status(self, status)
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn set_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError> {
+        http::Response::set_header(self, name, value)
+    }
+
+    fn append_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError> {
+        http::Response::append_header(self, name, value)
+    }
+
+    fn remove_header(&mut self, name: &str) -> Result<(), HeadersSentError> {
+        http::Response::remove_header(self, name)
+    }
+
+    fn buffering(&self) -> Buffering {
+        http::Response::buffering(self)
+    }
+
+    fn set_buffering(&mut self, buffering: bool) -> bool {
+        http::Response::set_buffering(self, buffering)
+    }
+
+    fn headers_sent(&self) -> bool {
+        self.headers_sent
+    }
+}
+
+impl AsyncWrite for MockResponse {
+    fn poll_write(&mut self, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.write(buf))
+    }
+
+    fn poll_flush(&mut self, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.flush())
+    }
+}
+
+
+/// A connection taken over by an ingot, which reads what the client sent and records what the ingot writes.
+struct MockStream {
+    input: io::Cursor<Vec<u8>>,
+    output: Arc<Mutex<Vec<u8>>>,
+    timeout: Option<Duration>,
+}
+
+impl io::Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.input.read(buf)? {
+            0 if !buf.is_empty() && self.timeout.is_some() => Err(io::ErrorKind::WouldBlock.into()),
+            len => Ok(len),
+        }
+    }
+}
+
+impl io::Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl http::Upgraded for MockStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+impl AsyncRead for MockStream {
+    fn poll_read(&mut self, _: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.read(buf))
+    }
+}
+
+impl AsyncWrite for MockStream {
+    fn poll_write(&mut self, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.write(buf))
+    }
+
+    fn poll_flush(&mut self, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.flush())
+    }
+}
+
+
+/// The response sent by an ingot for a `TestRequest`.
+#[derive(Clone, Debug)]
+pub struct TestResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    upgraded: Option<Vec<u8>>,
+    violations: Vec<Violation>,
+}
+
+impl TestResponse {
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Get the first value of a response header.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Get the body as text, replacing any invalid UTF-8.
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+
+    pub fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+
+    /// Get what the ingot wrote to the connection after taking it over, if it did.
+    pub fn upgraded(&self) -> Option<&[u8]> {
+        self.upgraded.as_deref()
+    }
+
+    /// Get the mistakes the ingot made while responding, in the order they were made.
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+}
+
+
+/// A mistake made by an ingot while responding, which a real server would reject or ignore.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The named method was called to change the status or headers after they had been sent.
+    HeadersAlreadySent(&'static str),
+    /// The status code is outside the range 100 to 599.
+    InvalidStatus(StatusCode),
+    /// A header name that is not a valid token.
+    InvalidHeaderName(String),
+    /// A header value containing a line break or null, or an unparseable `Content-Length`.
+    InvalidHeaderValue(String),
+    /// A body was written for a status that does not allow one.
+    BodyNotAllowed(StatusCode),
+    /// The body does not have the length given by the `Content-Length` header.
+    ContentLengthMismatch {
+        declared: u64,
+        actual: u64,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::HeadersAlreadySent(method) => write!(f, "{} called after the headers were sent", method),
+            Violation::InvalidStatus(status) => write!(f, "invalid status code {}", status),
+            Violation::InvalidHeaderName(ref name) => write!(f, "invalid header name {:?}", name),
+            Violation::InvalidHeaderValue(ref name) => write!(f, "invalid value for header {:?}", name),
+            Violation::BodyNotAllowed(status) => write!(f, "body written for status {}", status),
+            Violation::ContentLengthMismatch { declared, actual } => {
+                write!(f, "Content-Length is {} but the body is {} bytes", declared, actual)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use http::Context;
+    use super::*;
+
+    /// Responds with a status and a body, declaring the given length.
+    struct Respond {
+        status: StatusCode,
+        length: Option<&'static str>,
+        body: &'static [u8],
+    }
+
+    impl Ingot for Respond {
+        fn handle(&self, context: &mut dyn http::Context) {
+            context.response().set_status(self.status).unwrap();
+            if let Some(length) = self.length {
+                context.response().set_header("Content-Length", length.into()).unwrap();
+            }
+            context.response().write_all(self.body).unwrap();
+        }
+    }
+
+    fn respond(status: StatusCode, length: Option<&'static str>, body: &'static [u8]) -> Respond {
+        Respond {
+            status,
+            length,
+            body,
+        }
+    }
+
+    #[test]
+    fn request() {
+        let context = TestRequest::post("/items?sort=name")
+            .context_path("/api")
+            .header("Accept", "text/plain")
+            .body("hello")
+            .secure(true)
+            .into_context();
+
+        let request = context.request();
+        assert_eq!(request.method(), "POST");
+        assert_eq!(request.context_path(), "/api");
+        assert_eq!(request.path_info(), "/items");
+        assert_eq!(request.query_string().as_deref(), Some("sort=name"));
+        assert_eq!(request.get_header("Content-Length"), Some("5"));
+        assert!(request.is_secure());
+        assert_eq!(context.server_name(), "localhost");
+    }
+
+    #[test]
+    fn response() {
+        let response = TestRequest::get("/").run(&respond(201, Some("5"), b"Hello"));
+
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.text(), "Hello");
+        assert_eq!(response.upgraded(), None);
+        assert!(response.violations().is_empty(), "{:?}", response.violations());
+    }
+
+    #[test]
+    fn head_discards_body() {
+        let response = TestRequest::head("/").run(&respond(200, Some("5"), b"Hello"));
+
+        assert_eq!(response.header("Content-Length"), Some("5"));
+        assert!(response.body().is_empty());
+        assert!(response.violations().is_empty(), "{:?}", response.violations());
+
+        // The length of a HEAD response is that of the body that would have been sent.
+        let response = TestRequest::head("/").run(&respond(200, Some("1000"), b""));
+        assert!(response.violations().is_empty(), "{:?}", response.violations());
+    }
+
+    #[test]
+    fn content_length_mismatch() {
+        let response = TestRequest::get("/").run(&respond(200, Some("10"), b"Hello"));
+        assert_eq!(response.violations(), [Violation::ContentLengthMismatch {
+            declared: 10,
+            actual: 5,
+        }]);
+
+        let response = TestRequest::get("/").run(&respond(200, Some("five"), b"Hello"));
+        assert_eq!(response.violations(), [Violation::InvalidHeaderValue(String::from("Content-Length"))]);
+
+        // A 304 response carries the length of the resource it refers to.
+        let response = TestRequest::get("/").run(&respond(304, Some("10"), b""));
+        assert!(response.violations().is_empty(), "{:?}", response.violations());
+    }
+
+    #[test]
+    fn body_not_allowed() {
+        let response = TestRequest::get("/").run(&respond(204, None, b"Hello"));
+
+        assert_eq!(response.violations(), [Violation::BodyNotAllowed(204)]);
+    }
+
+    #[test]
+    fn headers_already_sent() {
+        let mut context = TestRequest::get("/").into_context();
+        context.response().write_all(b"Hello").unwrap();
+        context.response().flush().unwrap();
+
+        assert!(context.response().headers_sent());
+        assert_eq!(context.response().set_status(500), Err(HeadersSentError));
+        assert_eq!(context.response().set_header("X-Late", String::from("1")), Err(HeadersSentError));
+        assert_eq!(context.response().remove_header("X-Late"), Err(HeadersSentError));
+
+        let response = context.finish();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.header("X-Late"), None);
+        assert_eq!(response.violations(), [
+            Violation::HeadersAlreadySent("set_status"),
+            Violation::HeadersAlreadySent("set_header"),
+            Violation::HeadersAlreadySent("remove_header"),
+        ]);
+    }
+
+    #[test]
+    fn invalid_headers() {
+        let mut context = TestRequest::get("/").into_context();
+        context.response().set_status(99).unwrap();
+        context.response().set_header("Bad Name", String::from("1")).unwrap();
+        context.response().append_header("X-Injected", String::from("1\r\nSet-Cookie: a=b")).unwrap();
+
+        assert_eq!(context.finish().violations(), [
+            Violation::InvalidStatus(99),
+            Violation::InvalidHeaderName(String::from("Bad Name")),
+            Violation::InvalidHeaderValue(String::from("X-Injected")),
+        ]);
+    }
+
+    #[test]
+    fn buffering_flushes() {
+        let mut context = TestRequest::get("/").into_context();
+        assert!(matches!(context.response().buffering(), Buffering::On(size) if size as usize == BUFFER_SIZE));
+
+        // The headers are held back until the buffer overflows.
+        context.response().write_all(&[0; BUFFER_SIZE]).unwrap();
+        assert!(!context.response().headers_sent());
+        context.response().write_all(b"x").unwrap();
+        assert!(context.response().headers_sent());
+
+        // Without buffering, the first write sends them.
+        let mut context = TestRequest::get("/").buffering(false).into_context();
+        assert!(matches!(context.response().buffering(), Buffering::Off));
+        context.response().write_all(b"x").unwrap();
+        assert!(context.response().headers_sent());
+
+        // So does an explicit flush, or turning buffering off and writing.
+        let mut context = TestRequest::get("/").into_context();
+        context.response().flush().unwrap();
+        assert!(context.response().headers_sent());
+
+        let mut context = TestRequest::get("/").into_context();
+        context.response().write_all(b"x").unwrap();
+        assert!(context.response().set_buffering(false));
+        assert!(!context.response().headers_sent());
+        context.response().write_all(b"y").unwrap();
+        assert!(context.response().headers_sent());
+        assert_eq!(context.finish().text(), "xy");
+    }
+
+    #[test]
+    fn fixed_buffering() {
+        let mut context = TestRequest::get("/").fixed_buffering(true).into_context();
+
+        assert!(!context.response().set_buffering(false));
+        assert!(matches!(context.response().buffering(), Buffering::On(size) if size as usize == BUFFER_SIZE));
+    }
+
+    #[test]
+    fn run_async() {
+        let (mut context, pending) = TestRequest::get("/").into_async_context();
+        context.response_mut().set_header("Content-Type", String::from("text/plain")).unwrap();
+        async_io::block_on(async_io::AsyncWriteExt::write_all(context.response_mut(), b"Hello")).unwrap();
+        drop(context);
+
+        let response = pending.wait();
+        assert_eq!(response.header("Content-Type"), Some("text/plain"));
+        assert_eq!(response.text(), "Hello");
+    }
+}
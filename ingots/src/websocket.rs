@@ -0,0 +1,872 @@
+//! The WebSocket protocol (RFC 6455), spoken over a connection upgraded with `Context::upgrade_websocket`.
+//!
+//! Only the server side of the protocol is implemented: frames from the client must be masked, and frames sent to the
+//! client are not. No extensions are negotiated, so per-message compression is never used.
+use http::{Request, UpgradeError, Upgraded, Version};
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+
+/// Version of the protocol, which clients send in the `Sec-WebSocket-Version` header.
+pub const VERSION: &str = "13";
+
+/// Default limit on the size of a message received from the client, in bytes.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Close code for a normal closure.
+pub const CLOSE_NORMAL: u16 = 1000;
+
+/// Close code for an endpoint that is going away, such as a server shutting down.
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// Close code for a violation of the protocol.
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+/// Close code for a text message that is not valid UTF-8.
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+
+/// Close code for a message that is too big to process.
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Handshake error for a client asking for a version other than `VERSION`.
+pub(crate) const UNSUPPORTED_VERSION: &str = "unsupported WebSocket version";
+
+/// Appended to the key sent by the client to compute the `Sec-WebSocket-Accept` header.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Control frames carry at most this many bytes of payload.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+
+/// A message sent or received over a WebSocket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// A ping, which must be answered with a pong carrying the same data. Received pings are answered automatically.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The closing handshake, optionally with a status code and reason.
+    Close(Option<CloseFrame>),
+}
+
+/// Status code and reason sent with a close message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+
+/// Check that a request is a valid WebSocket handshake, and get the value of the `Sec-WebSocket-Accept` header.
+pub fn check_handshake(request: &dyn Request) -> Result<String, UpgradeError> {
+    if !request.method().eq_ignore_ascii_case("GET") {
+        return Err(UpgradeError::Handshake("method must be GET"));
+    }
+
+    if request.version() < Version::Http11 {
+        return Err(UpgradeError::Handshake("HTTP/1.1 or later is required"));
+    }
+
+    if !has_token(request.headers().get_all("Upgrade"), "websocket") {
+        return Err(UpgradeError::Handshake("missing `Upgrade: websocket` header"));
+    }
+
+    if !has_token(request.headers().get_all("Connection"), "upgrade") {
+        return Err(UpgradeError::Handshake("missing `Connection: Upgrade` header"));
+    }
+
+    if request.get_header("Sec-WebSocket-Version").map(str::trim) != Some(VERSION) {
+        return Err(UpgradeError::Handshake(UNSUPPORTED_VERSION));
+    }
+
+    match request.get_header("Sec-WebSocket-Key").map(str::trim) {
+        Some(key) if is_valid_key(key) => Ok(accept_key(key)),
+        _ => Err(UpgradeError::Handshake("missing or invalid `Sec-WebSocket-Key` header")),
+    }
+}
+
+/// Compute the `Sec-WebSocket-Accept` header for the key sent by the client.
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+/// Check if any of the comma-separated values of a header is the given token.
+fn has_token<'a, I: Iterator<Item = &'a str>>(values: I, token: &str) -> bool {
+    values.flat_map(|value| value.split(',')).any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+/// A valid key is 16 bytes encoded in base64.
+fn is_valid_key(key: &str) -> bool {
+    key.len() == 24
+        && key.ends_with("==")
+        && key[..22].bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'+' || byte == b'/')
+}
+
+/// Close codes that may be sent by an endpoint, excluding the ones reserved for local use.
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+
+/// A WebSocket connection to a client.
+///
+/// Every frame is sent as soon as it is written. Dropping the socket without closing it sends a close message with
+/// `CLOSE_GOING_AWAY`.
+///
+/// By default `receive` waits for as long as it takes the client to send a message. An ingot that also needs to send
+/// messages of its own, or to ping an idle client, can set a read timeout with `set_read_timeout` and do so whenever
+/// `receive` times out.
+pub struct WebSocket {
+    stream: Box<dyn Upgraded>,
+    /// Data received from the client that does not make up a complete frame yet.
+    input: Vec<u8>,
+    max_message_size: usize,
+    /// Opcode and data of a fragmented message that has not been received completely.
+    fragments: Option<(u8, Vec<u8>)>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl WebSocket {
+    /// Wrap a connection on which the handshake has been completed.
+    pub fn new(stream: Box<dyn Upgraded>) -> Self {
+        Self {
+            stream,
+            input: Vec::new(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            fragments: None,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    /// Get the limit on the size of received messages.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// Set the limit on the size of received messages. Larger messages close the connection with `CLOSE_TOO_BIG`.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Set how long `receive` waits for data from the client before failing with a `WouldBlock` or `TimedOut` error, or
+    /// `None` to wait indefinitely.
+    ///
+    /// A receive that times out loses nothing; the next call picks up any partially received message. Returns an
+    /// `Unsupported` error if the server cannot time out reads on the connection.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Send a message to the client.
+    pub fn send(&mut self, message: Message) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "the WebSocket has been closed"));
+        }
+
+        match message {
+            Message::Text(text) => self.write_frame(OPCODE_TEXT, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(OPCODE_BINARY, &data),
+            Message::Ping(data) | Message::Pong(data) if data.len() > MAX_CONTROL_PAYLOAD => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "control messages are limited to 125 bytes"))
+            }
+            Message::Ping(data) => self.write_frame(OPCODE_PING, &data),
+            Message::Pong(data) => self.write_frame(OPCODE_PONG, &data),
+            Message::Close(frame) => self.send_close(frame),
+        }
+    }
+
+    /// Receive the next message from the client, waiting until one arrives or the read timeout passes.
+    ///
+    /// When the client starts the closing handshake, the close message is answered and returned, and no more messages
+    /// can be received afterwards. If the client violates the protocol, the connection is closed with an appropriate
+    /// code and an `InvalidData` error is returned.
+    pub fn receive(&mut self) -> io::Result<Message> {
+        if self.close_received {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "the WebSocket has been closed"));
+        }
+
+        loop {
+            let (fin, opcode, payload) = self.read_frame()?;
+
+            match opcode {
+                OPCODE_TEXT | OPCODE_BINARY if self.fragments.is_some() => {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, "expected a continuation frame"));
+                }
+                OPCODE_TEXT | OPCODE_BINARY if fin => return self.complete(opcode, payload),
+                OPCODE_TEXT | OPCODE_BINARY => self.fragments = Some((opcode, payload)),
+                OPCODE_CONTINUATION => {
+                    let (opcode, mut data) = match self.fragments.take() {
+                        Some(fragments) => fragments,
+                        None => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "unexpected continuation frame")),
+                    };
+
+                    if data.len() + payload.len() > self.max_message_size {
+                        return Err(self.fail(CLOSE_TOO_BIG, "message is too big"));
+                    }
+                    data.extend_from_slice(&payload);
+
+                    if fin {
+                        return self.complete(opcode, data);
+                    }
+                    self.fragments = Some((opcode, data));
+                }
+                OPCODE_PING => {
+                    if !self.close_sent {
+                        self.write_frame(OPCODE_PONG, &payload)?;
+                    }
+                    return Ok(Message::Ping(payload));
+                }
+                OPCODE_PONG => return Ok(Message::Pong(payload)),
+                OPCODE_CLOSE => {
+                    let frame = self.parse_close(&payload)?;
+                    self.close_received = true;
+
+                    // Echo the status code back, as the protocol requires.
+                    let reply = frame.as_ref().map(|frame| CloseFrame {
+                        code: frame.code,
+                        reason: String::new(),
+                    });
+                    self.send_close(reply)?;
+
+                    return Ok(Message::Close(frame));
+                }
+                _ => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "unknown opcode")),
+            }
+        }
+    }
+
+    /// Start the closing handshake, and wait for the client to answer it.
+    ///
+    /// Messages received from the client in the meantime are discarded.
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        self.send_close(Some(CloseFrame {
+            code,
+            reason: reason.to_owned(),
+        }))?;
+
+        while !self.close_received {
+            match self.receive() {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn complete(&mut self, opcode: u8, data: Vec<u8>) -> io::Result<Message> {
+        match opcode {
+            OPCODE_TEXT => match String::from_utf8(data) {
+                Ok(text) => Ok(Message::Text(text)),
+                Err(_) => Err(self.fail(CLOSE_INVALID_DATA, "text message is not valid UTF-8")),
+            },
+            _ => Ok(Message::Binary(data)),
+        }
+    }
+
+    fn parse_close(&mut self, payload: &[u8]) -> io::Result<Option<CloseFrame>> {
+        if payload.is_empty() {
+            return Ok(None);
+        }
+
+        let code = match payload {
+            &[high, low, ..] => u16::from_be_bytes([high, low]),
+            _ => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "close message is too short")),
+        };
+
+        if !is_valid_close_code(code) {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "invalid close code"));
+        }
+
+        match String::from_utf8(payload[2..].to_vec()) {
+            Ok(reason) => Ok(Some(CloseFrame {
+                code,
+                reason,
+            })),
+            Err(_) => Err(self.fail(CLOSE_INVALID_DATA, "close reason is not valid UTF-8")),
+        }
+    }
+
+    /// Close the connection after a protocol violation by the client, returning the error to report.
+    fn fail(&mut self, code: u16, message: &'static str) -> io::Error {
+        let _ = self.send_close(Some(CloseFrame {
+            code,
+            reason: String::new(),
+        }));
+        self.close_received = true;
+
+        io::Error::new(io::ErrorKind::InvalidData, Error(message))
+    }
+
+    fn send_close(&mut self, frame: Option<CloseFrame>) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.close_sent = true;
+
+        let mut payload = Vec::new();
+        if let Some(frame) = frame {
+            payload.extend_from_slice(&frame.code.to_be_bytes());
+
+            // The reason must fit in a control frame after the code, without splitting a character.
+            let mut len = frame.reason.len().min(MAX_CONTROL_PAYLOAD - 2);
+            while !frame.reason.is_char_boundary(len) {
+                len -= 1;
+            }
+            payload.extend_from_slice(&frame.reason.as_bytes()[..len]);
+        }
+
+        self.write_frame(OPCODE_CLOSE, &payload)
+    }
+
+    /// Read a single frame, returning whether it is the final fragment, its opcode and the unmasked payload.
+    fn read_frame(&mut self) -> io::Result<(bool, u8, Vec<u8>)> {
+        loop {
+            if let Some(frame) = self.parse_frame()? {
+                return Ok(frame);
+            }
+
+            // Data is only kept once it has been read, so a read that times out can simply be retried later.
+            let mut buf = [0; 8 * 1024];
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(len) => self.input.extend_from_slice(&buf[..len]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Take the first frame out of the received data, if all of it has been received.
+    fn parse_frame(&mut self) -> io::Result<Option<(bool, u8, Vec<u8>)>> {
+        let head = match self.input.get(..2) {
+            Some(&[first, second]) => [first, second],
+            _ => return Ok(None),
+        };
+
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+
+        // The reserved bits are only used by extensions, and none are negotiated.
+        if head[0] & 0x70 != 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "reserved bits must not be set"));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "frames from the client must be masked"));
+        }
+
+        let (len, mut offset) = match head[1] & 0x7f {
+            126 => match self.input.get(2..4) {
+                Some(len) => (u16::from_be_bytes([len[0], len[1]]) as u64, 4),
+                None => return Ok(None),
+            },
+            127 => match self.input.get(2..10) {
+                Some(len) => (u64::from_be_bytes([len[0], len[1], len[2], len[3], len[4], len[5], len[6], len[7]]), 10),
+                None => return Ok(None),
+            },
+            len => (len as u64, 2),
+        };
+
+        if opcode & 0x8 != 0 && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "control frames must not be fragmented or longer than 125 bytes"));
+        }
+        if len > self.max_message_size as u64 {
+            return Err(self.fail(CLOSE_TOO_BIG, "message is too big"));
+        }
+
+        let mut mask = [0; 4];
+        match self.input.get(offset..offset + 4) {
+            Some(bytes) => mask.copy_from_slice(bytes),
+            None => return Ok(None),
+        }
+        offset += 4;
+
+        let end = offset + len as usize;
+        if self.input.len() < end {
+            return Ok(None);
+        }
+
+        let mut payload: Vec<u8> = self.input.drain(..end).skip(offset).collect();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok(Some((fin, opcode, payload)))
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= 0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        let _ = self.send_close(Some(CloseFrame {
+            code: CLOSE_GOING_AWAY,
+            reason: String::new(),
+        }));
+    }
+}
+
+
+/// A violation of the protocol by the client, reported as the source of an `InvalidData` error.
+#[derive(Debug)]
+struct Error(&'static str);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WebSocket protocol error: {}", self.0)
+    }
+}
+
+impl error::Error for Error {}
+
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut output = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for chunk in bytes.chunks(3) {
+        let group = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+
+    output
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+
+        for (i, &word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (value, add) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, value) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+
+#[cfg(test)]
+mod tests {
+    use http::Context;
+    use std::collections::VecDeque;
+    use testing::{MockContext, TestRequest};
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    fn handshake() -> TestRequest {
+        TestRequest::get("/chat")
+            .header("Upgrade", "websocket")
+            .header("Connection", "keep-alive, Upgrade")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("Sec-WebSocket-Version", "13")
+    }
+
+    /// Upgrade a connection on which the client sends the given bytes.
+    fn connect(input: Vec<u8>) -> (WebSocket, MockContext) {
+        let mut context = handshake().upgradable(input).into_context();
+        let socket = context.upgrade_websocket(None).unwrap();
+        (socket, context)
+    }
+
+    /// Get what the server wrote to the connection, after dropping the socket.
+    fn output(context: MockContext) -> Vec<u8> {
+        context.finish().upgraded().unwrap().to_vec()
+    }
+
+    /// Build a frame as a client would send it.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= 0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        frame.extend_from_slice(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ MASK[i % 4]));
+        frame
+    }
+
+    fn close_frame(code: u16) -> Vec<u8> {
+        let mut frame = vec![0x88, 2];
+        frame.extend_from_slice(&code.to_be_bytes());
+        frame
+    }
+
+    fn close_frame_masked(code: u16) -> Vec<u8> {
+        client_frame(true, OPCODE_CLOSE, &code.to_be_bytes())
+    }
+
+    #[test]
+    fn accept_key_vector() {
+        // The example from section 1.3 of RFC 6455.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn upgrade_response() {
+        let mut context = handshake().upgradable(Vec::new()).into_context();
+        let socket = context.upgrade_websocket(Some("chat")).unwrap();
+        drop(socket);
+
+        let response = context.finish();
+        assert_eq!(response.status(), 101);
+        assert_eq!(response.header("Upgrade"), Some("websocket"));
+        assert_eq!(response.header("Connection"), Some("Upgrade"));
+        assert_eq!(response.header("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert_eq!(response.header("Sec-WebSocket-Protocol"), Some("chat"));
+        assert!(response.violations().is_empty(), "{:?}", response.violations());
+    }
+
+    #[test]
+    fn invalid_handshake() {
+        let mut context = handshake().version(Version::Http10).upgradable(Vec::new()).into_context();
+        assert!(matches!(context.upgrade_websocket(None), Err(UpgradeError::Handshake(_))));
+        assert_eq!(context.finish().status(), 400);
+
+        let mut context = TestRequest::get("/chat")
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("Sec-WebSocket-Version", "8")
+            .into_context();
+        assert!(matches!(context.upgrade_websocket(None), Err(UpgradeError::Handshake(UNSUPPORTED_VERSION))));
+
+        let response = context.finish();
+        assert_eq!(response.status(), 400);
+        assert_eq!(response.header("Sec-WebSocket-Version"), Some(VERSION));
+    }
+
+    #[test]
+    fn upgrade_unsupported() {
+        let mut context = handshake().into_context();
+        assert!(matches!(context.upgrade_websocket(None), Err(UpgradeError::Unsupported)));
+
+        let response = context.finish();
+        assert_eq!(response.status(), 501);
+        assert_eq!(response.header("Upgrade"), None);
+        assert_eq!(response.header("Sec-WebSocket-Accept"), None);
+        assert_eq!(response.upgraded(), None);
+    }
+
+    #[test]
+    fn masked_frame() {
+        // The masked "Hello" from section 5.7 of RFC 6455.
+        let input = vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let (mut socket, context) = connect(input);
+
+        assert_eq!(socket.receive().unwrap(), Message::Text(String::from("Hello")));
+        socket.send(Message::Text(String::from("Hello"))).unwrap();
+        socket.close(CLOSE_NORMAL, "").unwrap();
+        drop(socket);
+
+        let mut expected = vec![0x81, 0x05];
+        expected.extend_from_slice(b"Hello");
+        expected.extend_from_slice(&close_frame(CLOSE_NORMAL));
+        assert_eq!(output(context), expected);
+    }
+
+    #[test]
+    fn unmasked_frame() {
+        let (mut socket, context) = connect(vec![0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+
+        let error = socket.receive().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        drop(socket);
+
+        assert_eq!(output(context), close_frame(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn extended_lengths() {
+        let medium = vec![b'm'; 300];
+        let large = vec![b'l'; 70000];
+
+        let mut input = client_frame(true, OPCODE_BINARY, &medium);
+        assert_eq!(input[1], 0x80 | 126);
+        input.extend(client_frame(true, OPCODE_BINARY, &large));
+        input.extend(close_frame_masked(CLOSE_NORMAL));
+
+        let (mut socket, context) = connect(input);
+        assert_eq!(socket.receive().unwrap(), Message::Binary(medium.clone()));
+        assert_eq!(socket.receive().unwrap(), Message::Binary(large.clone()));
+
+        socket.send(Message::Binary(medium)).unwrap();
+        socket.send(Message::Binary(large)).unwrap();
+        assert!(matches!(socket.receive().unwrap(), Message::Close(_)));
+        drop(socket);
+
+        let output = output(context);
+        assert_eq!(output[..4], [0x82, 126, 0x01, 0x2c]);
+        assert_eq!(output[304..314], [0x82, 127, 0, 0, 0, 0, 0, 0x01, 0x11, 0x70]);
+        assert_eq!(output.len(), 304 + 10 + 70000 + 4);
+    }
+
+    #[test]
+    fn fragmented_message() {
+        let mut input = client_frame(false, OPCODE_TEXT, b"Hel");
+        input.extend(client_frame(true, OPCODE_PING, b"are you there"));
+        input.extend(client_frame(false, OPCODE_CONTINUATION, b"lo, "));
+        input.extend(client_frame(true, OPCODE_CONTINUATION, b"world"));
+
+        let (mut socket, context) = connect(input);
+        assert_eq!(socket.receive().unwrap(), Message::Ping(b"are you there".to_vec()));
+        assert_eq!(socket.receive().unwrap(), Message::Text(String::from("Hello, world")));
+        drop(socket);
+
+        let mut expected = vec![0x8a, 13];
+        expected.extend_from_slice(b"are you there");
+        expected.extend_from_slice(&close_frame(CLOSE_GOING_AWAY));
+        assert_eq!(output(context), expected);
+    }
+
+    #[test]
+    fn unexpected_continuation() {
+        let (mut socket, context) = connect(client_frame(true, OPCODE_CONTINUATION, b"lo"));
+
+        assert_eq!(socket.receive().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(socket);
+        assert_eq!(output(context), close_frame(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn message_too_big() {
+        let (mut socket, context) = connect(client_frame(true, OPCODE_BINARY, &[0; 11]));
+        socket.set_max_message_size(10);
+
+        assert_eq!(socket.receive().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(socket);
+        assert_eq!(output(context), close_frame(CLOSE_TOO_BIG));
+
+        // The limit applies to the whole message, not to each fragment.
+        let mut input = client_frame(false, OPCODE_TEXT, b"123456");
+        input.extend(client_frame(true, OPCODE_CONTINUATION, b"78901"));
+
+        let (mut socket, context) = connect(input);
+        socket.set_max_message_size(10);
+
+        assert_eq!(socket.receive().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(socket);
+        assert_eq!(output(context), close_frame(CLOSE_TOO_BIG));
+    }
+
+    #[test]
+    fn close_from_client() {
+        let mut payload = CLOSE_GOING_AWAY.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+
+        let (mut socket, context) = connect(client_frame(true, OPCODE_CLOSE, &payload));
+        let frame = CloseFrame {
+            code: CLOSE_GOING_AWAY,
+            reason: String::from("bye"),
+        };
+        assert_eq!(socket.receive().unwrap(), Message::Close(Some(frame)));
+        assert_eq!(socket.receive().unwrap_err().kind(), io::ErrorKind::NotConnected);
+        assert_eq!(socket.send(Message::Text(String::new())).unwrap_err().kind(), io::ErrorKind::NotConnected);
+        drop(socket);
+
+        // The code is echoed without the reason, and nothing more is sent.
+        assert_eq!(output(context), close_frame(CLOSE_GOING_AWAY));
+    }
+
+    #[test]
+    fn close_from_server() {
+        let mut input = client_frame(true, OPCODE_TEXT, b"ignored");
+        input.extend(close_frame_masked(CLOSE_NORMAL));
+
+        let (mut socket, context) = connect(input);
+        socket.close(CLOSE_NORMAL, "done").unwrap();
+        drop(socket);
+
+        let mut expected = close_frame(CLOSE_NORMAL);
+        expected[1] += 4;
+        expected.extend_from_slice(b"done");
+        assert_eq!(output(context), expected);
+    }
+
+    #[test]
+    fn invalid_close_code() {
+        let (mut socket, context) = connect(close_frame_masked(1005));
+
+        assert_eq!(socket.receive().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(socket);
+        assert_eq!(output(context), close_frame(CLOSE_PROTOCOL_ERROR));
+    }
+
+    /// A connection on which the client sends data in the given chunks, with a read timeout passing between them.
+    struct Trickle {
+        chunks: VecDeque<Vec<u8>>,
+        timed_out: bool,
+        output: Vec<u8>,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.timed_out = !self.timed_out;
+            if self.timed_out {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+
+            let chunk = self.chunks.pop_front().unwrap_or_default();
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Upgraded for Trickle {
+        fn set_read_timeout(&mut self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn read_timeout() {
+        let (mut socket, context) = connect(client_frame(true, OPCODE_TEXT, b"Hello"));
+        socket.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+
+        assert_eq!(socket.receive().unwrap(), Message::Text(String::from("Hello")));
+        assert_eq!(socket.receive().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        // The socket can still be used after a receive times out.
+        socket.send(Message::Ping(b"idle".to_vec())).unwrap();
+        drop(socket);
+
+        let mut expected = vec![0x89, 0x04];
+        expected.extend_from_slice(b"idle");
+        expected.extend_from_slice(&close_frame(CLOSE_GOING_AWAY));
+        assert_eq!(output(context), expected);
+    }
+
+    #[test]
+    fn receive_resumes_after_timeout() {
+        let first = client_frame(false, OPCODE_TEXT, b"Hel");
+        let second = client_frame(true, OPCODE_CONTINUATION, b"lo");
+        let mut large = client_frame(true, OPCODE_BINARY, &[b'x'; 300]);
+        let large_end = large.split_off(3);
+
+        // Split the frames in the middle of their heads, extended lengths and masks.
+        let chunks = vec![
+            first[..1].to_vec(),
+            first[1..4].to_vec(),
+            first[4..].to_vec(),
+            second[..5].to_vec(),
+            second[5..].to_vec(),
+            large,
+            large_end,
+        ];
+
+        let mut socket = WebSocket::new(Box::new(Trickle {
+            chunks: chunks.into_iter().collect(),
+            timed_out: false,
+            output: Vec::new(),
+        }));
+
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            match socket.receive() {
+                Ok(message) => received.push(message),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert_eq!(received, [Message::Text(String::from("Hello")), Message::Binary(vec![b'x'; 300])]);
+    }
+}
@@ -1,51 +1,162 @@
 //! Loading of ingot objects at runtime using dynamic linking.
+//!
+//! Ingots are loaded through the stable C ABI defined in `ingots::abi`, so the library and the server do not need to be
+//! built with the same compiler.
 extern crate ingots;
 extern crate libloading;
 #[macro_use]
 extern crate log;
 
 use ingots::*;
+use ingots::abi::{IngotVTable, RawContext, RawStr, STATUS_ERROR, STATUS_OK, STATUS_PANIC};
 use libloading::{Library, Symbol};
-use std::ops::{Deref, DerefMut};
+use std::env;
+use std::error;
+use std::ffi::c_void;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem;
 use std::path::*;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
 
 
 
-#[derive(Clone, Copy, Debug)]
+/// An error that occurred while loading an ingot.
+///
+/// Every variant carries the path of the library file the ingot was loaded from.
+#[derive(Debug)]
 pub enum Error {
-    LoadLibraryError,
-    VersionMismatch,
-    UndefinedSymbol,
+    /// The library file could not be copied to the temporary directory.
+    Copy {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// The dynamic linker failed to load the library. The error contains the message reported by the linker.
+    LoadLibrary {
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// A symbol required by the ingots ABI is not exported by the library.
+    UndefinedSymbol {
+        path: PathBuf,
+        symbol: &'static str,
+        error: io::Error,
+    },
+    /// The library was built against an incompatible version of the ingots ABI.
+    VersionMismatch {
+        path: PathBuf,
+        expected: u16,
+        found: u16,
+    },
+    /// The ingot was loaded, but failed to start.
+    Start {
+        path: PathBuf,
+        error: StartError,
+    },
+}
+
+impl Error {
+    /// Get the path of the library file that failed to load.
+    pub fn path(&self) -> &Path {
+        match *self {
+            Error::Copy { ref path, .. } => path,
+            Error::LoadLibrary { ref path, .. } => path,
+            Error::UndefinedSymbol { ref path, .. } => path,
+            Error::VersionMismatch { ref path, .. } => path,
+            Error::Start { ref path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Copy { ref path, ref error } => {
+                write!(f, "failed to copy ingot library {} to a temporary file: {}", path.display(), error)
+            }
+            Error::LoadLibrary { ref path, ref error } => {
+                write!(f, "failed to load ingot library {}: {}", path.display(), error)
+            }
+            Error::UndefinedSymbol { ref path, symbol, ref error } => {
+                write!(f, "ingot library {} does not define symbol `{}`: {}", path.display(), symbol, error)
+            }
+            Error::VersionMismatch { ref path, expected, found } => {
+                write!(f, "ingot library {} was built for ingots ABI version {}, but version {} is required",
+                    path.display(), found, expected)
+            }
+            Error::Start { ref path, ref error } => {
+                write!(f, "ingot {} failed to start: {}", path.display(), error)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Copy { ref error, .. } => Some(error),
+            Error::LoadLibrary { ref error, .. } => Some(error),
+            Error::UndefinedSymbol { ref error, .. } => Some(error),
+            Error::VersionMismatch { .. } => None,
+            Error::Start { ref error, .. } => Some(error),
+        }
+    }
 }
 
 
 /// Wrapper around an ingot loaded dynamically at runtime.
+///
+/// The library is not loaded from its original path, but from a private copy in the temporary directory. The dynamic
+/// linker caches libraries by path, so opening the original file again after it changes would return the image that is
+/// already loaded.
 pub struct DynamicIngot {
     path: PathBuf,
-    library: Library,
-    ptr: *mut Ingot,
+    modified: Option<SystemTime>,
+    vtable: IngotVTable,
+    // Keeps the library loaded; must be dropped after the instance is freed.
+    _library: LibraryCopy,
 }
 
 impl DynamicIngot {
     /// Open a dynamic ingot from a shared library file.
     pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, Error> {
         let path = path.into();
-        let library = Self::load_library(&path)?;
+        let modified = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok();
+        let library = LibraryCopy::new(&path)?;
 
         // Initialize the ingot instance.
-        let ptr = unsafe {
-            let __ingot_init: Symbol<extern fn() -> *mut Ingot> = match library.get(b"__ingot_init\0") {
+        let vtable = unsafe {
+            let __ingot_init: Symbol<extern "C" fn() -> IngotVTable> = match library.get().get(b"__ingot_init\0") {
                 Ok(v) => v,
-                Err(_) => return Err(Error::UndefinedSymbol),
+                Err(error) => return Err(Error::UndefinedSymbol {
+                    path,
+                    symbol: "__ingot_init",
+                    error,
+                }),
             };
 
             __ingot_init()
         };
 
+        if vtable.abi_version != INGOTS_VERSION {
+            // The instance was allocated by the library, so it must be freed before the library is unloaded.
+            (vtable.free)(vtable.instance);
+
+            return Err(Error::VersionMismatch {
+                path,
+                expected: INGOTS_VERSION,
+                found: vtable.abi_version,
+            });
+        }
+
         Ok(Self {
-            path: path.into(),
-            library: library,
-            ptr: ptr,
+            path,
+            modified,
+            vtable,
+            _library: library,
         })
     }
 
@@ -54,73 +165,178 @@ impl DynamicIngot {
         &self.path
     }
 
+    /// Get the modification time of the library file at the time it was loaded.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
     /// Reload the ingot from the file system.
+    ///
+    /// The new instance is started before the current one is stopped and unloaded. If the library fails to load or the
+    /// new instance fails to start, the current instance is left untouched.
     pub fn reload(&mut self) -> Result<(), Error> {
-        match Self::open(self.path.clone()) {
-            Ok(v) => {
-                *self = v;
-                Ok(())
-            }
-            Err(e) => Err(e),
+        let mut ingot = Self::open(self.path.clone())?;
+
+        if let Err(error) = ingot.start() {
+            return Err(Error::Start {
+                path: self.path.clone(),
+                error,
+            });
         }
+
+        let mut old = mem::replace(self, ingot);
+        old.stop();
+
+        Ok(())
     }
+}
 
-    /// Load a shared library object.
-    fn load_library(path: &Path) -> Result<Library, Error> {
-        debug!("loading shared library: {}", path.display());
+impl Ingot for DynamicIngot {
+    fn handle(&self, context: &mut dyn http::Context) {
+        let status = RawContext::with(context, |raw| {
+            (self.vtable.handle)(self.vtable.instance, raw)
+        });
 
-        let library = match Library::new(path) {
-            Ok(v) => v,
-            Err(_) => return Err(Error::LoadLibraryError),
-        };
+        if status == STATUS_PANIC {
+            error!("ingot {} panicked while handling a request", self.path.display());
+        } else if status != STATUS_OK {
+            warn!("ingot {} returned status {}", self.path.display(), status);
+        }
+    }
 
-        // Sanity check: verify ingot API is compatible.
-        let library_version = unsafe {
-            let symbol: Symbol<*mut u16> = library.get(b"INGOTS_VERSION\0").expect("error loading ingot ABI version");
-            **symbol
-        };
+    fn start(&mut self) -> Result<(), StartError> {
+        extern "C" fn put_error(user: *mut c_void, message: RawStr) {
+            let target = unsafe { &mut *(user as *mut String) };
+            *target = message.into_string();
+        }
 
-        debug!("shared library has ingots version: {}", library_version);
+        let mut message = String::new();
 
-        if library_version != INGOTS_VERSION {
-            return Err(Error::VersionMismatch);
+        match (self.vtable.start)(self.vtable.instance, &mut message as *mut String as *mut c_void, put_error) {
+            STATUS_OK => Ok(()),
+            STATUS_ERROR => Err(StartError::new(message)),
+            STATUS_PANIC => Err(StartError::new("ingot panicked while starting")),
+            status => Err(StartError::new(format!("ingot returned status {} while starting", status))),
         }
+    }
 
-        Ok(library)
+    fn stop(&mut self) {
+        if (self.vtable.stop)(self.vtable.instance) != STATUS_OK {
+            error!("ingot {} failed to stop", self.path.display());
+        }
     }
 }
 
 impl Drop for DynamicIngot {
     fn drop(&mut self) {
-        // Drop the instance using __ingot_free().
-        unsafe {
-            if let Ok(__ingot_free) = self.library.get::<extern fn(*mut Ingot)>(b"__ingot_free\0") {
-                __ingot_free(self.ptr);
-            } else {
-                warn!("symbol missing: __ingot_free");
-                warn!("leaking memory");
-            }
-        }
+        (self.vtable.free)(self.vtable.instance);
     }
 }
 
-impl Deref for DynamicIngot {
-    type Target = Ingot + 'static;
+// The ingot is required to be thread-safe by the `Ingot` trait; the vtable only holds a pointer to it.
+unsafe impl Send for DynamicIngot {}
+unsafe impl Sync for DynamicIngot {}
+
+
+/// A shared library loaded from a private copy of the original file. The copy is deleted once it is no longer needed.
+struct LibraryCopy {
+    library: Option<Library>,
+    /// Path of the copy, if it still needs to be removed.
+    path: Option<PathBuf>,
+}
+
+impl LibraryCopy {
+    fn new(path: &Path) -> Result<Self, Error> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("ingot");
+        let mut name = format!("{}-{}-{}", stem, process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+        if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+            name.push('.');
+            name.push_str(extension);
+        }
+        let copy_path = env::temp_dir().join(name);
+
+        debug!("copying shared library {} to {}", path.display(), copy_path.display());
 
-    fn deref(&self) -> &Self::Target {
-        unsafe {
-            &*self.ptr
+        if let Err(error) = fs::copy(path, &copy_path) {
+            return Err(Error::Copy {
+                path: path.to_owned(),
+                error,
+            });
         }
+
+        // Take ownership of the copy first so that it is removed if loading fails.
+        let mut copy = Self {
+            library: None,
+            path: Some(copy_path.clone()),
+        };
+        copy.library = Some(load_library(path, &copy_path)?);
+
+        // On Unix a library stays mapped after its file is removed, so the copy can be removed right away. This way it
+        // is not left behind if the process exits without unloading the library.
+        if cfg!(unix) {
+            copy.remove_file();
+        }
+
+        Ok(copy)
     }
-}
 
-impl DerefMut for DynamicIngot {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe {
-            &mut *self.ptr
+    fn get(&self) -> &Library {
+        self.library.as_ref().unwrap()
+    }
+
+    fn remove_file(&mut self) {
+        if let Some(path) = self.path.take() {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("failed to remove shared library copy {}: {}", path.display(), e);
+            }
         }
     }
 }
 
-unsafe impl Send for DynamicIngot {}
-unsafe impl Sync for DynamicIngot {}
+impl Drop for LibraryCopy {
+    fn drop(&mut self) {
+        // Unload the library before removing the file it was mapped from.
+        self.library.take();
+        self.remove_file();
+    }
+}
+
+/// Load a shared library object from the copy of the library at `path`.
+fn load_library(path: &Path, copy_path: &Path) -> Result<Library, Error> {
+    debug!("loading shared library: {}", copy_path.display());
+
+    let library = match Library::new(copy_path) {
+        Ok(v) => v,
+        Err(error) => return Err(Error::LoadLibrary {
+            path: path.to_owned(),
+            error,
+        }),
+    };
+
+    // Sanity check: verify ingot API is compatible before calling into the library.
+    let library_version = unsafe {
+        let symbol: Symbol<*mut u16> = match library.get(b"INGOTS_VERSION\0") {
+            Ok(v) => v,
+            Err(error) => return Err(Error::UndefinedSymbol {
+                path: path.to_owned(),
+                symbol: "INGOTS_VERSION",
+                error,
+            }),
+        };
+        **symbol
+    };
+
+    debug!("shared library has ingots version: {}", library_version);
+
+    if library_version != INGOTS_VERSION {
+        return Err(Error::VersionMismatch {
+            path: path.to_owned(),
+            expected: INGOTS_VERSION,
+            found: library_version,
+        });
+    }
+
+    Ok(library)
+}
@@ -1,69 +1,338 @@
 use ingots::http;
-use std::io;
+use ingots::http::date::format_http_date;
+use std::borrow::Cow;
+use std::io::{self, Read, Write};
+use std::mem;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
 use tiny_http;
 
 
+/// Size of the response buffer, unless the ingot turns buffering off.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// The underlying request, shared by the request and the response until the response is started.
+type SharedRequest = Arc<Mutex<Option<tiny_http::Request>>>;
+
+/// Request context for a request received by the tiny_http server.
+///
+/// The response body is buffered in memory, and sent along with the status and headers by `respond` if it fits in the
+/// buffer. Otherwise the response is streamed to the client from a helper thread once the buffer fills up or is
+/// flushed; the request body can no longer be read after that point.
+///
+/// tiny_http refuses to send the `Accept-Ranges` and `Content-Range` headers, so responses that carry them and have a
+/// known length are written to the connection directly instead.
 pub struct Context {
     server_addr: SocketAddr,
+    server_name: String,
     request: Request,
     response: Response,
 }
 
+impl Context {
+    pub fn new(server_addr: SocketAddr, request: tiny_http::Request) -> Self {
+        let request = Request::new(request);
+
+        // Prefer the name the client used to reach the server, without the port.
+        let server_name = match request.headers.get("Host") {
+            Some(host) if host.starts_with('[') => host.split(']').next().map(|name| format!("{}]", name)),
+            Some(host) => host.split(':').next().map(String::from),
+            None => None,
+        };
+
+        Self {
+            server_addr,
+            server_name: server_name.unwrap_or_else(|| server_addr.ip().to_string()),
+            response: Response {
+                request: request.inner.clone(),
+                status: 200,
+                headers: http::HeaderMap::new(),
+                buffer: Vec::new(),
+                buffer_size: BUFFER_SIZE,
+                stream: None,
+                length: 0,
+                upgraded: false,
+            },
+            request,
+        }
+    }
+
+    /// Send the response to the client, returning the status code and the length of the body.
+    pub fn respond(self) -> io::Result<(http::StatusCode, usize)> {
+        let mut response = self.response;
+        if response.upgraded {
+            return Ok((response.status, 0));
+        }
+
+        match response.stream.take() {
+            Some(stream) => {
+                response.length += response.buffer.len();
+                stream.finish(&response.buffer)?;
+            }
+            None => {
+                let request = match response.request.lock().unwrap().take() {
+                    Some(request) => request,
+                    None => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "response was already sent")),
+                };
+                let mut headers = convert_headers(&response.headers);
+
+                // The length is taken from the buffered body, except for a HEAD request that the ingot answered
+                // with the length of the body it would have sent.
+                response.length = response.buffer.len();
+                let length = match content_length(&headers) {
+                    Some(length) if response.length == 0 && *request.method() == tiny_http::Method::Head => length,
+                    _ => response.length,
+                };
+                headers.retain(|header| !header.field.equiv("Content-Length"));
+
+                let body = io::Cursor::new(mem::take(&mut response.buffer));
+                send_response(request, response.status, headers, body, Some(length))?;
+            }
+        }
+
+        Ok((response.status, response.length))
+    }
+}
+
+/// Send a response with the given body, using the length from the `Content-Length` header if `length` is `None`.
+///
+/// The response is chunked if the length is unknown.
+fn send_response<R: Read>(request: tiny_http::Request, status: http::StatusCode, mut headers: Vec<tiny_http::Header>,
+                          body: R, length: Option<usize>) -> io::Result<()>
+{
+    let length = length.or_else(|| content_length(&headers));
+    let ranges = headers.iter().any(|header| {
+        header.field.equiv("Accept-Ranges") || header.field.equiv("Content-Range")
+    });
+
+    match length {
+        Some(length) if ranges => {
+            headers.retain(|header| !header.field.equiv("Content-Length"));
+            send_raw(request, status, &headers, body, length)
+        }
+        _ => request.respond(tiny_http::Response::new(tiny_http::StatusCode(status), headers, body, length, None)),
+    }
+}
+
+/// Write a response with a known length to the connection directly, bypassing tiny_http's header filtering.
+fn send_raw<R: Read>(request: tiny_http::Request, status: http::StatusCode, headers: &[tiny_http::Header], body: R,
+                     length: usize) -> io::Result<()>
+{
+    let version = request.http_version().clone();
+    let send_body = *request.method() != tiny_http::Method::Head && !matches!(status, 100..=199 | 204 | 304);
+    let mut writer = request.into_writer();
+
+    let mut head = format!("HTTP/{} {} {}\r\n", version, status, http::reason_phrase(status).unwrap_or(""));
+    if !headers.iter().any(|header| header.field.equiv("Date")) {
+        head.push_str(&format!("Date: {}\r\n", format_http_date(SystemTime::now())));
+    }
+    for header in headers {
+        head.push_str(&format!("{}: {}\r\n", header.field, header.value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", length));
+    writer.write_all(head.as_bytes())?;
+
+    if send_body {
+        io::copy(&mut body.take(length as u64), &mut writer)?;
+    }
+
+    writer.flush()
+}
+
+fn content_length(headers: &[tiny_http::Header]) -> Option<usize> {
+    headers.iter()
+        .find(|header| header.field.equiv("Content-Length"))
+        .and_then(|header| header.value.as_str().trim().parse().ok())
+}
+
+/// Convert response headers to tiny_http headers, dropping any that cannot be sent.
+fn convert_headers(headers: &http::HeaderMap) -> Vec<tiny_http::Header> {
+    headers.iter()
+        .filter_map(|(name, value)| {
+            // A line break would allow the value to inject additional headers.
+            let header = if value.contains(['\r', '\n']) {
+                Err(())
+            } else {
+                tiny_http::Header::from_bytes(name.as_bytes(), value.as_bytes())
+            };
+
+            if header.is_err() {
+                warn!("ignoring invalid response header {:?}", name);
+            }
+
+            header.ok()
+        })
+        .collect()
+}
+
 impl http::Context for Context {
     fn remote_addr(&self) -> SocketAddr {
-        // TODO
-        self.server_addr.clone()
+        self.request.remote_addr
     }
 
     fn server_addr(&self) -> SocketAddr {
-        self.server_addr.clone()
+        self.server_addr
     }
 
-    fn server_name(&self) -> String {
-        format!("{}", self.server_addr.ip())
+    fn server_name(&self) -> &str {
+        &self.server_name
     }
 
-    fn request(&self) -> &http::Request {
+    fn request(&self) -> &dyn http::Request {
         &self.request
     }
 
-    fn response(&mut self) -> &mut http::Response {
+    fn request_mut(&mut self) -> &mut dyn http::Request {
+        &mut self.request
+    }
+
+    fn response(&mut self) -> &mut dyn http::Response {
         &mut self.response
     }
+
+    fn upgrade(&mut self) -> Result<Box<dyn http::Upgraded>, http::UpgradeError> {
+        self.response.check_headers_sent()?;
+
+        // tiny_http sends the `Upgrade` and `Connection` headers itself, for the protocol it is given.
+        let protocol = match self.response.headers.get("Upgrade") {
+            Some(protocol) => protocol.to_owned(),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "the Upgrade header is not set").into()),
+        };
+        let request = match self.response.request.lock().unwrap().take() {
+            Some(request) => request,
+            None => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "response was already sent").into()),
+        };
+
+        let headers = convert_headers(&self.response.headers);
+        let response = tiny_http::Response::new(tiny_http::StatusCode(self.response.status), headers, io::empty(),
+                                                None, None);
+
+        self.response.upgraded = true;
+        Ok(Box::new(Upgraded(request.upgrade(&protocol, response))))
+    }
+}
+
+
+struct Request {
+    inner: SharedRequest,
+    remote_addr: SocketAddr,
+    method: String,
+    url: String,
+    secure: bool,
+    version: http::Version,
+    headers: http::HeaderMap,
 }
 
+impl Request {
+    fn new(request: tiny_http::Request) -> Self {
+        let headers = request.headers()
+            .iter()
+            .map(|header| (header.field.as_str().as_str(), header.value.as_str()))
+            .collect();
 
-struct Request(tiny_http::Request);
+        Self {
+            remote_addr: *request.remote_addr(),
+            method: request.method().to_string(),
+            url: request.url().to_owned(),
+            secure: request.secure(),
+            version: match *request.http_version() {
+                tiny_http::HTTPVersion(0, 9) => http::Version::Http09,
+                tiny_http::HTTPVersion(1, 0) => http::Version::Http10,
+                _ => http::Version::Http11,
+            },
+            inner: Arc::new(Mutex::new(Some(request))),
+            headers,
+        }
+    }
+}
 
 impl http::Request for Request {
-    fn method(&self) -> String {
-        format!("{}", self.0.method())
+    fn method(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.method)
+    }
+
+    fn context_path(&self) -> Cow<'_, str> {
+        Cow::Borrowed("")
+    }
+
+    fn path_info(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self.url.find('?').map(|index| &self.url[..index]).unwrap_or(&self.url))
     }
 
-    fn uri(&self) -> String {
-        self.0.url().to_owned()
+    fn query_string(&self) -> Option<Cow<'_, str>> {
+        self.url.find('?').map(|index| Cow::Borrowed(&self.url[index + 1..]))
     }
 
-    fn protocol(&self) -> String {
-        format!("{}", self.0.http_version())
+    fn headers(&self) -> &http::HeaderMap {
+        &self.headers
     }
 
-    fn headers(&self) -> &[(&str, &str)] {
-        &[]
+    fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    fn version(&self) -> http::Version {
+        self.version
     }
 }
 
 impl io::Read for Request {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
+        match *self.inner.lock().unwrap() {
+            Some(ref mut request) => request.as_reader().read(buf),
+            None => Err(io::Error::other("the request body cannot be read after the response has been started")),
+        }
     }
 }
 
 
 struct Response {
+    request: SharedRequest,
     status: http::StatusCode,
-    content: io::Cursor<Vec<u8>>,
+    headers: http::HeaderMap,
+    buffer: Vec<u8>,
+    /// Maximum size of the buffer, or zero if buffering is off.
+    buffer_size: usize,
+    /// The response being streamed to the client, once the headers have been sent.
+    stream: Option<Stream>,
+    /// Number of body bytes sent to the client.
+    length: usize,
+    /// Whether the connection has been taken over by the ingot.
+    upgraded: bool,
+}
+
+impl Response {
+    /// Send the status and headers to the client and start streaming the body, if not started already.
+    fn start(&mut self) -> io::Result<&mut Stream> {
+        if self.stream.is_none() {
+            let request = match self.request.lock().unwrap().take() {
+                Some(request) => request,
+                None => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "response was already sent")),
+            };
+
+            self.stream = Some(Stream::new(request, self.status, convert_headers(&self.headers)));
+        }
+
+        Ok(self.stream.as_mut().unwrap())
+    }
+
+    /// Send the contents of the buffer to the client.
+    fn flush_buffer(&mut self) -> io::Result<()> {
+        let buffer = mem::take(&mut self.buffer);
+        self.length += buffer.len();
+        self.start()?.send(buffer)
+    }
+
+    fn check_headers_sent(&self) -> Result<(), http::HeadersSentError> {
+        if self.stream.is_some() || self.upgraded {
+            Err(http::HeadersSentError)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl http::Response for Response {
@@ -71,25 +340,149 @@ impl http::Response for Response {
         self.status
     }
 
-    fn set_status(&mut self status: http::StatusCode) {
+    fn set_status(&mut self, status: http::StatusCode) -> Result<(), http::HeadersSentError> {
+        self.check_headers_sent()?;
         self.status = status;
+        Ok(())
+    }
+
+    fn headers(&self) -> &http::HeaderMap {
+        &self.headers
+    }
+
+    fn set_header(&mut self, name: &str, value: String) -> Result<(), http::HeadersSentError> {
+        self.check_headers_sent()?;
+        self.headers.insert(name, value);
+        Ok(())
     }
 
-    fn set_header(&mut self, name: &str, value: String);
+    fn append_header(&mut self, name: &str, value: String) -> Result<(), http::HeadersSentError> {
+        self.check_headers_sent()?;
+        self.headers.append(name, value);
+        Ok(())
+    }
+
+    fn remove_header(&mut self, name: &str) -> Result<(), http::HeadersSentError> {
+        self.check_headers_sent()?;
+        self.headers.remove(name);
+        Ok(())
+    }
 
     fn buffering(&self) -> http::Buffering {
-        Buffering::On(65536)
+        match self.buffer_size {
+            0 => http::Buffering::Off,
+            size => http::Buffering::On(size as u32),
+        }
     }
 
-    fn headers_sent(&self) -> bool;
+    fn set_buffering(&mut self, buffering: bool) -> bool {
+        self.buffer_size = if buffering { BUFFER_SIZE } else { 0 };
+        true
+    }
+
+    fn headers_sent(&self) -> bool {
+        self.check_headers_sent().is_err()
+    }
 }
 
 impl io::Write for Response {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        // self
+        if self.buffer.len() + buf.len() <= self.buffer_size {
+            return self.buffer.write(buf);
+        }
+
+        self.buffer.extend_from_slice(buf);
+        self.flush_buffer()?;
+
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        self.flush_buffer()
+    }
+}
+
+
+/// A response being sent by a helper thread, with the body passed to it in chunks.
+struct Stream {
+    sender: SyncSender<Vec<u8>>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl Stream {
+    fn new(request: tiny_http::Request, status: http::StatusCode, headers: Vec<tiny_http::Header>) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(4);
+
+        // The length is taken from the Content-Length header if the ingot set one, otherwise the body is chunked.
+        let body = ChannelReader {
+            receiver,
+            chunk: io::Cursor::default(),
+        };
+        let thread = thread::spawn(move || send_response(request, status, headers, body, None));
+
+        Self {
+            sender,
+            thread,
+        }
+    }
 
+    fn send(&mut self, chunk: Vec<u8>) -> io::Result<()> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+
+        self.sender.send(chunk).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "response stream closed"))
+    }
+
+    /// Send the remaining body, end the response and wait for it to be written.
+    fn finish(mut self, remaining: &[u8]) -> io::Result<()> {
+        let _ = self.send(remaining.to_vec());
+        drop(self.sender);
+
+        self.thread.join().unwrap_or_else(|_| Err(io::Error::other("response thread panicked")))
+    }
+}
+
+/// Reader that reads the chunks sent over a channel, ending when the sender is dropped.
+struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: io::Cursor<Vec<u8>>,
+}
+
+impl io::Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.chunk.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            match self.receiver.recv() {
+                Ok(chunk) => self.chunk = io::Cursor::new(chunk),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+
+/// A connection taken over from tiny_http, which gives no control over its read timeout.
+struct Upgraded(Box<dyn tiny_http::ReadWrite + Send>);
+
+impl http::Upgraded for Upgraded {}
+
+impl io::Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl io::Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
     }
 }
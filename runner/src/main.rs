@@ -1,43 +1,182 @@
+//! Lightweight development server that runs a single ingot library.
 extern crate tiny_http;
 extern crate ingots;
 extern crate ingots_loader;
 #[macro_use]
 extern crate log;
+extern crate signal_hook;
 extern crate simplelog;
 
 mod adapter;
 
+use ingots::lifecycle::Lifecycle;
+use ingots_loader::DynamicIngot;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use std::env;
-use tiny_http::{Server, Request, Response};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::Server;
 
 
+const USAGE: &str = "Usage: ingots-runner [options] <ingot>
+
+Options:
+    -b, --bind <addr>      Address to listen on [default: 127.0.0.1:8000]
+    -t, --threads <n>      Number of worker threads [default: number of CPUs]
+    -q, --quiet            Do not log requests
+    -v, --verbose          Log request headers and debug messages
+    -h, --help             Print this help message";
+
+/// How long to wait for requests in progress to complete when shutting down.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+struct Options {
+    ingot: PathBuf,
+    bind: String,
+    threads: usize,
+    log_requests: bool,
+    verbose: bool,
+}
+
 fn main() {
-    let _ = simplelog::SimpleLogger::init(log::LogLevelFilter::Debug, simplelog::Config::default());
-    let mut ingot;
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
 
-    if let Some(path) = env::args().nth(1) {
-        ingot = ingots_loader::DynamicIngot::open(path).expect("failed to load ingot");
+    let level = if options.verbose {
+        log::LogLevelFilter::Debug
     } else {
-        warn!("no ingot file given");
-        return;
+        log::LogLevelFilter::Info
+    };
+    let _ = simplelog::SimpleLogger::init(level, simplelog::Config::default());
+
+    let ingot = match DynamicIngot::open(&options.ingot) {
+        Ok(ingot) => Arc::new(Lifecycle::new(ingot)),
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = ingot.start() {
+        error!("ingot {} failed to start: {}", options.ingot.display(), e);
+        process::exit(1);
     }
 
-    let server = Server::http("0.0.0.0:8000").unwrap();
+    let server = match Server::http(options.bind.as_str()) {
+        Ok(server) => Arc::new(server),
+        Err(e) => {
+            error!("failed to listen on {}: {}", options.bind, e);
+            process::exit(1);
+        }
+    };
 
-    for request in server.incoming_requests() {
-        let mut context = adapter::Context {
-            server_addr: server.server_addr(),
-            request: request,
-            response: Response::from_data(Vec::new()),
-        };
+    let mut signals = Signals::new([SIGTERM, SIGINT]).expect("failed to install signal handlers");
+    {
+        let ingot = ingot.clone();
+
+        thread::spawn(move || {
+            if signals.forever().next().is_some() {
+                info!("shutting down, waiting up to {:?} for requests to complete", DRAIN_TIMEOUT);
+                if !ingot.shutdown(DRAIN_TIMEOUT) {
+                    warn!("{} requests still in progress after drain timeout", ingot.in_flight());
+                }
+                process::exit(0);
+            }
+        });
+    }
 
-        println!("received request! method: {:?}, url: {:?}, headers: {:?}",
-            request.method(),
-            request.url(),
-            request.headers()
-        );
+    info!("serving {} on http://{} with {} threads", options.ingot.display(), server.server_addr(), options.threads);
 
-        let response = Response::from_string("hello world");
-        request.respond(response);
+    let workers: Vec<_> = (0..options.threads).map(|i| {
+        let server = server.clone();
+        let ingot = ingot.clone();
+        let log_requests = options.log_requests;
+
+        thread::Builder::new()
+            .name(format!("ingots-runner-{}", i))
+            .spawn(move || loop {
+                match server.recv() {
+                    Ok(request) => handle_request(&server, &ingot, request, log_requests),
+                    Err(e) => error!("failed to receive request: {}", e),
+                }
+            })
+            .expect("failed to spawn worker thread")
+    }).collect();
+
+    for worker in workers {
+        let _ = worker.join();
     }
 }
+
+fn handle_request(server: &Server, ingot: &Lifecycle<DynamicIngot>, request: tiny_http::Request, log_requests: bool) {
+    let start = Instant::now();
+    let remote_addr = *request.remote_addr();
+    let summary = format!("{} {} HTTP/{}", request.method(), request.url(), request.http_version());
+
+    debug!("{} headers: {:?}", summary, request.headers());
+
+    let mut context = adapter::Context::new(server.server_addr(), request);
+    ingot.handle(&mut context);
+
+    match context.respond() {
+        Ok((status, length)) => if log_requests {
+            let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+            info!("{} \"{}\" {} {} {:.1}ms", remote_addr, summary, status, length, elapsed);
+        },
+        Err(e) => warn!("{} \"{}\" failed to send response: {}", remote_addr, summary, e),
+    }
+}
+
+/// Parse the command line arguments.
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut ingot = None;
+    let mut options = Options {
+        ingot: PathBuf::new(),
+        bind: String::from("127.0.0.1:8000"),
+        threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        log_requests: true,
+        verbose: false,
+    };
+
+    while let Some(arg) = args.next() {
+        // Accept both `--option value` and `--option=value`.
+        let (name, inline_value) = match arg.find('=') {
+            Some(index) if arg.starts_with("--") => (&arg[..index], Some(arg[index + 1..].to_owned())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || inline_value.clone().or_else(|| args.next()).ok_or(format!("missing value for {}", name));
+
+        match name {
+            "-b" | "--bind" => options.bind = value()?,
+            "-t" | "--threads" => {
+                options.threads = match value()?.parse() {
+                    Ok(threads) if threads > 0 => threads,
+                    _ => return Err(String::from("threads must be a positive number")),
+                };
+            }
+            "-q" | "--quiet" => options.log_requests = false,
+            "-v" | "--verbose" => options.verbose = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if name.starts_with('-') => return Err(format!("unknown option: {}", name)),
+            _ if ingot.is_none() => ingot = Some(PathBuf::from(&arg)),
+            _ => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
+
+    options.ingot = ingot.ok_or_else(|| String::from("no ingot file given"))?;
+
+    Ok(options)
+}
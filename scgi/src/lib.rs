@@ -0,0 +1,29 @@
+//! Running an ingot as an SCGI server.
+//!
+//! SCGI is a simpler alternative to FastCGI: the web server opens a connection for each request, sends the CGI
+//! meta-variables followed by the request body, and reads a CGI response until the connection is closed.
+extern crate ingots;
+extern crate ingots_cgi;
+
+pub mod protocol;
+
+use ingots::http::StatusCode;
+use ingots_cgi::server;
+use std::io::Read;
+
+
+/// Wraps a Rust ingot in an SCGI server.
+///
+/// See `ingots_cgi::server::Server` for how connections are handled and the server is shut down.
+pub type Server<I> = server::Server<Scgi, I>;
+
+/// The SCGI protocol, which answers with a CGI response.
+pub struct Scgi;
+
+impl server::Protocol for Scgi {
+    const NON_PARSED_HEADERS: bool = false;
+
+    fn read_request<R: Read>(stream: &mut R) -> Result<Vec<(String, String)>, StatusCode> {
+        protocol::read_headers(stream).map_err(|_| 400)
+    }
+}
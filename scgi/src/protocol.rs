@@ -0,0 +1,80 @@
+//! Reading SCGI request headers.
+//!
+//! A request starts with its CGI meta-variables, encoded as a netstring of null-terminated names and values:
+//!
+//! ```text
+//! 70:CONTENT_LENGTH\027\0SCGI\01\0REQUEST_METHOD\0POST\0REQUEST_URI\0/deepthought\0,
+//! ```
+//!
+//! The first variable must be `CONTENT_LENGTH`, and `SCGI` must be `1`. The request body follows the netstring.
+use std::io::{self, Read};
+
+
+/// Maximum size of the headers of a request, in bytes.
+pub const MAX_HEADERS_SIZE: usize = 64 * 1024;
+
+/// Read the headers of a request, returning the meta-variables in the order they were sent.
+///
+/// Nothing past the end of the headers is read, so the body can be read from the same stream afterwards.
+pub fn read_headers<R: Read>(reader: &mut R) -> io::Result<Vec<(String, String)>> {
+    let len = read_length(reader)?;
+
+    // The netstring is followed by a comma, which is read along with it.
+    let mut headers = vec![0; len + 1];
+    reader.read_exact(&mut headers)?;
+
+    if headers.pop() != Some(b',') {
+        return Err(invalid("headers must end with a comma"));
+    }
+    if headers.last() != Some(&0) {
+        return Err(invalid("headers must end with a null character"));
+    }
+    headers.pop();
+
+    let mut fields = headers.split(|&byte| byte == 0).map(|field| String::from_utf8_lossy(field).into_owned());
+    let mut vars = Vec::new();
+
+    while let Some(name) = fields.next() {
+        let value = fields.next().ok_or_else(|| invalid("header is missing a value"))?;
+        vars.push((name, value));
+    }
+
+    match vars.first() {
+        Some((name, value)) if name == "CONTENT_LENGTH" && value.parse::<u64>().is_ok() => {}
+        _ => return Err(invalid("the first header must be a valid CONTENT_LENGTH")),
+    }
+
+    if !vars.iter().any(|(name, value)| name == "SCGI" && value == "1") {
+        return Err(invalid("missing `SCGI: 1` header"));
+    }
+
+    Ok(vars)
+}
+
+/// Read the length of the headers netstring, up to and including the colon.
+fn read_length<R: Read>(reader: &mut R) -> io::Result<usize> {
+    let mut len: usize = 0;
+    let mut digits = 0;
+
+    loop {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+
+        match byte[0] {
+            b':' if digits > 0 => return Ok(len),
+            digit @ b'0'..=b'9' => {
+                len = len * 10 + (digit - b'0') as usize;
+                digits += 1;
+
+                if len > MAX_HEADERS_SIZE {
+                    return Err(invalid("headers are too large"));
+                }
+            }
+            _ => return Err(invalid("invalid netstring length")),
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid SCGI request: {}", message))
+}
@@ -0,0 +1,226 @@
+extern crate ingots;
+extern crate ingots_scgi;
+
+use ingots::http::Context;
+use ingots_scgi::Server;
+use std::env;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+
+/// Echoes the request back, except for the body at `/ignore`, and waits for another request at `/wait` before
+/// responding.
+struct Echo {
+    barrier: Arc<Barrier>,
+}
+
+impl ingots::Ingot for Echo {
+    fn handle(&self, context: &mut dyn Context) {
+        let path_info = context.request().path_info().into_owned();
+        let method = context.request().method().into_owned();
+
+        if path_info == "/wait" {
+            self.barrier.wait();
+        }
+
+        let mut body = String::new();
+        if path_info != "/ignore" {
+            context.request_mut().read_to_string(&mut body).unwrap();
+        }
+
+        let response = context.response();
+        response.set_header("Content-Type", "text/plain".into()).unwrap();
+        response.set_header("X-Method", method).unwrap();
+        write!(response, "{}:{}", path_info, body).unwrap();
+    }
+}
+
+fn echo() -> Echo {
+    Echo {
+        barrier: Arc::new(Barrier::new(2)),
+    }
+}
+
+/// Start a server on a free local port and return its address.
+fn serve_tcp() -> String {
+    serve_tcp_with(Server::new(echo()))
+}
+
+fn serve_tcp_with(server: Server<Echo>) -> String {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+    let server_addr = addr.clone();
+    thread::spawn(move || server.listen_tcp(server_addr).unwrap());
+
+    for _ in 0..100 {
+        if TcpStream::connect(&addr).is_ok() {
+            return addr;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    panic!("server did not start");
+}
+
+/// Encode a request as sent by the web server.
+fn request(method: &str, uri: &str, body: &str) -> Vec<u8> {
+    let content_length = body.len().to_string();
+    let vars = [
+        ("CONTENT_LENGTH", content_length.as_str()),
+        ("SCGI", "1"),
+        ("REQUEST_METHOD", method),
+        ("REQUEST_URI", uri),
+        ("REMOTE_ADDR", "127.0.0.1"),
+        ("SERVER_PORT", "80"),
+        ("HTTP_X_FORWARDED_PROTO", "http"),
+    ];
+
+    let mut headers = Vec::new();
+    for &(name, value) in vars.iter() {
+        headers.extend_from_slice(name.as_bytes());
+        headers.push(0);
+        headers.extend_from_slice(value.as_bytes());
+        headers.push(0);
+    }
+
+    let mut request = format!("{}:", headers.len()).into_bytes();
+    request.extend(headers);
+    request.push(b',');
+    request.extend_from_slice(body.as_bytes());
+    request
+}
+
+/// Send a raw request and read the response until the server closes the connection.
+fn send<S: Read + Write>(mut stream: S, request: &[u8]) -> String {
+    stream.write_all(request).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn connect(addr: &str) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream
+}
+
+#[test]
+fn get() {
+    let addr = serve_tcp();
+    let response = send(connect(&addr), &request("GET", "/hello?name=world", ""));
+
+    assert_eq!(response, "Status: 200 OK\r\nContent-Type: text/plain\r\nX-Method: GET\r\n\r\n/hello:");
+}
+
+#[test]
+fn post_body_is_limited_to_content_length() {
+    let addr = serve_tcp();
+
+    // The connection stays open after the body is sent, so reading past it would wait until the client times out.
+    let response = send(connect(&addr), &request("POST", "/echo", "hello"));
+
+    assert!(response.starts_with("Status: 200 OK\r\n"));
+    assert!(response.contains("X-Method: POST\r\n"));
+    assert!(response.ends_with("\r\n\r\n/echo:hello"));
+}
+
+#[test]
+fn unread_body() {
+    let addr = serve_tcp();
+    let body = "x".repeat(256 * 1024);
+    let response = send(connect(&addr), &request("POST", "/ignore", &body));
+
+    assert!(response.ends_with("\r\n\r\n/ignore:"));
+}
+
+#[test]
+fn malformed_headers() {
+    let addr = serve_tcp();
+
+    assert_eq!(send(connect(&addr), b"5x"), "Status: 400 Bad Request\r\n\r\n");
+    assert_eq!(send(connect(&addr), b"10:SCGI\x001\0ab\0;"), "Status: 400 Bad Request\r\n\r\n");
+    assert_eq!(send(connect(&addr), b"7:SCGI\x001\0,"), "Status: 400 Bad Request\r\n\r\n");
+}
+
+#[test]
+fn concurrent_connections() {
+    let addr = serve_tcp();
+
+    // Neither request can complete until both are being handled.
+    let clients: Vec<_> = (0..2)
+        .map(|_| {
+            let stream = connect(&addr);
+            thread::spawn(move || send(stream, &request("GET", "/wait", "")))
+        })
+        .collect();
+
+    for client in clients {
+        assert!(client.join().unwrap().ends_with("\r\n\r\n/wait:"));
+    }
+}
+
+#[test]
+fn queue_full() {
+    let barrier = Arc::new(Barrier::new(2));
+    let server = Server::new(Echo {
+        barrier: barrier.clone(),
+    });
+    let addr = serve_tcp_with(server.threads(1).max_queued(0));
+
+    // Let the thread finish with the connections made while waiting for the server to start.
+    thread::sleep(Duration::from_millis(100));
+
+    // Connections are accepted in order, so the next one takes the only thread.
+    let stream = connect(&addr);
+    let waiting = thread::spawn(move || send(stream, &request("GET", "/wait", "")));
+
+    assert_eq!(send(connect(&addr), b""), "Status: 503 Service Unavailable\r\n\r\n");
+
+    barrier.wait();
+    assert!(waiting.join().unwrap().ends_with("\r\n\r\n/wait:"));
+}
+
+#[test]
+fn timeout() {
+    let addr = serve_tcp_with(Server::new(echo()).timeout(Some(Duration::from_millis(100))));
+
+    // The server gives up waiting for the rest of the headers.
+    let mut stream = connect(&addr);
+    stream.write_all(b"70:CONTENT_LENGTH").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert_eq!(response, "Status: 400 Bad Request\r\n\r\n");
+}
+
+#[test]
+fn unix_socket() {
+    let path = env::temp_dir().join(format!("ingots-scgi-test-{}.sock", process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let server_path = path.clone();
+    thread::spawn(move || Server::new(echo()).listen_unix(server_path).unwrap());
+
+    let mut stream = None;
+    for _ in 0..100 {
+        if let Ok(connected) = UnixStream::connect(&path) {
+            stream = Some(connected);
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let stream = stream.expect("server did not start");
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let response = send(stream, &request("PUT", "/file", "contents"));
+    let _ = std::fs::remove_file(&path);
+
+    assert!(response.contains("X-Method: PUT\r\n"));
+    assert!(response.ends_with("\r\n\r\n/file:contents"));
+}
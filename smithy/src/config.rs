@@ -1,31 +1,263 @@
-extern crate toml;
-
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
+use toml::{self, Spanned};
 
 
 #[derive(Clone)]
 pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// How long to keep idle connections open between requests, or `None` to close them after each response.
     pub keep_alive: Option<Duration>,
     pub read_timeout: Option<Duration>,
     pub write_timeout: Option<Duration>,
+    /// How long a client may take to complete the TLS or HTTP/2 handshake, or to send anything at all before its
+    /// protocol is known. Unlike the other timeouts, it cannot be disabled.
+    pub handshake_timeout: Duration,
     pub threads: usize,
+    /// Maximum number of HTTP/2 requests waiting for a thread while every thread is busy. Requests beyond that are
+    /// answered with `503 Service Unavailable`.
+    pub max_queued: usize,
+    /// Maximum number of connections taken over by ingots, such as for WebSockets, on each listener. Each one holds
+    /// one of the threads for as long as it is open, so it is less than `threads`.
+    pub max_upgrades: usize,
+    /// How often to check ingot libraries for changes, or `None` to disable reloading.
+    pub reload_interval: Option<Duration>,
+    /// How long to wait for requests in progress to complete when shutting down.
+    pub drain_timeout: Duration,
+    /// Whether to accept HTTP/2, negotiated with ALPN over TLS or with prior knowledge over plain HTTP.
+    pub http2: bool,
+    /// Settings for an HTTPS listener, if enabled.
+    pub tls: Option<TlsConfig>,
     pub locations: Vec<Location>,
 }
 
 impl Default for ServerConfig {
     fn default() -> ServerConfig {
         ServerConfig {
+            host: String::from("0.0.0.0"),
+            port: 8001,
             keep_alive: None,
             read_timeout: None,
             write_timeout: None,
+            handshake_timeout: Duration::from_secs(10),
             threads: 1,
+            max_queued: 32,
+            max_upgrades: 0,
+            reload_interval: None,
+            drain_timeout: Duration::from_secs(30),
+            http2: false,
+            tls: None,
             locations: Vec::new(),
         }
     }
 }
 
 impl ServerConfig {
+    /// Load the server configuration from a TOML file.
+    ///
+    /// Relative paths are resolved against the directory containing the file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ServerConfig, Error> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|e| Error {
+            path: path.to_owned(),
+            line: None,
+            key: None,
+            message: e.to_string(),
+        })?;
+
+        Self::parse(path, &source)
+    }
+
+    /// Parse a server configuration from the contents of the given file.
+    pub fn parse(path: &Path, source: &str) -> Result<ServerConfig, Error> {
+        let file = ConfigFile {
+            path,
+            source,
+        };
+
+        let raw: RawConfig = toml::from_str(source).map_err(|e| {
+            let span = e.span();
+            file.error(span.clone(), span.and_then(|span| file.key_at(span.start)), e.message())
+        })?;
+
+        let mut config = ServerConfig::default();
+        let server = raw.server;
+
+        if let Some(host) = server.host {
+            if host.get_ref().is_empty() {
+                return Err(file.error(Some(host.span()), Some("server.host"), "host must not be empty"));
+            }
+            config.host = host.into_inner();
+        }
+
+        if let Some(port) = server.port {
+            if *port.get_ref() == 0 {
+                return Err(file.error(Some(port.span()), Some("server.port"), "port must be between 1 and 65535"));
+            }
+            config.port = port.into_inner();
+        }
+
+        if let Some(threads) = server.threads {
+            if *threads.get_ref() == 0 {
+                return Err(file.error(Some(threads.span()), Some("server.threads"), "at least one thread is required"));
+            }
+            config.threads = *threads.get_ref();
+        }
+
+        if let Some(max_queued) = server.max_queued {
+            config.max_queued = max_queued;
+        }
+
+        if let Some(max_upgrades) = server.max_upgrades {
+            if *max_upgrades.get_ref() >= config.threads {
+                let message = "max_upgrades must be less than threads, to leave threads for other requests";
+                return Err(file.error(Some(max_upgrades.span()), Some("server.max_upgrades"), message));
+            }
+            config.max_upgrades = max_upgrades.into_inner();
+        }
+
+        // Timeouts and intervals are given in seconds; zero disables them.
+        let timeout = |value: Spanned<u64>| match *value.get_ref() {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        };
+
+        if let Some(keep_alive) = server.keep_alive {
+            config.keep_alive = timeout(keep_alive);
+        }
+        config.read_timeout = server.read_timeout.and_then(timeout);
+        config.write_timeout = server.write_timeout.and_then(timeout);
+
+        if let Some(handshake_timeout) = server.handshake_timeout {
+            if *handshake_timeout.get_ref() == 0 {
+                let message = "handshake timeout must be at least one second";
+                return Err(file.error(Some(handshake_timeout.span()), Some("server.handshake_timeout"), message));
+            }
+            config.handshake_timeout = Duration::from_secs(handshake_timeout.into_inner());
+        }
+
+        if let Some(reload_interval) = server.reload_interval {
+            config.reload_interval = timeout(reload_interval);
+        }
+
+        if let Some(drain_timeout) = server.drain_timeout {
+            config.drain_timeout = Duration::from_secs(drain_timeout.into_inner());
+        }
+
+        if let Some(http2) = server.http2 {
+            config.http2 = http2;
+        }
+
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        if let Some(tls) = server.tls {
+            let span = tls.span();
+            let tls = tls.into_inner();
+            let port = tls.port.map(Spanned::into_inner).unwrap_or(443);
+
+            if port == 0 || port == config.port {
+                let message = "port must be between 1 and 65535, and differ from `server.port`";
+                return Err(file.error(Some(span), Some("server.tls.port"), message));
+            }
+
+            let default = match (tls.certificate, tls.key) {
+                (Some(certificate), Some(key)) => Some(CertificatePaths {
+                    certificate: base_dir.join(certificate.into_inner()),
+                    key: base_dir.join(key.into_inner()),
+                }),
+                (None, None) => None,
+                _ => return Err(file.error(Some(span), Some("server.tls"), "`certificate` and `key` must be given together")),
+            };
+
+            let hosts: Vec<_> = tls.host.into_iter()
+                .map(|(host, paths)| {
+                    let paths = paths.into_inner();
+                    (host, CertificatePaths {
+                        certificate: base_dir.join(paths.certificate),
+                        key: base_dir.join(paths.key),
+                    })
+                })
+                .collect();
+
+            if default.is_none() && hosts.is_empty() {
+                return Err(file.error(Some(span), Some("server.tls"), "at least one certificate is required"));
+            }
+
+            config.tls = Some(TlsConfig {
+                port,
+                default,
+                hosts,
+                redirect: tls.redirect.unwrap_or(false),
+            });
+        }
+
+        for (name, location) in server.location {
+            let key = format!("server.location.\"{}\"", name);
+            let span = location.span();
+            let location = location.into_inner();
+
+            // Locations are either a path prefix, or a virtual host name followed by a path prefix.
+            let (host, prefix) = match name.find('/') {
+                Some(0) => (None, name.clone()),
+                Some(index) => (Some(name[..index].to_owned()), name[index..].to_owned()),
+                None => (Some(name.clone()), String::from("/")),
+            };
+
+            if host.as_ref().is_some_and(|host| host.is_empty() || host.contains(char::is_whitespace)) {
+                return Err(file.error(Some(span), Some(&key), "invalid virtual host name"));
+            }
+
+            let mut methods = Vec::new();
+            if let Some(list) = location.methods {
+                let list_span = list.span();
+
+                for method in list.into_inner() {
+                    if method.is_empty() || !method.chars().all(|c| c.is_ascii_alphabetic()) {
+                        let message = format!("invalid method `{}`", method);
+                        return Err(file.error(Some(list_span), Some(format!("{}.methods", key)), &message));
+                    }
+                    methods.push(method.to_ascii_uppercase());
+                }
+            }
+
+            let (field, path) = match (location.ingot, location.root) {
+                (Some(ingot), None) => ("ingot", ingot),
+                (None, Some(root)) => ("root", root),
+                (Some(_), Some(_)) => {
+                    return Err(file.error(Some(span), Some(&key), "location cannot have both `ingot` and `root` keys"));
+                }
+                (None, None) => {
+                    return Err(file.error(Some(span), Some(&key), "location is missing the `ingot` or `root` key"));
+                }
+            };
+
+            if path.get_ref().is_empty() {
+                let message = format!("{} path must not be empty", field);
+                return Err(file.error(Some(path.span()), Some(format!("{}.{}", key, field)), &message));
+            }
+
+            let path = base_dir.join(path.into_inner());
+
+            config.add_location(Location {
+                host,
+                prefix,
+                methods,
+                target: match field {
+                    "ingot" => Target::Ingot(path),
+                    _ => Target::Root(path),
+                },
+            });
+        }
+
+        Ok(config)
+    }
+
     pub fn add_location(&mut self, location: Location) {
         self.locations.push(location);
     }
@@ -33,6 +265,250 @@ impl ServerConfig {
 
 #[derive(Clone)]
 pub struct Location {
+    /// Virtual host the location is bound to, or `None` to match any host.
+    pub host: Option<String>,
     pub prefix: String,
-    pub ingot: PathBuf,
+    /// Methods accepted by the location. Any method is accepted if empty.
+    pub methods: Vec<String>,
+    pub target: Target,
+}
+
+/// What a location serves requests with.
+#[derive(Clone, Debug)]
+pub enum Target {
+    /// An ingot loaded from a shared library.
+    Ingot(PathBuf),
+    /// Static files from a directory.
+    Root(PathBuf),
+}
+
+impl Target {
+    /// Get the path of the library or directory.
+    pub fn path(&self) -> &Path {
+        match *self {
+            Target::Ingot(ref path) | Target::Root(ref path) => path,
+        }
+    }
+}
+
+
+/// Settings for an HTTPS listener.
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub port: u16,
+    /// Certificate for clients that do not send a server name, or send one without a certificate of its own.
+    pub default: Option<CertificatePaths>,
+    /// Certificates by host name, selected by the server name the client sends. Names may start with `*.`.
+    pub hosts: Vec<(String, CertificatePaths)>,
+    /// Redirect requests to the plain HTTP listener to HTTPS.
+    pub redirect: bool,
+}
+
+/// Paths to a PEM certificate chain and its private key.
+#[derive(Clone, Debug)]
+pub struct CertificatePaths {
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+}
+
+
+/// An error in a configuration file.
+#[derive(Debug)]
+pub struct Error {
+    pub path: PathBuf,
+    /// 1-based line number of the error, if known.
+    pub line: Option<usize>,
+    /// Dotted path of the offending key, if known.
+    pub key: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+
+        if let Some(ref key) = self.key {
+            write!(f, ": {}", key)?;
+        }
+
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl error::Error for Error {}
+
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    server: RawServer,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawServer {
+    host: Option<Spanned<String>>,
+    port: Option<Spanned<u16>>,
+    threads: Option<Spanned<usize>>,
+    max_queued: Option<usize>,
+    max_upgrades: Option<Spanned<usize>>,
+    keep_alive: Option<Spanned<u64>>,
+    read_timeout: Option<Spanned<u64>>,
+    write_timeout: Option<Spanned<u64>>,
+    handshake_timeout: Option<Spanned<u64>>,
+    reload_interval: Option<Spanned<u64>>,
+    drain_timeout: Option<Spanned<u64>>,
+    http2: Option<bool>,
+    tls: Option<Spanned<RawTls>>,
+    #[serde(default)]
+    location: BTreeMap<String, Spanned<RawLocation>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawTls {
+    port: Option<Spanned<u16>>,
+    certificate: Option<Spanned<String>>,
+    key: Option<Spanned<String>>,
+    redirect: Option<bool>,
+    #[serde(default)]
+    host: BTreeMap<String, Spanned<RawCertificate>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCertificate {
+    certificate: String,
+    key: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLocation {
+    ingot: Option<Spanned<String>>,
+    root: Option<Spanned<String>>,
+    methods: Option<Spanned<Vec<String>>>,
+}
+
+
+/// Source of a configuration file, used to locate errors.
+struct ConfigFile<'a> {
+    path: &'a Path,
+    source: &'a str,
+}
+
+impl<'a> ConfigFile<'a> {
+    fn error<K: Into<String>>(&self, span: Option<Range<usize>>, key: Option<K>, message: &str) -> Error {
+        Error {
+            path: self.path.to_owned(),
+            line: span.map(|span| self.line_of(span.start)),
+            key: key.map(Into::into),
+            message: message.trim().replace('\n', ", "),
+        }
+    }
+
+    fn line_of(&self, offset: usize) -> usize {
+        self.source[..offset.min(self.source.len())].matches('\n').count() + 1
+    }
+
+    /// Find the dotted key that is defined on the line containing the given offset.
+    fn key_at(&self, offset: usize) -> Option<String> {
+        let offset = offset.min(self.source.len());
+        let line_start = self.source[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line = self.source[line_start..].lines().next().unwrap_or("");
+
+        let key = match line.find('=') {
+            Some(index) => line[..index].trim(),
+            None => return None,
+        };
+
+        // The key belongs to the last table header above it.
+        let table = self.source[..line_start]
+            .lines()
+            .rev()
+            .map(str::trim)
+            .find(|line| line.starts_with('['))
+            .map(|header| header.trim_matches(|c| c == '[' || c == ']').trim());
+
+        Some(match table {
+            Some(table) => format!("{}.{}", table, key),
+            None => key.to_owned(),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let config = ServerConfig::parse(Path::new("smithy.toml"), "").unwrap();
+
+        assert_eq!(config.host, "0.0.0.0");
+        assert_eq!(config.port, 8001);
+        assert_eq!(config.keep_alive, None);
+        assert_eq!(config.threads, 1);
+        assert_eq!(config.max_queued, 32);
+        assert_eq!(config.reload_interval, None);
+        assert_eq!(config.handshake_timeout, Duration::from_secs(10));
+        assert_eq!(config.max_upgrades, 0);
+        assert!(!config.http2);
+        assert!(config.locations.is_empty());
+    }
+
+    #[test]
+    fn example() {
+        let path = Path::new("examples/smithy.toml");
+        let config = ServerConfig::parse(path, include_str!("../examples/smithy.toml")).unwrap();
+
+        assert_eq!(config.threads, 8);
+        assert_eq!(config.locations.len(), 1);
+    }
+
+    #[test]
+    fn timeouts() {
+        let source = "[server]\nkeep_alive = 100\nread_timeout = 0\nwrite_timeout = 32\n";
+        let config = ServerConfig::parse(Path::new("smithy.toml"), source).unwrap();
+
+        assert_eq!(config.keep_alive, Some(Duration::from_secs(100)));
+        assert_eq!(config.read_timeout, None);
+        assert_eq!(config.write_timeout, Some(Duration::from_secs(32)));
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let source = "[server]\nhandshake_timeout = 3\n";
+        let config = ServerConfig::parse(Path::new("smithy.toml"), source).unwrap();
+        assert_eq!(config.handshake_timeout, Duration::from_secs(3));
+
+        let error = ServerConfig::parse(Path::new("smithy.toml"), "[server]\nhandshake_timeout = 0\n").err().unwrap();
+        assert_eq!(error.key.as_deref(), Some("server.handshake_timeout"));
+    }
+
+    #[test]
+    fn max_upgrades() {
+        let source = "[server]\nthreads = 4\nmax_upgrades = 3\n";
+        let config = ServerConfig::parse(Path::new("smithy.toml"), source).unwrap();
+        assert_eq!(config.max_upgrades, 3);
+
+        let error = ServerConfig::parse(Path::new("smithy.toml"), "[server]\nmax_upgrades = 1\n").err().unwrap();
+        assert_eq!(error.key.as_deref(), Some("server.max_upgrades"));
+    }
+
+    #[test]
+    fn unknown_key() {
+        let error = ServerConfig::parse(Path::new("smithy.toml"), "[server]\nthreads = 2\nssl = true\n").err().unwrap();
+
+        assert_eq!(error.line, Some(3));
+        assert_eq!(error.key.as_deref(), Some("server.ssl"));
+    }
 }
@@ -1,92 +1,529 @@
-use hyper::net::Streaming;
+use hyper::header::Headers;
+use hyper::net::{Fresh, HttpStream, NetworkStream, Streaming};
+use hyper::status::StatusCode as HttpStatusCode;
 use hyper::server::*;
+use hyper::uri::RequestUri;
+use hyper::version::HttpVersion;
 use ingots;
+use ingots::http::{Buffering, HeaderMap, HeadersSentError, StatusCode, UpgradeError, Upgraded, Version};
+use std::borrow::Cow;
 use std::io;
-use std::net::SocketAddr;
+use std::mem;
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+use tls::TlsStream;
 
 
+/// Limits the number of connections taken over by ingots on a listener.
+///
+/// hyper closes a connection as soon as the request handler returns, so an upgraded connection keeps its worker thread
+/// until the ingot drops it. Without a limit, long-lived connections such as WebSockets could take every thread.
+pub struct UpgradeLimit {
+    max: usize,
+    count: AtomicUsize,
+}
+
+impl UpgradeLimit {
+    pub fn new(max: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max,
+            count: AtomicUsize::new(0),
+        })
+    }
+
+    /// Count one more upgraded connection until the permit is dropped, unless the limit has been reached.
+    fn acquire(limit: &Arc<Self>) -> Option<UpgradePermit> {
+        let increment = |count: usize| Some(count + 1).filter(|&count| count <= limit.max);
+
+        limit.count.fetch_update(Ordering::SeqCst, Ordering::SeqCst, increment).ok().map(|_| UpgradePermit(limit.clone()))
+    }
+}
+
+struct UpgradePermit(Arc<UpgradeLimit>);
+
+impl Drop for UpgradePermit {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+/// Request context for a request on an HTTP/1 connection, handled on one of the listener's threads.
+///
+/// The status and headers are held until the body is first written or flushed. The body itself is not buffered beyond
+/// the connection's own write buffer: `buffering()` always returns `Buffering::Off`, and requests to turn buffering on
+/// are refused.
 pub struct ServerContext<'a, 'b: 'a> {
     server_addr: SocketAddr,
+    server_name: String,
     request: ServerRequest<'a, 'b>,
     response: ServerResponse<'a>,
+    upgrades: Arc<UpgradeLimit>,
 }
 
 impl<'a, 'b: 'a> ServerContext<'a, 'b> {
-    pub fn new(server_addr: SocketAddr, request: Request<'a, 'b>, response: Response<'a>) -> ServerContext<'a, 'b> {
+    /// Create a context for a request routed to an ingot mounted at the given context path.
+    pub fn new(
+        server_addr: SocketAddr,
+        context_path: &str,
+        request: Request<'a, 'b>,
+        response: Response<'a>,
+        upgrades: &Arc<UpgradeLimit>,
+    ) -> Self {
         Self {
-            server_addr: server_addr,
-            request: ServerRequest(request),
-            response: ServerResponse(response.start().unwrap()),
+            server_addr,
+            server_name: server_addr.ip().to_string(),
+            request: ServerRequest::new(context_path, request),
+            response: ServerResponse {
+                state: ResponseState::Fresh(response),
+                status: 200,
+                headers: HeaderMap::new(),
+            },
+            upgrades: upgrades.clone(),
         }
     }
+
+    /// Complete the response, sending the headers if nothing has been written yet.
+    ///
+    /// If the connection has been upgraded, this waits until the ingot is done with it, holding on to the thread. The
+    /// `UpgradeLimit` keeps such connections from taking every thread.
+    pub fn finish(self) -> io::Result<()> {
+        self.response.finish()
+    }
 }
 
 impl<'a, 'b: 'a> ingots::http::Context for ServerContext<'a, 'b> {
     fn remote_addr(&self) -> SocketAddr {
-        self.request.0.remote_addr.clone()
+        self.request.inner.remote_addr
     }
 
     fn server_addr(&self) -> SocketAddr {
-        self.server_addr.clone()
+        self.server_addr
     }
 
-    fn server_name(&self) -> String {
-        self.server_addr.ip().to_string()
+    fn server_name(&self) -> &str {
+        &self.server_name
     }
 
-    fn request(&self) -> &ingots::http::Request {
+    fn request(&self) -> &dyn ingots::http::Request {
         &self.request
     }
 
-    fn response(&mut self) -> &mut ingots::http::Response {
+    fn request_mut(&mut self) -> &mut dyn ingots::http::Request {
+        &mut self.request
+    }
+
+    fn response(&mut self) -> &mut dyn ingots::http::Response {
         &mut self.response
     }
+
+    fn upgrade(&mut self) -> Result<Box<dyn Upgraded>, UpgradeError> {
+        self.response.check_headers_sent()?;
+
+        // hyper has read the request through a buffer, but a client must wait for the response before speaking the new
+        // protocol, so no data is lost by reading from the connection directly.
+        let upgrades = &self.upgrades;
+        let permit = || UpgradeLimit::acquire(upgrades).ok_or(UpgradeError::Unavailable);
+        let (done, receiver) = mpsc::channel();
+        let stream: Box<dyn Upgraded> = if let Some(stream) = self.request.inner.downcast_ref::<HttpStream>() {
+            Box::new(UpgradedStream::new(stream.clone(), done, permit()?)?)
+        } else if let Some(stream) = self.request.inner.downcast_ref::<TlsStream>() {
+            Box::new(UpgradedStream::new(stream.clone(), done, permit()?)?)
+        } else {
+            return Err(UpgradeError::Unsupported);
+        };
+
+        io::Write::flush(&mut self.response)?;
+        self.response.state = ResponseState::Upgraded(receiver);
+
+        Ok(stream)
+    }
 }
 
-struct ServerRequest<'a, 'b: 'a>(Request<'a, 'b>);
+struct ServerRequest<'a, 'b: 'a> {
+    inner: Request<'a, 'b>,
+    context_path: String,
+    headers: HeaderMap,
+}
+
+impl<'a, 'b: 'a> ServerRequest<'a, 'b> {
+    fn new(context_path: &str, request: Request<'a, 'b>) -> Self {
+        let headers = convert_headers(&request.headers);
+
+        Self {
+            inner: request,
+            context_path: context_path.to_owned(),
+            headers,
+        }
+    }
+
+    fn path(&self) -> &str {
+        match self.inner.uri {
+            RequestUri::AbsolutePath(ref path) => match path.find('?') {
+                Some(index) => &path[..index],
+                None => path,
+            },
+            _ => "/",
+        }
+    }
+}
+
+/// Copy the raw values of hyper's typed header storage into a header map.
+fn convert_headers(headers: &Headers) -> HeaderMap {
+    let mut map = HeaderMap::new();
+
+    for header in headers.iter() {
+        for value in headers.get_raw(header.name()).unwrap_or(&[]) {
+            map.append(header.name(), String::from_utf8_lossy(value).into_owned());
+        }
+    }
+
+    map
+}
 
 impl<'a, 'b> ingots::http::Request for ServerRequest<'a, 'b> {
-    fn uri(&self) -> String {
-        self.0.uri.to_string()
+    fn method(&self) -> Cow<'_, str> {
+        Cow::Owned(self.inner.method.to_string())
     }
 
-    fn method(&self) -> String {
-        self.0.method.to_string()
+    fn context_path(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.context_path)
     }
 
-    fn protocol(&self) -> String {
-        self.0.version.to_string()
+    fn path_info(&self) -> Cow<'_, str> {
+        // The router only matches prefixes on segment boundaries, so the remainder is empty or starts with a slash.
+        Cow::Borrowed(&self.path()[self.context_path.len()..])
     }
 
-    fn query_string(&self) -> Option<String> {
-        None
+    fn query_string(&self) -> Option<Cow<'_, str>> {
+        match self.inner.uri {
+            RequestUri::AbsolutePath(ref path) => path.find('?').map(|index| Cow::Borrowed(&path[index + 1..])),
+            _ => None,
+        }
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
     }
 
     fn is_secure(&self) -> bool {
-        false
+        self.inner.ssl::<TlsStream>().is_some()
+    }
+
+    fn version(&self) -> Version {
+        match self.inner.version {
+            HttpVersion::Http09 => Version::Http09,
+            HttpVersion::Http10 => Version::Http10,
+            HttpVersion::Http11 => Version::Http11,
+            HttpVersion::Http20 => Version::Http2,
+        }
     }
 }
 
 impl<'a, 'b> io::Read for ServerRequest<'a, 'b> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
+        self.inner.read(buf)
     }
 }
 
-struct ServerResponse<'a>(Response<'a, Streaming>);
+enum ResponseState<'a> {
+    /// Status and headers are still being collected.
+    Fresh(Response<'a, Fresh>),
+    /// Status and headers have been sent, and the body is being written.
+    Streaming(Response<'a, Streaming>),
+    /// Sending the headers failed; nothing more can be written.
+    Failed,
+    /// The connection has been taken over by the ingot. The sender held by the ingot's stream is dropped along with it.
+    Upgraded(Receiver<()>),
+}
+
+struct ServerResponse<'a> {
+    state: ResponseState<'a>,
+    status: StatusCode,
+    headers: HeaderMap,
+}
+
+// hyper erases the writer of a response to `&mut dyn Write`, but the server always writes to a `BufWriter` around the
+// connection's `NetworkStream`, which is `Send`.
+unsafe impl<'a> Send for ServerResponse<'a> {}
+
+impl<'a> ServerResponse<'a> {
+    /// Send the status and headers to the client if they have not been sent already, and get the body stream.
+    fn start(&mut self) -> io::Result<&mut Response<'a, Streaming>> {
+        if let ResponseState::Fresh(_) = self.state {
+            let mut response = match mem::replace(&mut self.state, ResponseState::Failed) {
+                ResponseState::Fresh(response) => response,
+                _ => unreachable!(),
+            };
+
+            *response.status_mut() = HttpStatusCode::from_u16(self.status);
+
+            for name in self.headers.names() {
+                let values = self.headers.get_all(name)
+                    .filter(|value| {
+                        // A line break would allow the value to inject additional headers.
+                        let valid = !value.contains(['\r', '\n']);
+                        if !valid {
+                            warn!("dropping response header {:?} with a line break in its value", name);
+                        }
+                        valid
+                    })
+                    .map(|value| value.as_bytes().to_vec())
+                    .collect();
+                response.headers_mut().set_raw(name.to_owned(), values);
+            }
+
+            self.state = ResponseState::Streaming(response.start()?);
+        }
+
+        match self.state {
+            ResponseState::Streaming(ref mut response) => Ok(response),
+            ResponseState::Upgraded(_) => Err(io::Error::other("the connection has been upgraded")),
+            _ => Err(io::Error::new(io::ErrorKind::BrokenPipe, "failed to send response headers")),
+        }
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        if let ResponseState::Upgraded(ref done) = self.state {
+            // hyper shuts the connection down once the request has been handled, so hold on to it.
+            let _ = done.recv();
+            return Ok(());
+        }
+
+        self.start()?;
+
+        match self.state {
+            ResponseState::Streaming(response) => response.end(),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_headers_sent(&self) -> Result<(), HeadersSentError> {
+        match self.state {
+            ResponseState::Fresh(_) => Ok(()),
+            _ => Err(HeadersSentError),
+        }
+    }
+}
 
 impl<'a> ingots::http::Response for ServerResponse<'a> {
-    fn get_status(&self) -> u8 {
-        200
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    fn set_status(&mut self, status: StatusCode) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.status = status;
+        Ok(())
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn set_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.headers.insert(name, value);
+        Ok(())
+    }
+
+    fn append_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.headers.append(name, value);
+        Ok(())
+    }
+
+    fn remove_header(&mut self, name: &str) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.headers.remove(name);
+        Ok(())
+    }
+
+    fn buffering(&self) -> Buffering {
+        Buffering::Off
+    }
+
+    fn headers_sent(&self) -> bool {
+        self.check_headers_sent().is_err()
     }
 }
 
 impl<'a> io::Write for ServerResponse<'a> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+        self.start()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.start()?.flush()
+    }
+}
+
+
+/// A connection taken over by an ingot, which is shut down when the ingot drops it.
+struct UpgradedStream<S: NetworkStream> {
+    stream: S,
+    _done: Sender<()>,
+    _permit: UpgradePermit,
+}
+
+impl<S: NetworkStream> UpgradedStream<S> {
+    fn new(stream: S, done: Sender<()>, permit: UpgradePermit) -> io::Result<Self> {
+        // The connection may well be idle between messages of the new protocol; the ingot can set its own timeout.
+        stream.set_read_timeout(None)?;
+
+        Ok(Self {
+            stream,
+            _done: done,
+            _permit: permit,
+        })
+    }
+}
+
+impl<S: NetworkStream> io::Read for UpgradedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<S: NetworkStream> io::Write for UpgradedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.0.flush()
+        self.stream.flush()
+    }
+}
+
+impl<S: NetworkStream + Send> Upgraded for UpgradedStream<S> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+impl<S: NetworkStream> Drop for UpgradedStream<S> {
+    fn drop(&mut self) {
+        let _ = self.stream.close(Shutdown::Both);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ingots::http::Response as _;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+    use super::*;
+
+    /// Output of a response that can be inspected while the response is still being written.
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Output {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Run a function against a fresh response, returning everything sent once it has been finished.
+    fn respond<F: FnOnce(&mut ServerResponse, &Output)>(f: F) -> String {
+        let output = Output::default();
+        let mut writer = output.clone();
+        let mut headers = Headers::new();
+        let mut response = ServerResponse {
+            state: ResponseState::Fresh(Response::new(&mut writer, &mut headers)),
+            status: 200,
+            headers: HeaderMap::new(),
+        };
+
+        f(&mut response, &output);
+        response.finish().unwrap();
+
+        output.text()
+    }
+
+    #[test]
+    fn headers_held_until_first_write() {
+        let sent = respond(|response, output| {
+            response.set_status(201).unwrap();
+            response.set_header("X-Test", String::from("1")).unwrap();
+            response.append_header("X-Test", String::from("2")).unwrap();
+            response.set_header("X-Removed", String::from("1")).unwrap();
+            response.remove_header("X-Removed").unwrap();
+            assert!(!response.headers_sent());
+            assert_eq!(output.text(), "");
+
+            response.write_all(b"Hello").unwrap();
+            response.flush().unwrap();
+            assert!(response.headers_sent());
+            assert!(output.text().starts_with("HTTP/1.1 201 Created\r\n"));
+
+            assert_eq!(response.set_status(500), Err(HeadersSentError));
+            assert_eq!(response.set_header("X-Late", String::from("1")), Err(HeadersSentError));
+        });
+
+        assert!(sent.contains("X-Test: 1\r\nX-Test: 2\r\n"));
+        assert!(!sent.contains("X-Removed"));
+        assert!(!sent.contains("X-Late"));
+        assert!(sent.ends_with("\r\n\r\n5\r\nHello\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn finish_sends_headers() {
+        let sent = respond(|response, _| {
+            response.set_status(404).unwrap();
+        });
+
+        assert!(sent.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn buffering_is_off() {
+        respond(|response, _| {
+            assert!(!response.set_buffering(true));
+            assert!(match response.buffering() {
+                Buffering::Off => true,
+                Buffering::On(_) => false,
+            });
+        });
+    }
+
+    #[test]
+    fn line_breaks_dropped() {
+        let sent = respond(|response, _| {
+            response.set_header("X-Injected", String::from("1\r\nSet-Cookie: a=b")).unwrap();
+            response.append_header("X-Valid", String::from("1")).unwrap();
+        });
+
+        assert!(!sent.contains("X-Injected"));
+        assert!(!sent.contains("Set-Cookie"));
+        assert!(sent.contains("X-Valid: 1\r\n"));
+    }
+
+    #[test]
+    fn upgrade_limit() {
+        let limit = UpgradeLimit::new(2);
+        let first = UpgradeLimit::acquire(&limit).unwrap();
+        let _second = UpgradeLimit::acquire(&limit).unwrap();
+        assert!(UpgradeLimit::acquire(&limit).is_none());
+
+        drop(first);
+        assert!(UpgradeLimit::acquire(&limit).is_some());
+
+        assert!(UpgradeLimit::acquire(&UpgradeLimit::new(0)).is_none());
     }
 }
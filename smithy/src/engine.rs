@@ -1,45 +1,403 @@
-use ingots_loader::DynamicIngot;
+use config::Target;
+use ingots::Ingot;
+use ingots::blocking::{Blocking, BlockingPool};
+use ingots::http;
+use ingots::lifecycle::Lifecycle;
+use ingots::static_files::StaticFiles;
+use ingots_loader::{DynamicIngot, Error};
+use std::fs;
+use std::mem;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 
+/// An ingot put into service at a location.
+///
+/// Requests on HTTP/1 connections are handled on the listener thread that received them, through `Blocking::get_ref`,
+/// while requests on HTTP/2 connections are handled on the engine's blocking pool, so that waiting on their streams
+/// does not tie up a thread.
+pub type Instance = Arc<Blocking<Hosted>>;
+
+/// An ingot hosted by the engine, which refuses requests while it is not in service.
+pub struct Hosted(Lifecycle<Box<dyn Ingot>>);
+
+impl Ingot for Hosted {
+    fn handle(&self, context: &mut dyn http::Context) {
+        self.0.handle(context)
+    }
+}
+
+
+/// Routes requests to the ingots registered with the server.
+///
+/// A request is matched against every registered location. Locations bound to the request's virtual host take
+/// precedence over locations that match any host, whatever their prefixes; among those, the location with the longest
+/// prefix wins, and between locations with the same prefix, one that is constrained to the request method wins over an
+/// unconstrained one. Prefixes only match on path segment boundaries, so `/api` matches `/api` and `/api/users` but not
+/// `/apiary`.
+///
+/// Ingots loaded from shared libraries can be reloaded while the server is running. Each request holds a reference to
+/// the instance it was routed to, so requests in progress finish on the old instance while new requests are routed to
+/// the new one. The old instance is stopped and unloaded once its last request completes.
+///
+/// An ingot that fails to start is not taken out of the routing table; requests routed to it are refused with
+/// `503 Service Unavailable`, so they are not silently served by a location with a shorter prefix instead.
 pub struct IngotEngine {
     containers: Vec<IngotContainer>,
+    /// Instances that have been replaced, but may still be handling requests.
+    retired: Mutex<Vec<(PathBuf, Instance)>>,
+    /// Threads shared by every ingot for handling requests from HTTP/2 connections.
+    pool: Arc<BlockingPool>,
 }
 
 struct IngotContainer {
+    host: Option<String>,
+    /// Normalized prefix with no trailing slash, so that the root location is the empty string.
     prefix: String,
-    instance: DynamicIngot,
+    methods: Vec<String>,
+    target: Target,
+    instance: RwLock<Instance>,
+    reload: Mutex<ReloadState>,
+}
+
+struct ReloadState {
+    /// Modification time of the library file that was last loaded, or that failed to load.
+    seen: Option<SystemTime>,
+    /// Modification time of a change that has been seen, but not yet loaded.
+    pending: Option<SystemTime>,
+}
+
+/// Result of routing a request.
+pub enum Route<'a> {
+    /// An ingot was found to handle the request.
+    Found(Match<'a>),
+    /// One or more locations match the request path, but none of them accept the request method.
+    MethodNotAllowed(Vec<&'a str>),
+    /// No location matches the request.
+    NotFound,
+}
+
+/// An ingot matched to a request path.
+pub struct Match<'a> {
+    pub ingot: Instance,
+    /// The part of the request path that selected the ingot.
+    pub context_path: &'a str,
 }
 
 impl IngotEngine {
-    pub fn new() -> Self {
-        Self { containers: Vec::new() }
+    pub fn new(pool: Arc<BlockingPool>) -> Self {
+        Self {
+            containers: Vec::new(),
+            retired: Mutex::new(Vec::new()),
+            pool,
+        }
     }
 
-    pub fn register<S, P>(&mut self, prefix: S, path: P)
-        where S: Into<String>,
-              P: Into<PathBuf>
+    fn instance(&self, ingot: Lifecycle<Box<dyn Ingot>>) -> Instance {
+        Arc::new(Blocking::new(Hosted(ingot), self.pool.clone()))
+    }
+
+    /// Register an ingot or a static file directory to handle requests under a path prefix.
+    ///
+    /// If `host` is given, only requests for that virtual host are matched. If `methods` is non-empty, only requests
+    /// using one of the given methods are matched.
+    pub fn register<S>(&mut self, host: Option<&str>, prefix: S, methods: &[String], target: &Target) -> Result<(), Error>
+        where S: Into<String>
     {
-        let prefix = prefix.into();
-        let path = path.into();
+        let mut prefix = prefix.into();
 
-        info!("Loading ingot {:?} under prefix {}", path, prefix);
+        let (instance, modified): (Box<dyn Ingot>, _) = match *target {
+            Target::Ingot(ref path) => {
+                info!("Loading ingot {:?} under prefix {}{}", path, host.unwrap_or(""), prefix);
+
+                let instance = DynamicIngot::open(path)?;
+                let modified = instance.modified();
+                (Box::new(instance), modified)
+            }
+            Target::Root(ref path) => {
+                info!("Serving files from {:?} under prefix {}{}", path, host.unwrap_or(""), prefix);
 
-        let mut container = IngotContainer {
-            prefix: prefix,
-            instance: DynamicIngot::open(path).expect("could not load ingot"),
+                if !path.is_dir() {
+                    warn!("static file root {:?} is not a directory", path);
+                }
+                (Box::new(StaticFiles::new(path.clone())), None)
+            }
+        };
+
+        while prefix.ends_with('/') {
+            prefix.pop();
+        }
+
+        let instance = Lifecycle::new(instance);
+
+        if let Err(e) = instance.start() {
+            error!("ingot {:?} failed to start, refusing requests under prefix {}: {}", target.path(), prefix, e);
+        }
+
+        let container = IngotContainer {
+            host: host.map(|host| host.to_ascii_lowercase()),
+            prefix,
+            methods: methods.to_vec(),
+            target: target.clone(),
+            reload: Mutex::new(ReloadState {
+                seen: modified,
+                pending: None,
+            }),
+            instance: RwLock::new(self.instance(instance)),
         };
 
         self.containers.push(container);
+
+        Ok(())
     }
 
-    pub fn find_ingot_for_url(&self, url: &str) -> Option<&DynamicIngot> {
+    /// Find the ingot that should handle a request.
+    ///
+    /// The host is the value of the request's `Host` header, if any, and may include a port.
+    pub fn route(&self, host: Option<&str>, method: &str, path: &str) -> Route<'_> {
+        let host = host.map(strip_port);
+        let mut best: Option<&IngotContainer> = None;
+        let mut allowed = Vec::new();
+
         for container in self.containers.iter() {
-            if url.starts_with(&container.prefix) {
-                return Some(&container.instance);
+            if !container.matches_host(host) || !container.matches_path(path) {
+                continue;
+            }
+
+            if !container.matches_method(method) {
+                allowed.extend(container.methods.iter().map(String::as_str));
+                continue;
+            }
+
+            if best.is_none_or(|best| container.rank() > best.rank()) {
+                best = Some(container);
             }
         }
 
-        None
+        match best {
+            Some(container) => Route::Found(Match {
+                ingot: container.instance.read().unwrap().clone(),
+                context_path: &container.prefix,
+            }),
+            None if !allowed.is_empty() => {
+                allowed.sort();
+                allowed.dedup();
+                Route::MethodNotAllowed(allowed)
+            }
+            None => Route::NotFound,
+        }
+    }
+}
+
+impl IngotEngine {
+    /// Spawn a thread that reloads ingots whose library files change on disk, checking at the given interval.
+    pub fn watch(engine: Arc<IngotEngine>, interval: Duration) {
+        thread::Builder::new()
+            .name(String::from("smithy-reload"))
+            .spawn(move || loop {
+                thread::sleep(interval);
+                engine.reload_modified();
+                engine.unload_retired();
+            })
+            .expect("failed to spawn reload thread");
+    }
+
+    /// Reload every ingot whose library file has changed since it was loaded.
+    ///
+    /// A change is only picked up once the file has been left alone for one full check, so that a library is not loaded
+    /// while it is still being written.
+    pub fn reload_modified(&self) {
+        for container in self.containers.iter() {
+            let path = match container.target {
+                Target::Ingot(ref path) => path,
+                Target::Root(_) => continue,
+            };
+            let mut state = container.reload.lock().unwrap();
+
+            // The file may be missing while it is being replaced.
+            let modified = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+                Ok(modified) => Some(modified),
+                Err(_) => continue,
+            };
+
+            if modified == state.seen {
+                continue;
+            }
+
+            if modified != state.pending {
+                state.pending = modified;
+                continue;
+            }
+
+            // Whether or not loading succeeds, don't try again until the file changes.
+            state.seen = modified;
+            state.pending = None;
+
+            info!("Reloading ingot {:?}", path);
+
+            let ingot = match DynamicIngot::open(path) {
+                Ok(ingot) => Lifecycle::new(Box::new(ingot) as Box<dyn Ingot>),
+                Err(e) => {
+                    error!("failed to reload ingot: {}", e);
+                    continue;
+                }
+            };
+
+            // Keep serving from the old instance if the new one is broken.
+            if let Err(e) = ingot.start() {
+                error!("reloaded ingot {:?} failed to start, keeping previous instance: {}", path, e);
+                continue;
+            }
+
+            let old = mem::replace(&mut *container.instance.write().unwrap(), self.instance(ingot));
+            self.retired.lock().unwrap().push((path.clone(), old));
+        }
+    }
+
+    /// Stop and unload replaced instances that are no longer handling any requests.
+    pub fn unload_retired(&self) {
+        let retired = mem::take(&mut *self.retired.lock().unwrap());
+        let mut still_busy = Vec::new();
+
+        for (path, ingot) in retired {
+            match Arc::try_unwrap(ingot) {
+                Ok(ingot) => {
+                    ingot.get_ref().0.shutdown(Duration::from_secs(0));
+                    info!("Unloaded previous instance of ingot {:?}", path);
+                }
+                Err(ingot) => still_busy.push((path, ingot)),
+            }
+        }
+
+        self.retired.lock().unwrap().extend(still_busy);
+    }
+
+    /// Stop every ingot, waiting up to the given timeout in total for requests in progress to complete.
+    ///
+    /// New requests are refused once this method is called. Returns `false` if some ingots still had requests in
+    /// progress when the timeout elapsed; those ingots are not stopped.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut drained = true;
+
+        let retired = self.retired.lock().unwrap().clone();
+        let current = self.containers.iter()
+            .map(|container| (container.target.path().to_owned(), container.instance.read().unwrap().clone()));
+
+        for (path, ingot) in current.chain(retired) {
+            let ingot = &ingot.get_ref().0;
+
+            if !ingot.shutdown(deadline.saturating_duration_since(Instant::now())) {
+                warn!("ingot {:?} still has {} requests in progress, not stopping it", path, ingot.in_flight());
+                drained = false;
+            }
+        }
+
+        drained
+    }
+}
+
+impl IngotContainer {
+    fn matches_host(&self, host: Option<&str>) -> bool {
+        match (self.host.as_ref(), host) {
+            (None, _) => true,
+            (Some(expected), Some(host)) => expected.eq_ignore_ascii_case(host),
+            (Some(_), None) => false,
+        }
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        path.starts_with(&self.prefix) && match path.as_bytes().get(self.prefix.len()) {
+            None | Some(b'/') => true,
+            Some(_) => false,
+        }
+    }
+
+    fn matches_method(&self, method: &str) -> bool {
+        self.methods.is_empty() || self.methods.iter().any(|m| m == method)
+    }
+
+    /// Precedence of this location relative to other matching locations.
+    fn rank(&self) -> (bool, usize, bool) {
+        (self.host.is_some(), self.prefix.len(), !self.methods.is_empty())
+    }
+}
+
+/// Remove the port from a `Host` header value, taking care not to split IPv6 literals.
+pub fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(index) => &host[..index + 1],
+            None => host,
+        };
+    }
+
+    match host.rfind(':') {
+        Some(index) => &host[..index],
+        None => host,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use config::Target;
+    use std::path::PathBuf;
+    use super::*;
+
+    fn engine(locations: &[(Option<&str>, &str, &[&str])]) -> IngotEngine {
+        let mut engine = IngotEngine::new(Arc::new(BlockingPool::new(1, 0)));
+
+        for (index, &(host, prefix, methods)) in locations.iter().enumerate() {
+            let methods: Vec<String> = methods.iter().map(|&method| method.to_owned()).collect();
+            let target = Target::Root(PathBuf::from(format!("root{}", index)));
+            engine.register(host, prefix, &methods, &target).unwrap();
+        }
+
+        engine
+    }
+
+    /// Get the location a request was routed to, as its index in the list it was registered from.
+    fn route(engine: &IngotEngine, host: Option<&str>, method: &str, path: &str) -> Result<usize, Vec<String>> {
+        match engine.route(host, method, path) {
+            Route::Found(found) => Ok(engine.containers.iter()
+                .position(|container| Arc::ptr_eq(&container.instance.read().unwrap(), &found.ingot))
+                .unwrap()),
+            Route::MethodNotAllowed(allowed) => Err(allowed.into_iter().map(String::from).collect()),
+            Route::NotFound => Err(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn longest_prefix() {
+        let engine = engine(&[(None, "/", &[]), (None, "/api/", &[]), (None, "/api/v1", &[])]);
+
+        assert_eq!(route(&engine, None, "GET", "/"), Ok(0));
+        assert_eq!(route(&engine, None, "GET", "/api"), Ok(1));
+        assert_eq!(route(&engine, None, "GET", "/api/users"), Ok(1));
+        assert_eq!(route(&engine, None, "GET", "/api/v1/users"), Ok(2));
+        assert_eq!(route(&engine, None, "GET", "/apiary"), Ok(0));
+    }
+
+    #[test]
+    fn host_takes_precedence_over_prefix() {
+        let engine = engine(&[(None, "/api/v1", &[]), (Some("Example.com"), "/", &[]), (None, "/", &[])]);
+
+        assert_eq!(route(&engine, Some("example.com:8001"), "GET", "/api/v1/users"), Ok(1));
+        assert_eq!(route(&engine, Some("EXAMPLE.COM"), "GET", "/"), Ok(1));
+        assert_eq!(route(&engine, Some("other.example.com"), "GET", "/api/v1/users"), Ok(0));
+        assert_eq!(route(&engine, None, "GET", "/"), Ok(2));
+    }
+
+    #[test]
+    fn methods() {
+        let engine = engine(&[(None, "/api", &[]), (None, "/api", &["POST"]), (None, "/upload", &["PUT", "POST"])]);
+
+        assert_eq!(route(&engine, None, "POST", "/api"), Ok(1));
+        assert_eq!(route(&engine, None, "GET", "/api"), Ok(0));
+        assert_eq!(route(&engine, None, "GET", "/upload"), Err(vec![String::from("POST"), String::from("PUT")]));
+        assert_eq!(route(&engine, None, "GET", "/missing"), Err(Vec::new()));
     }
 }
@@ -0,0 +1,764 @@
+//! Serving HTTP/2 connections.
+//!
+//! Connections are driven on the runtime by `Serve`. Each request is handed to an ingot as an `Http2Context`, whose
+//! request and response bodies are polled on the stream, and the request is completed by a task on the runtime.
+use bytes::{Buf, Bytes};
+use h2;
+use h2::{RecvStream, SendStream};
+use h2::server::{Connection, Handshake, SendResponse};
+use http;
+use http::header::{HeaderName, HeaderValue};
+use ingots::async_io::{AsyncRead as IngotRead, AsyncWrite as IngotWrite};
+use ingots::http::{AsyncContext, AsyncRequest, AsyncResponse};
+use ingots::http::{Buffering, HeaderMap, HeadersSentError, StatusCode, Version};
+use listener::{self, Accept};
+use server::Handler;
+use std::borrow::Cow;
+use std::cmp;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{self, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio;
+use tokio::time::{self, Sleep};
+
+
+/// Size of the response buffer when an ingot turns buffering on.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Headers that only apply to an HTTP/1 connection, and must not be sent over HTTP/2.
+const CONNECTION_HEADERS: &[&str] = &["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+
+/// Start serving HTTP/2 on a connection on which the client has sent, or is about to send, the connection preface.
+///
+/// The handshake must complete within the timeout of the listener, and the client is limited to the listener's maximum
+/// number of concurrent streams.
+pub fn serve<T>(io: T, remote_addr: SocketAddr, secure: bool, accept: &Accept) -> Serve<T>
+    where T: AsyncRead + AsyncWrite + Unpin
+{
+    let handshake = h2::server::Builder::new()
+        .max_concurrent_streams(accept.max_streams)
+        .handshake(io);
+
+    Serve {
+        state: State::Handshaking(handshake),
+        deadline: Box::pin(time::sleep(accept.timeout)),
+        remote_addr,
+        secure,
+        handler: accept.handler.clone(),
+    }
+}
+
+/// Future that accepts the requests on an HTTP/2 connection until it is closed.
+pub struct Serve<T> {
+    state: State<T>,
+    /// Deadline for the handshake.
+    deadline: Pin<Box<Sleep>>,
+    remote_addr: SocketAddr,
+    secure: bool,
+    handler: Handler,
+}
+
+enum State<T> {
+    Handshaking(Handshake<T, Bytes>),
+    Accepting(Connection<T, Bytes>),
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Future for Serve<T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<()> {
+        let this = self.get_mut();
+
+        loop {
+            let connection = match this.state {
+                State::Handshaking(ref mut handshake) => match Pin::new(handshake).poll(cx) {
+                    Poll::Ready(Ok(connection)) => connection,
+                    Poll::Ready(Err(e)) => {
+                        debug!("HTTP/2 handshake with {} failed: {}", this.remote_addr, e);
+                        return Poll::Ready(());
+                    }
+                    Poll::Pending if listener::expired(&mut this.deadline, cx) => {
+                        debug!("HTTP/2 handshake with {} timed out", this.remote_addr);
+                        return Poll::Ready(());
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                // Polling for the next request also drives the streams of the requests already accepted.
+                State::Accepting(ref mut connection) => match connection.poll_accept(cx) {
+                    Poll::Ready(Some(Ok((request, respond)))) => {
+                        tokio::spawn(this.handler.handle_http2(request, respond, this.remote_addr, this.secure));
+                        continue;
+                    }
+                    Poll::Ready(Some(Err(e))) => {
+                        debug!("HTTP/2 connection with {} failed: {}", this.remote_addr, e);
+                        return Poll::Ready(());
+                    }
+                    Poll::Ready(None) => return Poll::Ready(()),
+                    Poll::Pending => return Poll::Pending,
+                },
+            };
+
+            this.state = State::Accepting(connection);
+        }
+    }
+}
+
+
+/// Send a response without a body to a request that was not handed to an ingot.
+pub fn send_empty(mut respond: SendResponse<Bytes>, status: u16, headers: &[(&str, &str)]) {
+    let mut response = http::Response::new(());
+    *response.status_mut() = http::StatusCode::from_u16(status).unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR);
+
+    for &(name, value) in headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+            response.headers_mut().append(name, value);
+        }
+    }
+
+    if let Err(e) = respond.send_response(response, true) {
+        warn!("failed to send response: {}", e);
+    }
+}
+
+
+/// Request context for a request on an HTTP/2 stream.
+///
+/// Reading the request body and writing the response body poll the stream, so a read or write that has to wait for the
+/// client does not tie up a thread. With buffering off, which is the default, every write is sent to the client as
+/// DATA frames right away. The response is completed when the context is dropped.
+pub struct Http2Context {
+    server_addr: SocketAddr,
+    server_name: String,
+    remote_addr: SocketAddr,
+    request: Http2Request,
+    response: Http2Response,
+}
+
+impl Http2Context {
+    /// Create a context for a request routed to an ingot mounted at the given context path.
+    pub fn new(
+        server_addr: SocketAddr,
+        remote_addr: SocketAddr,
+        secure: bool,
+        context_path: &str,
+        request: http::Request<RecvStream>,
+        respond: SendResponse<Bytes>,
+    ) -> Self {
+        let (parts, body) = request.into_parts();
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in parts.headers.iter() {
+            headers.append(name.as_str(), String::from_utf8_lossy(value.as_bytes()).into_owned());
+        }
+
+        // The host is sent in the :authority pseudo-header instead, but ingots look for it in the Host header.
+        if !headers.contains("Host") {
+            if let Some(authority) = parts.uri.authority() {
+                headers.insert("Host", authority.as_str());
+            }
+        }
+
+        let head = parts.method == http::Method::HEAD;
+
+        Self {
+            server_addr,
+            server_name: server_addr.ip().to_string(),
+            remote_addr,
+            request: Http2Request {
+                method: parts.method.as_str().to_owned(),
+                context_path: context_path.to_owned(),
+                path: parts.uri.path().to_owned(),
+                query: parts.uri.query().map(str::to_owned),
+                headers,
+                secure,
+                body,
+                chunk: Bytes::new(),
+            },
+            response: Http2Response {
+                state: ResponseState::Fresh(respond),
+                status: 200,
+                headers: HeaderMap::new(),
+                buffer: Vec::new(),
+                buffer_size: 0,
+                head,
+            },
+        }
+    }
+}
+
+impl AsyncContext for Http2Context {
+    fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    fn request(&self) -> &dyn AsyncRequest {
+        &self.request
+    }
+
+    fn request_mut(&mut self) -> &mut dyn AsyncRequest {
+        &mut self.request
+    }
+
+    fn response(&self) -> &dyn AsyncResponse {
+        &self.response
+    }
+
+    fn response_mut(&mut self) -> &mut dyn AsyncResponse {
+        &mut self.response
+    }
+}
+
+struct Http2Request {
+    method: String,
+    context_path: String,
+    path: String,
+    query: Option<String>,
+    headers: HeaderMap,
+    secure: bool,
+    body: RecvStream,
+    /// Data received but not yet read by the ingot.
+    chunk: Bytes,
+}
+
+impl AsyncRequest for Http2Request {
+    fn method(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.method)
+    }
+
+    fn context_path(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.context_path)
+    }
+
+    fn path_info(&self) -> Cow<'_, str> {
+        // The router only matches prefixes on segment boundaries, so the remainder is empty or starts with a slash.
+        Cow::Borrowed(&self.path[self.context_path.len()..])
+    }
+
+    fn query_string(&self) -> Option<Cow<'_, str>> {
+        self.query.as_ref().map(|query| Cow::Borrowed(query.as_str()))
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    fn version(&self) -> Version {
+        Version::Http2
+    }
+}
+
+impl IngotRead for Http2Request {
+    fn poll_read(&mut self, cx: &mut task::Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        while self.chunk.is_empty() {
+            match self.body.poll_data(cx) {
+                Poll::Ready(Some(Ok(data))) => {
+                    // Let the client send more as soon as the data has been taken off the connection.
+                    let _ = self.body.flow_control().release_capacity(data.len());
+                    self.chunk = data;
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(io::Error::other(e))),
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let len = cmp::min(buf.len(), self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk[..len]);
+        self.chunk.advance(len);
+
+        Poll::Ready(Ok(len))
+    }
+}
+
+enum ResponseState {
+    /// Status and headers are still being collected.
+    Fresh(SendResponse<Bytes>),
+    /// Status and headers have been sent, and the body is being written.
+    Streaming(SendStream<Bytes>),
+    /// The stream has ended, or sending on it failed; nothing more can be written.
+    Closed,
+}
+
+struct Http2Response {
+    state: ResponseState,
+    status: StatusCode,
+    headers: HeaderMap,
+    buffer: Vec<u8>,
+    /// Maximum size of the buffer, or zero if buffering is off.
+    buffer_size: usize,
+    /// Whether the request is a HEAD request, in which case the body is discarded.
+    head: bool,
+}
+
+impl Http2Response {
+    /// Send the status and headers to the client if they have not been sent already.
+    fn start(&mut self, end_of_stream: bool) -> io::Result<()> {
+        if let ResponseState::Fresh(_) = self.state {
+            let mut respond = match mem::replace(&mut self.state, ResponseState::Closed) {
+                ResponseState::Fresh(respond) => respond,
+                _ => unreachable!(),
+            };
+
+            let stream = respond.send_response(self.build_response(), end_of_stream).map_err(io::Error::other)?;
+
+            if !end_of_stream {
+                self.state = ResponseState::Streaming(stream);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn build_response(&self) -> http::Response<()> {
+        let mut response = http::Response::new(());
+        *response.status_mut() = http::StatusCode::from_u16(self.status)
+            .unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR);
+
+        for (name, value) in self.headers.iter() {
+            if CONNECTION_HEADERS.iter().any(|header| name.eq_ignore_ascii_case(header)) {
+                continue;
+            }
+
+            match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                (Ok(name), Ok(value)) => {
+                    response.headers_mut().append(name, value);
+                }
+                _ => warn!("dropping invalid response header {:?}", name),
+            }
+        }
+
+        response
+    }
+
+    /// Send as much of the given data as the client has granted flow control capacity for, returning how much was
+    /// sent.
+    fn poll_send(&mut self, cx: &mut task::Context, data: &[u8]) -> Poll<io::Result<usize>> {
+        if self.head || data.is_empty() {
+            return Poll::Ready(Ok(data.len()));
+        }
+
+        let stream = match self.state {
+            ResponseState::Streaming(ref mut stream) => stream,
+            _ => return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "response stream is closed"))),
+        };
+
+        stream.reserve_capacity(data.len());
+
+        loop {
+            let capacity = match stream.poll_capacity(cx) {
+                Poll::Ready(Some(Ok(0))) => continue,
+                Poll::Ready(Some(Ok(capacity))) => capacity,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(io::Error::other(e))),
+                Poll::Ready(None) => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "response stream is closed")));
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let len = cmp::min(capacity, data.len());
+            stream.send_data(Bytes::copy_from_slice(&data[..len]), false).map_err(io::Error::other)?;
+
+            return Poll::Ready(Ok(len));
+        }
+    }
+
+    /// Send the status and headers if they have not been sent yet, followed by everything in the buffer.
+    fn poll_send_buffer(&mut self, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        self.start(false)?;
+
+        let mut buffer = mem::take(&mut self.buffer);
+        let result = loop {
+            if buffer.is_empty() {
+                break Poll::Ready(Ok(()));
+            }
+
+            match self.poll_send(cx, &buffer) {
+                Poll::Ready(Ok(len)) => {
+                    buffer.drain(..len);
+                }
+                Poll::Ready(Err(e)) => break Poll::Ready(Err(e)),
+                Poll::Pending => break Poll::Pending,
+            }
+        };
+
+        self.buffer = buffer;
+        result
+    }
+
+    /// Complete the response, sending the headers if nothing has been written yet.
+    ///
+    /// The rest of the body is queued by the connection until the client grants capacity for it, so this never waits.
+    /// At most one buffer's worth of data is left by then.
+    fn finish(&mut self) -> io::Result<()> {
+        let buffer = mem::take(&mut self.buffer);
+
+        if let ResponseState::Fresh(_) = self.state {
+            if buffer.is_empty() {
+                return self.start(true);
+            }
+
+            // The whole body is known, so its length can be sent with the headers.
+            self.headers.insert("Content-Length", buffer.len().to_string());
+            self.start(false)?;
+        }
+
+        let data = match self.head {
+            true => Bytes::new(),
+            false => Bytes::from(buffer),
+        };
+
+        match mem::replace(&mut self.state, ResponseState::Closed) {
+            ResponseState::Streaming(mut stream) => stream.send_data(data, true).map_err(io::Error::other),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_headers_sent(&self) -> Result<(), HeadersSentError> {
+        match self.state {
+            ResponseState::Fresh(_) => Ok(()),
+            _ => Err(HeadersSentError),
+        }
+    }
+}
+
+impl Drop for Http2Response {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("failed to send response: {}", e);
+        }
+    }
+}
+
+impl AsyncResponse for Http2Response {
+    fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    fn set_status(&mut self, status: StatusCode) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.status = status;
+        Ok(())
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn set_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.headers.insert(name, value);
+        Ok(())
+    }
+
+    fn append_header(&mut self, name: &str, value: String) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.headers.append(name, value);
+        Ok(())
+    }
+
+    fn remove_header(&mut self, name: &str) -> Result<(), HeadersSentError> {
+        self.check_headers_sent()?;
+        self.headers.remove(name);
+        Ok(())
+    }
+
+    fn buffering(&self) -> Buffering {
+        match self.buffer_size {
+            0 => Buffering::Off,
+            size => Buffering::On(size as u32),
+        }
+    }
+
+    fn set_buffering(&mut self, buffering: bool) -> bool {
+        self.buffer_size = if buffering { BUFFER_SIZE } else { 0 };
+        true
+    }
+
+    fn headers_sent(&self) -> bool {
+        self.check_headers_sent().is_err()
+    }
+}
+
+impl IngotWrite for Http2Response {
+    fn poll_write(&mut self, cx: &mut task::Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.buffer.len() >= self.buffer_size {
+            // Also sends what is left over from when buffering was on.
+            match self.poll_send_buffer(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        if self.buffer_size == 0 {
+            return self.poll_send(cx, buf);
+        }
+
+        let len = cmp::min(buf.len(), self.buffer_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(&mut self, cx: &mut task::Context) -> Poll<io::Result<()>> {
+        self.poll_send_buffer(cx)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use h2::client::{self, ResponseFuture};
+    use ingots::{AsyncIngot, Ingot};
+    use ingots::blocking::{Blocking, BlockingPool};
+    use ingots::http::Context;
+    use std::future;
+    use std::net;
+    use std::sync::Arc;
+    use tokio::net::TcpStream;
+    use tokio::runtime::{self, Runtime};
+    use super::*;
+
+    struct Respond<F>(F);
+
+    impl<F: Fn(&mut dyn Context) + Send + Sync> Ingot for Respond<F> {
+        fn handle(&self, context: &mut dyn Context) {
+            (self.0)(context)
+        }
+    }
+
+    /// A request sent by an h2 client over a loopback connection, and handled by an ingot through an `Http2Context`.
+    struct Exchange {
+        runtime: Runtime,
+        _pool: Arc<BlockingPool>,
+        /// The rest of the request body, if the request was sent without ending the stream.
+        body: SendStream<Bytes>,
+        response: Option<ResponseFuture>,
+    }
+
+    impl Exchange {
+        fn new<F>(request: http::Request<()>, end_of_stream: bool, respond: F) -> Self
+            where F: Fn(&mut dyn Context) + Send + Sync + 'static
+        {
+            let runtime = runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+            let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let server_addr = listener.local_addr().unwrap();
+            let client = net::TcpStream::connect(server_addr).unwrap();
+            let (server, remote_addr) = listener.accept().unwrap();
+
+            let (client, server) = {
+                let _guard = runtime.enter();
+                client.set_nonblocking(true).unwrap();
+                server.set_nonblocking(true).unwrap();
+                (TcpStream::from_std(client).unwrap(), TcpStream::from_std(server).unwrap())
+            };
+
+            let (client, client_connection) = runtime.block_on(client::handshake(client)).unwrap();
+            runtime.spawn(client_connection);
+            let mut connection = runtime.block_on(h2::server::handshake(server)).unwrap();
+
+            let mut client = runtime.block_on(client.ready()).unwrap();
+            let (response, body) = client.send_request(request, end_of_stream).unwrap();
+
+            let (request, respond_to) = runtime.block_on(future::poll_fn(|cx| connection.poll_accept(cx)))
+                .unwrap()
+                .unwrap();
+            let context = Http2Context::new(server_addr, remote_addr, false, "", request, respond_to);
+
+            let pool = Arc::new(BlockingPool::new(1, 0));
+            runtime.spawn(Blocking::new(Respond(respond), pool.clone()).handle(Box::new(context)));
+
+            // Keep driving the connection until the client closes it.
+            runtime.spawn(future::poll_fn(move |cx| loop {
+                match connection.poll_accept(cx) {
+                    Poll::Ready(Some(Ok(_))) => continue,
+                    Poll::Ready(_) => return Poll::Ready(()),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }));
+
+            Self {
+                runtime,
+                _pool: pool,
+                body,
+                response: Some(response),
+            }
+        }
+
+        fn get(method: &str, respond: impl Fn(&mut dyn Context) + Send + Sync + 'static) -> Self {
+            let request = http::Request::builder().method(method).uri("http://localhost/").body(()).unwrap();
+            Self::new(request, true, respond)
+        }
+
+        fn response(&mut self) -> (http::response::Parts, RecvStream) {
+            let response = self.runtime.block_on(self.response.take().unwrap()).unwrap();
+            response.into_parts()
+        }
+
+        fn send_body(&mut self, data: &'static [u8]) {
+            self.body.send_data(Bytes::from_static(data), true).unwrap();
+        }
+
+        /// Receive the next chunk of a response body, or `None` at the end of the body.
+        fn next_chunk(&self, body: &mut RecvStream) -> Option<Vec<u8>> {
+            let data = self.runtime.block_on(body.data())?.unwrap();
+            let _ = body.flow_control().release_capacity(data.len());
+            Some(data.to_vec())
+        }
+
+        fn read_body(&self, body: &mut RecvStream) -> Vec<u8> {
+            let mut data = Vec::new();
+            while let Some(chunk) = self.next_chunk(body) {
+                data.extend_from_slice(&chunk);
+            }
+            data
+        }
+    }
+
+    /// Copy the request body to the response.
+    fn echo(context: &mut dyn Context) {
+        let mut body = Vec::new();
+        context.request_mut().read_to_end(&mut body).unwrap();
+        context.response().write_all(&body).unwrap();
+    }
+
+    #[test]
+    fn unbuffered_writes_are_sent_right_away() {
+        let request = http::Request::builder().method("POST").uri("http://localhost/").body(()).unwrap();
+        let mut exchange = Exchange::new(request, false, |context| {
+            context.response().write_all(b"hello ").unwrap();
+            echo(context);
+        });
+
+        let (parts, mut body) = exchange.response();
+        assert_eq!(parts.status, 200);
+        assert!(!parts.headers.contains_key("content-length"));
+
+        // The first write arrives while the ingot is still waiting for the request body.
+        assert_eq!(exchange.next_chunk(&mut body).unwrap(), b"hello ");
+
+        exchange.send_body(b"world");
+        assert_eq!(exchange.read_body(&mut body), b"world");
+    }
+
+    #[test]
+    fn buffered_response_has_length() {
+        let mut exchange = Exchange::get("GET", |context| {
+            assert!(context.response().set_buffering(true));
+            context.response().write_all(b"hello").unwrap();
+        });
+
+        let (parts, mut body) = exchange.response();
+        assert_eq!(parts.headers["content-length"], "5");
+        assert_eq!(exchange.read_body(&mut body), b"hello");
+    }
+
+    #[test]
+    fn flush_sends_buffer() {
+        let request = http::Request::builder().method("POST").uri("http://localhost/").body(()).unwrap();
+        let mut exchange = Exchange::new(request, false, |context| {
+            context.response().set_buffering(true);
+            context.response().write_all(b"hello ").unwrap();
+            context.response().flush().unwrap();
+            echo(context);
+        });
+
+        let (parts, mut body) = exchange.response();
+        assert!(!parts.headers.contains_key("content-length"));
+        assert_eq!(exchange.next_chunk(&mut body).unwrap(), b"hello ");
+
+        exchange.send_body(b"world");
+        assert_eq!(exchange.read_body(&mut body), b"world");
+    }
+
+    #[test]
+    fn empty_response() {
+        let mut exchange = Exchange::get("GET", |context| {
+            context.response().set_status(204).unwrap();
+        });
+
+        let (parts, body) = exchange.response();
+        assert_eq!(parts.status, 204);
+        assert!(body.is_end_stream());
+    }
+
+    #[test]
+    fn head_discards_body() {
+        for &buffering in &[false, true] {
+            let mut exchange = Exchange::get("HEAD", move |context| {
+                context.response().set_buffering(buffering);
+                context.response().write_all(b"hello").unwrap();
+            });
+
+            let (parts, mut body) = exchange.response();
+            assert_eq!(parts.headers.contains_key("content-length"), buffering);
+            assert_eq!(exchange.read_body(&mut body), b"");
+        }
+    }
+
+    #[test]
+    fn connection_headers_are_stripped() {
+        let mut exchange = Exchange::get("GET", |context| {
+            for &name in CONNECTION_HEADERS {
+                context.response().set_header(name, String::from("x")).unwrap();
+            }
+            context.response().set_header("Connection", String::from("close")).unwrap();
+            context.response().set_header("X-Custom", String::from("1")).unwrap();
+        });
+
+        let (parts, _) = exchange.response();
+        for &name in CONNECTION_HEADERS {
+            assert!(!parts.headers.contains_key(name), "{} was sent", name);
+        }
+        assert_eq!(parts.headers["x-custom"], "1");
+    }
+
+    #[test]
+    fn request() {
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("http://localhost/a/b?c=d")
+            .header("x-custom", "1")
+            .body(())
+            .unwrap();
+
+        let mut exchange = Exchange::new(request, false, |context| {
+            let request = context.request();
+            let properties = format!(
+                "{} {} {:?} {:?} {:?} {:?}",
+                request.method(),
+                request.path_info(),
+                request.query_string(),
+                request.headers().get("Host"),
+                request.headers().get("X-Custom"),
+                request.version(),
+            );
+
+            context.response().set_header("X-Request", properties).unwrap();
+            echo(context);
+        });
+        exchange.send_body(b"data");
+
+        let (parts, mut body) = exchange.response();
+        assert_eq!(parts.headers["x-request"], r#"POST /a/b Some("c=d") Some("localhost") Some("1") Http2"#);
+        assert_eq!(exchange.read_body(&mut body), b"data");
+    }
+}
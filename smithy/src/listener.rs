@@ -0,0 +1,480 @@
+//! Accepting connections and dispatching them by protocol.
+//!
+//! Connections are accepted on an asynchronous runtime, which performs the TLS handshake and detects the protocol without
+//! tying up a request thread. HTTP/2 connections are served on the runtime; see `http2`. HTTP/1 connections are handed
+//! over to hyper's worker threads through a `ChannelListener`.
+use http2;
+use hyper;
+use hyper::net::{HttpStream, NetworkListener, NetworkStream};
+use server::Handler;
+use std::future::{self, Future};
+use std::io;
+use std::net::{self, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::task::{self, Poll};
+use std::time::Duration;
+use tls::TlsStream;
+use tokio;
+use tokio::io::ReadBuf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio::time::{self, Sleep};
+use tokio_rustls::{self, TlsAcceptor};
+
+
+/// The connection preface a client sends to start HTTP/2 with prior knowledge.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// How long to wait before checking again for the rest of a partially received preface.
+const PREFACE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+
+/// Settings for accepting connections on a listener.
+#[derive(Clone)]
+pub struct Accept {
+    /// Handles requests on HTTP/2 connections.
+    pub handler: Handler,
+    /// Whether HTTP/2 is accepted on the listener.
+    pub http2: bool,
+    /// How long a client may take to complete the TLS handshake or send its first bytes, and to complete the HTTP/2
+    /// handshake after that.
+    pub timeout: Duration,
+    /// Maximum number of concurrent streams on an HTTP/2 connection, so that one client cannot queue more requests
+    /// than there are threads to handle them.
+    pub max_streams: u32,
+}
+
+/// Accept plain TCP connections, serving those that start with the HTTP/2 preface as h2c.
+pub fn accept_plain(runtime: &Runtime, listener: net::TcpListener, accept: Accept)
+    -> io::Result<ChannelListener<HttpStream>>
+{
+    let (listener, local_addr) = register(runtime, listener)?;
+    let (sender, receiver) = mpsc::channel();
+
+    runtime.spawn(accept_loop(listener, move |stream, remote_addr| {
+        if !accept.http2 {
+            return hand_over(stream, remote_addr, &sender, HttpStream);
+        }
+
+        tokio::spawn(PlainConnection {
+            stream: Some(stream),
+            remote_addr,
+            deadline: Box::pin(time::sleep(accept.timeout)),
+            retry: None,
+            received: false,
+            accept: accept.clone(),
+            sender: sender.clone(),
+        });
+    }));
+
+    Ok(ChannelListener::new(receiver, local_addr))
+}
+
+/// Accept TLS connections, serving those that negotiate `h2` with ALPN as HTTP/2.
+pub fn accept_tls(runtime: &Runtime, listener: net::TcpListener, acceptor: TlsAcceptor, accept: Accept)
+    -> io::Result<ChannelListener<TlsStream>>
+{
+    let (listener, local_addr) = register(runtime, listener)?;
+    let (sender, receiver) = mpsc::channel();
+
+    runtime.spawn(accept_loop(listener, move |stream, remote_addr| {
+        tokio::spawn(TlsConnection {
+            handshake: acceptor.accept(stream),
+            remote_addr,
+            deadline: Box::pin(time::sleep(accept.timeout)),
+            accept: accept.clone(),
+            sender: sender.clone(),
+        });
+    }));
+
+    Ok(ChannelListener::new(receiver, local_addr))
+}
+
+/// Move a bound listener onto the runtime.
+fn register(runtime: &Runtime, listener: net::TcpListener) -> io::Result<(TcpListener, SocketAddr)> {
+    let local_addr = listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+
+    let _guard = runtime.enter();
+    Ok((TcpListener::from_std(listener)?, local_addr))
+}
+
+/// Future that passes every accepted connection to the given function.
+fn accept_loop<F>(listener: TcpListener, mut dispatch: F) -> impl Future<Output = ()> + Send
+    where F: FnMut(TcpStream, SocketAddr) + Send + 'static
+{
+    future::poll_fn(move |cx| loop {
+        match listener.poll_accept(cx) {
+            Poll::Ready(Ok((stream, remote_addr))) => dispatch(stream, remote_addr),
+            Poll::Ready(Err(e)) => warn!("failed to accept connection: {}", e),
+            Poll::Pending => return Poll::Pending,
+        }
+    })
+}
+
+/// Send a connection to the HTTP/1 worker threads as a blocking stream.
+fn hand_over<S, F>(stream: TcpStream, remote_addr: SocketAddr, sender: &Sender<S>, wrap: F)
+    where F: FnOnce(net::TcpStream) -> S
+{
+    let result = stream.into_std().and_then(|stream| {
+        stream.set_nonblocking(false)?;
+        sender.send(wrap(stream)).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "listener has shut down"))
+    });
+
+    if let Err(e) = result {
+        debug!("failed to hand over connection from {}: {}", remote_addr, e);
+    }
+}
+
+/// Check whether a deadline has passed, registering for a wakeup when it does if not.
+pub fn expired(deadline: &mut Pin<Box<Sleep>>, cx: &mut task::Context) -> bool {
+    deadline.as_mut().poll(cx).is_ready()
+}
+
+
+/// A plain connection waiting for its first bytes, to tell HTTP/2 with prior knowledge from HTTP/1.
+///
+/// The bytes are only peeked at, so that hyper can read the request from the start if the connection is HTTP/1.
+struct PlainConnection {
+    stream: Option<TcpStream>,
+    remote_addr: SocketAddr,
+    /// After the deadline the connection is closed if the client has sent nothing, and otherwise assumed to be HTTP/1
+    /// and left to the read timeout of the worker threads.
+    deadline: Pin<Box<Sleep>>,
+    /// Delay before peeking again at a partially received preface.
+    retry: Option<Pin<Box<Sleep>>>,
+    /// Whether the client has sent anything yet.
+    received: bool,
+    accept: Accept,
+    sender: Sender<HttpStream>,
+}
+
+impl PlainConnection {
+    fn poll_preface(&mut self, cx: &mut task::Context) -> Poll<bool> {
+        let stream = self.stream.as_ref().unwrap();
+        let mut buf = [0; PREFACE.len()];
+
+        loop {
+            if let Some(ref mut retry) = self.retry {
+                if retry.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.retry = None;
+            }
+
+            let mut read_buf = ReadBuf::new(&mut buf);
+            let len = match stream.poll_peek(cx, &mut read_buf) {
+                Poll::Ready(Ok(len)) => len,
+                Poll::Ready(Err(_)) => return Poll::Ready(false),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            self.received |= len > 0;
+            if len == 0 || buf[..len] != PREFACE[..len] {
+                return Poll::Ready(false);
+            }
+            if len == PREFACE.len() {
+                return Poll::Ready(true);
+            }
+
+            // Peeking returns immediately while any data is available, so wait a moment for the rest.
+            self.retry = Some(Box::pin(time::sleep(PREFACE_POLL_INTERVAL)));
+        }
+    }
+}
+
+impl Future for PlainConnection {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<()> {
+        let this = self.get_mut();
+
+        let is_http2 = match this.poll_preface(cx) {
+            Poll::Ready(is_http2) => is_http2,
+            Poll::Pending if expired(&mut this.deadline, cx) => {
+                if !this.received {
+                    debug!("connection from {} sent nothing before the timeout", this.remote_addr);
+                    return Poll::Ready(());
+                }
+                false
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+
+        let stream = this.stream.take().unwrap();
+
+        if is_http2 {
+            tokio::spawn(http2::serve(stream, this.remote_addr, false, &this.accept));
+        } else {
+            hand_over(stream, this.remote_addr, &this.sender, HttpStream);
+        }
+
+        Poll::Ready(())
+    }
+}
+
+
+/// A TLS connection in the middle of the handshake, after which ALPN tells whether to serve HTTP/2 or HTTP/1.
+struct TlsConnection {
+    handshake: tokio_rustls::Accept<TcpStream>,
+    remote_addr: SocketAddr,
+    deadline: Pin<Box<Sleep>>,
+    accept: Accept,
+    sender: Sender<TlsStream>,
+}
+
+impl Future for TlsConnection {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<()> {
+        let this = self.get_mut();
+
+        let stream = match Pin::new(&mut this.handshake).poll(cx) {
+            Poll::Ready(Ok(stream)) => stream,
+            Poll::Ready(Err(e)) => {
+                debug!("TLS handshake with {} failed: {}", this.remote_addr, e);
+                return Poll::Ready(());
+            }
+            Poll::Pending if expired(&mut this.deadline, cx) => {
+                debug!("TLS handshake with {} timed out", this.remote_addr);
+                return Poll::Ready(());
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+
+        if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+            tokio::spawn(http2::serve(stream, this.remote_addr, true, &this.accept));
+        } else {
+            let (stream, connection) = stream.into_inner();
+            hand_over(stream, this.remote_addr, &this.sender, |socket| TlsStream::new(connection, HttpStream(socket)));
+        }
+
+        Poll::Ready(())
+    }
+}
+
+
+/// A listener for hyper that receives connections accepted elsewhere.
+pub struct ChannelListener<S> {
+    receiver: Arc<Mutex<Receiver<S>>>,
+    local_addr: SocketAddr,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl<S> ChannelListener<S> {
+    fn new(receiver: Receiver<S>, local_addr: SocketAddr) -> Self {
+        Self {
+            receiver: Arc::new(Mutex::new(receiver)),
+            local_addr,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+}
+
+impl<S> Clone for ChannelListener<S> {
+    fn clone(&self) -> Self {
+        Self {
+            receiver: self.receiver.clone(),
+            local_addr: self.local_addr,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+        }
+    }
+}
+
+impl<S: NetworkStream + Send + Clone> NetworkListener for ChannelListener<S> {
+    type Stream = S;
+
+    fn accept(&mut self) -> hyper::Result<S> {
+        let stream = self.receiver.lock().unwrap().recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "listener has shut down"))?;
+
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+
+        Ok(stream)
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn set_read_timeout(&mut self, duration: Option<Duration>) {
+        self.read_timeout = duration;
+    }
+
+    fn set_write_timeout(&mut self, duration: Option<Duration>) {
+        self.write_timeout = duration;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use engine::IngotEngine;
+    use h2::client;
+    use http;
+    use ingots::blocking::BlockingPool;
+    use rustls::{ClientConnection, StreamOwned};
+    use rustls::pki_types::ServerName;
+    use std::convert::TryFrom;
+    use std::io::{Read, Write};
+    use std::thread;
+    use tls::TlsServer;
+    use tls::tests::{client_config, config};
+    use tokio::runtime;
+    use super::*;
+
+    fn runtime() -> Runtime {
+        runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap()
+    }
+
+    fn accept(http2: bool) -> (net::TcpListener, Accept) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let engine = Arc::new(IngotEngine::new(Arc::new(BlockingPool::new(1, 0))));
+
+        let accept = Accept {
+            handler: Handler::new(engine, listener.local_addr().unwrap(), None, 0),
+            http2,
+            timeout: Duration::from_millis(200),
+            max_streams: 1,
+        };
+
+        (listener, accept)
+    }
+
+    fn connect(addr: SocketAddr) -> net::TcpStream {
+        let socket = net::TcpStream::connect(addr).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket
+    }
+
+    /// Read from a connection handed over to the HTTP/1 threads, which should start with everything the client sent.
+    fn read_handed_over<S: Read>(mut stream: S, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    /// Check that the server answers the preface with a SETTINGS frame, as an HTTP/2 server does.
+    fn assert_http2<S: Read + Write>(mut stream: S) {
+        stream.write_all(PREFACE).unwrap();
+        stream.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).unwrap();
+        stream.flush().unwrap();
+
+        let mut header = [0; 9];
+        stream.read_exact(&mut header).unwrap();
+        assert_eq!(header[3], 4, "expected a SETTINGS frame");
+    }
+
+    #[test]
+    fn prior_knowledge() {
+        let runtime = runtime();
+        let (listener, accept) = accept(true);
+        let addr = listener.local_addr().unwrap();
+        let _connections = accept_plain(&runtime, listener, accept).unwrap();
+
+        let socket = runtime.block_on(TcpStream::connect(addr)).unwrap();
+        let (client, connection) = runtime.block_on(client::handshake(socket)).unwrap();
+        runtime.spawn(connection);
+
+        let mut client = runtime.block_on(client.ready()).unwrap();
+        let request = http::Request::get("http://localhost/").body(()).unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+
+        // No ingots are registered, so the request is answered by the server itself.
+        assert_eq!(runtime.block_on(response).unwrap().status(), 404);
+    }
+
+    #[test]
+    fn split_preface() {
+        let runtime = runtime();
+        let (listener, accept) = accept(true);
+        let addr = listener.local_addr().unwrap();
+        let _connections = accept_plain(&runtime, listener, accept).unwrap();
+
+        let mut socket = connect(addr);
+        socket.write_all(&PREFACE[..10]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        socket.write_all(&PREFACE[10..]).unwrap();
+        socket.write_all(&[0, 0, 0, 4, 0, 0, 0, 0, 0]).unwrap();
+
+        let mut header = [0; 9];
+        socket.read_exact(&mut header).unwrap();
+        assert_eq!(header[3], 4, "expected a SETTINGS frame");
+    }
+
+    #[test]
+    fn http1_is_handed_over() {
+        let runtime = runtime();
+
+        for &http2 in &[true, false] {
+            let (listener, accept) = accept(http2);
+            let addr = listener.local_addr().unwrap();
+            let mut connections = accept_plain(&runtime, listener, accept).unwrap();
+
+            // The start of the preface is also a valid HTTP/1 request line until it differs.
+            for &request in &[&b"GET / HTTP/1.1\r\n\r\n"[..], b"PRI / HTTP/1.1\r\n\r\n"] {
+                let mut socket = connect(addr);
+                socket.write_all(request).unwrap();
+
+                let stream = connections.accept().unwrap();
+                assert_eq!(read_handed_over(stream, request.len()), request);
+            }
+        }
+    }
+
+    #[test]
+    fn http2_disabled() {
+        let runtime = runtime();
+        let (listener, accept) = accept(false);
+        let addr = listener.local_addr().unwrap();
+        let mut connections = accept_plain(&runtime, listener, accept).unwrap();
+
+        let mut socket = connect(addr);
+        socket.write_all(PREFACE).unwrap();
+
+        let stream = connections.accept().unwrap();
+        assert_eq!(read_handed_over(stream, PREFACE.len()), PREFACE);
+    }
+
+    #[test]
+    fn silent_connection_is_closed() {
+        let runtime = runtime();
+        let (listener, accept) = accept(true);
+        let addr = listener.local_addr().unwrap();
+        let _connections = accept_plain(&runtime, listener, accept).unwrap();
+
+        let mut socket = connect(addr);
+        assert_eq!(socket.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn alpn_selects_protocol() {
+        let runtime = runtime();
+        let (listener, accept) = accept(true);
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsServer::new(&config(), true).unwrap().acceptor();
+        let mut connections = accept_tls(&runtime, listener, acceptor, accept).unwrap();
+
+        let tls_connect = |alpn: &[&[u8]]| {
+            let server_name = ServerName::try_from("localhost").unwrap();
+            let connection = ClientConnection::new(client_config(alpn), server_name).unwrap();
+            StreamOwned::new(connection, connect(addr))
+        };
+
+        assert_http2(tls_connect(&[b"h2", b"http/1.1"]));
+
+        for &alpn in &[&[&b"http/1.1"[..]][..], &[]] {
+            let mut client = tls_connect(alpn);
+            client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+            let stream = connections.accept().unwrap();
+            assert_eq!(read_handed_over(stream, 18), b"GET / HTTP/1.1\r\n\r\n");
+        }
+    }
+}
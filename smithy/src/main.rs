@@ -1,26 +1,92 @@
+extern crate bytes;
+extern crate h2;
+extern crate http;
 extern crate hyper;
 extern crate ingots;
 extern crate ingots_loader;
 #[macro_use]
 extern crate log;
+extern crate rustls;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate signal_hook;
 extern crate simplelog;
+extern crate tokio;
+extern crate tokio_rustls;
 extern crate toml;
 
 mod config;
 mod context;
 mod engine;
+mod http2;
+mod listener;
 mod server;
+mod tls;
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+
+const USAGE: &str = "Usage: smithy [--config <path>]
+
+Options:
+    -c, --config <path>    Path to the configuration file [default: smithy.toml]
+    -h, --help             Print this help message";
 
 
 fn main() {
     let _ = simplelog::SimpleLogger::init(log::LogLevelFilter::Debug, simplelog::Config::default());
 
-    let mut config = config::ServerConfig::default();
-    config.add_location(config::Location {
-        prefix: String::from("/"),
-        ingot: "../target/debug/examples/libhello-2f78a9a51e5e3b87.so".into(),
-    });
+    let config_path = match parse_args(env::args().skip(1)) {
+        Ok(path) => path,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let config = match config::ServerConfig::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut server = match server::Server::new(config) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = server.listen() {
+        error!("server error: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Parse the command line arguments, returning the path to the configuration file.
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<PathBuf, String> {
+    let mut config_path = PathBuf::from("smithy.toml");
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => match args.next() {
+                Some(path) => config_path = path.into(),
+                None => return Err(format!("missing value for {}", arg)),
+            },
+            _ if arg.starts_with("--config=") => config_path = arg["--config=".len()..].into(),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => return Err(format!("unexpected argument: {}", arg)),
+        }
+    }
 
-    let mut server = server::Server::new(config);
-    server.listen();
+    Ok(config_path)
 }
@@ -1,69 +1,322 @@
+use bytes::Bytes;
 use config::*;
-use context::ServerContext;
-use engine::IngotEngine;
-use ingots::Ingot;
+use context::{ServerContext, UpgradeLimit};
+use engine::{self, IngotEngine, Instance, Route};
+use h2::RecvStream;
+use h2::server::SendResponse;
+use http;
+use http2::{self, Http2Context};
+use hyper;
+use hyper::method::Method;
+use hyper::net::NetworkListener;
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
 use hyper::server::Server as HttpServer;
 use hyper::server::Request as HttpRequest;
 use hyper::server::Response as HttpResponse;
 use hyper::server::Handler as HttpHandler;
-use std::net::SocketAddr;
+use ingots::{AsyncIngot, HandleFuture, Ingot};
+use ingots::blocking::BlockingPool;
+use ingots_loader;
+use listener::{self, Accept};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::future::{self, Future};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::pin::Pin;
+use std::process;
+use std::str;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use tls::TlsServer;
+use tokio::runtime;
 
 
 pub struct Server {
     config: ServerConfig,
-    server: Option<HttpServer>,
+    engine: Arc<IngotEngine>,
 }
 
 impl Server {
-    pub fn new(config: ServerConfig) -> Self {
-        Self {
-            config: config,
-            server: Some(HttpServer::http("0.0.0.0:8001").unwrap()),
+    /// Create a server for the given configuration, loading the ingot for every location.
+    pub fn new(config: ServerConfig) -> Result<Self, ingots_loader::Error> {
+        let mut engine = IngotEngine::new(Arc::new(BlockingPool::new(config.threads, config.max_queued)));
+
+        for location in config.locations.iter() {
+            engine.register(location.host.as_deref(), location.prefix.clone(), &location.methods, &location.target)?;
         }
+
+        Ok(Self {
+            config,
+            engine: Arc::new(engine),
+        })
     }
 
-    pub fn listen(&mut self) {
-        let local_addr = self.server.as_mut().unwrap().local_addr().unwrap();
+    /// Bind to the configured addresses and serve requests until the server exits.
+    ///
+    /// If TLS is enabled, an HTTPS listener is started next to the plain HTTP listener, each with its own threads for
+    /// HTTP/1 connections. Connections are accepted, and HTTP/2 connections served, on a shared runtime; ingots handle
+    /// HTTP/2 requests on a pool of threads shared by both listeners, so idle streams do not hold a thread.
+    pub fn listen(&mut self) -> hyper::Result<()> {
+        let runtime = runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("smithy-runtime")
+            .build()?;
+
+        let listener = TcpListener::bind((self.config.host.as_str(), self.config.port))?;
+        let local_addr = listener.local_addr()?;
+        let redirect = self.config.tls.as_ref().filter(|tls| tls.redirect).map(|tls| tls.port);
+        let handler = Handler::new(self.engine.clone(), local_addr, redirect, self.config.max_upgrades);
+
+        let connections = listener::accept_plain(&runtime, listener, self.accept(&handler))?;
+        let mut server = HttpServer::new(connections);
+        self.configure(&mut server);
+        info!("Listening on {} with {} threads", local_addr, self.config.threads);
+
+        let _tls_listening = match self.config.tls {
+            Some(ref tls) => {
+                let tls_server = TlsServer::new(tls, self.config.http2).map_err(io::Error::other)?;
+                let listener = TcpListener::bind((self.config.host.as_str(), tls.port))?;
+                let local_addr = listener.local_addr()?;
+                let handler = Handler::new(self.engine.clone(), local_addr, None, self.config.max_upgrades);
+
+                let connections = listener::accept_tls(&runtime, listener, tls_server.acceptor(), self.accept(&handler))?;
+                let mut server = HttpServer::new(connections);
+                self.configure(&mut server);
+                info!("Listening for HTTPS on {} with {} threads", local_addr, self.config.threads);
+
+                Some(server.handle_threads(handler, self.config.threads)?)
+            }
+            None => None,
+        };
+
+        if let Some(interval) = self.config.reload_interval {
+            IngotEngine::watch(self.engine.clone(), interval);
+        }
+
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        let engine = self.engine.clone();
+        let drain_timeout = self.config.drain_timeout;
+
+        thread::spawn(move || {
+            if signals.forever().next().is_some() {
+                info!("Shutting down, waiting up to {:?} for requests to complete", drain_timeout);
+                engine.shutdown(drain_timeout);
+                process::exit(0);
+            }
+        });
+
+        let _listening = server.handle_threads(handler, self.config.threads)?;
 
-        let listener = self.server.take()
-            .unwrap()
-            .handle(Handler::new(local_addr, self.config.clone()))
-            .unwrap();
+        // Dropping the listening guards blocks until the server stops.
+        Ok(())
     }
 
+    fn accept(&self, handler: &Handler) -> Accept {
+        Accept {
+            handler: handler.clone(),
+            http2: self.config.http2,
+            timeout: self.config.handshake_timeout,
+            max_streams: self.config.threads.min(u32::MAX as usize) as u32,
+        }
+    }
+
+    fn configure<L: NetworkListener>(&self, server: &mut HttpServer<L>) {
+        server.keep_alive(self.config.keep_alive);
+        server.set_read_timeout(self.config.read_timeout);
+        server.set_write_timeout(self.config.write_timeout);
+    }
 }
 
-struct Handler {
-    config: ServerConfig,
-    engine: IngotEngine,
+/// Routes requests to ingots, for both HTTP/1 and HTTP/2 connections on a listener.
+#[derive(Clone)]
+pub struct Handler {
+    engine: Arc<IngotEngine>,
     local_addr: SocketAddr,
+    /// Port of the HTTPS listener to redirect every request to, if enabled.
+    redirect: Option<u16>,
+    /// Connections taken over by ingots on the listener, each of which holds one of its threads.
+    upgrades: Arc<UpgradeLimit>,
 }
 
 impl Handler {
-    fn new(local_addr: SocketAddr, config: ServerConfig) -> Handler {
-        let mut engine = IngotEngine::new();
+    /// Create a handler for a listener bound to `local_addr`, redirecting every request to the HTTPS listener on
+    /// `redirect` if given, and allowing at most `max_upgrades` upgraded connections at once.
+    pub fn new(engine: Arc<IngotEngine>, local_addr: SocketAddr, redirect: Option<u16>, max_upgrades: usize) -> Self {
+        Self {
+            engine,
+            local_addr,
+            redirect,
+            upgrades: UpgradeLimit::new(max_upgrades),
+        }
+    }
 
-        for location in config.locations.iter() {
-            engine.register(location.prefix.clone(), &location.ingot);
+    /// Get the URL of the given path over HTTPS on the given port.
+    fn redirect_location(&self, host: Option<&str>, path: &str, port: u16) -> String {
+        let host = host
+            .map(|host| engine::strip_port(host).to_owned())
+            .unwrap_or_else(|| match self.local_addr {
+                SocketAddr::V4(addr) => addr.ip().to_string(),
+                SocketAddr::V6(addr) => format!("[{}]", addr.ip()),
+            });
+
+        match port {
+            443 => format!("https://{}{}", host, path),
+            port => format!("https://{}:{}{}", host, port, path),
+        }
+    }
+
+    /// Redirect a request to the same URL over HTTPS.
+    fn redirect(&self, request: HttpRequest, mut response: HttpResponse, port: u16) {
+        let host = request.headers.get_raw("Host")
+            .and_then(|values| values.first())
+            .and_then(|value| str::from_utf8(value).ok());
+        let path = match request.uri {
+            RequestUri::AbsolutePath(ref path) => path.as_str(),
+            _ => "/",
+        };
+
+        // 308 keeps the method and body of requests other than GET and HEAD.
+        *response.status_mut() = match request.method {
+            Method::Get | Method::Head => StatusCode::MovedPermanently,
+            _ => StatusCode::PermanentRedirect,
+        };
+        response.headers_mut().set_raw("Location", vec![self.redirect_location(host, path, port).into_bytes()]);
+        let _ = response.send(b"");
+    }
+
+    /// Handle a request received on an HTTP/2 stream, returning a future that resolves once the request is complete.
+    ///
+    /// Called on the runtime; the ingot itself is run on the engine's blocking pool.
+    pub fn handle_http2(
+        &self,
+        request: http::Request<RecvStream>,
+        respond: SendResponse<Bytes>,
+        remote_addr: SocketAddr,
+        secure: bool,
+    ) -> HandleFuture {
+        info!("{} {} HTTP/2", request.method(), request.uri());
+
+        let host = request.uri().authority().map(|authority| authority.as_str())
+            .or_else(|| request.headers().get(http::header::HOST).and_then(|value| value.to_str().ok()))
+            .map(str::to_owned);
+        let host = host.as_deref();
+
+        if let Some(port) = self.redirect {
+            let path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+            let status = match request.method() {
+                &http::Method::GET | &http::Method::HEAD => 301,
+                _ => 308,
+            };
+
+            http2::send_empty(respond, status, &[("location", &self.redirect_location(host, path, port))]);
+            return Box::pin(future::ready(()));
         }
 
-        Handler {
-            config: config,
-            engine: engine,
-            local_addr: local_addr,
+        match self.engine.route(host, request.method().as_str(), request.uri().path()) {
+            Route::Found(route) => {
+                let context = Http2Context::new(
+                    self.local_addr,
+                    remote_addr,
+                    secure,
+                    route.context_path,
+                    request,
+                    respond,
+                );
+
+                return Box::pin(InService {
+                    future: route.ingot.handle(Box::new(context)),
+                    _instance: route.ingot,
+                });
+            }
+            Route::MethodNotAllowed(allowed) => http2::send_empty(respond, 405, &[("allow", &allowed.join(", "))]),
+            Route::NotFound => http2::send_empty(respond, 404, &[]),
         }
+
+        Box::pin(future::ready(()))
     }
 }
 
 impl HttpHandler for Handler {
-    fn handle<'a, 'b>(&'a self, request: HttpRequest<'a, 'b>, response: HttpResponse<'a>) {
+    fn handle<'a, 'b>(&'a self, request: HttpRequest<'a, 'b>, mut response: HttpResponse<'a>) {
         info!("{} {}", request.method, request.uri);
 
-        let url = request.uri.to_string();
-        let mut context = ServerContext::new(self.local_addr.clone(), request, response);
+        if let Some(port) = self.redirect {
+            return self.redirect(request, response, port);
+        }
+
+        let route = {
+            let host = request.headers.get_raw("Host")
+                .and_then(|values| values.first())
+                .and_then(|value| str::from_utf8(value).ok());
+            let path = match request.uri {
+                RequestUri::AbsolutePath(ref path) => path.split('?').next().unwrap_or("/"),
+                _ => "/",
+            };
+
+            self.engine.route(host, request.method.as_ref(), path)
+        };
 
-        if let Some(ingot) = self.engine.find_ingot_for_url(&url) {
-            ingot.handle(&mut context);
+        match route {
+            Route::Found(route) => {
+                let mut context =
+                    ServerContext::new(self.local_addr, route.context_path, request, response, &self.upgrades);
+                route.ingot.get_ref().handle(&mut context);
+
+                if let Err(e) = context.finish() {
+                    warn!("failed to send response: {}", e);
+                }
+            }
+            Route::MethodNotAllowed(allowed) => {
+                *response.status_mut() = StatusCode::MethodNotAllowed;
+                response.headers_mut().set_raw("Allow", vec![allowed.join(", ").into_bytes()]);
+                let _ = response.send(b"");
+            }
+            Route::NotFound => {
+                *response.status_mut() = StatusCode::NotFound;
+                let _ = response.send(b"");
+            }
         }
     }
 }
+
+/// Future of a request handed to an ingot, which keeps the ingot's instance from being unloaded until it resolves.
+struct InService {
+    future: HandleFuture,
+    _instance: Instance,
+}
+
+impl Future for InService {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler(local_addr: &str) -> Handler {
+        let engine = Arc::new(IngotEngine::new(Arc::new(BlockingPool::new(1, 0))));
+        Handler::new(engine, local_addr.parse().unwrap(), Some(443), 0)
+    }
+
+    #[test]
+    fn redirect_location() {
+        let v4 = handler("192.0.2.1:80");
+
+        assert_eq!(v4.redirect_location(Some("example.com"), "/a?b=c", 443), "https://example.com/a?b=c");
+        assert_eq!(v4.redirect_location(Some("example.com:8080"), "/", 443), "https://example.com/");
+        assert_eq!(v4.redirect_location(Some("example.com:8080"), "/", 8443), "https://example.com:8443/");
+        assert_eq!(v4.redirect_location(Some("[::1]:8080"), "/", 8443), "https://[::1]:8443/");
+        assert_eq!(v4.redirect_location(None, "/", 443), "https://192.0.2.1/");
+
+        let v6 = handler("[2001:db8::1]:80");
+        assert_eq!(v6.redirect_location(None, "/", 8443), "https://[2001:db8::1]:8443/");
+    }
+}
@@ -0,0 +1,477 @@
+//! TLS termination for HTTPS listeners.
+//!
+//! The handshake is performed by the listener before the protocol is known; see `listener`. The certificate is chosen by
+//! the server name the client sends in the handshake (SNI), falling back to the default certificate.
+use config::{CertificatePaths, TlsConfig};
+use hyper::net::{HttpStream, NetworkStream};
+use rustls::{ServerConfig, ServerConnection};
+use rustls::crypto::ring as provider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_rustls::TlsAcceptor;
+
+
+/// Server side TLS settings, shared by all connections to an HTTPS listener.
+#[derive(Clone)]
+pub struct TlsServer {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsServer {
+    /// Load the certificates and keys named in the configuration.
+    ///
+    /// HTTP/2 is offered to clients through ALPN if `http2` is true.
+    pub fn new(config: &TlsConfig, http2: bool) -> Result<Self, Error> {
+        let default = match config.default {
+            Some(ref paths) => Some(load_certified_key(paths)?),
+            None => None,
+        };
+
+        let mut hosts = HashMap::new();
+        for (host, paths) in config.hosts.iter() {
+            hosts.insert(host.to_ascii_lowercase(), load_certified_key(paths)?);
+        }
+
+        let resolver = CertResolver {
+            default,
+            hosts,
+        };
+
+        let mut server_config = ServerConfig::builder_with_provider(Arc::new(provider::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::Tls(e.to_string()))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        if http2 {
+            server_config.alpn_protocols.push(b"h2".to_vec());
+        }
+        server_config.alpn_protocols.push(b"http/1.1".to_vec());
+
+        Ok(Self {
+            config: Arc::new(server_config),
+        })
+    }
+
+    /// Get an acceptor that performs the handshake for incoming connections.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.clone())
+    }
+}
+
+
+/// A TLS connection accepted by an HTTPS listener, served over HTTP/1 by hyper.
+///
+/// hyper clones the stream to read requests and write responses, and an upgraded connection may be read by one thread
+/// while another writes to it. The clones share the connection state, which is only locked while records are encrypted
+/// or decrypted; waiting on the socket happens outside the lock, so a blocked read never holds up a write.
+#[derive(Clone)]
+pub struct TlsStream {
+    socket: HttpStream,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    connection: Mutex<ServerConnection>,
+    /// Held for the whole of a read, so that records are decrypted in the order they arrive.
+    reading: Mutex<()>,
+    /// Held for the whole of a write, so that records are sent in the order they were encrypted.
+    writing: Mutex<()>,
+}
+
+/// Maximum amount of ciphertext read from the socket at once. This is no larger than the buffer rustls keeps for
+/// received plaintext, so that reading it can never overflow the buffer.
+const READ_SIZE: usize = 16 * 1024;
+
+impl TlsStream {
+    /// Wrap a connection that has completed the handshake on the given socket.
+    pub fn new(connection: ServerConnection, socket: HttpStream) -> Self {
+        Self {
+            socket,
+            shared: Arc::new(Shared {
+                connection: Mutex::new(connection),
+                reading: Mutex::new(()),
+                writing: Mutex::new(()),
+            }),
+        }
+    }
+
+    /// Send any records the connection has queued. The caller must hold the write lock.
+    fn send_queued(&self) -> io::Result<()> {
+        let mut records = Vec::new();
+        {
+            let mut connection = self.shared.connection.lock().unwrap();
+            while connection.wants_write() {
+                connection.write_tls(&mut records)?;
+            }
+        }
+
+        (&self.socket.0).write_all(&records)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let _reading = self.shared.reading.lock().unwrap();
+        let mut records = [0; READ_SIZE];
+
+        loop {
+            match self.shared.connection.lock().unwrap().reader().read(buf) {
+                // The client closing the connection without a close_notify alert is common, and harmless for HTTP,
+                // since messages carry their own length.
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            let len = (&self.socket.0).read(&mut records)?;
+            let mut received = &records[..len];
+
+            let (result, wants_write) = {
+                let mut connection = self.shared.connection.lock().unwrap();
+                let mut result = Ok(());
+
+                // An empty read tells the connection that the client closed the socket.
+                loop {
+                    if let Err(e) = connection.read_tls(&mut received) {
+                        result = Err(e);
+                        break;
+                    }
+                    if let Err(e) = connection.process_new_packets() {
+                        result = Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                        break;
+                    }
+                    if received.is_empty() {
+                        break;
+                    }
+                }
+
+                (result, connection.wants_write())
+            };
+
+            // Processing records may have queued alerts or handshake messages for the client. The connection lock is
+            // released first, since writers take it while holding the write lock.
+            if wants_write {
+                let _writing = self.shared.writing.lock().unwrap();
+                let sent = self.send_queued();
+                result.and(sent)?;
+            } else {
+                result?;
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _writing = self.shared.writing.lock().unwrap();
+        let len = self.shared.connection.lock().unwrap().writer().write(buf)?;
+        self.send_queued()?;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let _writing = self.shared.writing.lock().unwrap();
+        self.shared.connection.lock().unwrap().writer().flush()?;
+        self.send_queued()?;
+
+        (&self.socket.0).flush()
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    fn set_read_timeout(&self, duration: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(duration)
+    }
+
+    fn set_write_timeout(&self, duration: Option<Duration>) -> io::Result<()> {
+        self.socket.set_write_timeout(duration)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        {
+            let _writing = self.shared.writing.lock().unwrap();
+            self.shared.connection.lock().unwrap().send_close_notify();
+            let _ = self.send_queued();
+        }
+
+        self.socket.close(how)
+    }
+}
+
+
+/// Chooses the certificate for a connection from the server name sent by the client.
+#[derive(Debug)]
+struct CertResolver {
+    default: Option<Arc<CertifiedKey>>,
+    /// Certificates by lowercase host name. A name starting with `*.` matches any single subdomain.
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    /// Get the certificate for the given server name, if there is one.
+    fn select(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let name = server_name.map(str::to_ascii_lowercase);
+
+        name.and_then(|name| {
+            self.hosts.get(&name).or_else(|| {
+                let parent = name.split_once('.')?.1;
+                self.hosts.get(&format!("*.{}", parent))
+            }).cloned()
+        }).or_else(|| self.default.clone())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.select(client_hello.server_name())
+    }
+}
+
+fn load_certified_key(paths: &CertificatePaths) -> Result<Arc<CertifiedKey>, Error> {
+    let certificates = CertificateDer::pem_file_iter(&paths.certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::Load(paths.certificate.clone(), e.to_string()))?;
+
+    if certificates.is_empty() {
+        return Err(Error::Load(paths.certificate.clone(), String::from("no certificates found")));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(&paths.key)
+        .map_err(|e| Error::Load(paths.key.clone(), e.to_string()))?;
+
+    let key = provider::default_provider().key_provider.load_private_key(key)
+        .map_err(|e| Error::Load(paths.key.clone(), e.to_string()))?;
+
+    let certified_key = CertifiedKey::new(certificates, key);
+    certified_key.keys_match().map_err(|e| Error::Load(paths.key.clone(), e.to_string()))?;
+
+    Ok(Arc::new(certified_key))
+}
+
+
+/// An error setting up TLS.
+#[derive(Debug)]
+pub enum Error {
+    /// A certificate or key file could not be loaded.
+    Load(PathBuf, String),
+    Tls(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Load(ref path, ref message) => write!(f, "failed to load {:?}: {}", path, message),
+            Error::Tls(ref message) => write!(f, "TLS error: {}", message),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+
+#[cfg(test)]
+pub mod tests {
+    use config::{CertificatePaths, TlsConfig};
+    use hyper::net::HttpStream;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConnection, StreamOwned};
+    use rustls::crypto::ring as provider;
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use rustls::pki_types::pem::PemObject;
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+
+    fn paths(name: &str) -> CertificatePaths {
+        let directory = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata");
+
+        CertificatePaths {
+            certificate: directory.join(format!("{}.pem", name)),
+            key: directory.join(format!("{}.key", name)),
+        }
+    }
+
+    pub fn config() -> TlsConfig {
+        TlsConfig {
+            port: 443,
+            default: Some(paths("localhost")),
+            hosts: vec![
+                (String::from("Example.com"), paths("example.com")),
+                (String::from("*.example.org"), paths("wildcard.example.org")),
+            ],
+            redirect: false,
+        }
+    }
+
+    fn certificate(name: &str) -> CertificateDer<'static> {
+        CertificateDer::from_pem_file(paths(name).certificate).unwrap()
+    }
+
+    /// Get the name of the certificate the resolver selects for a server name.
+    fn select(resolver: &CertResolver, server_name: Option<&str>) -> Option<&'static str> {
+        let selected = resolver.select(server_name)?;
+
+        ["localhost", "example.com", "wildcard.example.org"].iter().cloned()
+            .find(|&name| selected.cert[0] == certificate(name))
+    }
+
+    #[test]
+    fn certificate_selection() {
+        let mut hosts = HashMap::new();
+        hosts.insert(String::from("example.com"), load_certified_key(&paths("example.com")).unwrap());
+        hosts.insert(String::from("*.example.org"), load_certified_key(&paths("wildcard.example.org")).unwrap());
+
+        let mut resolver = CertResolver {
+            default: Some(load_certified_key(&paths("localhost")).unwrap()),
+            hosts,
+        };
+
+        assert_eq!(select(&resolver, Some("example.com")), Some("example.com"));
+        assert_eq!(select(&resolver, Some("EXAMPLE.com")), Some("example.com"));
+        assert_eq!(select(&resolver, Some("www.example.org")), Some("wildcard.example.org"));
+        // A wildcard only matches a single label.
+        assert_eq!(select(&resolver, Some("a.b.example.org")), Some("localhost"));
+        assert_eq!(select(&resolver, Some("example.org")), Some("localhost"));
+        assert_eq!(select(&resolver, Some("www.example.com")), Some("localhost"));
+        assert_eq!(select(&resolver, None), Some("localhost"));
+
+        resolver.default = None;
+        assert_eq!(select(&resolver, Some("www.example.com")), None);
+        assert_eq!(select(&resolver, None), None);
+    }
+
+    #[test]
+    fn mismatched_key() {
+        let paths = CertificatePaths {
+            key: paths("localhost").key,
+            ..paths("example.com")
+        };
+
+        assert!(matches!(load_certified_key(&paths), Err(Error::Load(ref path, _)) if *path == paths.key));
+    }
+
+    /// Get the settings for a client that trusts the test certificates and offers the given protocols with ALPN.
+    pub fn client_config(alpn: &[&[u8]]) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add(certificate("ca")).unwrap();
+
+        let mut config = ClientConfig::builder_with_provider(Arc::new(provider::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+        Arc::new(config)
+    }
+
+    /// Connect a client to a server configured with `config()`, returning both ends of the connection.
+    fn connect(
+        server_name: &str,
+        http2: bool,
+        alpn: &[&[u8]],
+    ) -> (StreamOwned<ClientConnection, TcpStream>, TlsStream) {
+        let server = TlsServer::new(&config(), http2).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let accepting = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let mut connection = ServerConnection::new(server.config.clone()).unwrap();
+            while connection.is_handshaking() {
+                connection.complete_io(&mut socket).unwrap();
+            }
+
+            TlsStream::new(connection, HttpStream(socket))
+        });
+
+        let server_name = ServerName::try_from(server_name.to_owned()).unwrap();
+        let mut connection = ClientConnection::new(client_config(alpn), server_name).unwrap();
+        let mut socket = TcpStream::connect(addr).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        while connection.is_handshaking() {
+            connection.complete_io(&mut socket).unwrap();
+        }
+
+        (StreamOwned::new(connection, socket), accepting.join().unwrap())
+    }
+
+    #[test]
+    fn handshake() {
+        let (mut client, mut server) = connect("www.example.org", false, &[]);
+
+        assert_eq!(client.conn.peer_certificates().unwrap()[0], certificate("wildcard.example.org"));
+        assert_eq!(client.conn.alpn_protocol(), None);
+
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        server.write_all(b"pong").unwrap();
+        server.flush().unwrap();
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // Closing sends close_notify, which the client sees as a clean end of the stream.
+        server.close(Shutdown::Both).unwrap();
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn alpn() {
+        let (client, _server) = connect("example.com", true, &[b"h2", b"http/1.1"]);
+        assert_eq!(client.conn.alpn_protocol(), Some(&b"h2"[..]));
+
+        let (client, _server) = connect("example.com", false, &[b"h2", b"http/1.1"]);
+        assert_eq!(client.conn.alpn_protocol(), Some(&b"http/1.1"[..]));
+    }
+
+    #[test]
+    fn write_while_reading() {
+        let (mut client, server) = connect("example.com", false, &[]);
+
+        // One clone blocks waiting for the client, as an upgraded connection does.
+        let mut reader = server.clone();
+        let reading = thread::spawn(move || {
+            let mut buf = [0; 4];
+            reader.read_exact(&mut buf).unwrap();
+            buf
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        // The other clone can still write while the read is waiting.
+        let mut writer = server.clone();
+        writer.write_all(b"ping").unwrap();
+        writer.flush().unwrap();
+
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        client.write_all(b"pong").unwrap();
+        assert_eq!(&reading.join().unwrap(), b"pong");
+    }
+}
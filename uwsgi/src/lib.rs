@@ -0,0 +1,44 @@
+//! Running an ingot as a server for the uwsgi protocol, the native protocol of uWSGI.
+//!
+//! The web server opens a connection for each request, sends a packet with the CGI meta-variables followed by the
+//! request body, and reads an HTTP response until the connection is closed. Only regular requests, with `modifier1` set
+//! to 0, are accepted; `modifier2` is ignored.
+extern crate ingots;
+extern crate ingots_cgi;
+#[macro_use]
+extern crate log;
+
+pub mod protocol;
+
+use ingots::http::StatusCode;
+use ingots_cgi::server;
+use std::io::Read;
+
+
+/// Wraps a Rust ingot in a uwsgi server.
+///
+/// See `ingots_cgi::server::Server` for how connections are handled and the server is shut down.
+pub type Server<I> = server::Server<Uwsgi, I>;
+
+/// The uwsgi protocol, which answers with an HTTP response.
+pub struct Uwsgi;
+
+impl server::Protocol for Uwsgi {
+    const NON_PARSED_HEADERS: bool = true;
+
+    fn read_request<R: Read>(stream: &mut R) -> Result<Vec<(String, String)>, StatusCode> {
+        let packet = match protocol::read_packet(stream) {
+            Ok(packet) => packet,
+            Err(_) => return Err(400),
+        };
+
+        // Other values of modifier1 ask for a different kind of request, such as a PSGI or Rack request, or a command
+        // for uWSGI itself.
+        if packet.modifier1 != 0 {
+            debug!("rejecting packet with modifier1 {}", packet.modifier1);
+            return Err(501);
+        }
+
+        Ok(packet.vars)
+    }
+}
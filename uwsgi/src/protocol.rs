@@ -0,0 +1,67 @@
+//! Reading uwsgi request packets.
+//!
+//! Every packet starts with a four byte header: `modifier1`, the size of the packet body as a little-endian 16-bit
+//! integer, and `modifier2`. A request packet carries the CGI meta-variables of the request, each encoded as a
+//! little-endian 16-bit size followed by that many bytes, the name followed by the value. The request body follows the
+//! packet.
+use std::io::{self, Read};
+
+
+/// A request packet.
+#[derive(Clone, Debug)]
+pub struct Packet {
+    /// Selects the request handler in uWSGI itself. Web servers send 0 for a regular request unless configured
+    /// otherwise.
+    pub modifier1: u8,
+    pub modifier2: u8,
+    /// Meta-variables of the request, in the order they were sent.
+    pub vars: Vec<(String, String)>,
+}
+
+/// Read a request packet.
+///
+/// Nothing past the end of the packet is read, so the body can be read from the same stream afterwards.
+pub fn read_packet<R: Read>(reader: &mut R) -> io::Result<Packet> {
+    let mut header = [0; 4];
+    reader.read_exact(&mut header)?;
+
+    let size = u16::from_le_bytes([header[1], header[2]]) as usize;
+    let mut data = vec![0; size];
+    reader.read_exact(&mut data)?;
+
+    let mut data = &data[..];
+    let mut vars = Vec::new();
+
+    while !data.is_empty() {
+        let name = read_string(&mut data)?;
+        let value = read_string(&mut data)?;
+        vars.push((name, value));
+    }
+
+    Ok(Packet {
+        modifier1: header[0],
+        modifier2: header[3],
+        vars,
+    })
+}
+
+/// Read a size-prefixed string from the packet body.
+fn read_string(data: &mut &[u8]) -> io::Result<String> {
+    if data.len() < 2 {
+        return Err(invalid("truncated variable size"));
+    }
+
+    let size = u16::from_le_bytes([data[0], data[1]]) as usize;
+    if data.len() < 2 + size {
+        return Err(invalid("variable extends past the end of the packet"));
+    }
+
+    let string = String::from_utf8_lossy(&data[2..2 + size]).into_owned();
+    *data = &data[2 + size..];
+
+    Ok(string)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid uwsgi packet: {}", message))
+}